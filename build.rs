@@ -0,0 +1,34 @@
+//! Build details for /api/version, read back with env! in src/version.rs
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn main() {
+    // Outside of a checkout, such as a crate tarball, the commit is left empty
+    let commit = output("git", &["rev-parse", "HEAD"]).unwrap_or_default();
+    let dirty = !commit.is_empty() && output("git", &["status", "--porcelain", "--untracked-files=no"]).is_some_and(|status| !status.is_empty());
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default());
+    let rustc = output(&std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()), &["--version"]).unwrap_or_default();
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(|feature| feature.to_lowercase().replace('_', "-")))
+        // Implied by the others
+        .filter(|feature| feature != "default")
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=BUILD_GIT_DIRTY={}", dirty);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rustc-env=BUILD_RUSTC_VERSION={}", rustc);
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+    for path in [".git/HEAD", ".git/index", "src", "ui", "flashforge-protocol/src", "Cargo.toml", "build.rs"] {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
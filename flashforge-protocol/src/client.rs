@@ -0,0 +1,433 @@
+//! Request/response exchanges over any connection to the printer's API port ([API_PORT]).
+//!
+//! Commands are only answered once the printer was sent [PrinterRequest::ControlMessage] on the connection, which
+//! expects [PrinterRequest::ReleaseControl] before it is closed. Some firmwares answer read-only queries
+//! ([PrinterRequest::is_read_only]) without taking control, so they keep working while another client such as
+//! FlashPrint has it. The whole response is read before returning, so the connection can be used for the next request.
+//!
+//! Printers also send lines on their own, such as diagnostics while the connection is kept open. The lines before
+//! a response's echo ("CMD M119 Received.") are not part of it, and are kept for [AsyncClient::take_unsolicited].
+//! Lines sent between the echo and the final "ok" can't be told apart from the response
+use std::fmt::{Display, Formatter};
+#[cfg(any(feature = "sync", feature = "async"))]
+use log::debug;
+#[cfg(any(feature = "sync", feature = "async"))]
+use crate::socket::{PrinterRequest, PrinterResponse, RESPONSE_END};
+
+/// The TCP port printers listen on for G-code
+pub const API_PORT: u16 = 8899;
+
+/// Why an exchange failed
+#[derive(Debug)]
+pub enum ClientError {
+    /// The request could not be written in time
+    WriteTimeout,
+    /// The printer stopped sending before the end of the response
+    ReadTimeout,
+    /// Reading or writing failed
+    Io(std::io::Error),
+    /// The printer closed the connection before the end of the response
+    Closed,
+    /// The response was read, but is not what the request returns
+    InvalidResponse(String),
+    /// The printer answered a query with only the echo and "ok", as some do right after booting
+    EmptyResponse,
+    /// The response was read, but is not text
+    Undecodable(ParseError)
+}
+
+/// A response that is not UTF-8, such as the noise some printers send right after booting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// The first [ParseError::KEPT_BYTES] bytes of the response
+    pub bytes: Vec<u8>,
+    /// Length of the whole response
+    pub len: usize
+}
+
+impl ParseError {
+    /// Bytes of the response kept for the message, the whole response is only logged
+    pub const KEPT_BYTES: usize = 64;
+
+    fn new(response: &[u8]) -> Self {
+        Self { bytes: response[..response.len().min(Self::KEPT_BYTES)].to_vec(), len: response.len() }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let hex: Vec<String> = self.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        write!(f, "{} bytes that are not text, starting with {}", self.len, hex.join(" "))
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::WriteTimeout => write!(f, "write timed out"),
+            ClientError::ReadTimeout => write!(f, "read timed out"),
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Closed => write!(f, "connection closed by printer"),
+            ClientError::InvalidResponse(e) => write!(f, "invalid response: {}", e),
+            ClientError::EmptyResponse => write!(f, "empty response"),
+            ClientError::Undecodable(e) => write!(f, "invalid response: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => ClientError::ReadTimeout,
+            _ => ClientError::Io(e)
+        }
+    }
+}
+
+#[cfg(any(feature = "sync", feature = "async"))]
+fn parse(request: &PrinterRequest, text: &str) -> Result<PrinterResponse, ClientError> {
+    request.parse_response(text).map_err(ClientError::InvalidResponse)
+}
+
+/// Takes the response to the request out of the bytes received once its final "ok" arrived, along with the lines
+/// before its echo. Responses without an echo are taken whole. Responses that are not text are logged and are an
+/// error along with the lines before them, as they can't be told apart
+#[cfg(any(feature = "sync", feature = "async"))]
+fn take_response(received: &mut Vec<u8>, request: &PrinterRequest) -> Option<Result<(Vec<String>, String), ClientError>> {
+    // "ok" on its own line, lines such as "Level ok" don't end the response
+    let end = received.windows(RESPONSE_END.len()).enumerate()
+        .position(|(start, window)| window == RESPONSE_END && (start == 0 || received[start - 1] == b'\n'))? + RESPONSE_END.len();
+    let bytes: Vec<u8> = received.drain(..end).collect();
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => {
+            debug!("response to {} is not text:\n{}", request.get_gcode(), hex_dump(e.as_bytes()));
+            return Some(Err(ClientError::Undecodable(ParseError::new(e.as_bytes()))));
+        }
+    };
+    let gcode = request.get_gcode();
+    let echo = format!("CMD {} Received", gcode.trim_start_matches('~').split_whitespace().next().unwrap_or_default());
+    let start = text.match_indices(&echo).map(|(start, _)| start)
+        .find(|&start| start == 0 || text[..start].ends_with('\n'))
+        .unwrap_or(0);
+    let response = &text[start..];
+    if request.is_empty_response(response) {
+        return Some(Err(ClientError::EmptyResponse));
+    }
+    Some(Ok((lines(&text[..start]), response.to_string())))
+}
+
+/// 16 bytes per line with their offset, and the printable ones as ASCII
+#[cfg(any(feature = "sync", feature = "async"))]
+fn hex_dump(bytes: &[u8]) -> String {
+    bytes.chunks(16).enumerate().map(|(line, chunk)| {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = chunk.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+        format!("{:08x}  {:<47}  {}", line * 16, hex.join(" "), ascii)
+    }).collect::<Vec<_>>().join("\n")
+}
+
+/// Complete lines of the text, without their line endings and leaving out empty ones
+#[cfg(any(feature = "sync", feature = "async"))]
+fn lines(text: &str) -> Vec<String> {
+    text.lines().map(str::trim_end).filter(|line| !line.is_empty()).map(str::to_string).collect()
+}
+
+/// Blocking client, over a [std::net::TcpStream] or anything else that reads and writes.
+/// Timeouts are those of the stream, such as [std::net::TcpStream::set_read_timeout]
+#[cfg(feature = "sync")]
+pub struct Client<S> {
+    stream: S,
+    /// Received but not part of a response yet
+    received: Vec<u8>,
+    unsolicited: Vec<String>
+}
+
+#[cfg(feature = "sync")]
+impl<S: std::io::Read + std::io::Write> Client<S> {
+    /// A client over the connection, which should already be open
+    pub fn new(stream: S) -> Self {
+        Self { stream, received: Vec::new(), unsolicited: Vec::new() }
+    }
+
+    /// The connection, to close it
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Sends the request and returns the whole response, up to the final "ok"
+    pub fn send(&mut self, request: &PrinterRequest) -> Result<String, ClientError> {
+        self.stream.write_all(request.get_instruction().as_bytes()).map_err(|e| match e.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => ClientError::WriteTimeout,
+            _ => ClientError::Io(e)
+        })?;
+        let mut buf = [0; 1024];
+        loop {
+            if let Some(taken) = take_response(&mut self.received, request) {
+                let (unsolicited, response) = taken?;
+                self.unsolicited.extend(unsolicited);
+                return Ok(response);
+            }
+            let n = self.stream.read(&mut buf)?;
+            if n == 0 {
+                return Err(ClientError::Closed);
+            }
+            self.received.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Lines the printer sent before the responses, since the last call
+    pub fn take_unsolicited(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.unsolicited)
+    }
+
+    /// Sends the request and parses the response
+    pub fn request(&mut self, request: &PrinterRequest) -> Result<PrinterResponse, ClientError> {
+        let text = self.send(request)?;
+        parse(request, &text)
+    }
+}
+
+/// Tokio client, over a tokio TcpStream or anything else that reads and writes asynchronously
+#[cfg(feature = "async")]
+pub struct AsyncClient<S> {
+    stream: S,
+    write_timeout: Option<std::time::Duration>,
+    read_timeout: Option<std::time::Duration>,
+    /// Received but not part of a response yet
+    received: Vec<u8>,
+    unsolicited: Vec<String>,
+    /// Something arrived since the last request was written
+    answered: bool
+}
+
+#[cfg(feature = "async")]
+impl<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin> AsyncClient<S> {
+    /// A client without timeouts
+    pub fn new(stream: S) -> Self {
+        Self { stream, write_timeout: None, read_timeout: None, received: Vec::new(), unsolicited: Vec::new(), answered: false }
+    }
+
+    /// Fails writing the request after write, and reading after the printer sent nothing for read
+    pub fn with_timeouts(self, write: std::time::Duration, read: std::time::Duration) -> Self {
+        Self { write_timeout: Some(write), read_timeout: Some(read), ..self }
+    }
+
+    /// The connection, to close it
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Sends the request and returns the whole response, up to the final "ok"
+    pub async fn send(&mut self, request: &PrinterRequest) -> Result<String, ClientError> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        async fn timeout<T>(duration: Option<std::time::Duration>, error: ClientError, future: impl std::future::Future<Output = std::io::Result<T>>) -> Result<T, ClientError> {
+            match duration {
+                Some(duration) => tokio::time::timeout(duration, future).await.map_err(|_| error)?.map_err(ClientError::Io),
+                None => future.await.map_err(ClientError::Io)
+            }
+        }
+        let instruction = request.get_instruction();
+        self.answered = false;
+        timeout(self.write_timeout, ClientError::WriteTimeout, self.stream.write_all(instruction.as_bytes())).await?;
+        // Read the whole response, anything left over would be read as the answer to the next request
+        let mut buf = [0; 1024];
+        loop {
+            if let Some(taken) = take_response(&mut self.received, request) {
+                let (unsolicited, response) = taken?;
+                self.unsolicited.extend(unsolicited);
+                return Ok(response);
+            }
+            let n = timeout(self.read_timeout, ClientError::ReadTimeout, self.stream.read(&mut buf)).await?;
+            if n == 0 {
+                return Err(ClientError::Closed);
+            }
+            self.answered = true;
+            self.received.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Whether any of the response to the last request arrived. A connection that failed before that was most likely
+    /// closed by the printer while idle, without it seeing the request
+    pub fn was_answered(&self) -> bool {
+        self.answered
+    }
+
+    /// Waits without a timeout for the printer to send something while no request is in flight, keeping the complete
+    /// lines for [AsyncClient::take_unsolicited]. Can be cancelled without losing what was received
+    pub async fn read_unsolicited(&mut self) -> Result<(), ClientError> {
+        use tokio::io::AsyncReadExt;
+        let mut buf = [0; 1024];
+        let n = self.stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(ClientError::Closed);
+        }
+        self.received.extend_from_slice(&buf[..n]);
+        if let Some(end) = self.received.iter().rposition(|byte| *byte == b'\n') {
+            let text = String::from_utf8_lossy(&self.received.drain(..=end).collect::<Vec<u8>>()).into_owned();
+            self.unsolicited.extend(lines(&text));
+        }
+        Ok(())
+    }
+
+    /// Lines the printer sent on its own, before the responses or while waiting in [AsyncClient::read_unsolicited],
+    /// since the last call
+    pub fn take_unsolicited(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.unsolicited)
+    }
+
+    /// Sends the request and parses the response
+    pub async fn request(&mut self, request: &PrinterRequest) -> Result<PrinterResponse, ClientError> {
+        let text = self.send(request).await?;
+        parse(request, &text)
+    }
+}
+
+#[cfg(all(test, any(feature = "sync", feature = "async")))]
+mod tests {
+    use super::*;
+
+    const M105: &str = "CMD M105 Received.\r\nT0:210/210 B:60/60\r\nok\r\n";
+
+    /// Reads tests/fixtures/<name>.txt, which may not be text, with the CRLF line endings of the printer
+    #[cfg(feature = "async")]
+    fn fixture_bytes(name: &str) -> Vec<u8> {
+        let path = format!("{}/tests/fixtures/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+        let contents = std::fs::read(&path).unwrap_or_else(|e| panic!("missing fixture {}: {}", path, e));
+        contents.split(|&byte| byte == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line)).collect::<Vec<_>>().join(&b"\r\n"[..])
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn sync_client_reads_the_whole_response() {
+        /// Answers with the response in two reads, keeping what was written
+        struct Printer {
+            response: std::io::Cursor<Vec<u8>>,
+            written: Vec<u8>
+        }
+        impl std::io::Read for Printer {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(20);
+                self.response.read(&mut buf[..len])
+            }
+        }
+        impl std::io::Write for Printer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.written.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut client = Client::new(Printer { response: std::io::Cursor::new(M105.as_bytes().to_vec()), written: Vec::new() });
+        let Ok(PrinterResponse::PrinterTemperature(temps)) = client.request(&PrinterRequest::GetTemperature) else { panic!("expected temperatures") };
+        assert_eq!(temps.0["T0"].current, 210.0);
+        assert!(matches!(client.send(&PrinterRequest::GetStatus), Err(ClientError::Closed)));
+        assert_eq!(client.into_inner().written, b"~M105\r\n~M119\r\n");
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_client_reads_the_whole_response() {
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (stream, mut printer) = tokio::io::duplex(64);
+        let mut client = AsyncClient::new(stream).with_timeouts(Duration::from_secs(1), Duration::from_millis(100));
+        tokio::spawn(async move {
+            let mut line = [0; 7];
+            printer.read_exact(&mut line).await.unwrap();
+            assert_eq!(&line, b"~M105\r\n");
+            let (first, rest) = M105.split_at(20);
+            printer.write_all(first.as_bytes()).await.unwrap();
+            printer.write_all(rest.as_bytes()).await.unwrap();
+            // Keeps the connection open without answering
+            printer.read_exact(&mut line).await.ok();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+        assert_eq!(client.send(&PrinterRequest::GetTemperature).await.unwrap(), M105);
+        assert!(matches!(client.send(&PrinterRequest::GetStatus).await, Err(ClientError::ReadTimeout)));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn unsolicited_lines_are_kept_apart() {
+        use tokio::io::AsyncWriteExt;
+
+        let (stream, mut printer) = tokio::io::duplex(256);
+        let mut client = AsyncClient::new(stream);
+        printer.write_all(b"Heating done\r\nNozzle ").await.unwrap();
+        client.read_unsolicited().await.unwrap();
+        assert_eq!(client.take_unsolicited(), ["Heating done"]);
+
+        // The partial line and the one sent before the echo are not part of the response
+        printer.write_all(format!("clogged\r\n\r\nLevel ok\r\n{}CMD M119 Received.", M105).as_bytes()).await.unwrap();
+        assert_eq!(client.send(&PrinterRequest::GetTemperature).await.unwrap(), M105);
+        assert_eq!(client.take_unsolicited(), ["Nozzle clogged", "Level ok"]);
+        // What came after the response's ok is kept for the next one
+        printer.write_all(b"\r\nStatus: S:1\r\nok\r\n").await.unwrap();
+        assert_eq!(client.send(&PrinterRequest::GetStatus).await.unwrap(), "CMD M119 Received.\r\nStatus: S:1\r\nok\r\n");
+        assert!(client.take_unsolicited().is_empty());
+
+        drop(printer);
+        assert!(matches!(client.read_unsolicited().await, Err(ClientError::Closed)));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn closed_connections_tell_whether_the_request_was_answered() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (stream, mut printer) = tokio::io::duplex(64);
+        let mut client = AsyncClient::new(stream);
+        tokio::spawn(async move {
+            let mut line = [0; 7];
+            printer.read_exact(&mut line).await.unwrap();
+            // Hangs up halfway through the answer
+            printer.write_all(b"CMD M119 Received.\r\n").await.unwrap();
+        });
+        assert!(matches!(client.send(&PrinterRequest::GetStatus).await, Err(ClientError::Closed)));
+        assert!(client.was_answered());
+        // Writing to the closed connection fails
+        assert!(matches!(client.send(&PrinterRequest::GetStatus).await, Err(ClientError::Io(_))));
+        assert!(!client.was_answered());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn empty_and_undecodable_responses_are_errors() {
+        use tokio::io::AsyncWriteExt;
+
+        let (stream, mut printer) = tokio::io::duplex(1024);
+        let mut client = AsyncClient::new(stream);
+        printer.write_all(&fixture_bytes("M105_empty")).await.unwrap();
+        assert!(matches!(client.send(&PrinterRequest::GetTemperature).await, Err(ClientError::EmptyResponse)));
+        // Commands are answered with nothing
+        printer.write_all(b"CMD M146 Received.\r\nok\r\n").await.unwrap();
+        client.send(&PrinterRequest::SetLed(true)).await.unwrap();
+
+        let garbled = fixture_bytes("M105_garbled");
+        printer.write_all(&garbled).await.unwrap();
+        let Err(ClientError::Undecodable(e)) = client.send(&PrinterRequest::GetTemperature).await else { panic!("expected an undecodable response") };
+        assert_eq!((e.bytes.as_slice(), e.len), (&garbled[..ParseError::KEPT_BYTES], garbled.len()));
+        assert!(e.to_string().starts_with(&format!("{} bytes that are not text, starting with 43 4d 44 20", garbled.len())), "{}", e);
+        // The whole response was read, so the connection can be used for the next one
+        printer.write_all(M105.as_bytes()).await.unwrap();
+        assert_eq!(client.send(&PrinterRequest::GetTemperature).await.unwrap(), M105);
+    }
+
+    #[test]
+    fn hex_dumps_show_offsets_and_ascii() {
+        let dump = hex_dump(b"CMD M105 Received.\r\n\xff\xfe");
+        assert_eq!(dump, "00000000  43 4d 44 20 4d 31 30 35 20 52 65 63 65 69 76 65  CMD M105 Receive\n\
+                          00000010  64 2e 0d 0a ff fe                                d.....");
+    }
+
+    #[test]
+    fn unexpected_responses_are_errors() {
+        assert!(matches!(parse(&PrinterRequest::GetInfo, "CMD M115 Received.\r\nok\r\n"), Err(ClientError::InvalidResponse(_))));
+    }
+}
@@ -0,0 +1,34 @@
+//! The TCP protocol of FlashForge printers (Adventurer 3, 4 and 5M, Finder, Creator), without a server.
+//!
+//! Requests are G-code lines sent to port 8899, answered with `CMD <gcode> Received.` and lines of
+//! "key: value" up to a final "ok":
+//!
+//! ```no_run
+//! # #[cfg(feature = "sync")] {
+//! use flashforge_protocol::{Client, PrinterRequest, PrinterResponse, API_PORT};
+//!
+//! let mut client = Client::new(std::net::TcpStream::connect(("10.0.0.50", API_PORT)).unwrap());
+//! client.send(&PrinterRequest::ControlMessage).unwrap();
+//! if let Ok(PrinterResponse::PrinterStatus(status)) = client.request(&PrinterRequest::GetStatus) {
+//!     println!("{}", status.machine_status);
+//! }
+//! client.send(&PrinterRequest::ReleaseControl).unwrap();
+//! # }
+//! ```
+//!
+//! The `sync` feature, on by default, provides [Client] for blocking connections and the `async` feature
+//! `AsyncClient` for tokio ones
+#![warn(missing_docs)]
+
+pub mod client;
+pub mod models;
+pub mod parse;
+pub mod profile;
+pub mod socket;
+
+#[cfg(feature = "async")]
+pub use client::AsyncClient;
+#[cfg(feature = "sync")]
+pub use client::Client;
+pub use client::{ClientError, ParseError, API_PORT};
+pub use socket::{PrinterRequest, PrinterResponse, RESPONSE_END};
@@ -0,0 +1,427 @@
+//! What the printer reports, as parsed by [crate::PrinterRequest::parse_response]. Everything serializes
+//! to and deserializes from the JSON of flashforge-api-server's API
+use std::collections::{BTreeMap, HashMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::profile::Capabilities;
+
+/// Build volume of M115, in mm
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Dimensions {
+    /// Width
+    pub x: i32,
+    /// Depth
+    pub y: i32,
+    /// Height
+    pub z: i32
+}
+
+/// Endstops of M119, 1 when triggered
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EndStopPosition {
+    /// X axis at its maximum
+    pub x_max: i32,
+    /// Y axis at its maximum
+    pub y_max: i32,
+    /// Z axis at its minimum
+    pub z_min: i32
+}
+
+/// One sensor of M105, in Celsius unless converted with [TemperatureMeasurement::in_unit]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TemperatureMeasurement {
+    /// 0 when the heater is off
+    pub target: f32,
+    /// Measured now
+    pub current: f32
+}
+
+impl TemperatureMeasurement {
+    /// Converts from the printer's Celsius, Fahrenheit is rounded to one decimal
+    pub fn in_unit(&self, unit: TemperatureUnit) -> Self {
+        let convert = |celsius: f32| match unit {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => ((celsius * 9.0 / 5.0 + 32.0) * 10.0).round() / 10.0
+        };
+        Self { target: convert(self.target), current: convert(self.current) }
+    }
+}
+
+/// Unit of temperatures, serialized as C or F
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum TemperatureUnit {
+    /// What the printer reports
+    #[default]
+    #[serde(rename = "C")]
+    Celsius,
+    /// Converted with [TemperatureMeasurement::in_unit]
+    #[serde(rename = "F")]
+    Fahrenheit
+}
+
+impl TemperatureUnit {
+    /// Parses c or f, or the unit's full name, in any case
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "c" | "celsius" => Some(TemperatureUnit::Celsius),
+            "f" | "fahrenheit" => Some(TemperatureUnit::Fahrenheit),
+            _ => None
+        }
+    }
+}
+
+/// Response to requests that only change something, such as M104
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ControlSuccess {
+    /// Always true, failures are errors instead
+    pub success: bool
+}
+
+/// M115's machine info
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterInfo {
+    /// Name set on the printer
+    pub name: String,
+    /// Firmware version, such as "v2.4.5"
+    pub firmware_version: String,
+    /// Serial number
+    pub sn: String,
+    /// Number of extruders
+    pub tool_count: u8,
+    /// Machine type, such as "Flashforge Adventurer 5M Pro"
+    pub model_name: String,
+    /// MAC address of the network interface
+    pub mac_addr: String,
+    /// Largest print the machine takes, M115's X, Y and Z
+    pub build_volume: Dimensions,
+    /// What the model supports, from its [crate::profile::ModelProfile]
+    pub capabilities: Capabilities
+}
+
+/// M114's head position
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterHeadPosition {
+    /// Head X position in mm
+    pub x: f32,
+    /// Head Y position in mm
+    pub y: f32,
+    /// Bed Z position in mm
+    pub z: f32,
+    /// Extruder position
+    pub a: f32,
+    /// Second extruder position
+    pub b: u32
+}
+
+/// M105's sensors by the name the printer reported, such as T0 or B
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterTemperature(pub HashMap<String, TemperatureMeasurement>);
+
+impl PrinterTemperature {
+    /// Maps FlashForge sensor names to extruders (T0, T1, ...), bed (B) and chamber (C).
+    /// Sensors that aren't recognized are kept in raw
+    pub fn normalize(&self) -> NormalizedTemperature {
+        let mut extruders = Vec::new();
+        let mut normalized = NormalizedTemperature::default();
+        for (key, measurement) in &self.0 {
+            let key = key.trim();
+            match key {
+                "B" => normalized.bed = Some(measurement.clone()),
+                "C" => normalized.chamber = Some(measurement.clone()),
+                _ => match key.strip_prefix('T').and_then(|index| index.parse::<usize>().ok()) {
+                    Some(index) => extruders.push((index, measurement.clone())),
+                    None => { normalized.raw.insert(key.to_string(), measurement.clone()); }
+                }
+            }
+        }
+        extruders.sort_by_key(|(index, _)| *index);
+        normalized.extruders = extruders.into_iter().map(|(_, measurement)| measurement).collect();
+        normalized
+    }
+
+    /// Every sensor converted with [TemperatureMeasurement::in_unit]
+    pub fn in_unit(&self, unit: TemperatureUnit) -> Self {
+        PrinterTemperature(self.0.iter().map(|(key, measurement)| (key.clone(), measurement.in_unit(unit))).collect())
+    }
+}
+
+/// Temperatures by what they measure, see [PrinterTemperature::normalize]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct NormalizedTemperature {
+    /// Ordered by tool index
+    pub extruders: Vec<TemperatureMeasurement>,
+    /// Heated bed, if any
+    pub bed: Option<TemperatureMeasurement>,
+    /// Heated chamber, if any
+    pub chamber: Option<TemperatureMeasurement>,
+    /// Sensors that could not be mapped, keyed by the name the printer reported
+    pub raw: HashMap<String, TemperatureMeasurement>
+}
+
+/// Done out of total, of M27's n/n
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Progress {
+    /// Done so far
+    pub current: u32,
+    /// 0 when nothing is being printed
+    pub total: u32
+}
+
+impl Progress {
+    /// All of a non-zero total is done
+    pub fn is_done(&self) -> bool {
+        self.total > 0 && self.current >= self.total
+    }
+
+    /// current out of total as a percentage, None when the total is 0
+    pub fn percent(&self) -> Option<u8> {
+        (self.total > 0).then(|| (self.current as u64 * 100 / self.total as u64).min(100) as u8)
+    }
+}
+
+/// M27's progress of the current print
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterProgress {
+    /// Layers printed, 0 of 0 for models that only report bytes
+    pub layer: Progress,
+    /// Bytes of the file
+    pub byte: Progress
+}
+
+/// MachineStatus reported by M119. Serialized as the printer's original string
+#[derive(Debug, Clone, PartialEq)]
+pub enum MachineStatus {
+    /// Idle
+    Ready,
+    /// Printing, on the Adventurer 5M
+    Building,
+    /// Printing a file from its storage
+    BuildingFromSd,
+    /// Finished a print
+    BuildingCompleted,
+    /// Print paused
+    Paused,
+    /// Homing, leveling or another operation
+    Busy,
+    /// A fault, such as a filament runout or thermal error, as named by the firmware
+    Error(String),
+    /// Any other status, as named by the firmware
+    Unknown(String)
+}
+
+impl MachineStatus {
+    /// Parses M119's MachineStatus, statuses naming an error, fault or failure are [MachineStatus::Error]
+    pub fn parse(status: &str) -> Self {
+        let status = status.trim();
+        match status {
+            "READY" => MachineStatus::Ready,
+            "BUILDING" => MachineStatus::Building,
+            "BUILDING_FROM_SD" => MachineStatus::BuildingFromSd,
+            "BUILDING_COMPLETED" => MachineStatus::BuildingCompleted,
+            "PAUSED" => MachineStatus::Paused,
+            "BUSY" => MachineStatus::Busy,
+            // Firmwares report faults with a few different names (filament runout, thermal error, ...)
+            s if ["ERROR", "FAULT", "FAIL"].iter().any(|e| s.to_uppercase().contains(e)) => MachineStatus::Error(s.to_string()),
+            s => MachineStatus::Unknown(s.to_string())
+        }
+    }
+
+    /// The printer's original string
+    pub fn as_str(&self) -> &str {
+        match self {
+            MachineStatus::Ready => "READY",
+            MachineStatus::Building => "BUILDING",
+            MachineStatus::BuildingFromSd => "BUILDING_FROM_SD",
+            MachineStatus::BuildingCompleted => "BUILDING_COMPLETED",
+            MachineStatus::Paused => "PAUSED",
+            MachineStatus::Busy => "BUSY",
+            MachineStatus::Error(s) | MachineStatus::Unknown(s) => s
+        }
+    }
+
+    /// Printer is actively printing, paused prints are not counted
+    pub fn is_printing(&self) -> bool {
+        matches!(self, MachineStatus::Building | MachineStatus::BuildingFromSd)
+    }
+
+    /// Printer is not doing anything and can start a new print
+    pub fn is_idle(&self) -> bool {
+        matches!(self, MachineStatus::Ready | MachineStatus::BuildingCompleted)
+    }
+
+    /// Printer reported a fault
+    pub fn is_error(&self) -> bool {
+        matches!(self, MachineStatus::Error(_))
+    }
+}
+
+impl std::fmt::Display for MachineStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for MachineStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for MachineStatus {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|status| MachineStatus::parse(&status))
+    }
+}
+
+/// MoveMode reported by M119. Serialized as the printer's original string
+#[derive(Debug, Clone, PartialEq)]
+pub enum MoveMode {
+    /// Not moving
+    Ready,
+    /// Moving to print
+    Moving,
+    /// Stopped for a paused print
+    Paused,
+    /// Homing the axes
+    Homing,
+    /// Waiting for the extruder to heat
+    WaitOnTool,
+    /// Any other mode, as named by the firmware
+    Unknown(String)
+}
+
+impl MoveMode {
+    /// Parses M119's MoveMode
+    pub fn parse(mode: &str) -> Self {
+        match mode.trim() {
+            "READY" => MoveMode::Ready,
+            "MOVING" => MoveMode::Moving,
+            "PAUSED" => MoveMode::Paused,
+            "HOMING" => MoveMode::Homing,
+            "WAIT_ON_TOOL" => MoveMode::WaitOnTool,
+            s => MoveMode::Unknown(s.to_string())
+        }
+    }
+
+    /// The printer's original string
+    pub fn as_str(&self) -> &str {
+        match self {
+            MoveMode::Ready => "READY",
+            MoveMode::Moving => "MOVING",
+            MoveMode::Paused => "PAUSED",
+            MoveMode::Homing => "HOMING",
+            MoveMode::WaitOnTool => "WAIT_ON_TOOL",
+            MoveMode::Unknown(s) => s
+        }
+    }
+}
+
+impl Serialize for MoveMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for MoveMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|mode| MoveMode::parse(&mode))
+    }
+}
+
+/// The "Status: S:1 L:0 J:0 F:0" line of M119
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct StatusFlags {
+    /// F:1, the filament sensor finds no filament
+    pub filament_runout: bool,
+    /// Every flag by its letter as sent, F included. What S, L and J stand for is not known
+    pub raw: BTreeMap<String, u8>
+}
+
+impl StatusFlags {
+    /// Parses the value of the line, "S:1 L:0 J:0 F:0". Flags that aren't numbers are left out
+    pub fn parse(flags: &str) -> Self {
+        let raw: BTreeMap<String, u8> = flags.split_whitespace()
+            .filter_map(|flag| flag.split_once(':'))
+            .filter_map(|(letter, value)| Some((letter.to_string(), value.parse().ok()?)))
+            .collect();
+        Self { filament_runout: raw.get("F").is_some_and(|f| *f == 1), raw }
+    }
+}
+
+/// M119's status
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterStatus {
+    /// Endstops triggered
+    pub end_stop: EndStopPosition,
+    /// What the printer is doing
+    pub machine_status: MachineStatus,
+    /// What the motion system is doing
+    pub move_mode: MoveMode,
+    /// The Status line, None if the printer left it out
+    pub flags: Option<StatusFlags>,
+    /// Whether the light is on, None for models without one
+    pub led: Option<bool>,
+    /// File being printed, None when idle
+    pub current_file: Option<String>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_temperatures_to_fahrenheit() {
+        let measurement = TemperatureMeasurement { target: 0.0, current: 23.45 }.in_unit(TemperatureUnit::Fahrenheit);
+        assert_eq!((measurement.target, measurement.current), (32.0, 74.2));
+        let measurement = TemperatureMeasurement { target: 60.0, current: 23.45 }.in_unit(TemperatureUnit::Celsius);
+        assert_eq!((measurement.target, measurement.current), (60.0, 23.45));
+        assert_eq!(TemperatureUnit::from_name("F"), Some(TemperatureUnit::Fahrenheit));
+        assert_eq!(TemperatureUnit::from_name("k"), None);
+    }
+
+    fn round_trip<T: Serialize + for<'de> Deserialize<'de> + PartialEq + std::fmt::Debug>(value: T) {
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<T>(&json).unwrap(), value, "{}", json);
+    }
+
+    #[test]
+    fn models_round_trip_through_json() {
+        let measurement = TemperatureMeasurement { target: 210.0, current: 205.5 };
+        round_trip(ControlSuccess { success: true });
+        round_trip(PrinterInfo {
+            name: "My Printer".to_string(),
+            firmware_version: "v1.3.7".to_string(),
+            sn: "SNADVA9501234".to_string(),
+            tool_count: 1,
+            model_name: "FlashForge Adventurer III".to_string(),
+            mac_addr: "88:A9:A7:10:2F:C3".to_string(),
+            build_volume: Dimensions { x: 150, y: 150, z: 150 },
+            capabilities: Capabilities { led: true, camera: false, layer_progress: true }
+        });
+        round_trip(PrinterHeadPosition { x: 10.5, y: -20.0, z: 0.2, a: 1.5, b: 0 });
+        round_trip(PrinterTemperature(HashMap::from([("T0".to_string(), measurement.clone()), ("B".to_string(), measurement.clone())])));
+        round_trip(NormalizedTemperature { extruders: vec![measurement.clone()], bed: Some(measurement), chamber: None, raw: HashMap::new() });
+        round_trip(TemperatureUnit::Fahrenheit);
+        round_trip(PrinterProgress { layer: Progress { current: 12, total: 60 }, byte: Progress { current: 2400, total: 12000 } });
+        round_trip(PrinterStatus {
+            end_stop: EndStopPosition { x_max: 0, y_max: 0, z_min: 1 },
+            machine_status: MachineStatus::Error("THERMAL_ERROR".to_string()),
+            move_mode: MoveMode::WaitOnTool,
+            flags: Some(StatusFlags::parse("S:1 L:0 J:0 F:1")),
+            led: None,
+            current_file: Some("cube.gx".to_string())
+        });
+        round_trip(MachineStatus::BuildingFromSd);
+        round_trip(MoveMode::Unknown("LEVELING".to_string()));
+    }
+
+    #[test]
+    fn progress_is_named() {
+        let progress = PrinterProgress { layer: Progress { current: 12, total: 60 }, byte: Progress::default() };
+        assert_eq!(serde_json::to_value(&progress).unwrap(), serde_json::json!({
+            "layer": {"current": 12, "total": 60},
+            "byte": {"current": 0, "total": 0}
+        }));
+        assert_eq!(progress.layer.percent(), Some(20));
+        assert_eq!(progress.byte.percent(), None);
+    }
+}
@@ -0,0 +1,72 @@
+//! The "key: value" lines most responses are made of
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use log::{debug, trace, warn};
+use regex::Regex;
+
+static RE_KV: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"([a-zA-Z0-9\-]+):\s*([^:\s]+)").unwrap());
+
+/// Parses a line packing several values, "key1:val1 key2: val2"
+pub fn parse_multi_line(input: &str) -> HashMap<String, String> {
+    RE_KV.captures_iter(input)
+        .map(|cap| (cap[1].to_string(), cap[2].to_string()))
+        .collect()
+}
+
+/// Parses a whole response into its keys and values, logging the lines that could not be parsed
+pub fn parse_kv(content: &str) -> Result<HashMap<String, String>, String> {
+    let (kv, warnings) = parse_kv_with_warnings(content);
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+    Ok(kv)
+}
+
+/// Parses the response like [parse_kv], returning the lines that could not be parsed instead of logging them
+pub fn parse_kv_with_warnings(content: &str) -> (HashMap<String, String>, Vec<String>) {
+    trace!("parsing: {:?}", content);
+    let mut kv = HashMap::new();
+    let mut warnings = Vec::new();
+    // Skip first line ("CMD <GCODE> Received\r\n"), rest should be kv
+    for line in content.lines().skip(1) {
+        if line == "ok" {
+            debug!("kv: {:?}", kv);
+            return (kv, warnings);
+        }
+        let Some((key, val)) = line.split_once(':') else {
+            warnings.push(format!("Invalid line: {}", line));
+            continue;
+        };
+        match key.trim() {
+            // "X: 150 Y: 150 Z: 150" of M115 and "X:10.5 Y:-20 Z:0.2 A:123 B:0" of M114
+            "X" => kv.extend(parse_multi_line(line)),
+            // "Endstop: X-max:0 Y-max:0 Z-min:0"
+            "Endstop" => kv.extend(parse_multi_line(val)),
+            // Anything else is one value, which can contain spaces and colons
+            key => {
+                kv.insert(key.to_string(), val.trim().to_string());
+            }
+        }
+    }
+    warnings.push("end of data, but did not see \"ok\"".to_string());
+    (kv, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kv_values_keep_spaces_and_colons() {
+        let kv = parse_kv("CMD M115 Received.\r\nMachine Type: Flashforge Adventurer 5M Pro\r\nFirmware: v2.4.5-ML:2023\r\nX: 220 Y: 220 Z: 220\r\nMac Address:88:A9:A7:91:25:05\r\nok\r\n").unwrap();
+        assert_eq!(kv["Machine Type"], "Flashforge Adventurer 5M Pro");
+        assert_eq!(kv["Firmware"], "v2.4.5-ML:2023");
+        assert_eq!(kv["Mac Address"], "88:A9:A7:91:25:05");
+        assert_eq!((kv["X"].as_str(), kv["Y"].as_str(), kv["Z"].as_str()), ("220", "220", "220"));
+
+        let kv = parse_kv("CMD M119 Received.\r\nEndstop: X-max: 1 Y-max:0 Z-min:0\r\nCurrentFile: calibration cube.gx\r\nok\r\n").unwrap();
+        assert_eq!(kv["X-max"], "1");
+        assert_eq!(kv["Z-min"], "0");
+        assert_eq!(kv["CurrentFile"], "calibration cube.gx");
+    }
+}
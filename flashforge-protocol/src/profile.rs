@@ -0,0 +1,98 @@
+//! What each printer model supports, picked from the Machine Type of M115.
+//!
+//! Adding a model is an entry in [PROFILES], along with fixtures of its responses in tests/fixtures/<model>
+use serde::{Deserialize, Serialize};
+
+/// What the model can do, and so which fields its responses have
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Capabilities {
+    /// Has a light, reported by M119's LED line and set with M146
+    pub led: bool,
+    /// Has a camera streaming MJPEG on port 8080
+    pub camera: bool,
+    /// M27 reports the layers printed next to the bytes
+    pub layer_progress: bool
+}
+
+/// How a model's firmware differs from the others
+#[derive(Debug, PartialEq)]
+pub struct ModelProfile {
+    /// Name of the profile, such as "adventurer3"
+    pub id: &'static str,
+    /// Machine Types of M115 this profile is for, matched case-insensitively anywhere in it
+    pub machine_types: &'static [&'static str],
+    /// What the model supports
+    pub capabilities: Capabilities
+}
+
+/// Models that are not in [PROFILES] are expected to support everything
+pub const DEFAULT_PROFILE: ModelProfile = ModelProfile {
+    id: "default",
+    machine_types: &[],
+    capabilities: Capabilities { led: true, camera: true, layer_progress: true }
+};
+
+/// Every known model, the first matching entry is used
+pub const PROFILES: [ModelProfile; 4] = [
+    ModelProfile {
+        id: "adventurer3",
+        machine_types: &["Adventurer III", "Adventurer 3"],
+        capabilities: Capabilities { led: true, camera: true, layer_progress: true }
+    },
+    ModelProfile {
+        id: "adventurer5m",
+        machine_types: &["Adventurer 5M"],
+        capabilities: Capabilities { led: true, camera: true, layer_progress: true }
+    },
+    // Has no light, some firmwares send an LED line in M119 anyway
+    ModelProfile {
+        id: "finder",
+        machine_types: &["Finder"],
+        capabilities: Capabilities { led: false, camera: false, layer_progress: true }
+    },
+    ModelProfile {
+        id: "creator_pro",
+        machine_types: &["Creator Pro"],
+        capabilities: Capabilities { led: true, camera: false, layer_progress: true }
+    },
+];
+
+impl ModelProfile {
+    /// The profile of the Machine Type reported by M115, [DEFAULT_PROFILE] for unknown models
+    pub fn for_machine_type(machine_type: &str) -> &'static ModelProfile {
+        let machine_type = machine_type.to_ascii_lowercase();
+        PROFILES.iter()
+            .find(|profile| profile.machine_types.iter().any(|name| machine_type.contains(&name.to_ascii_lowercase())))
+            .unwrap_or(&DEFAULT_PROFILE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PrinterRequest, PrinterResponse};
+    use crate::models::Progress;
+
+    #[test]
+    fn profiles_are_picked_by_machine_type() {
+        assert_eq!(ModelProfile::for_machine_type("FlashForge Adventurer III").id, "adventurer3");
+        assert_eq!(ModelProfile::for_machine_type("Flashforge Adventurer 5M Pro").id, "adventurer5m");
+        assert_eq!(ModelProfile::for_machine_type("Flashforge Finder").id, "finder");
+        assert_eq!(ModelProfile::for_machine_type("FLASHFORGE CREATOR PRO").id, "creator_pro");
+        assert_eq!(ModelProfile::for_machine_type("Flashforge Adventurer 4"), &DEFAULT_PROFILE);
+    }
+
+    #[test]
+    fn missing_optional_fields_are_not_errors() {
+        let finder = ModelProfile::for_machine_type("Flashforge Finder");
+        let status = "CMD M119 Received.\r\nEndstop: X-max:0 Y-max:0 Z-min:1\r\nMachineStatus: READY\r\nMoveMode: READY\r\nStatus: S:1 L:0 J:0 F:0\r\nCurrentFile: \r\nok\r\n";
+        let Ok(PrinterResponse::PrinterStatus(parsed)) = PrinterRequest::GetStatus.parse_response_for(status, finder) else { panic!("expected status") };
+        assert_eq!(parsed.led, None);
+        assert!(PrinterRequest::GetStatus.parse_warnings_for(status, finder).is_empty());
+        assert_eq!(PrinterRequest::GetStatus.parse_warnings_for(status, &DEFAULT_PROFILE), ["no LED line, expected for the default profile"]);
+
+        let progress = "CMD M27 Received.\r\nSD printing byte 2400/12000\r\nok\r\n";
+        let Ok(PrinterResponse::PrinterProgress(parsed)) = PrinterRequest::GetProgress.parse_response_for(progress, finder) else { panic!("expected progress") };
+        assert_eq!((parsed.byte, parsed.layer), (Progress { current: 2400, total: 12000 }, Progress::default()));
+    }
+}
@@ -0,0 +1,567 @@
+//! Requests to the printer's API port and parsing of their responses
+use crate::models::{ControlSuccess, Dimensions, EndStopPosition, MachineStatus, MoveMode, PrinterHeadPosition, PrinterInfo, PrinterProgress, PrinterStatus, Progress, PrinterTemperature, StatusFlags, TemperatureMeasurement};
+use crate::parse::{parse_kv, parse_kv_with_warnings};
+use crate::profile::{ModelProfile, DEFAULT_PROFILE};
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+/// Every response from the printer ends with this
+pub const RESPONSE_END: &[u8] = b"ok\r\n";
+
+/// A command understood by the printer, see [PrinterRequest::get_gcode]
+#[derive(Debug, Clone)]
+pub enum PrinterRequest {
+    /// M601, sent first on every connection to take control of the printer
+    ControlMessage,
+    /// M602, sent before closing the connection
+    ReleaseControl,
+    /// M115, [PrinterInfo]
+    GetInfo,
+    /// M114, [PrinterHeadPosition]
+    GetHeadPosition,
+    /// M105, [PrinterTemperature]
+    GetTemperature,
+    /// M27, [PrinterProgress]
+    GetProgress,
+    /// M119, [PrinterStatus]
+    GetStatus,
+    /// M104, sets the tool's target temperature in Celsius
+    SetTemperature(u8, f32),
+    /// M146, turns the light on or off
+    SetLed(bool),
+    /// M220, sets the movement speed of the current print in percent of the file's
+    SetSpeedFactor(u16),
+    /// M221, sets the flow of the current print in percent of the file's
+    SetFlowFactor(u16),
+    /// G-code sent as is, the response is not parsed
+    Raw(String),
+}
+
+/// The parsed response to a [PrinterRequest]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum PrinterResponse {
+    /// Of requests that only change something, and raw G-code
+    #[serde(rename = "success")]
+    ControlSuccess(ControlSuccess),
+    /// Of [PrinterRequest::GetInfo]
+    #[serde(rename = "info")]
+    PrinterInfo(PrinterInfo),
+    /// Of [PrinterRequest::GetHeadPosition]
+    #[serde(rename = "position")]
+    PrinterHeadPosition(PrinterHeadPosition),
+    /// Of [PrinterRequest::GetTemperature]
+    #[serde(rename = "temperatures")]
+    PrinterTemperature(PrinterTemperature),
+    /// Of [PrinterRequest::GetProgress]
+    #[serde(rename = "progress")]
+    PrinterProgress(PrinterProgress),
+    /// Of [PrinterRequest::GetStatus]
+    #[serde(rename = "status")]
+    PrinterStatus(PrinterStatus),
+}
+
+impl PrinterResponse {
+    /// The name the response is tagged with when serialized, "status" for `{"status": {...}}`
+    pub fn tag(&self) -> &'static str {
+        match self {
+            PrinterResponse::ControlSuccess(_) => "success",
+            PrinterResponse::PrinterInfo(_) => "info",
+            PrinterResponse::PrinterHeadPosition(_) => "position",
+            PrinterResponse::PrinterTemperature(_) => "temperatures",
+            PrinterResponse::PrinterProgress(_) => "progress",
+            PrinterResponse::PrinterStatus(_) => "status"
+        }
+    }
+}
+
+
+
+static RE_PRINTER_PROGRESS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(\d+)/(\d+)").unwrap());
+/// A sensor like "T1:" or a heater power field like "@:" or "B@:", the value runs until the next one
+static RE_TEMPERATURE_KEY: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"([A-Za-z][A-Za-z0-9]*@?|@):").unwrap());
+
+/// Parses M105's "T0:210/210 T1:30/0 B:60/60", with any number of extruders. Power fields are ignored and
+/// segments that are not current/target are skipped, both are returned as warnings
+fn parse_temperatures(input: &str) -> (HashMap<String, TemperatureMeasurement>, Vec<String>) {
+    let mut temps = HashMap::new();
+    let mut warnings = Vec::new();
+    let mut saw_ok = false;
+    // Skip first line ("CMD M105 Received.")
+    for line in input.lines().skip(1) {
+        if line == "ok" {
+            saw_ok = true;
+            break;
+        }
+        let keys: Vec<_> = RE_TEMPERATURE_KEY.captures_iter(line).map(|cap| cap.get(0).unwrap()).collect();
+        match keys.first() {
+            None => warnings.push(format!("Invalid line: {}", line)),
+            Some(first) if !line[..first.start()].trim().is_empty() => warnings.push(format!("Invalid segment: {}", &line[..first.start()])),
+            _ => {}
+        }
+        for (i, key) in keys.iter().enumerate() {
+            let end = keys.get(i + 1).map(|next| next.start()).unwrap_or(line.len());
+            let name = key.as_str().trim_end_matches(':');
+            let value = line[key.end()..end].trim();
+            if name.ends_with('@') {
+                continue;
+            }
+            let measurement = value.split_once('/')
+                .and_then(|(current, target)| Some(TemperatureMeasurement {
+                    current: current.trim().parse().ok()?,
+                    target: target.trim().parse().ok()?
+                }));
+            match measurement {
+                Some(measurement) => { temps.insert(name.to_string(), measurement); },
+                None => warnings.push(format!("Invalid temperature {}: {:?}", name, value))
+            }
+        }
+    }
+    if !saw_ok {
+        warnings.push("end of data, but did not see \"ok\"".to_string());
+    }
+    (temps, warnings)
+}
+
+/// The value of a key the response must have
+fn required<'a>(kv: &'a HashMap<String, String>, key: &str) -> Result<&'a str, String> {
+    kv.get(key).map(String::as_str).ok_or_else(|| format!("no {} in response", key))
+}
+
+/// The value of a key the response must have, parsed
+fn required_parsed<T: FromStr>(kv: &HashMap<String, String>, key: &str) -> Result<T, String> {
+    let value = required(kv, key)?;
+    value.parse().map_err(|_| format!("invalid {}: {:?}", key, value))
+}
+
+/// Whether a line after the M601 echo says control failed or was denied, firmwares word it differently
+fn control_refused(input: &str) -> bool {
+    input.lines().skip(1).any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.contains("failed") || line.contains("denied")
+    })
+}
+
+impl PrinterRequest {
+    /// Parses the whole response, up to and including the final "ok". A response missing a value it must have is an error
+    pub fn parse_response(&self, input: &str) -> Result<PrinterResponse, String> {
+        self.parse_response_for(input, &DEFAULT_PROFILE)
+    }
+
+    /// Like [PrinterRequest::parse_response], for the response of a model that may leave out some fields
+    pub fn parse_response_for(&self, input: &str, profile: &ModelProfile) -> Result<PrinterResponse, String> {
+        let capabilities = profile.capabilities;
+        match self {
+            // Refused while another client such as FlashPrint has control
+            PrinterRequest::ControlMessage => Ok(PrinterResponse::ControlSuccess(ControlSuccess { success: !control_refused(input) })),
+            PrinterRequest::ReleaseControl => Ok(PrinterResponse::ControlSuccess(ControlSuccess { success: true })),
+            PrinterRequest::SetTemperature(_, _) => Ok(PrinterResponse::ControlSuccess(ControlSuccess { success: true})),
+            PrinterRequest::SetLed(_) => Ok(PrinterResponse::ControlSuccess(ControlSuccess { success: true })),
+            PrinterRequest::SetSpeedFactor(_) | PrinterRequest::SetFlowFactor(_) => Ok(PrinterResponse::ControlSuccess(ControlSuccess { success: true })),
+            PrinterRequest::Raw(_) => Ok(PrinterResponse::ControlSuccess(ControlSuccess { success: true })),
+            PrinterRequest::GetInfo => {
+                let kv = parse_kv(input)?;
+                let model_name = required(&kv, "Machine Type")?.to_string();
+                Ok(PrinterResponse::PrinterInfo(PrinterInfo{
+                    capabilities: ModelProfile::for_machine_type(&model_name).capabilities,
+                    name: required(&kv, "Machine Name")?.to_string(),
+                    firmware_version: required(&kv, "Firmware")?.to_string(),
+                    sn: required(&kv, "SN")?.to_string(),
+                    tool_count: required_parsed(&kv, "Tool Count")?,
+                    model_name,
+                    mac_addr: required(&kv, "Mac Address")?.to_string(),
+                    build_volume: Dimensions {
+                        x: required_parsed(&kv, "X")?,
+                        y: required_parsed(&kv, "Y")?,
+                        z: required_parsed(&kv, "Z")?,
+                    }
+                }))
+            },
+            PrinterRequest::GetProgress => {
+                let prog = RE_PRINTER_PROGRESS.captures_iter(input)
+                    .map(|c| Ok(Progress {
+                        current: c[1].parse().map_err(|_| format!("invalid progress: {:?}", &c[0]))?,
+                        total: c[2].parse().map_err(|_| format!("invalid progress: {:?}", &c[0]))?
+                    }))
+                    .collect::<Result<Vec<Progress>, String>>()?;
+                if prog.is_empty() {
+                    return Err("no progress in response".to_string());
+                }
+                Ok(PrinterResponse::PrinterProgress(PrinterProgress {
+                    byte: prog[0],
+                    layer: prog.get(1).copied().filter(|_| capabilities.layer_progress).unwrap_or_default(),
+                }))
+            },
+            PrinterRequest::GetTemperature => {
+                let (temps, warnings) = parse_temperatures(input);
+                for warning in warnings {
+                    warn!("{}", warning);
+                }
+                if temps.is_empty() {
+                    return Err("no temperatures in response".to_string());
+                }
+                Ok(PrinterResponse::PrinterTemperature(PrinterTemperature(temps)))
+            },
+            PrinterRequest::GetStatus => {
+                let kv = parse_kv(input)?;
+                let current_file = kv.get("CurrentFile").filter(|s| !s.is_empty()).map(|s| s.to_string());
+                Ok(PrinterResponse::PrinterStatus(PrinterStatus {
+                    end_stop: EndStopPosition {
+                        x_max: required_parsed(&kv, "X-max")?,
+                        y_max: required_parsed(&kv, "Y-max")?,
+                        z_min: required_parsed(&kv, "Z-min")?,
+                    },
+                    machine_status: MachineStatus::parse(required(&kv, "MachineStatus")?),
+                    move_mode: MoveMode::parse(required(&kv, "MoveMode")?),
+                    flags: kv.get("Status").map(|flags| StatusFlags::parse(flags)),
+                    led: kv.get("LED").filter(|_| capabilities.led).map(|led| led == "1"),
+                    current_file
+                }))
+            },
+            PrinterRequest::GetHeadPosition => {
+              let kv = parse_kv(input)?;
+                Ok(PrinterResponse::PrinterHeadPosition(PrinterHeadPosition {
+                    x: required_parsed(&kv, "X")?,
+                    y: required_parsed(&kv, "Y")?,
+                    z: required_parsed(&kv, "Z")?,
+                    a: required_parsed(&kv, "A")?,
+                    b: required_parsed(&kv, "B")?,
+                }))
+            }
+        }
+    }
+}
+
+// https://marlinfw.org/docs/gcode/M104.html
+impl PrinterRequest {
+    /// Problems with the response that parsing skips over, such as lines that are not key: value
+    pub fn parse_warnings(&self, input: &str) -> Vec<String> {
+        self.parse_warnings_for(input, &DEFAULT_PROFILE)
+    }
+
+    /// Like [PrinterRequest::parse_warnings], also listing the fields the model should have sent but didn't
+    pub fn parse_warnings_for(&self, input: &str, profile: &ModelProfile) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let expected = format!("CMD {} Received.", self.get_gcode().trim_start_matches('~').split(' ').next().unwrap_or_default());
+        if input.lines().next() != Some(expected.as_str()) {
+            warnings.push(format!("first line is not {:?}", expected));
+        }
+        match self {
+            PrinterRequest::GetStatus => {
+                let (kv, kv_warnings) = parse_kv_with_warnings(input);
+                warnings.extend(kv_warnings);
+                if profile.capabilities.led && !kv.contains_key("LED") {
+                    warnings.push(format!("no LED line, expected for the {} profile", profile.id));
+                }
+            },
+            PrinterRequest::GetInfo | PrinterRequest::GetHeadPosition =>
+                warnings.extend(parse_kv_with_warnings(input).1),
+            PrinterRequest::GetTemperature => warnings.extend(parse_temperatures(input).1),
+            PrinterRequest::GetProgress if RE_PRINTER_PROGRESS.captures_iter(input).count() < 1 + usize::from(profile.capabilities.layer_progress) =>
+                warnings.push(if profile.capabilities.layer_progress { "expected byte and layer progress (n/n)" } else { "expected byte progress (n/n)" }.to_string()),
+            _ => {}
+        }
+        warnings
+    }
+
+    /// Queries that change nothing on the printer, which some firmwares answer without [PrinterRequest::ControlMessage]
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, PrinterRequest::GetInfo | PrinterRequest::GetStatus | PrinterRequest::GetTemperature
+            | PrinterRequest::GetProgress | PrinterRequest::GetHeadPosition)
+    }
+
+    /// Safe to send again when the connection failed after it was sent, as the printer may have acted on it already.
+    /// Commands that change something are not, even those setting a value, so a reconnect never applies one twice
+    pub fn is_idempotent(&self) -> bool {
+        self.is_read_only() || matches!(self, PrinterRequest::ControlMessage | PrinterRequest::ReleaseControl)
+    }
+
+    /// The [PrinterResponse::tag] of each query's response
+    pub const QUERY_TAGS: [&'static str; 5] = ["info", "status", "temperatures", "progress", "position"];
+
+    /// The query answered with the response tagged `tag`, one of [PrinterRequest::QUERY_TAGS]
+    pub fn query_for_tag(tag: &str) -> Option<PrinterRequest> {
+        match tag {
+            "info" => Some(PrinterRequest::GetInfo),
+            "status" => Some(PrinterRequest::GetStatus),
+            "temperatures" => Some(PrinterRequest::GetTemperature),
+            "progress" => Some(PrinterRequest::GetProgress),
+            "position" => Some(PrinterRequest::GetHeadPosition),
+            _ => None
+        }
+    }
+
+    /// Whether the response to a query has nothing but the echo and the final "ok", as some printers answer right after
+    /// booting. Commands are always answered like that
+    pub fn is_empty_response(&self, input: &str) -> bool {
+        self.is_read_only() && input.lines().map(str::trim).filter(|line| !line.is_empty())
+            .all(|line| line == "ok" || (line.starts_with("CMD ") && line.ends_with("Received.")))
+    }
+
+    /// The G-code, "~M105" for [PrinterRequest::GetTemperature]
+    pub fn get_gcode(&self) -> String {
+        match self {
+            PrinterRequest::ControlMessage => "~M601 S1".to_string(),
+            PrinterRequest::ReleaseControl => "~M602".to_string(),
+            PrinterRequest::GetInfo => "~M115".to_string(),
+            PrinterRequest::GetHeadPosition => "~M114".to_string(),
+            PrinterRequest::GetTemperature => "~M105".to_string(),
+            PrinterRequest::GetProgress => "~M27".to_string(),
+            PrinterRequest::GetStatus => "~M119".to_string(),
+            PrinterRequest::SetTemperature(index, temp) => format!("~M104 S{} T{}", temp, index),
+            PrinterRequest::SetLed(true) => "~M146 r255 g255 b255 F0".to_string(),
+            PrinterRequest::SetLed(false) => "~M146 r0 g0 b0 F0".to_string(),
+            PrinterRequest::SetSpeedFactor(percent) => format!("~M220 S{}", percent),
+            PrinterRequest::SetFlowFactor(percent) => format!("~M221 S{}", percent),
+            PrinterRequest::Raw(gcode) => gcode.clone()
+        }
+    }
+    /// The line sent to the printer
+    pub fn get_instruction(&self) -> String {
+        format!("{}\r\n", self.get_gcode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /// Reads tests/fixtures/<name>.txt, with the CRLF line endings of the printer
+    fn fixture(name: &str) -> String {
+        let path = format!("{}/tests/fixtures/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+        let contents = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("missing fixture {}: {}", path, e));
+        contents.replace("\r\n", "\n").replace('\n', "\r\n")
+    }
+
+    fn parse_status(machine_status: &str, move_mode: &str) -> PrinterStatus {
+        let input = format!("CMD M119 Received.\r\nEndstop: X-max:0 Y-max:0 Z-min:1\r\nMachineStatus: {}\r\nMoveMode: {}\r\nStatus: S:1 L:0 J:0 F:0\r\nLED: 1\r\nCurrentFile: test.gx\r\nok\r\n", machine_status, move_mode);
+        match PrinterRequest::GetStatus.parse_response(&input) {
+            Ok(PrinterResponse::PrinterStatus(status)) => status,
+            _ => panic!("expected status response")
+        }
+    }
+
+    #[test]
+    fn parses_machine_status() {
+        // Adventurer 3/4 and Finder
+        assert_eq!(parse_status("READY", "READY").machine_status, MachineStatus::Ready);
+        assert_eq!(parse_status("BUILDING_FROM_SD", "MOVING").machine_status, MachineStatus::BuildingFromSd);
+        assert_eq!(parse_status("PAUSED", "PAUSED").machine_status, MachineStatus::Paused);
+        assert_eq!(parse_status("BUSY", "HOMING").machine_status, MachineStatus::Busy);
+        // Adventurer 5M
+        assert_eq!(parse_status("BUILDING", "MOVING").machine_status, MachineStatus::Building);
+        assert_eq!(parse_status("BUILDING_COMPLETED", "READY").machine_status, MachineStatus::BuildingCompleted);
+    }
+
+    #[test]
+    fn parses_error_and_unknown_machine_status() {
+        let status = parse_status("FILAMENT_ERROR", "PAUSED").machine_status;
+        assert!(status.is_error());
+        assert_eq!(status.as_str(), "FILAMENT_ERROR");
+        let status = parse_status("HEATING", "READY").machine_status;
+        assert_eq!(status, MachineStatus::Unknown("HEATING".to_string()));
+        assert_eq!(serde_json::to_string(&status).unwrap(), "\"HEATING\"");
+    }
+
+    #[test]
+    fn parses_move_mode() {
+        assert_eq!(parse_status("READY", "READY").move_mode, MoveMode::Ready);
+        assert_eq!(parse_status("BUILDING_FROM_SD", "MOVING").move_mode, MoveMode::Moving);
+        assert_eq!(parse_status("PAUSED", "PAUSED").move_mode, MoveMode::Paused);
+        assert_eq!(parse_status("BUSY", "HOMING").move_mode, MoveMode::Homing);
+        assert_eq!(parse_status("BUSY", "WAIT_ON_TOOL").move_mode, MoveMode::WaitOnTool);
+        assert_eq!(parse_status("READY", "SOMETHING").move_mode, MoveMode::Unknown("SOMETHING".to_string()));
+    }
+
+    fn parse_temperatures(input: &str) -> PrinterTemperature {
+        match PrinterRequest::GetTemperature.parse_response(input) {
+            Ok(PrinterResponse::PrinterTemperature(temps)) => temps,
+            _ => panic!("expected temperature response")
+        }
+    }
+
+    #[test]
+    fn normalizes_single_extruder_temperatures() {
+        let temps = parse_temperatures("CMD M105 Received.\r\nT0:210/210 B:60/60\r\nok\r\n").normalize();
+        assert_eq!(temps.extruders.len(), 1);
+        assert_eq!(temps.extruders[0].current, 210.0);
+        assert_eq!(temps.bed.unwrap().target, 60.0);
+        assert!(temps.chamber.is_none());
+        assert!(temps.raw.is_empty());
+    }
+
+    #[test]
+    fn normalizes_dual_extruder_temperatures() {
+        let temps = parse_temperatures("CMD M105 Received.\r\nT0:200/205 T1:25/0 B:50/50\r\nok\r\n").normalize();
+        assert_eq!(temps.extruders.len(), 2);
+        assert_eq!(temps.extruders[0].target, 205.0);
+        assert_eq!(temps.extruders[1].current, 25.0);
+        assert_eq!(temps.bed.unwrap().current, 50.0);
+        assert!(temps.raw.is_empty());
+    }
+
+    #[test]
+    fn parses_spaced_temperatures_and_ignores_power_fields() {
+        // Creator Pro
+        let temps = parse_temperatures("CMD M105 Received.\r\nT0:210 /210 T1:30 /0 B:60 /60 @:0 B@:0\r\nok\r\n").normalize();
+        assert_eq!(temps.extruders.len(), 2);
+        assert_eq!(temps.extruders[0].target, 210.0);
+        assert_eq!(temps.extruders[1].current, 30.0);
+        assert_eq!(temps.bed.unwrap().target, 60.0);
+        assert!(temps.raw.is_empty());
+        assert!(PrinterRequest::GetTemperature.parse_warnings("CMD M105 Received.\r\nT0:210 /210 T1:30 /0 B:60 /60 @:0 B@:0\r\nok\r\n").is_empty());
+    }
+
+    #[test]
+    fn skips_malformed_temperatures() {
+        let input = "CMD M105 Received.\r\nT0:210/210 T1:30 B:60/abc T2:1/2/3\r\nok\r\n";
+        let temps = parse_temperatures(input).normalize();
+        assert_eq!(temps.extruders.len(), 1);
+        assert!(temps.bed.is_none());
+        assert_eq!(PrinterRequest::GetTemperature.parse_warnings(input), vec![
+            "Invalid temperature T1: \"30\"", "Invalid temperature B: \"60/abc\"", "Invalid temperature T2: \"1/2/3\""
+        ]);
+        assert!(PrinterRequest::GetTemperature.parse_response("CMD M105 Received.\r\nnothing\r\nok\r\n").is_err());
+    }
+
+    #[test]
+    fn keeps_unknown_temperature_sensors_raw() {
+        let temps = PrinterTemperature(HashMap::from([
+            ("T0 ".to_string(), TemperatureMeasurement { current: 20.0, target: 0.0 }),
+            ("C".to_string(), TemperatureMeasurement { current: 30.0, target: 35.0 }),
+            ("X".to_string(), TemperatureMeasurement { current: 1.0, target: 0.0 }),
+        ])).normalize();
+        assert_eq!(temps.extruders.len(), 1);
+        assert_eq!(temps.chamber.unwrap().target, 35.0);
+        assert_eq!(temps.raw.len(), 1);
+        assert!(temps.raw.contains_key("X"));
+    }
+
+    #[test]
+    fn parses_status_flags() {
+        let flags = StatusFlags::parse("S:1 L:0 J:0 F:1");
+        assert!(flags.filament_runout);
+        assert_eq!(flags.raw["S"], 1);
+        // Unknown letters are kept, values that aren't numbers dropped
+        let flags = StatusFlags::parse("S:1 X:2 F:?");
+        assert!(!flags.filament_runout);
+        assert_eq!(flags.raw, BTreeMap::from([("S".to_string(), 1), ("X".to_string(), 2)]));
+
+        let status = "CMD M119 Received.\r\nEndstop: X-max:0 Y-max:0 Z-min:1\r\nMachineStatus: READY\r\nMoveMode: READY\r\nLED: 1\r\nCurrentFile: \r\nok\r\n";
+        let Ok(PrinterResponse::PrinterStatus(parsed)) = PrinterRequest::GetStatus.parse_response(status) else { panic!("expected status") };
+        assert_eq!(parsed.flags, None);
+    }
+
+    #[test]
+    fn incomplete_responses_are_errors() {
+        let info = "CMD M115 Received.\r\nMachine Type: Adventurer 4\r\nMachine Name: Ender\r\nok\r\n";
+        assert_eq!(PrinterRequest::GetInfo.parse_response(info), Err("no Firmware in response".to_string()));
+        let status = "CMD M119 Received.\r\nEndstop: X-max:0 Y-max:? Z-min:1\r\nMachineStatus: READY\r\nMoveMode: READY\r\nok\r\n";
+        assert_eq!(PrinterRequest::GetStatus.parse_response(status), Err("invalid Y-max: \"?\"".to_string()));
+        assert_eq!(PrinterRequest::GetProgress.parse_response("CMD M27 Received.\r\nok\r\n"), Err("no progress in response".to_string()));
+        let overflowing = "CMD M27 Received.\r\nSD printing byte 99999999999/100\r\nok\r\n";
+        assert!(PrinterRequest::GetProgress.parse_response(overflowing).unwrap_err().starts_with("invalid progress"));
+        let position = "CMD M114 Received.\r\nX:1 Y:2 Z:3 A:0\r\nok\r\n";
+        assert_eq!(PrinterRequest::GetHeadPosition.parse_response(position), Err("no B in response".to_string()));
+    }
+
+    #[test]
+    fn response_tags_are_stable() {
+        // Clients match on these, renaming one breaks them
+        let queries = PrinterRequest::QUERY_TAGS.map(|tag| (tag, PrinterRequest::query_for_tag(tag).unwrap()));
+        for (tag, query) in queries {
+            let input = match query {
+                PrinterRequest::GetInfo => fixture("M115"),
+                PrinterRequest::GetStatus => fixture("M119"),
+                PrinterRequest::GetTemperature => fixture("M105"),
+                PrinterRequest::GetProgress => fixture("M27"),
+                _ => fixture("M114")
+            };
+            let response = query.parse_response(&input).unwrap();
+            assert_eq!(response.tag(), tag);
+            let json = serde_json::to_value(&response).unwrap();
+            assert_eq!(json.as_object().unwrap().keys().collect::<Vec<_>>(), [tag], "{}", json);
+            assert_eq!(serde_json::from_value::<PrinterResponse>(json).unwrap(), response);
+        }
+        let success = serde_json::to_value(PrinterResponse::ControlSuccess(ControlSuccess { success: true })).unwrap();
+        assert_eq!(success, serde_json::json!({"success": {"success": true}}));
+        assert!(PrinterRequest::query_for_tag("success").is_none());
+    }
+
+    #[test]
+    fn parses_refused_control() {
+        let granted = PrinterRequest::ControlMessage.parse_response(&fixture("M601")).unwrap();
+        assert_eq!(granted, PrinterResponse::ControlSuccess(ControlSuccess { success: true }));
+        let refused = PrinterRequest::ControlMessage.parse_response(&fixture("M601_denied")).unwrap();
+        assert_eq!(refused, PrinterResponse::ControlSuccess(ControlSuccess { success: false }));
+        let denied = PrinterRequest::ControlMessage.parse_response("CMD M601 Received.\r\nControl Denied\r\nok\r\n").unwrap();
+        assert_eq!(denied, PrinterResponse::ControlSuccess(ControlSuccess { success: false }));
+        assert!(PrinterRequest::GetStatus.is_read_only());
+        assert!(!PrinterRequest::SetLed(true).is_read_only() && !PrinterRequest::Raw("~M105".to_string()).is_read_only());
+        assert!(PrinterRequest::ControlMessage.is_idempotent() && PrinterRequest::GetStatus.is_idempotent());
+        assert!(!PrinterRequest::SetTemperature(0, 200.0).is_idempotent());
+    }
+
+    #[test]
+    fn print_factors_are_percentages() {
+        assert_eq!(PrinterRequest::SetSpeedFactor(110).get_gcode(), "~M220 S110");
+        assert_eq!(PrinterRequest::SetFlowFactor(95).get_gcode(), "~M221 S95");
+        let response = PrinterRequest::SetFlowFactor(95).parse_response("CMD M221 Received.\r\nok\r\n").unwrap();
+        assert_eq!(response, PrinterResponse::ControlSuccess(ControlSuccess { success: true }));
+        assert!(!PrinterRequest::SetSpeedFactor(110).is_read_only());
+    }
+
+    #[test]
+    fn status_helpers() {
+        assert!(MachineStatus::BuildingFromSd.is_printing());
+        assert!(!MachineStatus::Paused.is_printing());
+        assert!(!MachineStatus::Paused.is_idle());
+        assert!(MachineStatus::Ready.is_idle());
+        assert!(MachineStatus::BuildingCompleted.is_idle());
+    }
+
+    fn parse_fixture(model: &str, profile: &ModelProfile, request: PrinterRequest) -> PrinterResponse {
+        let gcode = request.get_gcode();
+        let input = fixture(&format!("{}/{}", model, gcode.trim_start_matches('~')));
+        assert_eq!(request.parse_warnings_for(&input, profile), Vec::<String>::new(), "{} {}", model, gcode);
+        request.parse_response_for(&input, profile).unwrap_or_else(|e| panic!("{} {}: {}", model, gcode, e))
+    }
+
+    #[test]
+    fn parses_responses_of_each_model() {
+        // Model, machine type, firmware, build volume, status, current file, LED, T0 and bed
+        let models = [
+            ("", "FlashForge Adventurer III", "v1.3.7", 150, MachineStatus::Ready, None, Some(true), 210.0, 60.0),
+            ("adventurer4", "Flashforge Adventurer 4", "v2.2.8-4.0", 220, MachineStatus::Paused, Some("calibration cube.gx"), Some(false), 205.0, 58.0),
+            ("adventurer5m_pro", "Flashforge Adventurer 5M Pro", "v2.4.5-ML:2023", 220, MachineStatus::Building, Some("Benchy PLA 0.2mm.gcode"), Some(true), 219.8, 55.1),
+            ("finder", "Flashforge Finder", "V1.5 20170419", 140, MachineStatus::Ready, None, None, 24.0, 0.0),
+        ];
+        for (model, model_name, firmware, x, machine_status, current_file, led, t0, bed) in models {
+            let PrinterResponse::PrinterInfo(info) = parse_fixture(model, &DEFAULT_PROFILE, PrinterRequest::GetInfo) else { panic!("expected info") };
+            let profile = ModelProfile::for_machine_type(&info.model_name);
+            assert_eq!(info.capabilities, profile.capabilities);
+            assert_eq!(info.model_name, model_name);
+            assert_eq!(info.firmware_version, firmware);
+            assert_eq!(info.build_volume.x, x, "{}", model);
+            assert_eq!(info.tool_count, 1);
+            assert_eq!(info.mac_addr.len(), 17, "{}", model);
+
+            let PrinterResponse::PrinterStatus(status) = parse_fixture(model, profile, PrinterRequest::GetStatus) else { panic!("expected status") };
+            assert_eq!(status.machine_status, machine_status);
+            assert_eq!(status.current_file.as_deref(), current_file);
+            assert_eq!(status.led, led, "{}", model);
+            let flags = status.flags.unwrap_or_else(|| panic!("{} has a Status line", model));
+            assert!(!flags.filament_runout, "{}", model);
+            assert_eq!(flags.raw, BTreeMap::from([("S".to_string(), 1), ("L".to_string(), 0), ("J".to_string(), 0), ("F".to_string(), 0)]), "{}", model);
+
+            let PrinterResponse::PrinterTemperature(temps) = parse_fixture(model, profile, PrinterRequest::GetTemperature) else { panic!("expected temperatures") };
+            let temps = temps.normalize();
+            assert_eq!(temps.extruders[0].current, t0, "{}", model);
+            assert_eq!(temps.bed.unwrap().current, bed, "{}", model);
+        }
+    }
+}
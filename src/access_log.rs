@@ -0,0 +1,367 @@
+//! One line per request in [http.access_log], to stdout or a file, apart from the tracing logs
+use std::fs::Metadata;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use log::warn;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::response::Body;
+use rocket::{Data, Orbit, Request, Response, Rocket};
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use crate::config::{AccessLogConfig, LogFormat};
+use crate::util::{routed_printer_id, Authorization, Principal};
+
+#[derive(Serialize, Debug)]
+struct AccessEntry {
+    #[serde(with = "time::serde::rfc3339")]
+    time: OffsetDateTime,
+    client_ip: Option<IpAddr>,
+    method: String,
+    path: String,
+    status: u16,
+    duration_ms: u64,
+    /// Bytes of the body sent
+    bytes: u64,
+    printer_id: Option<String>,
+    /// "password" or "token:<name>", None when the request was not authorized by either
+    principal: Option<String>,
+    /// Logged once the stream ended or the client disconnected
+    streamed: bool
+}
+
+impl AccessEntry {
+    /// `<time> <client ip> <principal> "<method> <path>" <status> <bytes> <duration>ms <printer id>`, - for what is missing
+    fn text(&self) -> String {
+        let time = self.time.format(&Rfc3339).unwrap_or_default();
+        let client_ip = self.client_ip.map(|ip| ip.to_string());
+        format!("{} {} {} \"{} {}\" {} {} {}ms {}", time, client_ip.as_deref().unwrap_or("-"), self.principal.as_deref().unwrap_or("-"),
+                self.method, self.path, self.status, self.bytes, self.duration_ms, self.printer_id.as_deref().unwrap_or("-"))
+    }
+}
+
+/// Formats the entries and hands the lines to [write_lines], so requests never wait on the disk
+pub struct AccessLog {
+    format: LogFormat,
+    path: Option<PathBuf>,
+    lines: mpsc::UnboundedSender<String>,
+    /// Taken by [write_lines] once Rocket lifts off
+    receiver: std::sync::Mutex<Option<mpsc::UnboundedReceiver<String>>>
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Self {
+        let (lines, receiver) = mpsc::unbounded_channel();
+        Self { format: config.format, path: config.path.clone(), lines, receiver: std::sync::Mutex::new(Some(receiver)) }
+    }
+
+    fn write(&self, entry: &AccessEntry) {
+        let mut line = match self.format {
+            LogFormat::Text => entry.text(),
+            LogFormat::Json => serde_json::to_string(entry).unwrap_or_default()
+        };
+        line.push('\n');
+        // Only fails once the writer stopped, at shutdown
+        self.lines.send(line).ok();
+    }
+
+    /// Starts writing the lines sent so far and from now on, once
+    fn start(&self) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            tokio::spawn(write_lines(self.path.clone(), receiver));
+        }
+    }
+}
+
+/// Appends the lines to path, or stdout. The file is opened again when it was moved or deleted, such as by
+/// logrotate, so new lines go to the new file
+async fn write_lines(path: Option<PathBuf>, mut lines: mpsc::UnboundedReceiver<String>) {
+    let mut file: Option<(File, Metadata)> = None;
+    while let Some(mut batch) = lines.recv().await {
+        while let Ok(line) = lines.try_recv() {
+            batch.push_str(&line);
+        }
+        let Some(path) = &path else {
+            let mut stdout = tokio::io::stdout();
+            if stdout.write_all(batch.as_bytes()).await.is_ok() {
+                stdout.flush().await.ok();
+            }
+            continue;
+        };
+        let current = tokio::fs::metadata(path).await.ok();
+        if !file.as_ref().is_some_and(|(_, opened)| current.as_ref().is_some_and(|current| same_file(opened, current))) {
+            file = match open(path).await {
+                Ok(opened) => Some(opened),
+                Err(e) => {
+                    warn!("access log: could not open {}: {}", path.display(), e);
+                    None
+                }
+            };
+        }
+        let Some((opened, _)) = &mut file else { continue };
+        if let Err(e) = async { opened.write_all(batch.as_bytes()).await?; opened.flush().await }.await {
+            warn!("access log: failed to write to {}: {}", path.display(), e);
+            file = None;
+        }
+    }
+}
+
+async fn open(path: &Path) -> std::io::Result<(File, Metadata)> {
+    let file = OpenOptions::new().create(true).append(true).open(path).await?;
+    let metadata = file.metadata().await?;
+    Ok((file, metadata))
+}
+
+#[cfg(unix)]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/// Without inodes only a deleted file is noticed
+#[cfg(not(unix))]
+fn same_file(_: &Metadata, _: &Metadata) -> bool {
+    true
+}
+
+/// The client's ip, the first hop of X-Forwarded-For from the right that isn't a trusted proxy when the
+/// request came from one. Any other client could send the header to pretend to be someone else
+fn client_ip(remote: Option<IpAddr>, forwarded_for: &str, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let mut ip = remote?;
+    for hop in forwarded_for.rsplit(',').map(str::trim).filter(|hop| !hop.is_empty()) {
+        if !trusted_proxies.contains(&ip) {
+            break;
+        }
+        match hop.parse() {
+            Ok(hop) => ip = hop,
+            Err(_) => break
+        }
+    }
+    Some(ip)
+}
+
+fn principal(authorization: Option<Authorization>) -> Option<String> {
+    match authorization? {
+        Authorization::None => None,
+        Authorization::Password => Some("password".to_string()),
+        Authorization::Token(name) => Some(format!("token:{}", name))
+    }
+}
+
+/// When the request arrived, kept in the request's local cache
+struct RequestStart(Option<Instant>);
+
+/// Writes an [AccessEntry] for every response. Streamed bodies, such as the camera stream and the events,
+/// are logged once they end with the bytes sent and how long they were open
+pub struct AccessLogger {
+    log: Arc<AccessLog>,
+    trusted_proxies: Vec<IpAddr>
+}
+
+impl AccessLogger {
+    pub fn new(config: &AccessLogConfig) -> Self {
+        Self { log: Arc::new(AccessLog::new(config)), trusted_proxies: config.trusted_proxies.clone() }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for AccessLogger {
+    fn info(&self) -> Info {
+        Info { name: "Access log", kind: Kind::Liftoff | Kind::Request | Kind::Response }
+    }
+
+    async fn on_liftoff(&self, _: &Rocket<Orbit>) {
+        self.log.start();
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let started = request.local_cache(|| RequestStart(None)).0.unwrap_or_else(Instant::now);
+        let forwarded_for = request.headers().get("X-Forwarded-For").collect::<Vec<_>>().join(",");
+        let mut entry = AccessEntry {
+            time: OffsetDateTime::now_utc(),
+            client_ip: client_ip(request.remote().map(|remote| remote.ip()), &forwarded_for, &self.trusted_proxies),
+            method: request.method().to_string(),
+            path: request.uri().path().to_string(),
+            status: response.status().code,
+            duration_ms: started.elapsed().as_millis() as u64,
+            bytes: response.body().preset_size().unwrap_or(0) as u64,
+            printer_id: routed_printer_id(request).map(str::to_string),
+            principal: principal(request.local_cache(Principal::default).get()),
+            streamed: false
+        };
+        if response.body().preset_size().is_some() || response.body().is_none() {
+            self.log.write(&entry);
+            return;
+        }
+        entry.streamed = true;
+        let body = std::mem::take(response.body_mut());
+        response.set_streamed_body(CountedBody { body, bytes: 0, started, entry: Some(entry), log: self.log.clone() });
+    }
+}
+
+/// Counts the bytes read from a streamed body, logging the entry when it is dropped: at its end, or once the
+/// client disconnected
+struct CountedBody<'r> {
+    body: Body<'r>,
+    bytes: u64,
+    started: Instant,
+    entry: Option<AccessEntry>,
+    log: Arc<AccessLog>
+}
+
+impl AsyncRead for CountedBody<'_> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.body).poll_read(cx, buf);
+        self.bytes += (buf.filled().len() - filled) as u64;
+        result
+    }
+}
+
+impl Drop for CountedBody<'_> {
+    fn drop(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.bytes = self.bytes;
+            entry.duration_ms = self.started.elapsed().as_millis() as u64;
+            self.log.write(&entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn entry(path: &str) -> AccessEntry {
+        AccessEntry {
+            time: OffsetDateTime::UNIX_EPOCH,
+            client_ip: None,
+            method: "GET".to_string(),
+            path: path.to_string(),
+            status: 200,
+            duration_ms: 0,
+            bytes: 0,
+            printer_id: None,
+            principal: None,
+            streamed: false
+        }
+    }
+
+    /// Waits for the writer to have written count lines
+    async fn read_lines(path: &Path, count: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let lines: Vec<String> = std::fs::read_to_string(path).unwrap_or_default().lines().map(str::to_string).collect();
+            if lines.len() >= count {
+                return lines;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("{} has less than {} lines", path.display(), count);
+    }
+
+    #[tokio::test]
+    async fn file_is_opened_again_after_being_rotated() {
+        let path = std::env::temp_dir().join(format!("flashforge-access-rotation-{}.log", std::process::id()));
+        let rotated = path.with_extension("log.1");
+        std::fs::remove_file(&path).ok();
+        let log = AccessLog::new(&AccessLogConfig { path: Some(path.clone()), format: LogFormat::Text, trusted_proxies: Vec::new() });
+        log.start();
+        log.write(&entry("/first"));
+        read_lines(&path, 1).await;
+        std::fs::rename(&path, &rotated).unwrap();
+        // logrotate's create
+        std::fs::write(&path, "").unwrap();
+        log.write(&entry("/second"));
+        let lines = read_lines(&path, 1).await;
+        assert_eq!(lines, ["1970-01-01T00:00:00Z - - \"GET /second\" 200 0 0ms -"]);
+        assert_eq!(std::fs::read_to_string(&rotated).unwrap().lines().count(), 1);
+        std::fs::remove_file(&rotated).ok();
+
+        // Moved without a new file being created
+        std::fs::rename(&path, &rotated).unwrap();
+        log.write(&entry("/third"));
+        assert!(read_lines(&path, 1).await[0].contains("/third"));
+        std::fs::remove_file(&rotated).ok();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn forwarded_for_is_only_taken_from_trusted_proxies() {
+        let proxy = ip("127.0.0.1");
+        assert_eq!(client_ip(Some(proxy), "203.0.113.7", &[proxy]), Some(ip("203.0.113.7")));
+        // The client added a hop of its own before the proxy appended its address
+        assert_eq!(client_ip(Some(proxy), "198.51.100.1, 203.0.113.7", &[proxy]), Some(ip("203.0.113.7")));
+        assert_eq!(client_ip(Some(proxy), "198.51.100.1, 203.0.113.7, 10.0.0.2", &[proxy, ip("10.0.0.2")]), Some(ip("203.0.113.7")));
+        assert_eq!(client_ip(Some(ip("203.0.113.7")), "198.51.100.1", &[proxy]), Some(ip("203.0.113.7")));
+        assert_eq!(client_ip(Some(proxy), "unknown", &[proxy]), Some(proxy));
+        assert_eq!(client_ip(None, "203.0.113.7", &[proxy]), None);
+    }
+
+    #[test]
+    fn text_lines_have_a_stable_layout() {
+        let entry = AccessEntry {
+            time: OffsetDateTime::UNIX_EPOCH,
+            client_ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            method: "POST".to_string(),
+            path: "/api/printers/main/temperature".to_string(),
+            status: 401,
+            duration_ms: 3,
+            bytes: 96,
+            printer_id: Some("main".to_string()),
+            principal: None,
+            streamed: false
+        };
+        assert_eq!(entry.text(), "1970-01-01T00:00:00Z 127.0.0.1 - \"POST /api/printers/main/temperature\" 401 96 3ms main");
+        let entry = AccessEntry { client_ip: None, status: 200, printer_id: None, principal: Some("token:ci".to_string()), ..entry };
+        assert_eq!(entry.text(), "1970-01-01T00:00:00Z - token:ci \"POST /api/printers/main/temperature\" 200 96 3ms -");
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_are_logged_when_dropped() {
+        let path = std::env::temp_dir().join(format!("flashforge-access-{}.log", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let log = Arc::new(AccessLog::new(&AccessLogConfig { path: Some(path.clone()), format: LogFormat::Json, trusted_proxies: Vec::new() }));
+        log.start();
+        let entry = AccessEntry {
+            time: OffsetDateTime::now_utc(),
+            client_ip: None,
+            method: "GET".to_string(),
+            path: "/api/printers/main/camera".to_string(),
+            status: 200,
+            duration_ms: 0,
+            bytes: 0,
+            printer_id: Some("main".to_string()),
+            principal: None,
+            streamed: true
+        };
+        let mut response = Response::build().streamed_body(std::io::Cursor::new(vec![0u8; 1000])).finalize();
+        let mut body = CountedBody { body: std::mem::take(response.body_mut()), bytes: 0, started: Instant::now(), entry: Some(entry), log };
+        let mut start = [0u8; 600];
+        tokio::io::AsyncReadExt::read_exact(&mut body, &mut start).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!path.exists());
+        // The client went away before the end
+        drop(body);
+        let line: serde_json::Value = serde_json::from_str(read_lines(&path, 1).await[0].as_str()).unwrap();
+        assert_eq!(line["bytes"], 600);
+        assert_eq!(line["streamed"], true);
+        assert!(line["duration_ms"].as_u64().unwrap() >= 20);
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,172 @@
+//! Append-only log of every request that checked for write access, as JSON lines in [audit] path
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use log::warn;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::{Data, Request, Response};
+use time::OffsetDateTime;
+use crate::config::AuditConfig;
+use crate::models::AuditEntry;
+use crate::util::{routed_printer_id, Authorization, WriteAccess};
+
+/// Bytes of the request body kept in an entry
+const MAX_PAYLOAD: usize = 4096;
+
+/// The log file and the one it was last rotated to, a write appends to the file under a lock
+#[derive(Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    lock: Arc<std::sync::Mutex<()>>
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig) -> Self {
+        Self { path: config.path.clone(), max_bytes: config.max_bytes, lock: Default::default() }
+    }
+
+    /// <path>.1, where the log is moved once it reaches max_bytes
+    fn rotated_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".1");
+        self.path.with_file_name(name)
+    }
+
+    pub async fn append(&self, entry: &AuditEntry) -> Result<(), String> {
+        let mut line = serde_json::to_vec(entry).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        let log = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _lock = log.lock.lock().unwrap();
+            let size = std::fs::metadata(&log.path).map(|metadata| metadata.len()).unwrap_or(0);
+            if size > 0 && size + line.len() as u64 > log.max_bytes {
+                std::fs::rename(&log.path, log.rotated_path()).map_err(|e| e.to_string())?;
+            }
+            let mut file = OpenOptions::new().create(true).append(true).open(&log.path).map_err(|e| e.to_string())?;
+            file.write_all(&line).map_err(|e| e.to_string())
+        }).await.map_err(|e| e.to_string())?
+    }
+
+    /// The last limit entries, newest first
+    pub async fn recent(&self, limit: usize) -> Result<Vec<AuditEntry>, String> {
+        let log = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _lock = log.lock.lock().unwrap();
+            let mut entries = read_entries(&log.rotated_path())?;
+            entries.extend(read_entries(&log.path)?);
+            Ok(entries.into_iter().rev().take(limit).collect())
+        }).await.map_err(|e| e.to_string())?
+    }
+}
+
+/// Reads every entry, skipping lines that can't be parsed (such as one cut off by a crash)
+fn read_entries(path: &Path) -> Result<Vec<AuditEntry>, String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.to_string())
+    };
+    Ok(BufReader::new(file).lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect())
+}
+
+/// The start of the request body, kept in the request's local cache
+struct RequestPayload(Vec<u8>);
+
+/// Appends an [AuditEntry] for every response to a request whose route checked for write access,
+/// whether it was allowed or not
+pub struct AuditLogger {
+    log: AuditLog
+}
+
+impl AuditLogger {
+    pub fn new(log: AuditLog) -> Self {
+        Self { log }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for AuditLogger {
+    fn info(&self) -> Info {
+        Info { name: "Audit log", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, data: &mut Data<'_>) {
+        // Peeked data is still read by the route's data guard
+        if request.method() != Method::Get && request.method() != Method::Head {
+            let payload = data.peek(MAX_PAYLOAD).await.to_vec();
+            request.local_cache(|| RequestPayload(payload));
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(authorization) = request.local_cache(WriteAccess::default).get() else {
+            return;
+        };
+        let payload = &request.local_cache(|| RequestPayload(Vec::new())).0;
+        let (auth, token) = match authorization {
+            Authorization::None => ("none", None),
+            Authorization::Password => ("password", None),
+            Authorization::Token(name) => ("token", Some(name))
+        };
+        let entry = AuditEntry {
+            time: OffsetDateTime::now_utc(),
+            client_ip: request.client_ip(),
+            auth: auth.to_string(),
+            token,
+            method: request.method().to_string(),
+            route: request.route().map(|route| route.uri.path().to_string()).unwrap_or_default(),
+            uri: request.uri().to_string(),
+            printer_id: routed_printer_id(request).map(str::to_string),
+            payload: (!payload.is_empty()).then(|| serde_json::from_slice(payload)
+                .unwrap_or_else(|_| String::from_utf8_lossy(payload).into_owned().into())),
+            status: response.status().code
+        };
+        if let Err(e) = self.log.append(&entry).await {
+            warn!("audit: failed to write {} {}: {}", entry.method, entry.uri, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(uri: &str) -> AuditEntry {
+        AuditEntry {
+            // Entries of the same length, the current time's fraction can be shorter
+            time: OffsetDateTime::UNIX_EPOCH,
+            client_ip: None,
+            auth: "none".to_string(),
+            token: None,
+            method: "POST".to_string(),
+            route: "/api/printers/refresh".to_string(),
+            uri: uri.to_string(),
+            printer_id: None,
+            payload: None,
+            status: 200
+        }
+    }
+
+    #[tokio::test]
+    async fn log_is_rotated_once_full() {
+        let path = std::env::temp_dir().join(format!("flashforge-audit-rotation-{}.log", std::process::id()));
+        let line_length = serde_json::to_vec(&entry("/1")).unwrap().len() as u64 + 1;
+        // Room for two entries per file
+        let log = AuditLog::new(&AuditConfig { path: path.clone(), max_bytes: line_length * 2 });
+        for uri in ["/1", "/2", "/3", "/4", "/5"] {
+            log.append(&entry(uri)).await.unwrap();
+        }
+        let uris: Vec<String> = log.recent(10).await.unwrap().into_iter().map(|entry| entry.uri).collect();
+        assert_eq!(uris, ["/5", "/4", "/3"]);
+        assert_eq!(log.recent(1).await.unwrap()[0].uri, "/5");
+        assert!(std::fs::metadata(&path).unwrap().len() <= line_length * 2);
+        std::fs::remove_file(log.rotated_path()).ok();
+        std::fs::remove_file(&path).ok();
+    }
+}
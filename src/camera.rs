@@ -0,0 +1,627 @@
+//! The printer's MJPEG camera, only compiled with the camera feature
+use futures::StreamExt;
+use bytes::Bytes;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{Delay, DynamicImage, Frame, ImageFormat};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use log::{trace, warn};
+use multipart_stream::Part;
+use reqwest::Url;
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::task::JoinHandle;
+use tracing::field::Empty;
+use tracing::{debug_span, Instrument, Span};
+use crate::config::Flip;
+use crate::models::CameraHealth;
+use crate::printer::{ConnectionLimit, DEFAULT_CONNECTIONS, DEFAULT_CONNECTION_WAIT};
+use crate::util::host_port;
+
+pub const PRINTER_CAM_STREAM_PATH: &str = "/?action=stream";
+
+/// Quality of frames encoded again after [Transform::apply]
+const JPEG_QUALITY: u8 = 85;
+
+/// How frames are turned and scaled. Frames are decoded and encoded again with the image crate, so this costs CPU
+/// and is skipped entirely for [Transform::NONE]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    /// Degrees clockwise, after flipping
+    rotate: u16,
+    flip: Option<Flip>,
+    /// Scaled down to this width keeping the aspect ratio, narrower frames are left as they are
+    width: Option<u32>
+}
+
+impl Transform {
+    pub const NONE: Transform = Transform { rotate: 0, flip: None, width: None };
+
+    /// Mirrored first, then turned clockwise by rotate degrees. None if rotate is not 0, 90, 180 or 270
+    pub fn new(rotate: u16, flip: Option<Flip>) -> Option<Self> {
+        matches!(rotate, 0 | 90 | 180 | 270).then_some(Transform { rotate, flip, width: None })
+    }
+
+    pub fn with_width(self, width: Option<u32>) -> Self {
+        Transform { width, ..self }
+    }
+
+    /// The JPEG flipped, turned and scaled down, encoded again. [Transform::NONE] returns it as it is
+    pub fn apply(self, jpeg: &[u8]) -> Result<Vec<u8>, String> {
+        if self == Transform::NONE {
+            return Ok(jpeg.to_vec());
+        }
+        let mut image = image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg)
+            .map_err(|e| format!("could not decode the frame: {}", e))?;
+        image = match self.flip {
+            Some(Flip::Horizontal) => image.fliph(),
+            Some(Flip::Vertical) => image.flipv(),
+            None => image
+        };
+        image = match self.rotate {
+            90 => image.rotate90(),
+            180 => image.rotate180(),
+            270 => image.rotate270(),
+            _ => image
+        };
+        if let Some(width) = self.width {
+            image = scaled_down(image, width);
+        }
+        let mut encoded = Vec::with_capacity(jpeg.len());
+        JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY).encode_image(&image.to_rgb8())
+            .map_err(|e| format!("could not encode the frame: {}", e))?;
+        Ok(encoded)
+    }
+
+    /// [Transform::apply] on a blocking thread, decoding takes too long for the async workers
+    pub async fn apply_blocking(self, jpeg: Vec<u8>) -> Result<Vec<u8>, String> {
+        if self == Transform::NONE {
+            return Ok(jpeg);
+        }
+        tokio::task::spawn_blocking(move || self.apply(&jpeg)).await.map_err(|e| e.to_string())?
+    }
+}
+
+/// The image scaled down to width keeping the aspect ratio, narrower images are returned as they are
+fn scaled_down(image: DynamicImage, width: u32) -> DynamicImage {
+    if width >= image.width() {
+        return image;
+    }
+    let height = (image.height() as u64 * width as u64 / image.width() as u64).max(1) as u32;
+    image.resize_exact(width, height, FilterType::Triangle)
+}
+
+/// Speed of the GIF encoder's color quantization, from 1 (best colors) to 30
+const GIF_SPEED: i32 = 10;
+
+/// How many frames are kept for [Camera::gif], from camera.gif_*
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GifSettings {
+    /// 0 keeps none
+    pub frames: usize,
+    /// Frames arriving sooner after the last kept one are skipped, it is also the delay between frames of the GIF
+    pub interval: Duration,
+    /// Total size of the kept frames, the oldest are dropped past it
+    pub max_bytes: usize,
+    /// Wider frames are scaled down to this
+    pub width: u32
+}
+
+impl Default for GifSettings {
+    fn default() -> Self {
+        GifSettings { frames: 0, interval: Duration::from_secs(2), max_bytes: 0, width: 640 }
+    }
+}
+
+/// The last frames for [Camera::gif], as encoded by the camera
+#[derive(Default)]
+struct RecentFrames {
+    settings: GifSettings,
+    frames: VecDeque<(Bytes, Instant)>,
+    /// Size of every frame in frames
+    bytes: usize
+}
+
+impl RecentFrames {
+    fn push(&mut self, frame: &Bytes, now: Instant) {
+        if self.settings.frames == 0 || self.frames.back().is_some_and(|(_, at)| now.duration_since(*at) < self.settings.interval) {
+            return;
+        }
+        self.frames.push_back((frame.clone(), now));
+        self.bytes += frame.len();
+        while self.frames.len() > self.settings.frames || self.bytes > self.settings.max_bytes {
+            let Some((dropped, _)) = self.frames.pop_front() else { break };
+            self.bytes -= dropped.len();
+        }
+    }
+}
+
+/// The JPEG frames as an animated GIF looping forever, each shown for delay. Frames are scaled down to width, and
+/// to the size of the first one should the camera's change
+fn encode_gif(frames: &[Bytes], delay: Duration, width: u32) -> Result<Vec<u8>, String> {
+    let mut gif = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut gif, GIF_SPEED);
+        encoder.set_repeat(Repeat::Infinite).map_err(|e| e.to_string())?;
+        let mut size = None;
+        for jpeg in frames {
+            let image = image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg)
+                .map_err(|e| format!("could not decode the frame: {}", e))?;
+            let image = scaled_down(image, width);
+            let (width, height) = *size.get_or_insert((image.width(), image.height()));
+            let image = if (image.width(), image.height()) == (width, height) { image } else { image.resize_exact(width, height, FilterType::Triangle) };
+            encoder.encode_frame(Frame::from_parts(image.to_rgba8(), 0, 0, Delay::from_saturating_duration(delay)))
+                .map_err(|e| format!("could not encode the GIF: {}", e))?;
+        }
+    }
+    Ok(gif)
+}
+
+/// A frame and when it was received
+type ReceivedImage = (Vec<u8>, Instant);
+
+/// Frames buffered per subscriber until configured, see [Camera::set_buffered_frames]
+const DEFAULT_BUFFERED_FRAMES: usize = 4;
+/// Frames are counted over this long for [CameraHealth::frames_last_minute]
+const FRAME_WINDOW: Duration = Duration::from_secs(60);
+
+/// What the camera task saw, for [Camera::health]
+#[derive(Default)]
+struct StreamStats {
+    /// When each frame of the last [FRAME_WINDOW] was received
+    recent_frames: VecDeque<Instant>,
+    last_frame_at: Option<OffsetDateTime>,
+    last_error: Option<String>
+}
+
+impl StreamStats {
+    fn frame_received(&mut self, now: Instant) {
+        self.recent_frames.push_back(now);
+        self.last_frame_at = Some(OffsetDateTime::now_utc());
+        self.prune(now);
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while self.recent_frames.front().is_some_and(|frame| now.duration_since(*frame) > FRAME_WINDOW) {
+            self.recent_frames.pop_front();
+        }
+    }
+}
+
+/// The next frame for the subscriber. One that fell behind the buffered frames skips to the newest frame instead of
+/// failing, the skipped frames are dropped. None once the camera task stopped
+pub async fn next_frame(rx: &mut broadcast::Receiver<Part>) -> Option<Part> {
+    loop {
+        match rx.recv().await {
+            Ok(part) => return Some(part),
+            Err(RecvError::Lagged(skipped)) => {
+                trace!("subscriber lagged, skipping {} frames", skipped);
+                // Only the oldest frames were dropped, the newest is at the end of the buffer
+                let mut newest = None;
+                loop {
+                    match rx.try_recv() {
+                        Ok(part) => newest = Some(part),
+                        Err(TryRecvError::Lagged(_)) => continue,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Closed) => return newest
+                    }
+                }
+                if newest.is_some() {
+                    return newest;
+                }
+            },
+            Err(RecvError::Closed) => return None
+        }
+    }
+}
+
+/// One connection to the camera's stream, shared by every subscriber and dropped once they are all gone
+pub struct Camera {
+    /// Printer name, for the camera task's span
+    name: String,
+    stream_url: String,
+    /// Replaced by [Camera::set_buffered_frames], the running task keeps the one it was started with
+    channel: Mutex<broadcast::Sender<Part>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+    /// Already in the transform of [Camera::set_transform], so notifications don't turn every frame they attach
+    last_image: Arc<RwLock<Option<ReceivedImage>>>,
+    transform: Arc<Mutex<Transform>>,
+    /// Fed by the camera task, for [Camera::gif]
+    recent: Arc<Mutex<RecentFrames>>,
+    /// Keeps the camera task running without a viewer, see [Camera::record]
+    recorder: Mutex<Option<broadcast::Receiver<Part>>>,
+    stats: Arc<Mutex<StreamStats>>,
+    /// Held by the camera task while it is connected, and by [Camera::probe]
+    connections: Arc<ConnectionLimit>
+}
+
+impl Camera {
+    pub fn new(name: String, host: &str, port: u16) -> Self {
+        let (tx, _) = broadcast::channel(DEFAULT_BUFFERED_FRAMES);
+        Camera {
+            name,
+            stream_url: format!("http://{}{}", host_port(host, port), PRINTER_CAM_STREAM_PATH),
+            channel: Mutex::new(tx),
+            task: Mutex::new(None),
+            last_image: Arc::new(RwLock::new(None)),
+            transform: Arc::new(Mutex::new(Transform::NONE)),
+            recent: Arc::new(Mutex::new(RecentFrames::default())),
+            recorder: Mutex::new(None),
+            stats: Arc::new(Mutex::new(StreamStats::default())),
+            connections: Arc::new(ConnectionLimit::new(DEFAULT_CONNECTIONS, DEFAULT_CONNECTION_WAIT)),
+        }
+    }
+
+    pub fn stream_url(&self) -> &str {
+        &self.stream_url
+    }
+
+    /// Whether the camera task runs, who is watching and how recently frames arrived
+    pub fn health(&self) -> CameraHealth {
+        let mut stats = self.stats.lock().unwrap();
+        stats.prune(Instant::now());
+        CameraHealth {
+            running: self.task.lock().unwrap().as_ref().is_some_and(|task| !task.is_finished()),
+            subscribers: self.channel.lock().unwrap().receiver_count(),
+            frames_last_minute: stats.recent_frames.len(),
+            last_frame_at: stats.last_frame_at,
+            last_error: stats.last_error.clone(),
+            connections: self.connections.usage()
+        }
+    }
+
+    pub fn connections(&self) -> &ConnectionLimit {
+        &self.connections
+    }
+
+    /// Checks that the stream answers, for the self-test. While the camera task is connected and receiving frames
+    /// that counts, instead of opening another connection than the limit allows
+    pub async fn probe(&self) -> Result<(), String> {
+        let Some(_permit) = self.connections.try_acquire() else {
+            return match self.health().frames_last_minute {
+                0 => Err("every connection to the camera is in use and no frame arrived in the last minute".to_string()),
+                _ => Ok(())
+            };
+        };
+        reqwest::Client::new().head(self.stream_url()).send().await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Frames kept for each subscriber, from camera.buffered_frames. Frames are large, a subscriber falling further
+    /// behind skips to the newest frame with [next_frame]
+    pub fn set_buffered_frames(&self, frames: usize) {
+        *self.channel.lock().unwrap() = broadcast::channel(frames.max(1)).0;
+    }
+
+    /// The rotation and flip of printers.<id>.camera. Frames received from now on are turned once by the camera task,
+    /// for the stream, snapshots and notifications alike
+    pub fn set_transform(&self, transform: Transform) {
+        *self.transform.lock().unwrap() = transform;
+    }
+
+    /// How many frames are kept for [Camera::gif], frames already kept are dropped
+    pub fn set_gif(&self, settings: GifSettings) {
+        *self.recent.lock().unwrap() = RecentFrames { settings, ..Default::default() };
+    }
+
+    /// The kept frames as an animated GIF and when its last frame was received, encoded on a blocking thread.
+    /// Frames are only kept while the camera task runs, for a viewer of the stream, a snapshot or [Camera::record]
+    pub async fn gif(&self) -> Result<(Vec<u8>, Instant), String> {
+        let (frames, settings, last) = {
+            let recent = self.recent.lock().unwrap();
+            let Some(last) = recent.frames.back().map(|(_, at)| *at) else {
+                return Err("no frames were kept yet".to_string());
+            };
+            (recent.frames.iter().map(|(frame, _)| frame.clone()).collect::<Vec<_>>(), recent.settings, last)
+        };
+        let gif = tokio::task::spawn_blocking(move || encode_gif(&frames, settings.interval, settings.width)).await.map_err(|e| e.to_string())??;
+        Ok((gif, last))
+    }
+
+    /// While on, keeps the camera task running without a viewer so [Camera::gif] has the last frames. Called on
+    /// every poll, reconnecting once the stream ended. The recording counts as a subscriber
+    pub fn record(&self, on: bool) {
+        let mut recorder = self.recorder.lock().unwrap();
+        if !on {
+            *recorder = None;
+            return;
+        }
+        let running = self.task.lock().unwrap().as_ref().is_some_and(|task| !task.is_finished());
+        if recorder.is_none() || !running {
+            *recorder = self.subscribe().inspect_err(|e| warn!("could not record camera: {}", e)).ok();
+        }
+    }
+
+    /// Returns the last received image, in the transform of [Camera::set_transform], and when it was received, if any.
+    /// It can be from long ago, call [Camera::snapshot] for a live one
+    pub fn last_image(&self) -> Option<ReceivedImage> {
+        let read = self.last_image.read().expect("poisoned");
+        read.clone()
+    }
+
+    /// Stores the image as if the camera sent it at received
+    #[cfg(test)]
+    pub fn store_image(&self, image: Vec<u8>, received: Instant) {
+        *self.last_image.write().unwrap() = Some((image, received));
+    }
+
+    /// Gets a fresh camera snapshot in the transform of [Camera::set_transform], by internally calling [Camera::subscribe]
+    pub async fn snapshot(&self) -> Result<Vec<u8>, String> {
+        let mut rx = self.subscribe().map_err(|e| e.to_string())?;
+        trace!("subscribed, now waiting for image");
+        let part = tokio::select! {
+            biased;
+            part = next_frame(&mut rx) => part.ok_or("camera stream ended")?,
+            // The task records why it stopped, such as every connection being in use
+            _ = self.stopped() => return Err(self.stats.lock().unwrap().last_error.clone()
+                .unwrap_or_else(|| "camera stream ended without a frame".to_string()))
+        };
+        trace!("returning image");
+        Ok(part.body.to_vec())
+    }
+
+    /// Resolves once the camera task has stopped, such as when the camera could not be reached
+    async fn stopped(&self) {
+        loop {
+            if self.task.lock().unwrap().as_ref().is_none_or(|task| task.is_finished()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Stops the camera task, the next subscriber reconnects
+    pub fn stop(&self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+
+    /// Returns a receiver that returns Part (header and image body from multipart/x-mixed-replace)
+    /// If there is not already a connection to printer's camera, a new one will be created.
+    /// Image is JPEG, size is provided in header `Content-length`
+    pub fn subscribe(&self) -> Result<broadcast::Receiver<Part>, String> {
+        let sub = self.channel.lock().unwrap().subscribe();
+        let image_store = self.last_image.clone();
+        let transform = self.transform.clone();
+        let recent = self.recent.clone();
+        let stats = self.stats.clone();
+        let connections = self.connections.clone();
+        let mut camera_task = self.task.lock().unwrap();
+        if camera_task.is_none() || camera_task.as_ref().unwrap().is_finished() {
+            let stream_url = Url::parse(&self.stream_url).map_err(|e| e.to_string())?;
+            trace!("starting new camera task. stream url = {:?}", stream_url);
+
+            let tx = self.channel.lock().unwrap().clone();
+            let span = debug_span!("camera", printer = %self.name, frames = Empty, duration_ms = Empty);
+            let task = tokio::spawn(async move {
+                let started = Instant::now();
+                // Held until the stream ends
+                let _permit = match connections.acquire("camera").await {
+                    Ok(permit) => permit,
+                    Err(e) => {
+                        warn!("could not connect to camera: {}", e);
+                        stats.lock().unwrap().last_error = Some(e.to_string());
+                        return;
+                    }
+                };
+                trace!("starting reqwest");
+                let res = match reqwest::get(stream_url).await.and_then(|res| res.error_for_status()) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        warn!("could not connect to camera: {}", e);
+                        stats.lock().unwrap().last_error = Some(format!("could not connect: {}", e));
+                        return;
+                    }
+                };
+                let bytes_stream = res.bytes_stream();
+                trace!("starting read loop");
+                let image_store = image_store;
+                let mut chunk_stream = multipart_stream::parse(bytes_stream, "boundarydonotcross");
+                let mut frames: u64 = 0;
+                let mut transform_failed = false;
+                loop {
+                    let part = match chunk_stream.next().await {
+                        Some(Ok(part)) => part,
+                        Some(Err(e)) => {
+                            stats.lock().unwrap().last_error = Some(format!("stream failed: {}", e));
+                            break;
+                        },
+                        None => {
+                            stats.lock().unwrap().last_error = Some("stream ended".to_string());
+                            break;
+                        }
+                    };
+                    frames += 1;
+                    stats.lock().unwrap().frame_received(Instant::now());
+                    let transform = *transform.lock().unwrap();
+                    let part = match transformed(part.clone(), transform).await {
+                        Ok(part) => part,
+                        Err(e) => {
+                            // Every frame would fail the same way, sent as received instead
+                            if !transform_failed {
+                                warn!("could not transform camera frames, sending them as received: {}", e);
+                                transform_failed = true;
+                            }
+                            part
+                        }
+                    };
+                    *image_store.write().unwrap() = Some((part.body.to_vec(), Instant::now()));
+                    recent.lock().unwrap().push(&part.body, Instant::now());
+                    if tx.send(part).is_err() {
+                        trace!("no more subscribers, stopping task");
+                        break;
+                    }
+                }
+                let span = Span::current();
+                span.record("frames", frames);
+                span.record("duration_ms", started.elapsed().as_millis() as u64);
+                trace!("camera stream ended");
+            }.instrument(span));
+            *camera_task = Some(task);
+        }
+        Ok(sub)
+    }
+}
+
+/// The frame in the transform, with its Content-Length updated for the stream
+async fn transformed(part: Part, transform: Transform) -> Result<Part, String> {
+    if transform == Transform::NONE {
+        return Ok(part);
+    }
+    let image = transform.apply_blocking(part.body.to_vec()).await?;
+    let mut headers = part.headers;
+    headers.insert("content-length", image.len().into());
+    Ok(Part { headers, body: image.into() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{decode, is_red, mock_camera, unused_port, CAMERA_IMAGE};
+
+    fn frame(n: u8) -> Part {
+        Part { headers: Default::default(), body: vec![n].into() }
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_skip_to_the_newest_frame() {
+        let camera = Camera::new("main".to_string(), "127.0.0.1", unused_port().await);
+        camera.set_buffered_frames(2);
+        let tx = camera.channel.lock().unwrap().clone();
+        let mut slow = tx.subscribe();
+        let mut fast = tx.subscribe();
+        let mut received = Vec::new();
+        for n in 0..10 {
+            tx.send(frame(n)).unwrap();
+            received.push(next_frame(&mut fast).await.unwrap().body[0]);
+        }
+        assert_eq!(received, (0..10).collect::<Vec<u8>>());
+
+        // Fell 8 frames behind, only the newest is sent and then it keeps up again
+        assert_eq!(next_frame(&mut slow).await.unwrap().body[0], 9);
+        tx.send(frame(10)).unwrap();
+        assert_eq!(next_frame(&mut slow).await.unwrap().body[0], 10);
+        drop((tx, camera));
+        assert!(next_frame(&mut slow).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn health_follows_the_stream() {
+        let camera = Camera::new("main".to_string(), "127.0.0.1", mock_camera(Duration::ZERO).await);
+        let health = camera.health();
+        assert!(!health.running);
+        assert_eq!((health.subscribers, health.frames_last_minute), (0, 0));
+
+        let mut frames = camera.subscribe().unwrap();
+        frames.recv().await.unwrap();
+        let health = camera.health();
+        assert!(health.running);
+        assert_eq!(health.subscribers, 1);
+        assert!(health.frames_last_minute >= 1);
+        assert!(health.last_frame_at.is_some());
+        assert_eq!(health.last_error, None);
+
+        let offline = Camera::new("offline".to_string(), "127.0.0.1", unused_port().await);
+        assert!(offline.snapshot().await.is_err());
+        let health = offline.health();
+        assert!(!health.running);
+        assert!(health.last_error.unwrap().starts_with("could not connect"));
+    }
+
+    #[tokio::test]
+    async fn snapshots_wait_for_a_free_connection() {
+        let camera = Camera::new("main".to_string(), "127.0.0.1", mock_camera(Duration::ZERO).await);
+        camera.connections().set(1, Duration::from_millis(100));
+        let held = camera.connections().try_acquire().unwrap();
+        let error = camera.snapshot().await.unwrap_err();
+        assert_eq!(error, "all 1 connections to the camera stayed in use for 100ms");
+        assert_eq!(camera.health().connections.in_use, 1);
+        assert!(camera.probe().await.is_err());
+        drop(held);
+        assert!(camera.probe().await.is_ok());
+        assert_eq!(camera.snapshot().await.unwrap(), CAMERA_IMAGE);
+    }
+
+    #[test]
+    fn transforms_encode_the_frame_again() {
+        assert_eq!(Transform::NONE.apply(CAMERA_IMAGE).unwrap(), CAMERA_IMAGE);
+        assert_eq!(Transform::new(45, None), None);
+        // Red on the left, blue on the right
+        assert_eq!((decode(CAMERA_IMAGE).width(), decode(CAMERA_IMAGE).height()), (16, 8));
+
+        let turned = decode(&Transform::new(90, None).unwrap().apply(CAMERA_IMAGE).unwrap());
+        assert_eq!((turned.width(), turned.height()), (8, 16));
+        assert!(is_red(turned.get_pixel(4, 2)) && !is_red(turned.get_pixel(4, 13)));
+        let mirrored = decode(&Transform::new(0, Some(Flip::Horizontal)).unwrap().apply(CAMERA_IMAGE).unwrap());
+        assert!(!is_red(mirrored.get_pixel(2, 4)) && is_red(mirrored.get_pixel(13, 4)));
+        // Vertically the halves stay where they are
+        let mirrored = decode(&Transform::new(0, Some(Flip::Vertical)).unwrap().apply(CAMERA_IMAGE).unwrap());
+        assert!(is_red(mirrored.get_pixel(2, 4)));
+
+        let scaled = decode(&Transform::NONE.with_width(Some(8)).apply(CAMERA_IMAGE).unwrap());
+        assert_eq!((scaled.width(), scaled.height()), (8, 4));
+        // Never scaled up
+        let wide = decode(&Transform::NONE.with_width(Some(640)).apply(CAMERA_IMAGE).unwrap());
+        assert_eq!(wide.width(), 16);
+        assert!(Transform::new(180, None).unwrap().apply(b"not a jpeg").is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_are_transformed_once_for_everyone() {
+        let camera = Camera::new("main".to_string(), "127.0.0.1", mock_camera(Duration::ZERO).await);
+        camera.set_transform(Transform::new(180, None).unwrap());
+        let mut frames = camera.subscribe().unwrap();
+        let part = next_frame(&mut frames).await.unwrap();
+        assert_eq!(part.headers["content-length"].to_str().unwrap(), part.body.len().to_string());
+        let image = decode(&part.body);
+        assert!(!is_red(image.get_pixel(2, 4)) && is_red(image.get_pixel(13, 4)));
+        assert_eq!(camera.last_image().unwrap().0, part.body.to_vec());
+    }
+
+    #[test]
+    fn kept_frames_are_capped() {
+        let settings = GifSettings { frames: 3, interval: Duration::from_secs(2), max_bytes: 10, width: 640 };
+        let mut recent = RecentFrames { settings, ..Default::default() };
+        let start = Instant::now();
+        for (n, secs) in [0, 1, 2, 4, 6].into_iter().enumerate() {
+            recent.push(&Bytes::from(vec![n as u8; 2]), start + Duration::from_secs(secs));
+        }
+        // The one a second after the first is skipped, then the oldest of four is dropped
+        let kept: Vec<u8> = recent.frames.iter().map(|(frame, _)| frame[0]).collect();
+        assert_eq!(kept, [2, 3, 4]);
+        assert_eq!(recent.bytes, 6);
+
+        recent.push(&Bytes::from(vec![5; 8]), start + Duration::from_secs(8));
+        let kept: Vec<u8> = recent.frames.iter().map(|(frame, _)| frame[0]).collect();
+        assert_eq!(kept, [4, 5]);
+        assert_eq!(recent.bytes, 10);
+        // Larger than the cap on its own
+        recent.push(&Bytes::from(vec![6; 11]), start + Duration::from_secs(10));
+        assert!(recent.frames.is_empty());
+        assert_eq!(recent.bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn kept_frames_are_a_gif() {
+        let camera = Camera::new("main".to_string(), "127.0.0.1", mock_camera(Duration::ZERO).await);
+        assert_eq!(camera.gif().await.unwrap_err(), "no frames were kept yet");
+        camera.set_gif(GifSettings { frames: 3, interval: Duration::ZERO, max_bytes: 1 << 20, width: 8 });
+        let mut frames = camera.subscribe().unwrap();
+        for _ in 0..4 {
+            next_frame(&mut frames).await.unwrap();
+        }
+        let (gif, _) = camera.gif().await.unwrap();
+        let decoder = image::codecs::gif::GifDecoder::new(std::io::Cursor::new(gif)).unwrap();
+        let frames = image::AnimationDecoder::into_frames(decoder).collect_frames().unwrap();
+        assert_eq!(frames.len(), 3);
+        // Scaled down to gif_width
+        assert_eq!(frames[0].buffer().dimensions(), (8, 4));
+    }
+}
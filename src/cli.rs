@@ -0,0 +1,133 @@
+//! Subcommands that talk to a printer directly, without starting the server. No subcommand launches the server
+use std::time::Duration;
+use serde::Serialize;
+use flashforge_protocol::PrinterRequest;
+use crate::printer::{Printer, PrinterError, PRINTER_API_PORT};
+
+const USAGE: &str = "\
+Usage:
+  flashforge-api-server [--check-config]          Start the server
+  flashforge-api-server query --ip <ip> <command> Print the printer's status, info, temps, progress or position as JSON
+  flashforge-api-server send --ip <ip> <gcode>    Send G-code such as \"~M105\" and print the response";
+
+/// Commands of query, by name
+const QUERIES: [(&str, PrinterRequest); 5] = [
+    ("status", PrinterRequest::GetStatus),
+    ("info", PrinterRequest::GetInfo),
+    ("temps", PrinterRequest::GetTemperature),
+    ("progress", PrinterRequest::GetProgress),
+    ("position", PrinterRequest::GetHeadPosition),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Query { ip: String, query: &'static str },
+    Send { ip: String, gcode: String }
+}
+
+/// The subcommand in args, without the program name. None when the server should be started
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Command>, String> {
+    let subcommand = match args.next() {
+        Some(subcommand) if subcommand == "query" || subcommand == "send" => subcommand,
+        _ => return Ok(None)
+    };
+    let mut ip = None;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--ip") {
+            Some("") => ip = Some(args.next().ok_or("--ip needs a value")?),
+            Some(value) if value.starts_with('=') => ip = Some(value[1..].to_string()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => positional.push(arg)
+        }
+    }
+    let ip = ip.ok_or("--ip is required")?;
+    let [argument] = <[String; 1]>::try_from(positional)
+        .map_err(|_| format!("{} takes exactly one {}", subcommand, if subcommand == "query" { "command" } else { "G-code" }))?;
+    if subcommand == "send" {
+        return Ok(Some(Command::Send { ip, gcode: argument }));
+    }
+    let (query, _) = QUERIES.iter().find(|(name, _)| *name == argument)
+        .ok_or_else(|| format!("unknown command {}, expected one of {}", argument, QUERIES.map(|(name, _)| name).join(", ")))?;
+    Ok(Some(Command::Query { ip, query }))
+}
+
+/// Runs the subcommand in the process's arguments and exits, returns when there is none.
+/// Output is the JSON or response on stdout, errors go to stderr with a non-zero exit code
+pub async fn run_if_requested() {
+    let command = match parse(std::env::args().skip(1)) {
+        Ok(Some(command)) => command,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let ip = match &command {
+        Command::Query { ip, .. } | Command::Send { ip, .. } => ip.clone()
+    };
+    let printer = Printer::new(ip.clone(), ip, PRINTER_API_PORT, Duration::from_secs(5));
+    let output = run(&printer, command).await;
+    printer.shutdown().await;
+    match output {
+        Ok(output) => println!("{}", output),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run(printer: &Printer, command: Command) -> Result<String, PrinterError> {
+    fn to_json<T: Serialize>(value: T) -> String {
+        serde_json::to_string_pretty(&value).unwrap_or_default()
+    }
+    match command {
+        Command::Query { query: "status", .. } => printer.get_status().await.map(to_json),
+        Command::Query { query: "info", .. } => printer.get_info().await.map(to_json),
+        Command::Query { query: "temps", .. } => printer.get_temperatures().await.map(to_json),
+        Command::Query { query: "progress", .. } => printer.get_progress().await.map(to_json),
+        Command::Query { .. } => printer.get_head_position().await.map(to_json),
+        Command::Send { gcode, .. } => printer.send_raw(PrinterRequest::Raw(gcode)).await.map(|response| response.text.trim_end().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockPrinter;
+
+    fn parse_args(args: &[&str]) -> Result<Option<Command>, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        assert_eq!(parse_args(&[]), Ok(None));
+        assert_eq!(parse_args(&["--check-config"]), Ok(None));
+        assert_eq!(parse_args(&["query", "--ip", "10.0.0.50", "status"]), Ok(Some(Command::Query { ip: "10.0.0.50".to_string(), query: "status" })));
+        assert_eq!(parse_args(&["query", "temps", "--ip=10.0.0.50"]), Ok(Some(Command::Query { ip: "10.0.0.50".to_string(), query: "temps" })));
+        assert_eq!(parse_args(&["send", "--ip", "10.0.0.50", "~M105"]), Ok(Some(Command::Send { ip: "10.0.0.50".to_string(), gcode: "~M105".to_string() })));
+
+        assert_eq!(parse_args(&["query", "status"]), Err("--ip is required".to_string()));
+        assert_eq!(parse_args(&["query", "--ip"]), Err("--ip needs a value".to_string()));
+        assert_eq!(parse_args(&["query", "--ip", "10.0.0.50", "--port", "8899"]), Err("unknown option --port".to_string()));
+        assert_eq!(parse_args(&["send", "--ip", "10.0.0.50"]), Err("send takes exactly one G-code".to_string()));
+        assert!(parse_args(&["query", "--ip", "10.0.0.50", "files"]).unwrap_err().starts_with("unknown command files"));
+    }
+
+    #[tokio::test]
+    async fn queries_and_sends_to_the_printer() {
+        let mock = MockPrinter::start().await;
+        let printer = Printer::with_ports("mock".to_string(), "127.0.0.1".to_string(), mock.port, 0, Duration::from_secs(5));
+
+        let status = run(&printer, Command::Query { ip: String::new(), query: "status" }).await.unwrap();
+        let status: serde_json::Value = serde_json::from_str(&status).unwrap();
+        assert!(status["machine_status"].is_string());
+
+        mock.respond("M105", "CMD M105 Received.\nT0:205/210 B:60/60\nok\n");
+        let response = run(&printer, Command::Send { ip: String::new(), gcode: "~M105".to_string() }).await.unwrap();
+        assert_eq!(response, "CMD M105 Received.\r\nT0:205/210 B:60/60\r\nok");
+        assert!(mock.received().concat().contains(&"~M105".to_string()));
+    }
+}
@@ -0,0 +1,115 @@
+//! Compression of JSON responses, for clients on slow connections polling the larger endpoints
+use std::io::{Cursor, Write};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use log::warn;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::{Request, Response};
+use crate::config::CompressionConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Gzip,
+    Deflate
+}
+
+impl Encoding {
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate"
+        }
+    }
+
+    /// Picks gzip over deflate, leaving out encodings the client refused with q=0
+    fn accepted(accept_encoding: &str) -> Option<Self> {
+        let accepted: Vec<&str> = accept_encoding.split(',')
+            .filter_map(|value| {
+                let mut params = value.split(';').map(str::trim);
+                let name = params.next()?;
+                let refused = params.any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+                (!refused).then_some(name)
+            })
+            .collect();
+        [Encoding::Gzip, Encoding::Deflate].into_iter()
+            .find(|encoding| accepted.iter().any(|name| name.eq_ignore_ascii_case(encoding.name()) || *name == "*"))
+    }
+
+    fn compress(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            },
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compresses JSON responses of at least http.compression.min_size bytes. Only JSON is compressed,
+/// the snapshot is already compressed and the camera stream has to be sent as it arrives
+pub struct Compression {
+    min_size: usize
+}
+
+impl Compression {
+    pub fn new(config: &CompressionConfig) -> Self {
+        Self { min_size: config.min_size }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info { name: "Compression", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if response.content_type() != Some(ContentType::JSON) || response.headers().contains("Content-Encoding") {
+            return;
+        }
+        // The same uri may be answered compressed or not, so caches have to key on the header either way
+        response.adjoin_raw_header("Vary", "Accept-Encoding");
+        let Some(encoding) = request.headers().get_one("Accept-Encoding").and_then(Encoding::accepted) else { return };
+        // Streamed bodies have no size up front
+        if response.body().preset_size().is_none_or(|size| size < self.min_size) {
+            return;
+        }
+        let body = match response.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("could not read response to compress: {}", e);
+                return;
+            }
+        };
+        let body = match encoding.compress(&body) {
+            Ok(compressed) => {
+                response.set_raw_header("Content-Encoding", encoding.name());
+                compressed
+            },
+            Err(e) => {
+                warn!("could not compress response: {}", e);
+                body
+            }
+        };
+        response.set_sized_body(body.len(), Cursor::new(body));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_an_accepted_encoding() {
+        assert_eq!(Encoding::accepted("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::accepted("deflate;q=0.5, gzip;q=0"), Some(Encoding::Deflate));
+        assert_eq!(Encoding::accepted("*"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::accepted("br, identity"), None);
+    }
+}
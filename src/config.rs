@@ -1,56 +1,761 @@
 use std::cmp::PartialEq;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::{IpAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use log::{error};
-use mail_send::{Credentials, SmtpClient, SmtpClientBuilder};
-use serde::{Deserialize, Serialize};
-use tokio::net::{TcpStream};
-use tokio::sync::Mutex;
-use tokio_rustls::client::TlsStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use log::{error, info};
+use rustls_pemfile::Item;
+use serde::{Deserialize, Deserializer, Serialize};
+use time::macros::format_description;
+use flashforge_protocol::PrinterRequest;
+#[cfg(feature = "smtp")]
+use crate::mailer::Mailer;
 
-use crate::manager::NotificationType;
+use crate::models::{DestinationKind, PrinterLabels, SmtpState, SmtpStatus};
+use crate::notifications::NotificationType;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub(crate) smtp: Option<EmailConfig>,
-    pub(crate) notifications: Option<HashMap<String, NotificationDestinations>>,
+    pub(crate) notifications: Option<NotificationsConfig>,
     pub(crate) auth: Option<AuthConfig>,
+    pub(crate) watch: Option<WatchConfig>,
+    #[serde(default)]
+    pub(crate) webhook: WebhookSettings,
+    pub(crate) mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub(crate) http: HttpConfig,
+    #[serde(default)]
+    pub(crate) discovery: DiscoveryConfig,
+    pub(crate) moonraker: Option<MoonrakerConfig>,
+    pub(crate) history: Option<HistoryConfig>,
+    pub(crate) filament: Option<FilamentConfig>,
+    pub(crate) gcode: Option<GcodeConfig>,
+    pub(crate) state: Option<StateConfig>,
+    pub(crate) audit: Option<AuditConfig>,
+    #[serde(default)]
+    pub(crate) shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub(crate) logging: LoggingConfig,
+    pub(crate) debug: Option<DebugConfig>,
+    #[serde(default)]
+    pub(crate) camera: CameraConfig,
+    #[serde(default)]
+    pub(crate) privacy: PrivacyConfig,
+    /// Named sequences of G-code, run by POST /api/printers/<id>/macros/<name>
+    #[serde(default)]
+    pub(crate) macros: BTreeMap<String, MacroConfig>,
     pub(crate) printers: HashMap<String, PrinterConfig>
 }
 
+static CONFIG_PATH: &str = "config.toml";
+
+/// G-codes macros can send: temperatures, fans, the light, homing and moves. Anything that starts, stops or
+/// changes a print, or takes control of the printer, is left to the API's own routes
+pub const RAW_COMMAND_ALLOWLIST: [&str; 9] = ["M104", "M140", "M106", "M107", "M146", "G28", "G1", "G90", "G91"];
+
+/// Keys of the [notifications] table, see [ConfigManager::get_notification_destinations]
+static NOTIFICATION_KEYS: [&str; 6] = ["on_started", "on_done", "on_error", "on_cancelled", "on_failed", "on_thermal"];
+
+impl Config {
+    /// Lowercases printer ids and the references to them, so ids can be looked up case insensitively
+    fn normalize_ids(&mut self) {
+        self.printers = std::mem::take(&mut self.printers).into_iter()
+            .map(|(id, printer)| (id.to_lowercase(), printer))
+            .collect();
+        if let Some(moonraker) = &mut self.moonraker {
+            moonraker.printer = moonraker.printer.to_lowercase();
+        }
+    }
+
+    /// Checks the config for problems serde can't catch, returning each one prefixed with its TOML key path
+    fn validate_destinations(&self, key: &str, destinations: &NotificationDestinations, problems: &mut Vec<String>) {
+        let has_emails = destinations.emails.as_ref().is_some_and(|emails| !emails.is_empty());
+        if cfg!(not(feature = "smtp")) && has_emails {
+            problems.push(format!("notifications.{}.emails: compiled without email support, rebuild with the smtp feature", key));
+        } else if has_emails && self.smtp.is_none() {
+            problems.push(format!("notifications.{}.emails: emails are configured but there is no [smtp] section", key));
+        }
+        for (i, webhook) in destinations.webhooks.iter().flatten().enumerate() {
+            match reqwest::Url::parse(&webhook.url) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {},
+                Ok(url) => problems.push(format!("notifications.{}.webhooks[{}]: unsupported url scheme \"{}\", expected http or https", key, i, url.scheme())),
+                Err(e) => problems.push(format!("notifications.{}.webhooks[{}]: invalid url \"{}\": {}", key, i, webhook.url, e))
+            }
+            if webhook.slack.is_some() && webhook.format != WebhookFormat::Slack {
+                problems.push(format!("notifications.{}.webhooks[{}].slack: only used with format = \"slack\"", key, i));
+            }
+            if webhook.timeout_seconds == Some(0) {
+                problems.push(format!("notifications.{}.webhooks[{}].timeout_seconds: must be at least 1", key, i));
+            }
+            if webhook.secret.as_ref().is_some_and(|secret| secret.is_empty()) {
+                problems.push(format!("notifications.{}.webhooks[{}].secret: must not be empty", key, i));
+            }
+            let mut names: Vec<_> = webhook.headers.keys().collect();
+            names.sort();
+            for name in names {
+                if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    problems.push(format!("notifications.{}.webhooks[{}].headers: invalid header name \"{}\"", key, i, name));
+                } else if reqwest::header::HeaderValue::from_str(&webhook.headers[name]).is_err() {
+                    problems.push(format!("notifications.{}.webhooks[{}].headers.{}: invalid header value", key, i, name));
+                }
+            }
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let mut ids: Vec<&String> = self.printers.keys().collect();
+        ids.sort();
+        let mut hosts: HashMap<(String, u16), &String> = HashMap::new();
+        let mut lowercase_ids: HashMap<String, &String> = HashMap::new();
+        for id in ids {
+            if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                problems.push(format!("printers.{:?}: printer id can only contain letters, numbers, '-' and '_'", id));
+            }
+            // Ids are lowercased when loaded
+            if let Some(other) = lowercase_ids.insert(id.to_lowercase(), id) {
+                problems.push(format!("printers.{:?}: same id as printers.{:?}, ids are not case sensitive", id, other));
+            }
+            let printer = &self.printers[id];
+            let (key, host) = match (&printer.ip, &printer.host) {
+                (Some(_), Some(_)) => {
+                    problems.push(format!("printers.{:?}: only one of ip or host can be set", id));
+                    continue;
+                },
+                (None, None) => {
+                    problems.push(format!("printers.{:?}: ip or host is required", id));
+                    continue;
+                },
+                (Some(ip), None) => ("ip", ip.to_string()),
+                (None, Some(host)) if host.is_empty() || host.contains(['/', ':', ' ']) => {
+                    let hint = if host.contains(':') { ", IPv6 addresses go in ip" } else { "" };
+                    problems.push(format!("printers.{:?}.host: {:?} is not a valid hostname{}", id, host, hint));
+                    continue;
+                },
+                (None, Some(host)) => ("host", host.clone())
+            };
+            if printer.api_port == 0 {
+                problems.push(format!("printers.{:?}.api_port: port is invalid", id));
+            }
+            if printer.console.capture && printer.console.max_lines == 0 {
+                problems.push(format!("printers.{:?}.console.max_lines: must be at least 1", id));
+            }
+            for (key, limit) in [("gcode", printer.connections.gcode), ("camera", printer.connections.camera)] {
+                if limit == 0 {
+                    problems.push(format!("printers.{:?}.connections.{}: must be at least 1", id, key));
+                }
+            }
+            if let Some(camera) = &printer.camera {
+                if cfg!(not(feature = "camera")) {
+                    problems.push(format!("printers.{:?}.camera: compiled without camera support, rebuild with the camera feature", id));
+                } else if camera.rotate % 90 != 0 || camera.rotate >= 360 {
+                    problems.push(format!("printers.{:?}.camera.rotate: {} is not 0, 90, 180 or 270", id, camera.rotate));
+                }
+            }
+            // Printers behind the same NAT share its address, on different ports
+            if let Some(other) = hosts.insert((host.to_lowercase(), printer.api_port), id) {
+                problems.push(format!("printers.{:?}.{}: {} is already used by printers.{:?}", id, key, host, other));
+            }
+        }
+
+        if let Some(notifications) = &self.notifications {
+            let mut keys: Vec<&String> = notifications.destinations.keys().collect();
+            keys.sort();
+            for key in keys {
+                if !NOTIFICATION_KEYS.contains(&key.as_str()) {
+                    problems.push(format!("notifications.{}: unknown notification type, expected one of {}, digest, history, templates_path, attach_gif", key, NOTIFICATION_KEYS.join(", ")));
+                    continue;
+                }
+                self.validate_destinations(key, &notifications.destinations[key], &mut problems);
+            }
+            if let Some(digest) = &notifications.digest {
+                if digest.time().is_none() {
+                    problems.push(format!("notifications.digest.time: invalid time \"{}\", expected HH:MM", digest.time));
+                }
+                if digest.offset().is_none() {
+                    problems.push(format!("notifications.digest.timezone: invalid timezone \"{}\", expected UTC or an offset such as +02:00", digest.timezone));
+                }
+                self.validate_destinations("digest", &digest.destinations, &mut problems);
+            }
+            if notifications.history.max_entries == 0 {
+                problems.push("notifications.history.max_entries: must be at least 1".to_string());
+            }
+            if let Some(path) = notifications.templates_path.as_ref().filter(|path| !path.is_dir()) {
+                problems.push(format!("notifications.templates_path: {} is not a directory", path.display()));
+            }
+            if notifications.attach_gif && cfg!(not(feature = "camera")) {
+                problems.push("notifications.attach_gif: compiled without camera support, rebuild with the camera feature".to_string());
+            } else if notifications.attach_gif && self.camera.gif_frames == 0 {
+                problems.push("notifications.attach_gif: camera.gif_frames is 0, no frames are kept to make the GIF of".to_string());
+            }
+        }
+
+        if let Some(auth) = &self.auth {
+            if auth.password.is_empty() && auth.tokens.is_empty() && (auth.password_for_write || auth.password_for_read) {
+                problems.push("auth.password: password is empty and there are no tokens but password_for_write or password_for_read is enabled, no request would be accepted".to_string());
+            }
+            let mut names = Vec::new();
+            for (i, token) in auth.tokens.iter().enumerate() {
+                if token.token.is_empty() {
+                    problems.push(format!("auth.tokens[{}].token: token is empty", i));
+                }
+                if names.contains(&&token.name) {
+                    problems.push(format!("auth.tokens[{}].name: {:?} is used by another token", i, token.name));
+                }
+                names.push(&token.name);
+            }
+        }
+
+        if cfg!(not(feature = "smtp")) && self.smtp.is_some() {
+            problems.push("smtp: compiled without email support, rebuild with the smtp feature".to_string());
+        } else if let Some(smtp) = &self.smtp {
+            if smtp.host.is_empty() {
+                problems.push("smtp.host: host is empty".to_string());
+            }
+            if smtp.port == 0 {
+                problems.push("smtp.port: port is invalid".to_string());
+            }
+            if smtp.user.is_empty() {
+                problems.push("smtp.user: user is empty, it is also used as the from address".to_string());
+            }
+            match (smtp.port, &smtp.encryption) {
+                (465, EmailEncryption::StartTls | EmailEncryption::None) => problems.push("smtp.encryption: port 465 expects encryption = \"tls\"".to_string()),
+                (587, EmailEncryption::Tls) => problems.push("smtp.encryption: port 587 expects encryption = \"starttls\"".to_string()),
+                _ => {}
+            }
+        }
+
+        if let Some(tls) = &self.http.tls {
+            problems.extend(tls.validate());
+        }
+        if let Some(path) = &self.http.unix_socket {
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if cfg!(not(unix)) {
+                problems.push("http.unix_socket: unix sockets are only supported on unix".to_string());
+            } else if !dir.is_dir() {
+                problems.push(format!("http.unix_socket: directory {} does not exist", dir.display()));
+            }
+            if self.http.tls.is_some() {
+                problems.push("http.unix_socket: can't be used with [http.tls], the reverse proxy in front does TLS".to_string());
+            }
+            if self.http.unix_socket_mode().is_none() {
+                problems.push(format!("http.unix_socket_mode: {:?} is not octal permissions such as \"660\"", self.http.unix_socket_mode));
+            }
+        }
+        if let Some(path) = self.http.access_log.as_ref().and_then(|access_log| access_log.path.as_ref()) {
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if !dir.is_dir() {
+                problems.push(format!("http.access_log.path: directory {} does not exist", dir.display()));
+            }
+        }
+        if self.http.port == Some(0) {
+            problems.push("http.port: port is invalid".to_string());
+        }
+        if cfg!(not(feature = "camera")) && self.camera.placeholder_path.is_some() {
+            problems.push("camera.placeholder_path: compiled without camera support, rebuild with the camera feature".to_string());
+        } else if let Some(path) = &self.camera.placeholder_path {
+            if let Err(e) = std::fs::metadata(path) {
+                problems.push(format!("camera.placeholder_path: {}: {}", path.display(), e));
+            }
+        }
+        if self.camera.buffered_frames == 0 {
+            problems.push("camera.buffered_frames: must be at least 1".to_string());
+        }
+        if self.camera.gif_interval_secs == 0 {
+            problems.push("camera.gif_interval_secs: must be at least 1".to_string());
+        }
+        if self.camera.gif_width == 0 {
+            problems.push("camera.gif_width: must be at least 1".to_string());
+        }
+        if let Some(rate_limit) = &self.http.rate_limit {
+            problems.extend(rate_limit.validate());
+        }
+
+        if let Some(history) = &self.history {
+            if history.retention_days == 0 {
+                problems.push("history.retention_days: must be at least 1".to_string());
+            }
+            if history.db_path.as_os_str().is_empty() {
+                problems.push("history.db_path: path is empty".to_string());
+            }
+        }
+
+        if let Some(filament) = &self.filament {
+            if filament.grams_per_megabyte.is_nan() || filament.grams_per_megabyte <= 0.0 {
+                problems.push(format!("filament.grams_per_megabyte: {} must be more than 0", filament.grams_per_megabyte));
+            }
+        }
+
+        if let Some(dir) = self.gcode.as_ref().map(|gcode| &gcode.dir).filter(|dir| !dir.is_dir()) {
+            problems.push(format!("gcode.dir: {} is not a directory", dir.display()));
+        }
+
+        if self.state.as_ref().is_some_and(|state| state.path.as_os_str().is_empty()) {
+            problems.push("state.path: path is empty".to_string());
+        }
+
+        if self.webhook.timeout_seconds == 0 {
+            problems.push("webhook.timeout_seconds: must be at least 1".to_string());
+        }
+        if self.webhook.retries > MAX_WEBHOOK_RETRIES {
+            problems.push(format!("webhook.retries: {} is more than {}", self.webhook.retries, MAX_WEBHOOK_RETRIES));
+        }
+        if let Some(proxy) = &self.webhook.proxy {
+            if let Err(e) = reqwest::Proxy::all(proxy) {
+                problems.push(format!("webhook.proxy: invalid proxy url \"{}\": {}", proxy, e));
+            }
+        }
+
+        if let Some(audit) = &self.audit {
+            if audit.path.as_os_str().is_empty() {
+                problems.push("audit.path: path is empty".to_string());
+            }
+            if audit.max_bytes == 0 {
+                problems.push("audit.max_bytes: must be at least 1".to_string());
+            }
+        }
+
+        if let Some(moonraker) = self.moonraker.as_ref().filter(|m| m.enabled) {
+            if !self.printers.keys().any(|id| id.eq_ignore_ascii_case(&moonraker.printer)) {
+                problems.push(format!("moonraker.printer: unknown printer {:?}", moonraker.printer));
+            }
+        }
+
+        for address in &self.discovery.broadcast_addresses {
+            if address.is_ipv6() {
+                problems.push(format!("discovery.broadcast_addresses: {} is not an IPv4 address, IPv6 has no broadcast", address));
+            }
+        }
+
+        for (name, printer_macro) in &self.macros {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                problems.push(format!("macros.{:?}: macro name can only contain letters, numbers, '-' and '_'", name));
+            }
+            if printer_macro.printer_commands.is_empty() {
+                problems.push(format!("macros.{:?}.printer_commands: has no commands", name));
+            }
+            for (i, command) in printer_macro.printer_commands.iter().enumerate() {
+                if !RAW_COMMAND_ALLOWLIST.contains(&MacroConfig::code(command).as_str()) {
+                    problems.push(format!("macros.{:?}.printer_commands[{}]: {:?} is not an allowed command, expected one of {}",
+                                          name, i, command, RAW_COMMAND_ALLOWLIST.join(", ")));
+                }
+            }
+        }
+
+        problems
+    }
+}
+
 pub struct ConfigManager {
     config: Config,
-    mailer: Option<Arc<Mutex<Mailer>>>,
+    /// File the config was read from, changes made at runtime are written back to it
+    path: Option<PathBuf>,
+    /// Held while the file is being rewritten, so concurrent changes don't overwrite each other
+    write_lock: std::sync::Mutex<()>,
+    /// Set up when SMTP is configured, it connects in the background
+    #[cfg(feature = "smtp")]
+    mailer: Option<Arc<Mailer>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NotificationsConfig {
+    pub(crate) digest: Option<DigestConfig>,
+    #[serde(default)]
+    pub(crate) history: NotificationHistoryConfig,
+    /// Directory of <type>.subject and <type>.body files replacing the built-in English subjects and bodies, such as print_complete.subject
+    pub(crate) templates_path: Option<PathBuf>,
+    /// print_error notifications attach a GIF of the last camera frames instead of a still, see [CameraConfig::gif_frames]
+    #[serde(default)]
+    pub(crate) attach_gif: bool,
+    /// Destinations of each notification type, keyed by one of [NOTIFICATION_KEYS]
+    #[serde(flatten)]
+    pub(crate) destinations: HashMap<String, NotificationDestinations>
+}
+
+/// The notifications sent, returned by GET /api/notifications/history
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NotificationHistoryConfig {
+    /// JSON lines file the history is kept in across restarts, only kept in memory when not set
+    pub(crate) path: Option<PathBuf>,
+    /// Most recent notifications kept, older ones are dropped
+    #[serde(default = "default_notification_history_max_entries")]
+    pub(crate) max_entries: usize
+}
+
+fn default_notification_history_max_entries() -> usize { 200 }
+
+impl Default for NotificationHistoryConfig {
+    fn default() -> Self {
+        Self { path: None, max_entries: default_notification_history_max_entries() }
+    }
+}
+
+/// One summary of the past day, sent at the same time every day
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DigestConfig {
+    /// Time of day to send at, as HH:MM
+    #[serde(default = "default_digest_time")]
+    pub(crate) time: String,
+    /// UTC or a fixed offset such as +02:00, there is no timezone database so daylight saving time is not followed
+    #[serde(default = "default_digest_timezone")]
+    pub(crate) timezone: String,
+    #[serde(flatten)]
+    pub(crate) destinations: NotificationDestinations
+}
+
+fn default_digest_time() -> String { "08:00".to_string() }
+fn default_digest_timezone() -> String { "UTC".to_string() }
+
+impl DigestConfig {
+    pub fn time(&self) -> Option<time::Time> {
+        time::Time::parse(&self.time, format_description!("[hour]:[minute]")).ok()
+    }
+
+    pub fn offset(&self) -> Option<time::UtcOffset> {
+        if self.timezone.eq_ignore_ascii_case("utc") {
+            return Some(time::UtcOffset::UTC);
+        }
+        time::UtcOffset::parse(&self.timezone, format_description!("[offset_hour sign:mandatory]:[offset_minute]")).ok()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NotificationDestinations {
-    pub(crate) emails: Option<Vec<String>>,
-    pub(crate) webhooks: Option<Vec<String>>
+    #[serde(default, deserialize_with = "deserialize_emails")]
+    pub(crate) emails: Option<Vec<EmailDestination>>,
+    #[serde(default, deserialize_with = "deserialize_webhooks")]
+    pub(crate) webhooks: Option<Vec<WebhookConfig>>
+}
+
+/// Whether a destination is sent to, shared so PUT /api/notifications/destinations/<index>/enabled can change it
+/// while the config is in use. (De)serialized as a plain bool
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "bool", into = "bool")]
+pub struct Toggle(Arc<AtomicBool>);
+
+impl Toggle {
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+}
+
+impl Default for Toggle {
+    fn default() -> Self {
+        Self::from(true)
+    }
+}
+
+impl From<bool> for Toggle {
+    fn from(enabled: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(enabled)))
+    }
 }
 
-pub type Mailer = SmtpClient<TlsStream<TcpStream>>;
+impl From<Toggle> for bool {
+    fn from(toggle: Toggle) -> Self {
+        toggle.get()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmailDestination {
+    pub(crate) address: String,
+    #[serde(default)]
+    pub(crate) enabled: Toggle
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WebhookConfig {
+    pub(crate) url: String,
+    /// Not sent to while false, a plain url string is enabled
+    #[serde(default)]
+    pub(crate) enabled: Toggle,
+    /// Body to send instead of the default discord payload, see [crate::util::render_template] for the variables
+    pub(crate) template: Option<String>,
+    /// Signs the body with HMAC-SHA256, sent as `X-Flashforge-Signature: sha256=<hex>`
+    pub(crate) secret: Option<String>,
+    /// Sent as is with every request
+    #[serde(default)]
+    pub(crate) headers: HashMap<String, String>,
+    #[serde(default)]
+    pub(crate) format: WebhookFormat,
+    /// Uploads the snapshot to a channel with a bot token, incoming webhooks can't attach files
+    pub(crate) slack: Option<SlackUploadConfig>,
+    /// Seconds to wait for the destination, instead of webhook.timeout_seconds
+    pub(crate) timeout_seconds: Option<u64>
+}
+
+impl WebhookConfig {
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_seconds.map(Duration::from_secs)
+    }
+}
+
+/// Payload sent to the webhook, when it has no template
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// Embed with the snapshot attached
+    #[default]
+    Discord,
+    /// Block Kit message
+    Slack,
+    /// Flat JSON of the notification fields
+    Generic
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlackUploadConfig {
+    pub(crate) bot_token: String,
+    /// Channel id to upload the snapshot to, such as C0123456789
+    pub(crate) channel: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebhookSettings {
+    /// How many times a failed delivery is retried (5xx or network errors only)
+    #[serde(default = "default_webhook_retries")]
+    pub(crate) retries: u32,
+    /// Delay before the first retry, doubled on every following retry
+    #[serde(default = "default_webhook_backoff_ms")]
+    pub(crate) backoff_ms: u64,
+    /// Seconds to wait for a destination to answer, per attempt
+    #[serde(default = "default_webhook_timeout_seconds")]
+    pub(crate) timeout_seconds: u64,
+    /// Proxy every webhook request goes through, such as "http://proxy.lan:3128" or "socks5://proxy.lan:1080"
+    pub(crate) proxy: Option<String>
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            retries: default_webhook_retries(),
+            backoff_ms: default_webhook_backoff_ms(),
+            timeout_seconds: default_webhook_timeout_seconds(),
+            proxy: None
+        }
+    }
+}
+
+impl WebhookSettings {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_seconds)
+    }
+
+    /// Delay before retrying after the attempt failed, doubling from backoff_ms up to [MAX_WEBHOOK_BACKOFF]
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u64.checked_pow(attempt.saturating_sub(1)).unwrap_or(u64::MAX);
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor)).min(MAX_WEBHOOK_BACKOFF)
+    }
+}
+
+/// More retries than this would keep a notification going for hours, with the backoff capped
+const MAX_WEBHOOK_RETRIES: u32 = 10;
+/// Longest wait between two attempts of a webhook
+const MAX_WEBHOOK_BACKOFF: Duration = Duration::from_secs(300);
+
+fn default_webhook_retries() -> u32 { 3 }
+fn default_webhook_backoff_ms() -> u64 { 1000 }
+fn default_webhook_timeout_seconds() -> u64 { 5 }
+
+/// Webhooks can either be a plain url string, or a table with url and extra options
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WebhookEntry {
+    Url(String),
+    Table(WebhookConfig)
+}
+
+fn deserialize_webhooks<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<WebhookConfig>>, D::Error> {
+    let entries: Option<Vec<WebhookEntry>> = Option::deserialize(deserializer)?;
+    Ok(entries.map(|entries| entries.into_iter().map(|entry| match entry {
+        WebhookEntry::Url(url) => WebhookConfig { url, ..Default::default() },
+        WebhookEntry::Table(webhook) => webhook
+    }).collect()))
+}
+
+/// Emails can either be a plain address string, or a table with address and enabled
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EmailEntry {
+    Address(String),
+    Table(EmailDestination)
+}
+
+fn deserialize_emails<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<EmailDestination>>, D::Error> {
+    let entries: Option<Vec<EmailEntry>> = Option::deserialize(deserializer)?;
+    Ok(entries.map(|entries| entries.into_iter().map(|entry| match entry {
+        EmailEntry::Address(address) => EmailDestination { address, enabled: Toggle::default() },
+        EmailEntry::Table(email) => email
+    }).collect()))
+}
+
+/// An email or webhook destination, see [ConfigManager::destinations]
+pub struct Destination<'a> {
+    /// Key of the [notifications] table it is in, one of [NOTIFICATION_KEYS] or "digest"
+    pub key: &'static str,
+    pub kind: DestinationKind,
+    /// Index in the key's emails or webhooks
+    pub position: usize,
+    /// Address or url
+    pub target: &'a str,
+    pub enabled: &'a Toggle
+}
+
+/// The config file's contents with enabled set on the destination, keeping its formatting and comments.
+/// A plain address or url becomes an inline table
+fn with_destination_enabled(contents: &str, destination: &Destination, enabled: bool) -> Result<String, String> {
+    let mut document: toml_edit::DocumentMut = contents.parse().map_err(|e: toml_edit::TomlError| e.to_string())?;
+    let (list, target_key) = match destination.kind {
+        DestinationKind::Email => ("emails", "address"),
+        DestinationKind::Webhook => ("webhooks", "url")
+    };
+    let path = format!("notifications.{}.{}[{}]", destination.key, list, destination.position);
+    let entries = document.get_mut("notifications")
+        .and_then(|notifications| notifications.get_mut(destination.key))
+        .and_then(|destinations| destinations.get_mut(list))
+        .ok_or_else(|| format!("{} is not in the file", path))?;
+    match entries {
+        toml_edit::Item::ArrayOfTables(tables) => {
+            let table = tables.get_mut(destination.position).ok_or_else(|| format!("{} is not in the file", path))?;
+            table.insert("enabled", toml_edit::value(enabled));
+        },
+        toml_edit::Item::Value(toml_edit::Value::Array(array)) => {
+            let entry = array.get_mut(destination.position).ok_or_else(|| format!("{} is not in the file", path))?;
+            match entry {
+                toml_edit::Value::InlineTable(table) => {
+                    table.insert("enabled", enabled.into());
+                },
+                toml_edit::Value::String(target) => {
+                    let decor = target.decor().clone();
+                    let mut table = toml_edit::InlineTable::new();
+                    table.insert(target_key, target.value().as_str().into());
+                    table.insert("enabled", enabled.into());
+                    *table.decor_mut() = decor;
+                    *entry = toml_edit::Value::InlineTable(table);
+                },
+                _ => return Err(format!("{} is not a string or table", path))
+            }
+        },
+        _ => return Err(format!("notifications.{}.{} is not an array", destination.key, list))
+    }
+    Ok(document.to_string())
+}
+
+/// The config file's contents with the printer's display_name and location set, or removed when None, keeping its
+/// formatting and comments
+fn with_printer_labels(contents: &str, id: &str, labels: &PrinterLabels) -> Result<String, String> {
+    let mut document: toml_edit::DocumentMut = contents.parse().map_err(|e: toml_edit::TomlError| e.to_string())?;
+    let printer = document.get_mut("printers")
+        .and_then(|printers| printers.get_mut(id))
+        .ok_or_else(|| format!("printers.{:?} is not in the file", id))?;
+    let fields = [("display_name", &labels.display_name), ("location", &labels.location)];
+    match printer {
+        toml_edit::Item::Table(table) => for (key, value) in fields {
+            match value {
+                Some(value) => { table.insert(key, toml_edit::value(value.as_str())); },
+                None => { table.remove(key); }
+            }
+        },
+        toml_edit::Item::Value(toml_edit::Value::InlineTable(table)) => for (key, value) in fields {
+            match value {
+                Some(value) => { table.insert(key, value.as_str().into()); },
+                None => { table.remove(key); }
+            }
+        },
+        _ => return Err(format!("printers.{:?} is not a table", id))
+    }
+    Ok(document.to_string())
+}
 
 #[allow(unused)]
 impl ConfigManager {
-    pub async fn load() -> Self {
-        let config = toml::from_str(&std::fs::read_to_string("config.toml").expect("could not read config.toml file")).map_err(|e| {
-            error!("Failed to parse config.toml: {} span={:?}", e.message(), e.span());
-            std::process::exit(1);
-        }).unwrap();
-        let mut s = ConfigManager {
+    /// Reads config.toml and starts connecting to the SMTP server in the background, without waiting for it
+    pub fn load() -> Self {
+        let config = Self::read_config();
+        #[cfg(feature = "smtp")]
+        let mailer = config.smtp.clone().map(|smtp| Arc::new(Mailer::new(smtp)));
+        #[cfg(feature = "smtp")]
+        if let Some(mailer) = &mailer {
+            mailer.connect_in_background();
+        }
+        ConfigManager {
             config,
-            mailer: None
-        };
-        match s.setup_mailer().await {
-            Ok(Some(m)) => { s.mailer = Some(Arc::new(Mutex::new(m))); },
-            Err(e) => {
-                error!("Failed to setup mailer: {}", e);
+            path: Some(PathBuf::from(CONFIG_PATH)),
+            write_lock: std::sync::Mutex::new(()),
+            #[cfg(feature = "smtp")]
+            mailer
+        }
+    }
+
+    /// Reads, parses and validates config.toml, exiting the process with every problem logged if anything is wrong
+    fn read_config() -> Config {
+        let contents = std::fs::read_to_string(CONFIG_PATH).unwrap_or_else(|e| {
+            error!("Could not read {}: {}", CONFIG_PATH, e);
+            std::process::exit(1);
+        });
+        let mut config: Config = toml::from_str(&contents).unwrap_or_else(|e| {
+            error!("Failed to parse {}: {} span={:?}", CONFIG_PATH, e.message(), e.span());
+            std::process::exit(1);
+        });
+        let problems = config.validate();
+        if !problems.is_empty() {
+            error!("{} has {} problem(s):", CONFIG_PATH, problems.len());
+            for problem in problems {
+                error!("  {}", problem);
             }
-            _ => {}
+            std::process::exit(1);
+        }
+        config.normalize_ids();
+        config
+    }
+
+    /// Reads only [logging], so logging can be set up before the config is loaded. Any problems with the
+    /// file are reported by [ConfigManager::load]
+    pub fn logging() -> LoggingConfig {
+        #[derive(Deserialize)]
+        struct LoggingOnly {
+            #[serde(default)]
+            logging: LoggingConfig
         }
-        s
+        std::fs::read_to_string(CONFIG_PATH).ok()
+            .and_then(|contents| toml::from_str::<LoggingOnly>(&contents).ok())
+            .map(|config| config.logging)
+            .unwrap_or_default()
+    }
+
+    #[cfg(test)]
+    pub fn from_toml(contents: &str) -> Self {
+        let mut config: Config = toml::from_str(contents).expect("invalid test config");
+        config.normalize_ids();
+        ConfigManager {
+            // Only connected when a test sends
+            #[cfg(feature = "smtp")]
+            mailer: config.smtp.clone().map(|smtp| Arc::new(Mailer::new(smtp))),
+            config,
+            path: None,
+            write_lock: std::sync::Mutex::new(())
+        }
+    }
+
+    /// Validates config.toml and exits, used by --check-config
+    pub fn check_config() -> ! {
+        Self::read_config();
+        info!("{} is valid", CONFIG_PATH);
+        std::process::exit(0);
+    }
+
+    /// File the config was read from, None for tests
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
     }
 
     pub fn smtp(&self) -> Option<&EmailConfig> {
@@ -60,51 +765,217 @@ impl ConfigManager {
     pub fn get_notification_destinations(&self, notification_type: &NotificationType) -> Option<&NotificationDestinations> {
         if let Some(notifications) = &self.config.notifications {
             let key = match notification_type {
+                NotificationType::PrintStarted => { "on_started" },
                 NotificationType::PrintComplete => { "on_done" },
+                NotificationType::PrintError => { "on_error" },
+                NotificationType::PrintCancelled(_) => { "on_cancelled" },
+                NotificationType::PrintFailed { .. } => { "on_failed" },
+                NotificationType::TemperatureAlert { .. } | NotificationType::TemperatureRecovered { .. } => { "on_thermal" },
+                #[allow(unreachable_patterns)]
                 _ => return None
             };
-            return notifications.get(key)
+            return notifications.destinations.get(key)
         }
         None
     }
 
+    /// Every enabled webhook of every notification type and the digest, once per url
+    pub fn webhooks(&self) -> Vec<&WebhookConfig> {
+        let Some(notifications) = &self.config.notifications else { return Vec::new() };
+        let mut webhooks: Vec<&WebhookConfig> = Vec::new();
+        let destinations = notifications.destinations.values().chain(notifications.digest.as_ref().map(|digest| &digest.destinations));
+        for webhook in destinations.flat_map(|destinations| destinations.webhooks.iter().flatten()).filter(|webhook| webhook.enabled.get()) {
+            if !webhooks.iter().any(|known| known.url == webhook.url) {
+                webhooks.push(webhook);
+            }
+        }
+        webhooks
+    }
+
+    /// Every email and webhook destination: those of [NOTIFICATION_KEYS] in order then the digest's, emails first.
+    /// Indexes into it are the ones of /api/notifications/destinations
+    pub fn destinations(&self) -> Vec<Destination<'_>> {
+        let Some(notifications) = &self.config.notifications else { return Vec::new() };
+        let keyed = NOTIFICATION_KEYS.iter()
+            .filter_map(|key| notifications.destinations.get(*key).map(|destinations| (*key, destinations)))
+            .chain(notifications.digest.as_ref().map(|digest| ("digest", &digest.destinations)));
+        let mut list = Vec::new();
+        for (key, destinations) in keyed {
+            for (position, email) in destinations.emails.iter().flatten().enumerate() {
+                list.push(Destination { key, kind: DestinationKind::Email, position, target: &email.address, enabled: &email.enabled });
+            }
+            for (position, webhook) in destinations.webhooks.iter().flatten().enumerate() {
+                list.push(Destination { key, kind: DestinationKind::Webhook, position, target: &webhook.url, enabled: &webhook.enabled });
+            }
+        }
+        list
+    }
+
+    /// Turns the destination on or off, and writes it to the config file when the config was read from one
+    pub fn set_destination_enabled(&self, destination: &Destination, enabled: bool) -> Result<(), String> {
+        let previous = destination.enabled.get();
+        destination.enabled.set(enabled);
+        let written = self.edit_file(|contents| with_destination_enabled(contents, destination, enabled));
+        if let Err(e) = &written {
+            error!("Could not save notifications.{} to {}: {}", destination.key, CONFIG_PATH, e);
+            destination.enabled.set(previous);
+        }
+        written
+    }
+
+    /// Writes the printer's display_name and location to the config file when the config was read from one.
+    /// The running config keeps the ones it was started with, the printer has the current ones
+    pub fn set_printer_labels(&self, id: &str, labels: &PrinterLabels) -> Result<(), String> {
+        let written = self.edit_file(|contents| with_printer_labels(contents, id, labels));
+        if let Err(e) = &written {
+            error!("Could not save printers.{:?} to {}: {}", id, CONFIG_PATH, e);
+        }
+        written
+    }
+
+    /// Replaces the config file with what edit makes of its contents, one edit at a time
+    fn edit_file(&self, edit: impl FnOnce(&str) -> Result<String, String>) -> Result<(), String> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let _guard = self.write_lock.lock().unwrap();
+        std::fs::read_to_string(path).map_err(|e| e.to_string())
+            .and_then(|contents| edit(&contents))
+            .and_then(|contents| {
+                let tmp_path = path.with_extension("tmp");
+                std::fs::write(&tmp_path, contents).map_err(|e| e.to_string())?;
+                std::fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+            })
+    }
+
+    pub fn notification_history(&self) -> Option<&NotificationHistoryConfig> {
+        self.config.notifications.as_ref().map(|notifications| &notifications.history)
+    }
+
+    pub fn attach_gif(&self) -> bool {
+        self.config.notifications.as_ref().is_some_and(|notifications| notifications.attach_gif)
+    }
+
+    pub fn notification_templates_path(&self) -> Option<&Path> {
+        self.config.notifications.as_ref().and_then(|notifications| notifications.templates_path.as_deref())
+    }
+
+    pub fn digest(&self) -> Option<&DigestConfig> {
+        self.config.notifications.as_ref().and_then(|notifications| notifications.digest.as_ref())
+    }
+
     pub fn auth(&self) -> Option<&AuthConfig> {
         self.config.auth.as_ref()
     }
 
+    pub fn webhook_settings(&self) -> &WebhookSettings {
+        &self.config.webhook
+    }
+
+    pub fn mqtt(&self) -> Option<&MqttConfig> {
+        self.config.mqtt.as_ref()
+    }
+
+    pub fn history(&self) -> Option<&HistoryConfig> {
+        self.config.history.as_ref()
+    }
+
+    pub fn filament(&self) -> Option<&FilamentConfig> {
+        self.config.filament.as_ref()
+    }
+
+    pub fn gcode(&self) -> Option<&GcodeConfig> {
+        self.config.gcode.as_ref()
+    }
+
+    pub fn audit(&self) -> Option<&AuditConfig> {
+        self.config.audit.as_ref()
+    }
+
+    pub fn state(&self) -> Option<&StateConfig> {
+        self.config.state.as_ref()
+    }
+
+    pub fn shutdown(&self) -> &ShutdownConfig {
+        &self.config.shutdown
+    }
+
+    pub fn camera(&self) -> &CameraConfig {
+        &self.config.camera
+    }
+
+    pub fn macros(&self) -> &BTreeMap<String, MacroConfig> {
+        &self.config.macros
+    }
+
+    pub fn privacy(&self) -> &PrivacyConfig {
+        &self.config.privacy
+    }
+
+    pub fn http(&self) -> &HttpConfig {
+        &self.config.http
+    }
+
+    /// Returns the moonraker config only if it's enabled
+    pub fn moonraker(&self) -> Option<&MoonrakerConfig> {
+        self.config.moonraker.as_ref().filter(|m| m.enabled)
+    }
+
+    /// Returns the debug config only if it's enabled
+    pub fn debug(&self) -> Option<&DebugConfig> {
+        self.config.debug.as_ref().filter(|d| d.enabled)
+    }
+
+    pub fn discovery(&self) -> &DiscoveryConfig {
+        &self.config.discovery
+    }
+
+    pub fn thermal(&self) -> Option<&ThermalConfig> {
+        self.config.watch.as_ref().and_then(|w| w.thermal.as_ref())
+    }
+
+    pub fn watch_head_position(&self) -> bool {
+        self.config.watch.as_ref().is_some_and(|w| w.head_position)
+    }
+
+    pub fn watch_first_layers(&self) -> u32 {
+        self.config.watch.as_ref().map_or_else(default_first_layers, |w| w.first_layers)
+    }
+
     pub fn printers(&self) -> &HashMap<String, PrinterConfig> {
         &self.config.printers
     }
 
-    pub fn mailer(&self) -> Option<Arc<Mutex<Mailer>>> {
-        self.mailer.as_ref().map(|m| m.clone())
+    /// The SMTP session, if SMTP is configured
+    #[cfg(feature = "smtp")]
+    pub fn mailer(&self) -> Option<&Arc<Mailer>> {
+        self.mailer.as_ref()
     }
 
-    /// Sets up SMTP mailer, if configured. Ok(None) if not setup, Err if invalid configuration
-    async fn setup_mailer(&mut self) -> Result<Option<Mailer>, String> {
-        if let Some(smtp) = &self.config.smtp {
-            if smtp.port == 0 {
-               Err("SMTP: Smtp port is invalid, smtp support not enabled".to_string())
-            } else if smtp.user.is_empty() {
-                Err("SMTP: Smtp user is empty, smtp support not enabled".to_string())
-            } else if smtp.host.is_empty() {
-                Err("SMTP: Smtp host is empty, smtp support not enabled".to_string())
-            } else {
-                let client = SmtpClientBuilder::new(&smtp.host, smtp.port)
-                    .implicit_tls(smtp.encryption == EmailEncryption::Tls)
-                    .credentials(Credentials::new(&smtp.user, &smtp.password))
-                    .connect()
-                    .await
-                    .unwrap();
-                Ok(Some(client))
-            }
-        } else {
-            Ok(None)
+    /// Sends a NOOP on the SMTP session, connecting a new one when there is none or it was closed
+    #[cfg(feature = "smtp")]
+    pub async fn noop_mailer(&self) -> Result<(), String> {
+        let Some(mailer) = &self.mailer else { return Err("SMTP is not configured".to_string()) };
+        mailer.noop().await
+    }
+
+    /// Ends the SMTP session, if there is a working connection
+    #[cfg(feature = "smtp")]
+    pub async fn close_mailer(&self) {
+        if let Some(mailer) = &self.mailer {
+            mailer.close().await;
+        }
+    }
+
+    /// Where the SMTP session is, for GET /readyz
+    pub fn smtp_status(&self) -> SmtpStatus {
+        #[cfg(feature = "smtp")]
+        if let Some(mailer) = &self.mailer {
+            return mailer.status();
         }
+        SmtpStatus { state: SmtpState::NotConfigured, error: None, failed_attempts: 0, next_attempt_at: None }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum EmailEncryption {
     None,
@@ -112,30 +983,1062 @@ pub enum EmailEncryption {
     Tls
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EmailConfig {
     pub(crate) host: String,
     pub(crate) port: u16,
     pub(crate) encryption: EmailEncryption,
     pub(crate) user: String,
-    pub(crate) password: String
+    pub(crate) password: String,
+    /// Seconds to wait for an email to be sent, it is reported as failed after
+    #[serde(default = "default_smtp_timeout_seconds")]
+    pub(crate) timeout_seconds: u64
 }
-#[derive(Debug, Serialize, Deserialize)]
-pub struct NotificationConfig {
-    pub(crate) emails: Option<Vec<String>>,
 
-    pub(crate) on_done: Option<Vec<String>>
-}
+fn default_smtp_timeout_seconds() -> u64 { 30 }
 
+impl EmailConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_seconds)
+    }
+}
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AuthConfig {
     pub(crate)password_for_write: bool,
     pub(crate)password_for_read: bool,
-    pub(crate)password: String
+    pub(crate)password: String,
+    /// Named tokens, accepted as "Authorization: Bearer <token>" or the x-secret header
+    #[serde(default)]
+    pub(crate)tokens: Vec<AuthToken>,
+    /// Failed attempts allowed from a client within failure_window_secs before it is locked out
+    #[serde(default = "default_max_failures")]
+    pub(crate)max_failures: u32,
+    #[serde(default = "default_failure_window_secs")]
+    pub(crate)failure_window_secs: u64
+}
+
+fn default_max_failures() -> u32 { 10 }
+fn default_failure_window_secs() -> u64 { 300 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuthToken {
+    /// Shown in logs when the token is used
+    pub(crate) name: String,
+    pub(crate) token: String,
+    pub(crate) scope: TokenScope
+}
+
+/// What a token can access, each scope includes the ones before it
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    Read,
+    Write,
+    Admin
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MqttConfig {
+    pub(crate) host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub(crate) port: u16,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    /// Printer state is published to <base_topic>/<printer id>/state
+    #[serde(default = "default_mqtt_base_topic")]
+    pub(crate) base_topic: String,
+    /// Home assistant discovery prefix
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub(crate) discovery_prefix: String
+}
+
+fn default_mqtt_port() -> u16 { 1883 }
+fn default_mqtt_base_topic() -> String { "flashforge".to_string() }
+fn default_mqtt_discovery_prefix() -> String { "homeassistant".to_string() }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HttpConfig {
+    #[serde(default = "default_http_address")]
+    pub(crate) address: IpAddr,
+    /// 8080 when not set, unless unix_socket is
+    pub(crate) port: Option<u16>,
+    /// Socket to listen on instead of a TCP port, for a reverse proxy on the same host
+    pub(crate) unix_socket: Option<PathBuf>,
+    /// Octal permissions of the socket file
+    #[serde(default = "default_unix_socket_mode")]
+    pub(crate) unix_socket_mode: String,
+    /// Numeric user id the socket file is given to, so the reverse proxy can be let in through the mode
+    pub(crate) unix_socket_uid: Option<u32>,
+    /// Numeric group id the socket file is given to
+    pub(crate) unix_socket_gid: Option<u32>,
+    pub(crate) tls: Option<TlsConfig>,
+    pub(crate) rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub(crate) compression: CompressionConfig,
+    /// API requests taking longer are logged with the printer request they were waiting on, 0 never logs
+    #[serde(default = "default_slow_request_ms")]
+    pub(crate) slow_request_ms: u64,
+    /// Requests changing a printer are answered 503 PRINTER_BUSY once its current command took this long, 0 always queues them
+    #[serde(default = "default_busy_after_ms")]
+    pub(crate) busy_after_ms: u64,
+    /// Casing of the fields in JSON responses
+    #[serde(default)]
+    pub(crate) json_case: JsonCase,
+    /// Larger JSON bodies are answered 413 PAYLOAD_TOO_LARGE without being read, Rocket.toml's limits.json overrides it
+    #[serde(default = "default_max_json_kb")]
+    pub(crate) max_json_kb: u64,
+    pub(crate) access_log: Option<AccessLogConfig>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonCase {
+    /// firmware_version, as the models are written
+    #[default]
+    Snake,
+    /// firmwareVersion
+    Camel
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            address: default_http_address(),
+            port: None,
+            unix_socket: None,
+            unix_socket_mode: default_unix_socket_mode(),
+            unix_socket_uid: None,
+            unix_socket_gid: None,
+            tls: None,
+            rate_limit: None,
+            compression: CompressionConfig::default(),
+            slow_request_ms: default_slow_request_ms(),
+            busy_after_ms: default_busy_after_ms(),
+            json_case: JsonCase::default(),
+            max_json_kb: default_max_json_kb(),
+            access_log: None
+        }
+    }
+}
+
+impl HttpConfig {
+    /// The TCP port to serve on, None when only serving on the unix socket
+    pub fn port(&self) -> Option<u16> {
+        self.port.or_else(|| self.unix_socket.is_none().then(default_http_port))
+    }
+
+    pub fn unix_socket_mode(&self) -> Option<u32> {
+        u32::from_str_radix(&self.unix_socket_mode, 8).ok().filter(|mode| *mode <= 0o777)
+    }
+
+    pub fn busy_after(&self) -> Option<Duration> {
+        (self.busy_after_ms > 0).then(|| Duration::from_millis(self.busy_after_ms))
+    }
+}
+
+/// One line per request, separate from the logs, for fail2ban and reviewing usage
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccessLogConfig {
+    /// File the lines are appended to, stdout when not set
+    pub(crate) path: Option<PathBuf>,
+    #[serde(default)]
+    pub(crate) format: LogFormat,
+    /// Reverse proxies whose X-Forwarded-For header is taken as the client ip
+    #[serde(default)]
+    pub(crate) trusted_proxies: Vec<IpAddr>
+}
+
+/// Compression of JSON responses for clients sending Accept-Encoding, images and the camera stream are never compressed
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CompressionConfig {
+    #[serde(default = "default_compression_enabled")]
+    pub(crate) enabled: bool,
+    /// Smaller responses are sent as is, compressing them saves little
+    #[serde(default = "default_compression_min_size")]
+    pub(crate) min_size: usize
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_compression_enabled(),
+            min_size: default_compression_min_size()
+        }
+    }
+}
+
+fn default_compression_enabled() -> bool { true }
+fn default_compression_min_size() -> usize { 1024 }
+
+/// Token buckets per client ip, expensive requests are camera snapshots, streams and debug requests
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    /// Requests a client can make per second on average
+    #[serde(default = "default_requests_per_second")]
+    pub(crate) requests_per_second: f64,
+    /// Requests a client can make at once, after being idle
+    #[serde(default = "default_burst")]
+    pub(crate) burst: u32,
+    #[serde(default = "default_expensive_requests_per_second")]
+    pub(crate) expensive_requests_per_second: f64,
+    #[serde(default = "default_expensive_burst")]
+    pub(crate) expensive_burst: u32,
+    /// Clients tracked at once, a new client makes the longest idle one be forgotten
+    #[serde(default = "default_max_clients")]
+    pub(crate) max_clients: usize
+}
+
+fn default_requests_per_second() -> f64 { 10.0 }
+fn default_burst() -> u32 { 20 }
+fn default_expensive_requests_per_second() -> f64 { 1.0 }
+fn default_expensive_burst() -> u32 { 3 }
+fn default_max_clients() -> usize { 1024 }
+
+impl RateLimitConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (key, rate) in [("requests_per_second", self.requests_per_second), ("expensive_requests_per_second", self.expensive_requests_per_second)] {
+            if !(rate > 0.0 && rate.is_finite()) {
+                problems.push(format!("http.rate_limit.{}: must be more than 0", key));
+            }
+        }
+        for (key, burst) in [("burst", self.burst), ("expensive_burst", self.expensive_burst)] {
+            if burst == 0 {
+                problems.push(format!("http.rate_limit.{}: must be at least 1", key));
+            }
+        }
+        if self.max_clients == 0 {
+            problems.push("http.rate_limit.max_clients: must be at least 1".to_string());
+        }
+        problems
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryConfig {
+    /// SQLite database every poll is written to, created when missing
+    pub(crate) db_path: PathBuf,
+    /// Records older than this are pruned
+    #[serde(default = "default_history_retention_days")]
+    pub(crate) retention_days: u64
+}
+
+fn default_history_retention_days() -> u64 { 7 }
+
+impl HistoryConfig {
+    pub fn retention(&self) -> Duration {
+        Duration::from_secs(self.retention_days.saturating_mul(24 * 60 * 60))
+    }
+}
+
+/// The filament used by each print, returned by GET /api/stats/filament
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FilamentConfig {
+    /// JSON lines file the prints are kept in across restarts, only kept in memory when not set
+    pub(crate) path: Option<PathBuf>,
+    /// Estimate of prints whose file has none, from the size of the file
+    #[serde(default = "default_grams_per_megabyte")]
+    pub(crate) grams_per_megabyte: f64
+}
+
+fn default_grams_per_megabyte() -> f64 { 10.0 }
+
+/// The sliced files of the prints, for the slicer's filament estimate and the thumbnail of GET /api/printers/<id>/job/thumbnail
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GcodeConfig {
+    /// Looked up by the name of the file being printed, the printer only reports the name
+    pub(crate) dir: PathBuf
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StateConfig {
+    /// JSON file the sent notifications and running jobs are saved to, so they survive restarts
+    pub(crate) path: PathBuf
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditConfig {
+    /// JSON lines file every write request is appended to
+    pub(crate) path: PathBuf,
+    /// Once the file would grow past this it is moved to <path>.1, replacing the previous one
+    #[serde(default = "default_audit_max_bytes")]
+    pub(crate) max_bytes: u64
+}
+
+fn default_audit_max_bytes() -> u64 { 10 * 1024 * 1024 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShutdownConfig {
+    /// Longest to wait for printers to be released and notifications to be sent before exiting
+    #[serde(default = "default_shutdown_grace_seconds")]
+    pub(crate) grace_seconds: u64
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self { grace_seconds: default_shutdown_grace_seconds() }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn grace(&self) -> Duration {
+        Duration::from_secs(self.grace_seconds)
+    }
+}
+
+fn default_shutdown_grace_seconds() -> u64 { 10 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CameraConfig {
+    /// PNG or JPEG returned by /snapshot when the camera is unavailable, instead of the built in "no image"
+    pub(crate) placeholder_path: Option<PathBuf>,
+    /// Notifications fall back to the last frame when a fresh snapshot fails, but only if it is at most this old
+    #[serde(default = "default_max_image_age_secs")]
+    pub(crate) max_image_age_secs: u64,
+    /// Frames kept for each viewer of the stream, slower viewers skip to the newest frame
+    #[serde(default = "default_buffered_frames")]
+    pub(crate) buffered_frames: usize,
+    /// Frames of each printer kept for /snapshot.gif, one every gif_interval_secs. 0 keeps none
+    #[serde(default = "default_gif_frames")]
+    pub(crate) gif_frames: usize,
+    #[serde(default = "default_gif_interval_secs")]
+    pub(crate) gif_interval_secs: u64,
+    /// The oldest frames are dropped once those of a printer take more than this
+    #[serde(default = "default_gif_max_kb")]
+    pub(crate) gif_max_kb: usize,
+    /// Wider frames are scaled down to this for the GIF
+    #[serde(default = "default_gif_width")]
+    pub(crate) gif_width: u32
+}
+
+fn default_max_image_age_secs() -> u64 { 60 }
+fn default_buffered_frames() -> usize { 4 }
+fn default_gif_frames() -> usize { 10 }
+fn default_gif_interval_secs() -> u64 { 2 }
+fn default_gif_max_kb() -> usize { 4096 }
+fn default_gif_width() -> u32 { 640 }
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        Self {
+            placeholder_path: None,
+            max_image_age_secs: default_max_image_age_secs(),
+            buffered_frames: default_buffered_frames(),
+            gif_frames: default_gif_frames(),
+            gif_interval_secs: default_gif_interval_secs(),
+            gif_max_kb: default_gif_max_kb(),
+            gif_width: default_gif_width()
+        }
+    }
+}
+
+impl CameraConfig {
+    pub fn max_image_age(&self) -> Duration {
+        Duration::from_secs(self.max_image_age_secs)
+    }
+
+    #[cfg(feature = "camera")]
+    pub fn gif(&self) -> crate::camera::GifSettings {
+        crate::camera::GifSettings {
+            frames: self.gif_frames,
+            interval: Duration::from_secs(self.gif_interval_secs),
+            max_bytes: self.gif_max_kb.saturating_mul(1024),
+            width: self.gif_width
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct PrivacyConfig {
+    /// Fields left out of the printer info, status and list responses, such as sn or mac_addr
+    #[serde(default)]
+    pub(crate) hide_fields: Vec<String>
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub(crate) format: LogFormat
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers
+    Json
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DebugConfig {
+    #[serde(default)]
+    pub(crate) enabled: bool,
+    /// Where POST /api/printers/<id>/debug/record saves the printer's responses
+    #[serde(default = "default_debug_record_dir")]
+    pub(crate) record_dir: PathBuf
+}
+
+fn default_debug_record_dir() -> PathBuf { PathBuf::from("recordings") }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MoonrakerConfig {
+    #[serde(default)]
+    pub(crate) enabled: bool,
+    /// Moonraker serves a single printer, the id of the one exposed
+    pub(crate) printer: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiscoveryConfig {
+    /// Register discovered printers on startup, using their machine name as the id
+    #[serde(default)]
+    pub(crate) auto_add: bool,
+    /// Discovery packet is sent to each, add the broadcast address of every network printers are on
+    #[serde(default = "default_discovery_broadcast_addresses")]
+    pub(crate) broadcast_addresses: Vec<IpAddr>,
+    /// How long to wait for printers to answer
+    #[serde(default = "default_discovery_timeout_secs")]
+    pub(crate) timeout_secs: u64
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            auto_add: false,
+            broadcast_addresses: default_discovery_broadcast_addresses(),
+            timeout_secs: default_discovery_timeout_secs()
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+fn default_discovery_broadcast_addresses() -> Vec<IpAddr> { vec![IpAddr::V4(std::net::Ipv4Addr::BROADCAST)] }
+fn default_discovery_timeout_secs() -> u64 { 3 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain
+    pub(crate) cert_path: PathBuf,
+    /// PEM encoded PKCS8, RSA or SEC1 private key
+    pub(crate) key_path: PathBuf
+}
+
+impl TlsConfig {
+    /// Checks the certificate and key files exist and contain PEM items rustls can use
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        match read_pem(&self.cert_path) {
+            Ok(items) if !items.iter().any(|item| matches!(item, Item::X509Certificate(_))) =>
+                problems.push(format!("http.tls.cert_path: {} contains no PEM certificates", self.cert_path.display())),
+            Err(e) => problems.push(format!("http.tls.cert_path: {}", e)),
+            _ => {}
+        }
+        match read_pem(&self.key_path) {
+            Ok(items) if !items.iter().any(|item| matches!(item, Item::PKCS8Key(_) | Item::RSAKey(_) | Item::ECKey(_))) =>
+                problems.push(format!("http.tls.key_path: {} contains no PEM private key", self.key_path.display())),
+            Err(e) => problems.push(format!("http.tls.key_path: {}", e)),
+            _ => {}
+        }
+        problems
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+    rustls_pemfile::read_all(&mut std::io::BufReader::new(file))
+        .map_err(|e| format!("could not parse {}: {}", path.display(), e))
+}
+
+fn default_http_address() -> IpAddr { IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED) }
+fn default_http_port() -> u16 { 8080 }
+fn default_unix_socket_mode() -> String { "660".to_string() }
+
+fn default_slow_request_ms() -> u64 { 2000 }
+fn default_busy_after_ms() -> u64 { 1000 }
+fn default_max_json_kb() -> u64 { 64 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WatchConfig {
+    pub(crate) thermal: Option<ThermalConfig>,
+    /// Also poll the head position (M114), for /head-position?cached=true. Off by default, it is an extra request every poll
+    #[serde(default)]
+    pub(crate) head_position: bool,
+    /// Layers after which a first_layers_complete event is sent
+    #[serde(default = "default_first_layers")]
+    pub(crate) first_layers: u32
 }
 
+fn default_first_layers() -> u32 { 3 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThermalConfig {
+    /// How many degrees a heater can be away from its target before the sample counts as deviating
+    #[serde(default = "default_deviation_celsius")]
+    pub(crate) deviation_celsius: f32,
+    /// How many deviating polls in a row are needed before an alert is sent
+    #[serde(default = "default_consecutive_samples")]
+    pub(crate) consecutive_samples: u32,
+    pub(crate) max_bed: Option<f32>,
+    pub(crate) max_nozzle: Option<f32>,
+    pub(crate) max_chamber: Option<f32>
+}
+
+fn default_deviation_celsius() -> f32 { 15.0 }
+fn default_consecutive_samples() -> u32 { 3 }
+
+/// Either ip or host must be set
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PrinterConfig {
-    pub(crate) ip: IpAddr
+    pub(crate) ip: Option<IpAddr>,
+    pub(crate) host: Option<String>,
+    /// Shown instead of the id in notification subjects and the UI, the name set on the printer when not set.
+    /// Changed with PATCH /api/printers/<id>
+    pub(crate) display_name: Option<String>,
+    /// Where the printer is, such as "Garage"
+    pub(crate) location: Option<String>,
+    /// Seconds the connection to the printer is kept open after the last request
+    #[serde(default = "default_idle_timeout_secs")]
+    pub(crate) idle_timeout_secs: u64,
+    /// Port of the TCP API, for printers whose port is forwarded
+    #[serde(default = "default_api_port")]
+    pub(crate) api_port: u16,
+    /// Start in maintenance mode, until it is turned off through the API
+    #[serde(default)]
+    pub(crate) maintenance: bool,
+    /// Take control (M601) for read-only queries too. Firmwares that answer queries without it can set this to false,
+    /// so the printer stays online while FlashPrint is connected
+    #[serde(default = "default_require_control")]
+    pub(crate) require_control: bool,
+    #[serde(default)]
+    pub(crate) console: ConsoleConfig,
+    #[serde(default)]
+    pub(crate) connections: ConnectionsConfig,
+    /// Commands the printer takes longer to answer are logged, 0 never logs
+    #[serde(default = "default_slow_exchange_ms")]
+    pub(crate) slow_exchange_ms: u64,
+    pub(crate) camera: Option<PrinterCameraConfig>
+}
+
+fn default_require_control() -> bool { true }
+fn default_slow_exchange_ms() -> u64 { 2000 }
+
+/// Lines the printer sends on its own, returned by GET /api/printers/<id>/console
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConsoleConfig {
+    /// Keeps the connection open between polls instead of closing it after idle_timeout_secs, to receive them
+    #[serde(default)]
+    pub(crate) capture: bool,
+    /// Most recent lines kept
+    #[serde(default = "default_console_max_lines")]
+    pub(crate) max_lines: usize
+}
+
+fn default_console_max_lines() -> usize { 500 }
+
+impl Default for ConsoleConfig {
+    fn default() -> Self {
+        Self { capture: false, max_lines: default_console_max_lines() }
+    }
+}
+
+impl ConsoleConfig {
+    /// Lines to keep, 0 when not capturing
+    pub fn kept_lines(&self) -> usize {
+        if self.capture { self.max_lines } else { 0 }
+    }
+}
+
+/// Connections open to the printer at once, from the watcher thread, routes, the camera and notifications together
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConnectionsConfig {
+    /// To the TCP API
+    #[serde(default = "default_connection_limit")]
+    pub(crate) gcode: usize,
+    /// To the camera's stream, which every viewer and snapshot shares
+    #[serde(default = "default_connection_limit")]
+    pub(crate) camera: usize,
+    /// How long a connection waits for one of the others to close, before failing with PRINTER_BUSY
+    #[serde(default = "default_connection_wait_secs")]
+    pub(crate) wait_secs: u64
+}
+
+fn default_connection_limit() -> usize { 1 }
+fn default_connection_wait_secs() -> u64 { 5 }
+
+impl Default for ConnectionsConfig {
+    fn default() -> Self {
+        Self { gcode: default_connection_limit(), camera: default_connection_limit(), wait_secs: default_connection_wait_secs() }
+    }
+}
+
+impl ConnectionsConfig {
+    pub fn wait(&self) -> Duration {
+        Duration::from_secs(self.wait_secs)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MacroConfig {
+    /// G-code lines, with or without the leading ~, each checked against [RAW_COMMAND_ALLOWLIST]
+    pub(crate) printer_commands: Vec<String>
+}
+
+impl MacroConfig {
+    /// The G-code of the command, like M104
+    fn code(command: &str) -> String {
+        command.trim().trim_start_matches('~').split_whitespace().next().unwrap_or_default().to_ascii_uppercase()
+    }
+
+    /// The commands as sent to the printer
+    pub fn requests(&self) -> Vec<PrinterRequest> {
+        self.printer_commands.iter()
+            .map(|command| PrinterRequest::Raw(format!("~{}", command.trim().trim_start_matches('~'))))
+            .collect()
+    }
+}
+
+/// How the printer's camera is mounted, snapshots and images of notifications are shown turned back
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct PrinterCameraConfig {
+    /// Degrees clockwise, 0, 90, 180 or 270
+    #[serde(default)]
+    pub(crate) rotate: u16,
+    /// Mirrored before being rotated
+    pub(crate) flip: Option<Flip>
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Flip {
+    Horizontal,
+    Vertical
+}
+
+#[cfg(feature = "camera")]
+impl Flip {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "horizontal" => Some(Flip::Horizontal),
+            "vertical" => Some(Flip::Vertical),
+            _ => None
+        }
+    }
+}
+
+pub(crate) fn default_idle_timeout_secs() -> u64 { 30 }
+fn default_api_port() -> u16 { flashforge_protocol::API_PORT }
+
+impl PrinterConfig {
+    /// Returns the configured ip or hostname, None when neither is set which [Config::validate] rejects
+    pub fn host(&self) -> Option<String> {
+        match (&self.ip, &self.host) {
+            (Some(ip), _) => Some(ip.to_string()),
+            (None, host) => host.clone()
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn slow_exchange(&self) -> Duration {
+        Duration::from_millis(self.slow_exchange_ms)
+    }
+
+    /// Rotation and flip of printers.<id>.camera, validated so always valid
+    #[cfg(feature = "camera")]
+    pub fn camera_transform(&self) -> crate::camera::Transform {
+        self.camera.as_ref()
+            .and_then(|camera| crate::camera::Transform::new(camera.rotate, camera.flip))
+            .unwrap_or(crate::camera::Transform::NONE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printer_ids_are_validated_and_lowercased() {
+        let config: Config = toml::from_str(r#"
+            [printers]
+            Ender = { ip = "192.168.1.2" }
+            ender = { ip = "192.168.1.3" }
+            "my printer" = { ip = "192.168.1.4" }
+        "#).unwrap();
+        let problems = config.validate();
+        assert!(problems.contains(&"printers.\"ender\": same id as printers.\"Ender\", ids are not case sensitive".to_string()), "{:?}", problems);
+        assert!(problems.contains(&"printers.\"my printer\": printer id can only contain letters, numbers, '-' and '_'".to_string()), "{:?}", problems);
+
+        // Forwarded ports of the same address are different printers
+        let config: Config = toml::from_str(r#"
+            [printers]
+            first = { ip = "203.0.113.7" }
+            second = { ip = "203.0.113.7", api_port = 18899 }
+            third = { ip = "203.0.113.7", api_port = 18899 }
+        "#).unwrap();
+        assert_eq!(config.validate(), ["printers.\"third\".ip: 203.0.113.7 is already used by printers.\"second\""]);
+        assert_eq!(config.printers["first"].api_port, 8899);
+
+        let config: Config = toml::from_str(r#"
+            [printers]
+            nowhere = { api_port = 8899 }
+            named = { host = "Printer.local" }
+        "#).unwrap();
+        assert_eq!(config.validate(), ["printers.\"nowhere\": ip or host is required"]);
+        assert_eq!(config.printers["nowhere"].host(), None);
+        assert_eq!(config.printers["named"].host().as_deref(), Some("Printer.local"));
+
+        let config = ConfigManager::from_toml(r#"
+            [moonraker]
+            enabled = true
+            printer = "Ender"
+            [printers]
+            Ender = { ip = "192.168.1.2" }
+        "#);
+        assert!(config.config.printers.contains_key("ender"));
+        assert_eq!(config.moonraker().unwrap().printer, "ender");
+    }
+
+    #[test]
+    fn digest_is_read_next_to_the_notification_types() {
+        let config: Config = toml::from_str(r#"
+            [notifications.on_done]
+            webhooks = ["https://example.com/done"]
+            [notifications.digest]
+            time = "8am"
+            timezone = "Europe/Berlin"
+            webhooks = [{ url = "https://example.com/digest", format = "generic" }]
+            [printers]
+        "#).unwrap();
+        assert_eq!(config.validate(), [
+            "notifications.digest.time: invalid time \"8am\", expected HH:MM",
+            "notifications.digest.timezone: invalid timezone \"Europe/Berlin\", expected UTC or an offset such as +02:00"
+        ]);
+
+        let config = ConfigManager::from_toml(r#"
+            [notifications.digest]
+            timezone = "-05:30"
+            emails = ["farm@example.com"]
+            [printers]
+        "#);
+        let digest = config.digest().unwrap();
+        assert_eq!(digest.time(), Some(time::macros::time!(08:00)));
+        assert_eq!(digest.offset(), Some(time::macros::offset!(-5:30)));
+        let emails: Vec<&str> = digest.destinations.emails.iter().flatten().map(|email| email.address.as_str()).collect();
+        assert_eq!(emails, ["farm@example.com"]);
+        assert!(config.get_notification_destinations(&NotificationType::PrintComplete).is_none());
+
+        // templates_path is not taken as a notification type
+        let config: Config = toml::from_str(r#"
+            [notifications]
+            templates_path = "/nonexistent/templates"
+            [printers]
+        "#).unwrap();
+        assert_eq!(config.validate(), ["notifications.templates_path: /nonexistent/templates is not a directory"]);
+    }
+
+    #[test]
+    fn webhook_backoff_doubles_up_to_a_cap() {
+        let settings = WebhookSettings { backoff_ms: 1000, ..Default::default() };
+        let delays: Vec<u64> = (1..=4).map(|attempt| settings.backoff(attempt).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8]);
+        assert_eq!(settings.backoff(10), MAX_WEBHOOK_BACKOFF);
+        // Would overflow the multiplication and the power
+        let settings = WebhookSettings { backoff_ms: u64::MAX / 2, ..Default::default() };
+        assert_eq!(settings.backoff(3), MAX_WEBHOOK_BACKOFF);
+        assert_eq!(settings.backoff(u32::MAX), MAX_WEBHOOK_BACKOFF);
+
+        let config: Config = toml::from_str("[webhook]\nretries = 4000000000\n[printers]").unwrap();
+        assert_eq!(config.validate(), ["webhook.retries: 4000000000 is more than 10"]);
+    }
+
+    #[test]
+    fn webhook_timeouts_and_proxy_are_checked() {
+        let config: Config = toml::from_str(r#"
+            [webhook]
+            timeout_seconds = 0
+            proxy = "not a proxy"
+            [notifications.on_done]
+            webhooks = ["https://example.com/done", { url = "https://example.com/slow", timeout_seconds = 0 }]
+            [printers]
+        "#).unwrap();
+        let problems = config.validate();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].starts_with("notifications.on_done.webhooks[1].timeout_seconds: must be at least 1"));
+        assert_eq!(problems[1], "webhook.timeout_seconds: must be at least 1");
+        assert!(problems[2].starts_with("webhook.proxy: invalid proxy url \"not a proxy\""), "{}", problems[2]);
+
+        let config = ConfigManager::from_toml(r#"
+            [webhook]
+            proxy = "http://proxy.lan:3128"
+            [notifications.on_done]
+            webhooks = [{ url = "https://example.com/slow", timeout_seconds = 30 }]
+            [printers]
+        "#);
+        assert_eq!(config.webhook_settings().timeout(), Duration::from_secs(5));
+        let webhooks = config.get_notification_destinations(&NotificationType::PrintComplete).unwrap().webhooks.as_ref().unwrap();
+        assert_eq!(webhooks[0].timeout(), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn unix_socket_replaces_the_port() {
+        let config = ConfigManager::from_toml(r#"
+            [http]
+            unix_socket = "/tmp/flashforge.sock"
+            [printers]
+        "#);
+        assert!(config.config.validate().is_empty(), "{:?}", config.config.validate());
+        assert_eq!(config.http().port(), None);
+        assert_eq!(config.http().unix_socket_mode(), Some(0o660));
+        assert_eq!(ConfigManager::from_toml("[printers]").http().port(), Some(8080));
+
+        let config: Config = toml::from_str(r#"
+            [http]
+            unix_socket = "/nonexistent/flashforge/api.sock"
+            unix_socket_mode = "rw-rw----"
+            port = 8080
+            [printers]
+        "#).unwrap();
+        assert_eq!(config.validate(), [
+            "http.unix_socket: directory /nonexistent/flashforge does not exist",
+            "http.unix_socket_mode: \"rw-rw----\" is not octal permissions such as \"660\""
+        ]);
+        assert_eq!(config.http.port(), Some(8080));
+    }
+
+    #[test]
+    fn access_log_is_read_under_http() {
+        let config = ConfigManager::from_toml(r#"
+            [http.access_log]
+            format = "json"
+            trusted_proxies = ["127.0.0.1"]
+            [printers]
+        "#);
+        let access_log = config.http().access_log.as_ref().unwrap();
+        assert_eq!(access_log.format, LogFormat::Json);
+        assert_eq!(access_log.trusted_proxies, [IpAddr::V4(std::net::Ipv4Addr::LOCALHOST)]);
+        assert!(access_log.path.is_none());
+
+        let config: Config = toml::from_str(r#"
+            [http.access_log]
+            path = "/nonexistent/flashforge/access.log"
+            [printers]
+        "#).unwrap();
+        assert_eq!(config.validate(), ["http.access_log.path: directory /nonexistent/flashforge does not exist"]);
+    }
+
+    #[test]
+    fn disabled_features_are_reported() {
+        let config: Config = toml::from_str(r#"
+            [smtp]
+            host = "smtp.example.com"
+            port = 587
+            user = "farm@example.com"
+            password = "secret"
+            encryption = "starttls"
+            [notifications.on_done]
+            emails = ["farm@example.com"]
+            [camera]
+            placeholder_path = "Cargo.toml"
+            [printers]
+            main = { ip = "10.0.0.50", camera = { rotate = 180 } }
+        "#).unwrap();
+        let problems = config.validate();
+        let compiled_without = |key: &str| problems.iter().any(|problem| problem.starts_with(key) && problem.contains("compiled without"));
+        assert_eq!(compiled_without("smtp:"), cfg!(not(feature = "smtp")), "{:?}", problems);
+        assert_eq!(compiled_without("notifications.on_done.emails:"), cfg!(not(feature = "smtp")), "{:?}", problems);
+        assert_eq!(compiled_without("camera.placeholder_path:"), cfg!(not(feature = "camera")), "{:?}", problems);
+        assert_eq!(compiled_without("printers.\"main\".camera:"), cfg!(not(feature = "camera")), "{:?}", problems);
+        if cfg!(all(feature = "smtp", feature = "camera")) {
+            assert!(problems.is_empty(), "{:?}", problems);
+        }
+    }
+
+    #[cfg(feature = "camera")]
+    #[test]
+    fn camera_rotation_is_a_quarter_turn() {
+        let config: Config = toml::from_str(r#"
+            [printers]
+            main = { ip = "10.0.0.50", camera = { rotate = 45 } }
+            side = { ip = "10.0.0.51", camera = { rotate = 270, flip = "vertical" } }
+        "#).unwrap();
+        assert_eq!(config.validate(), vec!["printers.\"main\".camera.rotate: 45 is not 0, 90, 180 or 270".to_string()]);
+        assert_eq!(config.printers["side"].camera_transform(), crate::camera::Transform::new(270, Some(Flip::Vertical)).unwrap());
+        assert!(toml::from_str::<Config>("[printers]\nmain = { ip = \"10.0.0.50\", camera = { flip = \"diagonal\" } }").is_err());
+    }
+
+    #[cfg(feature = "camera")]
+    #[test]
+    fn gif_needs_frames_to_attach() {
+        let config: Config = toml::from_str(r#"
+            [notifications]
+            attach_gif = true
+            [camera]
+            gif_frames = 0
+            gif_interval_secs = 0
+            [printers]
+        "#).unwrap();
+        assert_eq!(config.validate(), [
+            "notifications.attach_gif: camera.gif_frames is 0, no frames are kept to make the GIF of",
+            "camera.gif_interval_secs: must be at least 1"
+        ]);
+        let config: Config = toml::from_str("[camera]\ngif_max_kb = 512\n[printers]").unwrap();
+        assert_eq!(config.camera.gif().max_bytes, 512 * 1024);
+        assert_eq!(config.camera.gif().frames, 10);
+    }
+
+    #[test]
+    fn console_capture_keeps_at_least_a_line() {
+        let config: Config = toml::from_str(r#"
+            [printers]
+            main = { ip = "10.0.0.50", console = { capture = true, max_lines = 0 } }
+            side = { ip = "10.0.0.51", console = { capture = true } }
+            other = { ip = "10.0.0.52" }
+        "#).unwrap();
+        assert_eq!(config.validate(), vec!["printers.\"main\".console.max_lines: must be at least 1".to_string()]);
+        assert_eq!(config.printers["side"].console.kept_lines(), 500);
+        assert_eq!(config.printers["other"].console.kept_lines(), 0);
+    }
+
+    #[test]
+    fn connections_are_limited_to_one_by_default() {
+        let config: Config = toml::from_str(r#"
+            [printers]
+            main = { ip = "10.0.0.50", connections = { gcode = 0, camera = 2 } }
+            other = { ip = "10.0.0.52" }
+        "#).unwrap();
+        assert_eq!(config.validate(), vec!["printers.\"main\".connections.gcode: must be at least 1".to_string()]);
+        let connections = &config.printers["other"].connections;
+        assert_eq!((connections.gcode, connections.camera, connections.wait()), (1, 1, Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn filament_weight_is_positive_and_gcode_dir_exists() {
+        let config: Config = toml::from_str(r#"
+            [filament]
+            grams_per_megabyte = 0
+            [gcode]
+            dir = "/nonexistent/gcode"
+            [printers]
+            main = { ip = "10.0.0.50" }
+        "#).unwrap();
+        assert_eq!(config.validate(), [
+            "filament.grams_per_megabyte: 0 must be more than 0".to_string(),
+            "gcode.dir: /nonexistent/gcode is not a directory".to_string()
+        ]);
+        let config: Config = toml::from_str("[filament]\n[printers]\nmain = { ip = \"10.0.0.50\" }").unwrap();
+        assert!(config.validate().is_empty());
+        assert_eq!(config.filament.unwrap().grams_per_megabyte, 10.0);
+    }
+
+    #[test]
+    fn macros_only_send_allowed_commands() {
+        let config: Config = toml::from_str(r#"
+            [macros.preheat_pla]
+            printer_commands = ["M104 S200 T0", "~m140 S60", "M146 r255 g255 b255 F0"]
+            [macros."print now"]
+            printer_commands = ["M23 0:/user/cube.gx"]
+            [macros.nothing]
+            printer_commands = []
+            [printers]
+            main = { ip = "10.0.0.50" }
+        "#).unwrap();
+        assert_eq!(config.validate(), [
+            "macros.\"nothing\".printer_commands: has no commands".to_string(),
+            "macros.\"print now\": macro name can only contain letters, numbers, '-' and '_'".to_string(),
+            format!("macros.\"print now\".printer_commands[0]: \"M23 0:/user/cube.gx\" is not an allowed command, expected one of {}", RAW_COMMAND_ALLOWLIST.join(", ")),
+        ]);
+        assert_eq!(config.macros["preheat_pla"].requests()[1].get_gcode(), "~m140 S60");
+    }
+
+    #[test]
+    fn ipv6_addresses_are_accepted() {
+        let config: Config = toml::from_str(r#"
+            [http]
+            address = "::"
+            [printers]
+            main = { ip = "fd00::50" }
+            side = { host = "[fd00::51]" }
+        "#).unwrap();
+        assert_eq!(config.validate(), vec!["printers.\"side\".host: \"[fd00::51]\" is not a valid hostname, IPv6 addresses go in ip".to_string()]);
+        let config: Config = toml::from_str(r#"
+            [http]
+            address = "::"
+            [printers]
+            main = { ip = "fd00::50" }
+        "#).unwrap();
+        assert_eq!(config.validate(), Vec::<String>::new());
+        assert_eq!(config.http.address, IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED));
+        assert_eq!(config.printers["main"].host().as_deref(), Some("fd00::50"));
+    }
+
+    #[test]
+    fn destinations_can_be_disabled_and_saved() {
+        let contents = r#"
+[notifications.on_done]
+# Who gets told
+emails = ["me@example.com", { address = "team@example.com", enabled = false }]
+webhooks = ["https://example.com/done"]
+
+[[notifications.digest.webhooks]]
+url = "https://example.com/digest"
+
+[printers]
+"#;
+        let config = ConfigManager::from_toml(contents);
+        let destinations = config.destinations();
+        let listed: Vec<_> = destinations.iter().map(|d| (d.key, d.kind.clone(), d.position, d.target, d.enabled.get())).collect();
+        assert_eq!(listed, [
+            ("on_done", DestinationKind::Email, 0, "me@example.com", true),
+            ("on_done", DestinationKind::Email, 1, "team@example.com", false),
+            ("on_done", DestinationKind::Webhook, 0, "https://example.com/done", true),
+            ("digest", DestinationKind::Webhook, 0, "https://example.com/digest", true),
+        ]);
+
+        // Without a file only the running config changes
+        config.set_destination_enabled(&destinations[3], false).unwrap();
+        assert!(!destinations[3].enabled.get());
+        assert!(config.webhooks().iter().all(|webhook| webhook.url != "https://example.com/digest"));
+
+        let saved = with_destination_enabled(contents, &destinations[0], false).unwrap();
+        let saved = with_destination_enabled(&saved, &destinations[1], true).unwrap();
+        let saved = with_destination_enabled(&saved, &destinations[2], false).unwrap();
+        let saved = with_destination_enabled(&saved, &destinations[3], false).unwrap();
+        assert!(saved.contains("# Who gets told\n"), "{}", saved);
+        assert!(saved.contains(r#"emails = [{ address = "me@example.com", enabled = false }, { address = "team@example.com", enabled = true }]"#), "{}", saved);
+        assert!(saved.contains(r#"webhooks = [{ url = "https://example.com/done", enabled = false }]"#), "{}", saved);
+        assert!(saved.contains("url = \"https://example.com/digest\"\nenabled = false\n"), "{}", saved);
+        let reloaded = ConfigManager::from_toml(&saved);
+        assert!(reloaded.destinations().iter().all(|destination| destination.enabled.get() == (destination.position == 1)));
+    }
+
+    #[test]
+    fn printer_labels_are_saved_to_their_entry() {
+        let contents = r#"
+[printers]
+# The one by the door
+main = { ip = "192.168.1.89", location = "Old shelf" }
+
+[printers.second]
+host = "adventurer3.lan"
+"#;
+        let labels = PrinterLabels { display_name: Some("Door".to_string()), location: None };
+        let saved = with_printer_labels(contents, "main", &labels).unwrap();
+        assert!(saved.contains("# The one by the door\nmain = { ip = \"192.168.1.89\", display_name = \"Door\" }\n"), "{}", saved);
+        let labels = PrinterLabels { display_name: Some("Second".to_string()), location: Some("Lab 2".to_string()) };
+        let saved = with_printer_labels(&saved, "second", &labels).unwrap();
+        assert!(saved.contains("host = \"adventurer3.lan\"\ndisplay_name = \"Second\"\nlocation = \"Lab 2\"\n"), "{}", saved);
+        assert!(with_printer_labels(&saved, "third", &labels).is_err());
+
+        let reloaded = ConfigManager::from_toml(&saved);
+        let main = &reloaded.printers()["main"];
+        assert_eq!((main.display_name.as_deref(), main.location.as_deref()), (Some("Door"), None));
+    }
+}
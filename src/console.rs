@@ -0,0 +1,83 @@
+//! Lines printers send on their own while the connection is kept open, captured with printers.<id>.console.capture
+use std::collections::VecDeque;
+use log::{debug, trace};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+use crate::models::{ConsoleLine, PrinterEvent};
+
+/// The latest lines, each also published as a [PrinterEvent::Console]
+pub struct Console {
+    /// Lines kept, 0 while not capturing
+    max_lines: AtomicUsize,
+    lines: Mutex<VecDeque<ConsoleLine>>,
+    events: broadcast::Sender<PrinterEvent>
+}
+
+impl Console {
+    pub fn new(events: broadcast::Sender<PrinterEvent>) -> Self {
+        Self { max_lines: AtomicUsize::new(0), lines: Mutex::new(VecDeque::new()), events }
+    }
+
+    /// Keeps the last max_lines lines, 0 stops capturing and forgets them
+    pub fn set_capture(&self, max_lines: usize) {
+        self.max_lines.store(max_lines, Ordering::Relaxed);
+        let mut lines = self.lines.lock().unwrap();
+        while lines.len() > max_lines {
+            lines.pop_front();
+        }
+    }
+
+    pub fn capturing(&self) -> bool {
+        self.max_lines.load(Ordering::Relaxed) > 0
+    }
+
+    /// Called by the command task with the lines the printer sent outside of a response, dropped unless capturing
+    pub fn record(&self, printer: &str, lines: Vec<String>) {
+        let max_lines = self.max_lines.load(Ordering::Relaxed);
+        for line in lines {
+            if max_lines == 0 {
+                trace!("printer/{} sent \"{}\", not capturing the console", printer, line);
+                continue;
+            }
+            debug!("printer/{} console: {}", printer, line);
+            // Fails only when nobody is subscribed
+            self.events.send(PrinterEvent::Console(line.clone())).ok();
+            let mut kept = self.lines.lock().unwrap();
+            if kept.len() >= max_lines {
+                kept.pop_front();
+            }
+            kept.push_back(ConsoleLine { at: OffsetDateTime::now_utc(), line });
+        }
+    }
+
+    /// The last count lines, oldest first. None when not capturing
+    pub fn last(&self, count: usize) -> Option<Vec<ConsoleLine>> {
+        if !self.capturing() {
+            return None;
+        }
+        let lines = self.lines.lock().unwrap();
+        Some(lines.iter().skip(lines.len().saturating_sub(count)).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_last_lines_while_capturing() {
+        let (events, mut received) = broadcast::channel(16);
+        let console = Console::new(events);
+        console.record("main", vec!["dropped".to_string()]);
+        assert_eq!(console.last(10), None);
+
+        console.set_capture(2);
+        console.record("main", vec!["one".to_string(), "two".to_string(), "three".to_string()]);
+        let lines: Vec<String> = console.last(10).unwrap().into_iter().map(|line| line.line).collect();
+        assert_eq!(lines, ["two", "three"]);
+        assert_eq!(console.last(1).unwrap()[0].line, "three");
+        assert_eq!(received.try_recv().unwrap(), PrinterEvent::Console("one".to_string()));
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use futures::future::join_all;
+use log::{debug, trace, warn};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use crate::models::DiscoveredPrinter;
+use crate::printer::{Printer, PRINTER_API_PORT};
+
+/// Port printers listen on for the discovery broadcast FlashPrint sends
+pub const DISCOVERY_PORT: u16 = 48899;
+/// Printers answer any datagram on the discovery port
+const DISCOVERY_PACKET: &[u8] = b"flashforge-api-server\0";
+/// The machine name is the start of the reply, NUL padded
+const NAME_LENGTH: usize = 32;
+
+/// Broadcasts to each address and collects the printers that answer within `timeout`,
+/// de-duplicated by serial number so printers reachable from several networks are only listed once
+pub async fn discover(broadcast_addresses: &[IpAddr], timeout: Duration) -> Result<Vec<DiscoveredPrinter>, String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.map_err(|e| e.to_string())?;
+    socket.set_broadcast(true).map_err(|e| e.to_string())?;
+    for address in broadcast_addresses {
+        trace!("sending discovery packet to {}", address);
+        // Unreachable networks shouldn't prevent finding printers on the others
+        if let Err(e) = socket.send_to(DISCOVERY_PACKET, SocketAddr::new(*address, DISCOVERY_PORT)).await {
+            warn!("discovery: failed to send to {}: {}", address, e);
+        }
+    }
+
+    let mut replies: Vec<(IpAddr, String)> = Vec::new();
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; 512];
+    while let Ok(result) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (n, from) = result.map_err(|e| e.to_string())?;
+        let name = parse_name(&buf[..n]).unwrap_or_else(|| from.ip().to_string());
+        debug!("discovery: {} answered as \"{}\"", from.ip(), name);
+        if !replies.iter().any(|(ip, _)| *ip == from.ip()) {
+            replies.push((from.ip(), name));
+        }
+    }
+
+    let printers = join_all(replies.into_iter().map(|(ip, name)| identify(ip, name))).await;
+    let mut serials = HashSet::new();
+    Ok(printers.into_iter()
+        .filter(|printer| printer.sn.as_ref().is_none_or(|sn| serials.insert(sn.clone())))
+        .collect())
+}
+
+fn parse_name(reply: &[u8]) -> Option<String> {
+    let name = &reply[..reply.len().min(NAME_LENGTH)];
+    let name = name.split(|b| *b == 0).next().unwrap_or_default();
+    let name = String::from_utf8_lossy(name).trim().to_string();
+    (!name.is_empty()).then_some(name)
+}
+
+/// Asks the printer for its info (M115), to get the serial number and model
+async fn identify(ip: IpAddr, name: String) -> DiscoveredPrinter {
+    let printer = Printer::new(name.clone(), ip.to_string(), PRINTER_API_PORT, Duration::ZERO);
+    let info = printer.get_info().await
+        .inspect_err(|e| debug!("discovery: {} did not answer M115: {}", ip, e))
+        .ok();
+    DiscoveredPrinter {
+        name: info.as_ref().map(|info| info.name.clone()).unwrap_or(name),
+        host: ip.to_string(),
+        sn: info.as_ref().map(|info| info.sn.clone()),
+        model_name: info.map(|info| info.model_name),
+    }
+}
+
+/// Turns a machine name into a printer id, replacing characters ids can't contain
+pub fn printer_id(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
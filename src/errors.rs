@@ -0,0 +1,123 @@
+//! The error codes of API responses, so clients can match on them. Every code is listed by GET /api/errors
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use crate::models::{ErrorCodeInfo, GenericError};
+
+/// Declares [ErrorCode] with the status it is usually returned with, its name and what it means
+macro_rules! error_codes {
+    ($($variant:ident => $status:ident, $name:literal, $description:literal;)*) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum ErrorCode {
+            $($variant,)*
+        }
+
+        impl ErrorCode {
+            pub const ALL: &'static [ErrorCode] = &[$(ErrorCode::$variant,)*];
+
+            /// As it is in the `error` field of responses
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $(ErrorCode::$variant => $name,)*
+                }
+            }
+
+            pub fn status(self) -> Status {
+                match self {
+                    $(ErrorCode::$variant => Status::$status,)*
+                }
+            }
+
+            pub fn description(self) -> &'static str {
+                match self {
+                    $(ErrorCode::$variant => $description,)*
+                }
+            }
+        }
+    };
+}
+
+error_codes! {
+    BadRequest => BadRequest, "BAD_REQUEST", "The request could not be parsed, such as a malformed JSON body";
+    PasswordRequired => Unauthorized, "PASSWORD_REQUIRED", "The route needs the password or a token, in the X-Secret or Authorization: Bearer header";
+    InsufficientScope => Forbidden, "INSUFFICIENT_SCOPE", "The token's scope does not allow the action";
+    Forbidden => Forbidden, "FORBIDDEN", "The request is not allowed";
+    NotFound => NotFound, "NOT_FOUND", "No route matches the path";
+    PayloadTooLarge => PayloadTooLarge, "PAYLOAD_TOO_LARGE", "The body is over Rocket's limits for its type";
+    UnprocessableRequest => UnprocessableEntity, "UNPROCESSABLE_REQUEST", "The body or a query parameter has the wrong type, is missing fields or has values out of range, details lists the fields of the body";
+    TooManyAttempts => TooManyRequests, "TOO_MANY_ATTEMPTS", "Locked out after failed auth attempts, see Retry-After";
+    RateLimited => TooManyRequests, "RATE_LIMITED", "Over the http.rate_limit budget, see Retry-After";
+    InternalError => InternalServerError, "INTERNAL_ERROR", "The server failed to handle the request";
+    HttpError => InternalServerError, "HTTP_ERROR", "Any other error status, the message has its reason";
+    ServiceUnavailable => ServiceUnavailable, "SERVICE_UNAVAILABLE", "The server can't handle the request right now";
+    UnknownPrinter => NotFound, "UNKNOWN_PRINTER", "No printer has the id or serial number";
+    NotSupportedByModel => NotImplemented, "NOT_SUPPORTED_BY_MODEL", "The printer's model has no light, camera or other feature the route uses";
+    PrinterUnreachable => ServiceUnavailable, "PRINTER_UNREACHABLE", "The printer could not be connected to or dropped the connection";
+    PrinterTimeout => GatewayTimeout, "PRINTER_TIMEOUT", "The printer did not answer in time";
+    PrinterInvalidResponse => BadGateway, "PRINTER_INVALID_RESPONSE", "The printer answered with something that could not be parsed";
+    PrinterEmptyResponse => BadGateway, "PRINTER_EMPTY_RESPONSE", "The printer answered the query with nothing, also when it was sent again";
+    ControlDenied => Conflict, "CONTROL_DENIED", "The printer refused control (M601), another client such as FlashPrint has it";
+    PrinterBusy => ServiceUnavailable, "PRINTER_BUSY", "The printer is busy with a command past http.busy_after_ms, or its connections stayed in use, see Retry-After";
+    UnknownQuery => BadRequest, "UNKNOWN_QUERY", "what of the query route is not info, status, temperatures, progress or position";
+    UnknownField => BadRequest, "UNKNOWN_FIELD", "fields selects a field the response does not have or that is hidden";
+    SerializationFailed => InternalServerError, "SERIALIZATION_FAILED", "The response could not be serialized";
+    InvalidUnit => BadRequest, "INVALID_UNIT", "unit is not c or f";
+    InvalidSince => BadRequest, "INVALID_SINCE", "since is not an RFC 3339 time, or a unix timestamp for the history";
+    UnknownMetric => BadRequest, "UNKNOWN_METRIC", "The history has no such metric";
+    InvalidResolution => BadRequest, "INVALID_RESOLUTION", "resolution of the history is not a duration such as 60s, 5m or 1h";
+    InvalidTransform => BadRequest, "INVALID_TRANSFORM", "rotate, flip or width of the snapshot is not valid";
+    CameraUnavailable => BadGateway, "CAMERA_UNAVAILABLE", "No frame from the camera, 503 when the stream can't be set up";
+    NotPrinting => Conflict, "NOT_PRINTING", "The printer is not printing a file";
+    NoActiveJob => NotFound, "NO_ACTIVE_JOB", "The printer is not printing a file";
+    NoThumbnail => NotFound, "NO_THUMBNAIL", "The file being printed has no sliced file with a thumbnail in gcode.dir";
+    NoCachedPosition => NotFound, "NO_CACHED_POSITION", "The head position was never polled, it needs watch.head_position";
+    ConsoleDisabled => NotFound, "CONSOLE_DISABLED", "The printer's console.capture is off";
+    HistoryDisabled => NotFound, "HISTORY_DISABLED", "No [history] is configured";
+    HistoryError => InternalServerError, "HISTORY_ERROR", "The history could not be read";
+    UnknownSeries => BadRequest, "UNKNOWN_SERIES", "A Grafana target is not <printer>.<metric>";
+    FilamentDisabled => NotFound, "FILAMENT_DISABLED", "No [filament] is configured";
+    UnknownMacro => NotFound, "UNKNOWN_MACRO", "No macro has the name";
+    MacroStepFailed => ServiceUnavailable, "MACRO_STEP_FAILED", "A step of the macro failed, the status is the step's error and the steps after it were not sent";
+    UnknownDestination => NotFound, "UNKNOWN_DESTINATION", "No notification destination has the index";
+    ConfigWriteFailed => InternalServerError, "CONFIG_WRITE_FAILED", "The change could not be saved to the config file";
+    DiscoveryFailed => InternalServerError, "DISCOVERY_FAILED", "The discovery broadcast could not be sent";
+    UnknownCommand => BadRequest, "UNKNOWN_COMMAND", "cmd of the debug route is not one of the commands it knows";
+    RecordingFailed => InternalServerError, "RECORDING_FAILED", "The responses could not be recorded";
+    AuditError => InternalServerError, "AUDIT_ERROR", "The audit log could not be read";
+}
+
+impl ErrorCode {
+    /// The body of an error response
+    pub fn with_message(self, message: impl Into<String>) -> GenericError {
+        GenericError { error: self.as_str().to_string(), message: Some(message.into()), details: Vec::new() }
+    }
+
+    /// The error response with the code's usual status
+    pub fn response(self, message: impl Into<String>) -> (Status, Json<GenericError>) {
+        self.response_with(self.status(), message)
+    }
+
+    /// The error response with another status than the code's usual one
+    pub fn response_with(self, status: Status, message: impl Into<String>) -> (Status, Json<GenericError>) {
+        (status, Json(self.with_message(message)))
+    }
+
+    pub fn info(self) -> ErrorCodeInfo {
+        ErrorCodeInfo { code: self.as_str().to_string(), status: self.status().code, description: self.description().to_string() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn codes_are_unique_and_screaming_snake_case() {
+        let names: HashSet<&str> = ErrorCode::ALL.iter().map(|code| code.as_str()).collect();
+        assert_eq!(names.len(), ErrorCode::ALL.len());
+        assert!(names.iter().all(|name| name.chars().all(|c| c.is_ascii_uppercase() || c == '_')), "{:?}", names);
+        let (status, Json(body)) = ErrorCode::UnknownPrinter.response("unknown printer x");
+        assert_eq!(status, Status::NotFound);
+        assert_eq!(body, GenericError { error: "UNKNOWN_PRINTER".to_string(), message: Some("unknown printer x".to_string()), details: Vec::new() });
+    }
+}
@@ -0,0 +1,197 @@
+//! The filament used by each print, from the slicer's estimate in the file or from its size, kept in memory and,
+//! with filament.path, in a JSON lines file
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use log::{debug, warn};
+use time::OffsetDateTime;
+use crate::config::FilamentConfig;
+use crate::gcode;
+use crate::models::{FilamentRecord, FilamentSource, FilamentStats, PrinterFilament};
+
+/// Shared by the watch thread and the stats route
+#[derive(Clone)]
+pub struct FilamentLog(Arc<FilamentLogInner>);
+
+struct FilamentLogInner {
+    path: Option<PathBuf>,
+    gcode_dir: Option<PathBuf>,
+    grams_per_megabyte: f64,
+    /// Oldest first
+    records: Mutex<Vec<FilamentRecord>>,
+    /// Estimate of the whole file being printed, by printer id
+    running: Mutex<HashMap<String, JobEstimate>>
+}
+
+#[derive(Clone)]
+struct JobEstimate {
+    file: String,
+    grams: f64,
+    source: FilamentSource
+}
+
+impl FilamentLog {
+    /// Loads the prints of the file, if there is one. Sliced files are looked up in gcode_dir
+    pub fn new(config: &FilamentConfig, gcode_dir: Option<&Path>) -> Self {
+        let records = match &config.path {
+            Some(path) => read_records(path).unwrap_or_else(|e| {
+                warn!("Could not read filament usage {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            None => Vec::new()
+        };
+        Self(Arc::new(FilamentLogInner {
+            path: config.path.clone(),
+            gcode_dir: gcode_dir.map(Path::to_path_buf),
+            grams_per_megabyte: config.grams_per_megabyte,
+            records: Mutex::new(records),
+            running: Mutex::new(HashMap::new())
+        }))
+    }
+
+    /// Estimates the filament of the file the printer started, file_bytes is the size M27 reports
+    pub async fn job_started(&self, printer: &str, file: &str, file_bytes: Option<u32>) {
+        let log = self.0.clone();
+        let owned = file.to_string();
+        let estimate = tokio::task::spawn_blocking(move || log.estimate(&owned, file_bytes)).await.ok().flatten();
+        match estimate {
+            Some(estimate) => {
+                debug!("printer/{} print of {} estimated at {:.1}g ({:?})", printer, file, estimate.grams, estimate.source);
+                self.0.running.lock().unwrap().insert(printer.to_string(), estimate);
+            },
+            None => debug!("printer/{} print of {} has no filament estimate", printer, file)
+        }
+    }
+
+    /// Records the filament the print used, the part of its estimate up to progress_percent
+    pub async fn job_ended(&self, printer: &str, file: &str, progress_percent: u8) {
+        let started = self.0.running.lock().unwrap().remove(printer).filter(|estimate| estimate.file == file);
+        let estimate = match started {
+            Some(estimate) => Some(estimate),
+            // Started before a restart, only the file can tell
+            None => {
+                let log = self.0.clone();
+                let owned = file.to_string();
+                tokio::task::spawn_blocking(move || log.estimate(&owned, None)).await.ok().flatten()
+            }
+        };
+        let Some(estimate) = estimate else {
+            debug!("printer/{} print of {} ended without a filament estimate", printer, file);
+            return;
+        };
+        let progress_percent = progress_percent.min(100);
+        let record = FilamentRecord {
+            printer: printer.to_string(),
+            file: file.to_string(),
+            ended_at: OffsetDateTime::now_utc(),
+            grams: estimate.grams * progress_percent as f64 / 100.0,
+            progress_percent,
+            source: estimate.source
+        };
+        let log = self.0.clone();
+        match tokio::task::spawn_blocking(move || log.push(record)).await {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => warn!("Could not save filament usage: {}", e),
+            Err(e) => warn!("Could not save filament usage: {}", e)
+        }
+    }
+
+    /// Grams used by prints that ended since, by printer
+    pub fn totals(&self, since: OffsetDateTime) -> FilamentStats {
+        let mut printers: BTreeMap<String, PrinterFilament> = BTreeMap::new();
+        for record in self.0.records.lock().unwrap().iter().filter(|record| record.ended_at >= since) {
+            let totals = printers.entry(record.printer.clone()).or_insert_with(|| PrinterFilament {
+                printer: record.printer.clone(), jobs: 0, grams: 0.0, estimated_from_file_size: 0
+            });
+            totals.jobs += 1;
+            totals.grams += record.grams;
+            if record.source == FilamentSource::FileSize {
+                totals.estimated_from_file_size += 1;
+            }
+        }
+        FilamentStats {
+            since,
+            total_grams: printers.values().map(|printer| printer.grams).sum(),
+            printers: printers.into_values().collect()
+        }
+    }
+}
+
+impl FilamentLogInner {
+    /// The slicer's estimate if the file is in gcode_dir, otherwise from the file size
+    fn estimate(&self, file: &str, file_bytes: Option<u32>) -> Option<JobEstimate> {
+        let slicer = self.gcode_dir.as_ref()
+            .and_then(|dir| gcode::sliced_file(dir, file))
+            .and_then(|path| match gcode::read_estimate(&path) {
+                Ok(estimate) => estimate.grams(),
+                Err(e) => {
+                    debug!("No sliced file for {} at {}: {}", file, path.display(), e);
+                    None
+                }
+            });
+        match (slicer, file_bytes) {
+            (Some(grams), _) => Some(JobEstimate { file: file.to_string(), grams, source: FilamentSource::Slicer }),
+            (None, Some(bytes)) if bytes > 0 => Some(JobEstimate {
+                file: file.to_string(),
+                grams: bytes as f64 / 1_000_000.0 * self.grams_per_megabyte,
+                source: FilamentSource::FileSize
+            }),
+            _ => None
+        }
+    }
+
+    fn push(&self, record: FilamentRecord) -> Result<(), String> {
+        let line = serde_json::to_string(&record).map_err(|e| e.to_string())?;
+        self.records.lock().unwrap().push(record);
+        let Some(path) = &self.path else { return Ok(()) };
+        let mut file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| e.to_string())?;
+        writeln!(file, "{}", line).map_err(|e| e.to_string())
+    }
+}
+
+/// Every record, skipping lines that can't be parsed (such as one cut off by a crash)
+fn read_records(path: &Path) -> Result<Vec<FilamentRecord>, String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.to_string())
+    };
+    Ok(BufReader::new(file).lines().map_while(Result::ok).filter_map(|line| serde_json::from_str(&line).ok()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn prints_are_estimated_from_the_slicer_or_the_file_size() {
+        let dir = std::env::temp_dir().join(format!("flashforge-filament-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("benchy.gcode"), "; filament used [g] = 12.0\nG28\n").unwrap();
+        let path = dir.join("filament.jsonl");
+        let config = FilamentConfig { path: Some(path.clone()), grams_per_megabyte: 10.0 };
+        let log = FilamentLog::new(&config, Some(&dir));
+
+        log.job_started("main", "benchy.gcode", Some(3_000_000)).await;
+        log.job_started("side", "cube.gx", Some(3_000_000)).await;
+        log.job_ended("main", "benchy.gcode", 100).await;
+        // Cancelled halfway, only the filament up to there counts
+        log.job_ended("side", "cube.gx", 50).await;
+        log.job_ended("side", "unknown.gx", 100).await;
+
+        let stats = log.totals(OffsetDateTime::now_utc() - time::Duration::days(30));
+        assert_eq!(stats.total_grams, 27.0);
+        assert_eq!(stats.printers, vec![
+            PrinterFilament { printer: "main".to_string(), jobs: 1, grams: 12.0, estimated_from_file_size: 0 },
+            PrinterFilament { printer: "side".to_string(), jobs: 1, grams: 15.0, estimated_from_file_size: 1 }
+        ]);
+        assert!(log.totals(OffsetDateTime::now_utc() + time::Duration::minutes(1)).printers.is_empty());
+
+        // Kept across restarts
+        let reloaded = FilamentLog::new(&config, None);
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!(reloaded.totals(OffsetDateTime::UNIX_EPOCH).total_grams, 27.0);
+    }
+}
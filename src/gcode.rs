@@ -0,0 +1,262 @@
+//! The filament estimates and thumbnails slicers write as comments in the G-code they generate. Cura and FlashPrint
+//! write the estimate at the top of the file, PrusaSlicer at the end. Thumbnails are always at the top
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::Bytes;
+use crate::util::ImageFormat;
+
+/// Bytes read from the start and from the end of a file for its comments, the moves in between are skipped
+const SCAN_BYTES: u64 = 64 * 1024;
+/// Bytes read from the start of a file for its thumbnails, a large PNG is a few hundred KB once encoded
+const THUMBNAIL_SCAN_BYTES: u64 = 2 * 1024 * 1024;
+/// Weight of a mm of 1.75mm PLA (1.24 g/cm³), for slicers that only report the length
+const PLA_GRAMS_PER_MM: f64 = 0.00298;
+
+/// What the slicer estimated the print uses, summed over the extruders
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SlicerEstimate {
+    pub filament_mm: Option<f64>,
+    pub filament_grams: Option<f64>
+}
+
+impl SlicerEstimate {
+    /// The weight, from the length as PLA when the slicer only reported that
+    pub fn grams(&self) -> Option<f64> {
+        self.filament_grams.or(self.filament_mm.map(|mm| mm * PLA_GRAMS_PER_MM))
+    }
+
+    fn or(self, other: SlicerEstimate) -> SlicerEstimate {
+        SlicerEstimate {
+            filament_mm: self.filament_mm.or(other.filament_mm),
+            filament_grams: self.filament_grams.or(other.filament_grams)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Unit {
+    Mm,
+    Grams
+}
+
+/// Parses the comments such as Cura's ";Filament used: 1.5m" or PrusaSlicer's "; filament used [g] = 4.2"
+pub fn parse_estimate(text: &str) -> SlicerEstimate {
+    let mut estimate = SlicerEstimate::default();
+    for line in text.lines() {
+        let Some(comment) = line.trim().strip_prefix(';') else { continue };
+        let Some((key, value)) = comment.split_once([':', '=']) else { continue };
+        let key = key.trim().to_ascii_lowercase();
+        if !key.contains("filament") || !(key.contains("used") || key.contains("usage")) {
+            continue;
+        }
+        // PrusaSlicer puts the unit in the key, "filament used [mm]"
+        let key_unit = key.split_once('[').map(|(_, unit)| unit.trim_end_matches(']').trim());
+        // One value per extruder, "1.2m, 0.5m"
+        let mut total = None;
+        for part in value.split(',') {
+            let Some((amount, unit)) = quantity(part.trim(), key_unit) else { continue };
+            let (sum, total_unit) = total.get_or_insert((0.0, unit));
+            if *total_unit == unit {
+                *sum += amount;
+            }
+        }
+        match total {
+            // PrusaSlicer also writes "total filament used [g]", which is the same sum
+            Some((mm, Unit::Mm)) => estimate.filament_mm = estimate.filament_mm.or(Some(mm)),
+            Some((grams, Unit::Grams)) => estimate.filament_grams = estimate.filament_grams.or(Some(grams)),
+            None => {}
+        }
+    }
+    estimate
+}
+
+/// An amount such as "1.5m", "1234.5" with the unit of the key, or "4.2 g". Volumes are skipped
+fn quantity(value: &str, key_unit: Option<&str>) -> Option<(f64, Unit)> {
+    let split = value.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-')).unwrap_or(value.len());
+    let amount: f64 = value[..split].parse().ok()?;
+    let unit = match value[split..].trim() {
+        "" => key_unit?,
+        unit => unit
+    };
+    match unit.to_ascii_lowercase().as_str() {
+        "mm" => Some((amount, Unit::Mm)),
+        "cm" => Some((amount * 10.0, Unit::Mm)),
+        "m" => Some((amount * 1000.0, Unit::Mm)),
+        "g" => Some((amount, Unit::Grams)),
+        _ => None
+    }
+}
+
+/// Reads the estimate from the comments at the start and the end of the file
+pub fn read_estimate(path: &Path) -> std::io::Result<SlicerEstimate> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut head = Vec::new();
+    (&mut file).take(SCAN_BYTES).read_to_end(&mut head)?;
+    let estimate = parse_estimate(&String::from_utf8_lossy(&head));
+    if len <= SCAN_BYTES {
+        return Ok(estimate);
+    }
+    file.seek(SeekFrom::Start(len.saturating_sub(SCAN_BYTES).max(SCAN_BYTES)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    Ok(estimate.or(parse_estimate(&String::from_utf8_lossy(&tail))))
+}
+
+/// The printer's file in dir, by its name only so a file such as "0:/user/benchy.gcode" can't point outside of it
+pub fn sliced_file(dir: &Path, file: &str) -> Option<PathBuf> {
+    Path::new(file).file_name().map(|name| dir.join(name))
+}
+
+/// An image the slicer embedded, base64 between "; thumbnail begin 300x300 51234" and "; thumbnail end"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    pub format: ImageFormat,
+    pub image: Bytes
+}
+
+/// The largest PNG or JPEG thumbnail of the comments. PrusaSlicer's QOI thumbnails are skipped, browsers can't show them
+pub fn largest_thumbnail(text: &str) -> Option<Thumbnail> {
+    let mut largest: Option<Thumbnail> = None;
+    // Size and base64 of the thumbnail being read
+    let mut reading: Option<(u32, u32, String)> = None;
+    for line in text.lines() {
+        let Some(comment) = line.trim().strip_prefix(';').map(str::trim) else {
+            // Moves before the end, the thumbnail was cut off
+            reading = None;
+            continue;
+        };
+        match thumbnail_marker(comment) {
+            Some(Marker::Begin(width, height)) => reading = Some((width, height, String::new())),
+            Some(Marker::End) => {
+                let Some((width, height, encoded)) = reading.take() else { continue };
+                let Ok(image) = BASE64.decode(encoded) else { continue };
+                let Some(format) = ImageFormat::detect(&image) else { continue };
+                if largest.as_ref().is_none_or(|largest| area(width, height) > area(largest.width, largest.height)) {
+                    largest = Some(Thumbnail { width, height, format, image: image.into() });
+                }
+            },
+            None => if let Some((_, _, encoded)) = &mut reading {
+                encoded.push_str(comment);
+            }
+        }
+    }
+    largest
+}
+
+fn area(width: u32, height: u32) -> u64 {
+    width as u64 * height as u64
+}
+
+enum Marker {
+    Begin(u32, u32),
+    End
+}
+
+/// "thumbnail begin 300x300 51234", or thumbnail_JPG and thumbnail_PNG as PrusaSlicer writes them for other formats
+fn thumbnail_marker(comment: &str) -> Option<Marker> {
+    let mut words = comment.split_whitespace();
+    if !matches!(words.next()?, "thumbnail" | "thumbnail_PNG" | "thumbnail_JPG") {
+        return None;
+    }
+    match words.next()? {
+        "begin" => {
+            let (width, height) = words.next()?.split_once('x')?;
+            Some(Marker::Begin(width.parse().ok()?, height.parse().ok()?))
+        },
+        "end" => Some(Marker::End),
+        _ => None
+    }
+}
+
+/// Reads the largest thumbnail from the comments at the start of the file
+pub fn read_thumbnail(path: &Path) -> std::io::Result<Option<Thumbnail>> {
+    let mut head = Vec::new();
+    File::open(path)?.take(THUMBNAIL_SCAN_BYTES).read_to_end(&mut head)?;
+    Ok(largest_thumbnail(&String::from_utf8_lossy(&head)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURA: &str = ";FLAVOR:Marlin\n;TIME:5632\n;Filament used: 3.21542m\n;Layer height: 0.2\n;MINX:90.12\n;Generated with Cura_SteamEngine 5.7.1\nM140 S60\n";
+    const PRUSASLICER_END: &str = "M84 ; disable motors\n; filament used [mm] = 2543.21\n; filament used [cm3] = 6.12\n; filament used [g] = 7.58\n\
+        ; filament cost = 0.19\n; total filament used [g] = 7.58\n; estimated printing time (normal mode) = 1h 12m 3s\n";
+    const FLASHPRINT: &str = ";generated by ffslicer\n;machine_type: Adventurer 3 Series\n;filament_diameter0: 1.75\n;layer_height: 0.18\n\
+        ;filament_used: 4012.6mm, 0.0mm\n;estimated_build_time: 4210\nG90\n";
+
+    #[test]
+    fn parses_the_slicers_comments() {
+        assert_eq!(parse_estimate(CURA), SlicerEstimate { filament_mm: Some(3215.42), filament_grams: None });
+        assert_eq!(parse_estimate(PRUSASLICER_END), SlicerEstimate { filament_mm: Some(2543.21), filament_grams: Some(7.58) });
+        assert_eq!(parse_estimate(FLASHPRINT), SlicerEstimate { filament_mm: Some(4012.6), filament_grams: None });
+        assert_eq!(parse_estimate("G28\n;filament_diameter0: 1.75\n;Layer height: 0.2\n"), SlicerEstimate::default());
+    }
+
+    #[test]
+    fn weight_falls_back_to_the_length_as_pla() {
+        assert_eq!(parse_estimate(PRUSASLICER_END).grams(), Some(7.58));
+        let cura = parse_estimate(CURA).grams().unwrap();
+        assert!((cura - 9.58).abs() < 0.01, "{}", cura);
+        assert_eq!(parse_estimate("G28\n").grams(), None);
+    }
+
+    #[test]
+    fn reads_the_start_and_end_of_large_files() {
+        let path = std::env::temp_dir().join(format!("flashforge-gcode-{}.gcode", std::process::id()));
+        let moves = "G1 X10 Y10 E0.5\n".repeat(10_000);
+        std::fs::write(&path, format!("; generated by PrusaSlicer 2.7.1\n{}{}", moves, PRUSASLICER_END)).unwrap();
+        let estimate = read_estimate(&path);
+        std::fs::remove_file(&path).ok();
+        assert_eq!(estimate.unwrap().filament_grams, Some(7.58));
+    }
+
+    /// The block PrusaSlicer writes for an image
+    fn thumbnail_block(marker: &str, size: &str, image: &[u8]) -> String {
+        let encoded = BASE64.encode(image);
+        let lines: Vec<String> = encoded.as_bytes().chunks(78).map(|chunk| format!("; {}", String::from_utf8_lossy(chunk))).collect();
+        format!(";\n; {} begin {} {}\n{}\n; {} end\n;\n", marker, size, encoded.len(), lines.join("\n"), marker)
+    }
+
+    #[test]
+    fn the_largest_thumbnail_is_extracted() {
+        let small = b"\x89PNG\r\n\x1a\nsmall image";
+        let large = b"\x89PNG\r\n\x1a\nlarge image, long enough to be split over several lines of the comment block";
+        let text = format!("; generated by PrusaSlicer 2.7.1\n{}{}{}{}G28\n",
+                           thumbnail_block("thumbnail", "16x16", small),
+                           thumbnail_block("thumbnail", "300x300", large),
+                           thumbnail_block("thumbnail_QOI", "400x400", b"qoifimage"),
+                           thumbnail_block("thumbnail", "220x124", small));
+        let thumbnail = largest_thumbnail(&text).unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height, thumbnail.format), (300, 300, ImageFormat::Png));
+        assert_eq!(thumbnail.image.as_ref(), large);
+
+        let jpeg = b"\xff\xd8\xff\xe0 jpeg image";
+        let thumbnail = largest_thumbnail(&thumbnail_block("thumbnail_JPG", "300x300", jpeg)).unwrap();
+        assert_eq!((thumbnail.format, thumbnail.image.as_ref()), (ImageFormat::Jpeg, &jpeg[..]));
+    }
+
+    #[test]
+    fn cut_off_or_missing_thumbnails_are_none() {
+        assert_eq!(largest_thumbnail(CURA), None);
+        let block = thumbnail_block("thumbnail", "300x300", b"\x89PNG\r\n\x1a\nimage");
+        let (cut, _) = block.split_once("; thumbnail end").unwrap();
+        assert_eq!(largest_thumbnail(&format!("{}G28\n; thumbnail end\n", cut)), None);
+        // Not an image once decoded
+        assert_eq!(largest_thumbnail(&thumbnail_block("thumbnail", "300x300", b"text")), None);
+    }
+
+    #[test]
+    fn sliced_files_are_looked_up_by_name() {
+        let dir = Path::new("/srv/gcode");
+        assert_eq!(sliced_file(dir, "0:/user/benchy.gcode"), Some(dir.join("benchy.gcode")));
+        assert_eq!(sliced_file(dir, "../../etc/passwd"), Some(dir.join("passwd")));
+        assert_eq!(sliced_file(dir, ".."), None);
+    }
+}
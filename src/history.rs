@@ -0,0 +1,324 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use log::{debug, trace, warn};
+use rusqlite::{params, Connection};
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+use crate::config::HistoryConfig;
+use crate::models::{HistoryPoint, NormalizedTemperature};
+
+/// Records waiting to be written, polls are dropped rather than stalling the watch thread
+const QUEUE_SIZE: usize = 256;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long a query waits for the writer to finish, and the other way around
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One row per poll of a printer, the columns are the names of [HistoryMetric]
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS history (
+        t INTEGER NOT NULL,
+        printer TEXT NOT NULL,
+        nozzle_temp REAL,
+        nozzle_target REAL,
+        bed_temp REAL,
+        bed_target REAL,
+        chamber_temp REAL,
+        progress INTEGER,
+        layer INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS history_printer_t ON history (printer, t);";
+
+/// One poll of a printer
+#[derive(Debug)]
+struct HistoryRecord {
+    /// Unix timestamp in seconds
+    t: i64,
+    printer: String,
+    nozzle_temp: Option<f32>,
+    nozzle_target: Option<f32>,
+    bed_temp: Option<f32>,
+    bed_target: Option<f32>,
+    chamber_temp: Option<f32>,
+    progress: Option<u8>,
+    layer: Option<u32>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryMetric {
+    NozzleTemp,
+    NozzleTarget,
+    BedTemp,
+    BedTarget,
+    ChamberTemp,
+    Progress,
+    Layer
+}
+
+impl HistoryMetric {
+    pub const ALL: [HistoryMetric; 7] = [
+        HistoryMetric::NozzleTemp, HistoryMetric::NozzleTarget, HistoryMetric::BedTemp, HistoryMetric::BedTarget,
+        HistoryMetric::ChamberTemp, HistoryMetric::Progress, HistoryMetric::Layer
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HistoryMetric::NozzleTemp => "nozzle_temp",
+            HistoryMetric::NozzleTarget => "nozzle_target",
+            HistoryMetric::BedTemp => "bed_temp",
+            HistoryMetric::BedTarget => "bed_target",
+            HistoryMetric::ChamberTemp => "chamber_temp",
+            HistoryMetric::Progress => "progress",
+            HistoryMetric::Layer => "layer"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nozzle_temp" => Some(HistoryMetric::NozzleTemp),
+            "nozzle_target" => Some(HistoryMetric::NozzleTarget),
+            "bed_temp" => Some(HistoryMetric::BedTemp),
+            "bed_target" => Some(HistoryMetric::BedTarget),
+            "chamber_temp" => Some(HistoryMetric::ChamberTemp),
+            "progress" => Some(HistoryMetric::Progress),
+            "layer" => Some(HistoryMetric::Layer),
+            _ => None
+        }
+    }
+
+}
+
+/// Stores polls in the SQLite database at history.db_path. Writing and pruning is done by a thread of its own,
+/// recording is fire and forget so a slow disk can't stall polling
+#[derive(Clone)]
+pub struct History {
+    path: PathBuf,
+    tx: mpsc::Sender<HistoryRecord>
+}
+
+impl History {
+    pub fn start(config: HistoryConfig) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let path = config.db_path.clone();
+        let spawned = std::thread::Builder::new().name("history".to_string()).spawn(move || write(config, rx));
+        if let Err(e) = spawned {
+            warn!("history: could not start the writer, polls are not recorded: {}", e);
+        }
+        Self { path, tx }
+    }
+
+    /// Queues a record of the printer's temperatures (first extruder), progress and current layer
+    pub fn record(&self, printer: &str, temps: Option<&NormalizedTemperature>, progress: Option<u8>, layer: Option<u32>) {
+        let nozzle = temps.and_then(|t| t.extruders.first());
+        let bed = temps.and_then(|t| t.bed.as_ref());
+        let record = HistoryRecord {
+            t: OffsetDateTime::now_utc().unix_timestamp(),
+            printer: printer.to_string(),
+            nozzle_temp: nozzle.map(|m| m.current),
+            nozzle_target: nozzle.map(|m| m.target),
+            bed_temp: bed.map(|m| m.current),
+            bed_target: bed.map(|m| m.target),
+            chamber_temp: temps.and_then(|t| t.chamber.as_ref()).map(|m| m.current),
+            progress,
+            layer
+        };
+        if self.tx.try_send(record).is_err() {
+            trace!("history queue full or closed, dropping record");
+        }
+    }
+
+    /// Returns the metric for the printer between the given times, averaged over buckets of `resolution`
+    pub async fn query(&self, printer: &str, metric: HistoryMetric, since: OffsetDateTime, until: OffsetDateTime, resolution: Duration) -> Result<Vec<HistoryPoint>, String> {
+        let path = self.path.clone();
+        let printer = printer.to_string();
+        tokio::task::spawn_blocking(move || {
+            let connection = open(&path).map_err(|e| e.to_string())?;
+            query_points(&connection, &printer, metric, since.unix_timestamp(), until.unix_timestamp(), resolution)
+                .map_err(|e| e.to_string())
+        }).await.map_err(|e| e.to_string())?
+    }
+}
+
+/// Opens the database, creating it and the table when missing
+fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let connection = Connection::open(path)?;
+    connection.busy_timeout(BUSY_TIMEOUT)?;
+    // Queries don't wait for writes to finish
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.execute_batch(SCHEMA)?;
+    Ok(connection)
+}
+
+/// Writes the queued records until every [History] is dropped, one transaction per batch, pruning every [PRUNE_INTERVAL]
+fn write(config: HistoryConfig, mut rx: mpsc::Receiver<HistoryRecord>) {
+    let mut connection = match open(&config.db_path) {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("history: could not open {}, polls are not recorded: {}", config.db_path.display(), e);
+            return;
+        }
+    };
+    let mut pruned_at: Option<Instant> = None;
+    while let Some(record) = rx.blocking_recv() {
+        let mut records = vec![record];
+        while let Ok(record) = rx.try_recv() {
+            records.push(record);
+        }
+        if let Err(e) = insert_records(&mut connection, &records) {
+            warn!("history: failed to write {}: {}", config.db_path.display(), e);
+        }
+        if pruned_at.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+            let retention = i64::try_from(config.retention().as_secs()).unwrap_or(i64::MAX);
+            match prune_records(&connection, OffsetDateTime::now_utc().unix_timestamp().saturating_sub(retention)) {
+                Ok(pruned) => debug!("history: pruned {} records", pruned),
+                Err(e) => warn!("history: failed to prune {}: {}", config.db_path.display(), e)
+            }
+            pruned_at = Some(Instant::now());
+        }
+    }
+}
+
+fn insert_records(connection: &mut Connection, records: &[HistoryRecord]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut insert = transaction.prepare_cached("INSERT INTO history (t, printer, nozzle_temp, nozzle_target, bed_temp, bed_target, chamber_temp, progress, layer)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)")?;
+        for record in records {
+            insert.execute(params![record.t, record.printer, record.nozzle_temp, record.nozzle_target, record.bed_temp, record.bed_target,
+                record.chamber_temp, record.progress, record.layer])?;
+        }
+    }
+    transaction.commit()
+}
+
+/// The metric averaged over buckets of resolution, starting at multiples of it. Polls without the metric are left out
+fn query_points(connection: &Connection, printer: &str, metric: HistoryMetric, since: i64, until: i64, resolution: Duration) -> rusqlite::Result<Vec<HistoryPoint>> {
+    let resolution = i64::try_from(resolution.as_secs()).unwrap_or(i64::MAX).max(1);
+    // The column is one of the metric names, never user input
+    let mut select = connection.prepare(&format!("SELECT t - t % ?1 AS bucket, AVG({metric}) FROM history
+        WHERE printer = ?2 AND t >= ?3 AND t <= ?4 AND {metric} IS NOT NULL GROUP BY bucket ORDER BY bucket", metric = metric.name()))?;
+    let rows = select.query_map(params![resolution, printer, since, until], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?)))?;
+    let mut points = Vec::new();
+    for row in rows {
+        let (start, value) = row?;
+        if let Ok(time) = OffsetDateTime::from_unix_timestamp(start) {
+            points.push(HistoryPoint { time, value: value as f32 });
+        }
+    }
+    Ok(points)
+}
+
+/// Deletes the records older than the cutoff, returning how many were removed
+fn prune_records(connection: &Connection, cutoff: i64) -> rusqlite::Result<usize> {
+    connection.execute("DELETE FROM history WHERE t < ?1", params![cutoff])
+}
+
+/// Parses a resolution such as "60s", "5m", "1h" or a number of seconds
+pub fn parse_duration(input: &str) -> Option<Duration> {
+    let (number, multiplier) = match input.char_indices().last()? {
+        (i, 's') => (&input[..i], 1),
+        (i, 'm') => (&input[..i], 60),
+        (i, 'h') => (&input[..i], 60 * 60),
+        (i, 'd') => (&input[..i], 24 * 60 * 60),
+        _ => (input, 1)
+    };
+    let secs: u64 = number.parse().ok()?;
+    // None when it doesn't fit, which routes answer with a 400 like any other invalid duration
+    (secs > 0).then(|| secs.checked_mul(multiplier).map(Duration::from_secs)).flatten()
+}
+
+/// Writes nozzle temperatures and layers straight to the database, for the route tests
+#[cfg(test)]
+pub(crate) fn seed(path: &Path, polls: &[(i64, &str, f32, u32)]) {
+    std::fs::remove_file(path).ok();
+    let records: Vec<HistoryRecord> = polls.iter().map(|(t, printer, nozzle_temp, layer)| HistoryRecord {
+        t: *t, printer: printer.to_string(), nozzle_temp: Some(*nozzle_temp), nozzle_target: None, bed_temp: None, bed_target: None,
+        chamber_temp: None, progress: None, layer: Some(*layer)
+    }).collect();
+    insert_records(&mut open(path).unwrap(), &records).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(t: i64, printer: &str, nozzle_temp: Option<f32>) -> HistoryRecord {
+        HistoryRecord { t, printer: printer.to_string(), nozzle_temp, nozzle_target: None, bed_temp: None, bed_target: None,
+            chamber_temp: None, progress: Some(50), layer: None }
+    }
+
+    fn database(name: &str) -> (PathBuf, Connection) {
+        let path = std::env::temp_dir().join(format!("flashforge-history-{}-{}.db", name, std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let connection = open(&path).unwrap();
+        (path, connection)
+    }
+
+    #[test]
+    fn points_are_averaged_per_bucket() {
+        let (path, mut connection) = database("buckets");
+        insert_records(&mut connection, &[
+            record(60, "main", Some(200.0)),
+            record(90, "main", Some(210.0)),
+            // No nozzle temperature, left out of the average
+            record(100, "main", None),
+            record(100, "side", Some(20.0)),
+            record(130, "main", Some(215.0)),
+            record(300, "main", Some(220.0))
+        ]).unwrap();
+
+        let points = query_points(&connection, "main", HistoryMetric::NozzleTemp, 0, 200, Duration::from_secs(60)).unwrap();
+        let points: Vec<(i64, f32)> = points.iter().map(|point| (point.time.unix_timestamp(), point.value)).collect();
+        assert_eq!(points, [(60, 205.0), (120, 215.0)]);
+        let points = query_points(&connection, "main", HistoryMetric::Progress, 0, 1000, Duration::from_secs(1000)).unwrap();
+        assert_eq!((points.len(), points[0].value), (1, 50.0));
+        // A resolution past the timestamps is a single bucket
+        assert_eq!(query_points(&connection, "main", HistoryMetric::NozzleTemp, 0, 1000, Duration::MAX).unwrap().len(), 1);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn old_records_are_pruned() {
+        let (path, mut connection) = database("prune");
+        insert_records(&mut connection, &[record(100, "main", Some(200.0)), record(200, "main", Some(210.0)), record(300, "main", Some(220.0))]).unwrap();
+        assert_eq!(prune_records(&connection, 200).unwrap(), 1);
+        assert_eq!(prune_records(&connection, 200).unwrap(), 0);
+        let points = query_points(&connection, "main", HistoryMetric::NozzleTemp, 0, 1000, Duration::from_secs(1)).unwrap();
+        assert_eq!(points.len(), 2);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn polls_are_written_off_the_caller() {
+        let (path, _) = database("writer");
+        let history = History::start(HistoryConfig { db_path: path.clone(), retention_days: 7 });
+        history.record("main", None, Some(42), Some(3));
+        let now = OffsetDateTime::now_utc();
+        let mut points = Vec::new();
+        for _ in 0..100 {
+            points = history.query("main", HistoryMetric::Layer, now - Duration::from_secs(60), now + Duration::from_secs(60), Duration::from_secs(60)).await.unwrap();
+            if !points.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].value, 3.0);
+        drop(history);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn durations_have_a_unit() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("60s"), Some(Duration::from_secs(60)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("30d"), Some(Duration::from_secs(30 * 24 * 60 * 60)));
+        assert_eq!(parse_duration("0s"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("-5m"), None);
+        // Parses, but overflows once multiplied
+        assert_eq!(parse_duration("300000000000000000d"), None);
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+    }
+}
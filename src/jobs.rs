@@ -0,0 +1,159 @@
+//! The prints that ended, kept for [JOB_RETENTION] for GET /api/stats, in memory and with state.path across restarts
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use time::{Duration, OffsetDateTime, Time, UtcOffset};
+use crate::manager::PrinterContainer;
+use crate::models::{FarmStats, JobCounts, JobEta, JobRecord, JobResult, PrinterCounts, PrinterPrintHours};
+
+/// Covers the whole week, whatever the timezone
+pub const JOB_RETENTION: Duration = Duration::days(8);
+
+/// Shared by the watch thread and the stats route
+#[derive(Clone, Default)]
+pub struct JobLog(Arc<Mutex<Vec<JobRecord>>>);
+
+impl JobLog {
+    /// Starts with the jobs saved before the last restart
+    pub fn new(saved: Vec<JobRecord>) -> Self {
+        let log = Self(Arc::new(Mutex::new(saved)));
+        log.prune();
+        log
+    }
+
+    pub fn record(&self, record: JobRecord) {
+        self.0.lock().unwrap().push(record);
+        self.prune();
+    }
+
+    /// Oldest first
+    pub fn records(&self) -> Vec<JobRecord> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn prune(&self) {
+        let oldest = OffsetDateTime::now_utc() - JOB_RETENTION;
+        self.0.lock().unwrap().retain(|record| record.ended_at >= oldest);
+    }
+}
+
+/// What the stats need of a printer, read once
+pub struct PrinterNow {
+    pub name: String,
+    pub online: bool,
+    /// File, elapsed and remaining seconds of the print it is on
+    pub job: Option<(String, Option<u64>, Option<u64>)>
+}
+
+impl PrinterNow {
+    pub fn of(printer: &PrinterContainer) -> Self {
+        Self {
+            name: printer.name().to_string(),
+            online: printer.online(),
+            job: printer.is_printing().then(|| printer.job())
+                .flatten()
+                .map(|job| (job.file, job.elapsed_seconds, job.remaining_seconds_estimate))
+        }
+    }
+}
+
+/// Midnight of today and of this week's Monday, in the offset
+fn today_and_week(now: OffsetDateTime, offset: UtcOffset) -> (OffsetDateTime, OffsetDateTime) {
+    let today = now.to_offset(offset).replace_time(Time::MIDNIGHT);
+    (today, today - Duration::days(today.weekday().number_days_from_monday() as i64))
+}
+
+/// The part of the print that ended at ended_at after elapsed_seconds that was in the week
+fn seconds_since(week: OffsetDateTime, ended_at: OffsetDateTime, elapsed_seconds: Option<u64>) -> f64 {
+    let started = ended_at - Duration::seconds(elapsed_seconds.unwrap_or_default() as i64);
+    (ended_at - started.max(week)).as_seconds_f64().max(0.0)
+}
+
+/// Counts the jobs that ended today and this week along with what the printers are doing now.
+/// Printers that were removed but printed this week are still listed
+pub fn farm_stats(printers: &[PrinterNow], jobs: &[JobRecord], now: OffsetDateTime, offset: UtcOffset) -> FarmStats {
+    let (today, week) = today_and_week(now, offset);
+    let mut counts = JobCounts { completed_today: 0, completed_this_week: 0, failed_today: 0, failed_this_week: 0, cancelled_this_week: 0 };
+    let mut seconds: BTreeMap<&str, f64> = printers.iter().map(|printer| (printer.name.as_str(), 0.0)).collect();
+    for job in jobs.iter().filter(|job| job.ended_at >= week) {
+        let ended_today = job.ended_at >= today;
+        match job.result {
+            JobResult::Completed => {
+                counts.completed_this_week += 1;
+                counts.completed_today += u32::from(ended_today);
+            },
+            JobResult::Failed => {
+                counts.failed_this_week += 1;
+                counts.failed_today += u32::from(ended_today);
+            },
+            JobResult::Cancelled => counts.cancelled_this_week += 1
+        }
+        *seconds.entry(job.printer.as_str()).or_default() += seconds_since(week, job.ended_at, job.elapsed_seconds);
+    }
+    let mut fastest_eta: Option<JobEta> = None;
+    for printer in printers {
+        let Some((file, elapsed_seconds, remaining_seconds)) = &printer.job else { continue };
+        *seconds.entry(printer.name.as_str()).or_default() += seconds_since(week, now, *elapsed_seconds);
+        if let Some(remaining_seconds) = *remaining_seconds {
+            if fastest_eta.as_ref().is_none_or(|fastest| remaining_seconds < fastest.remaining_seconds) {
+                fastest_eta = Some(JobEta {
+                    printer: printer.name.clone(),
+                    file: file.clone(),
+                    remaining_seconds,
+                    eta: now + Duration::seconds(remaining_seconds as i64)
+                });
+            }
+        }
+    }
+    FarmStats {
+        today_since: today,
+        week_since: week,
+        printers: PrinterCounts {
+            total: printers.len(),
+            online: printers.iter().filter(|printer| printer.online).count(),
+            printing: printers.iter().filter(|printer| printer.job.is_some()).count()
+        },
+        jobs: counts,
+        print_hours_this_week: seconds.into_iter()
+            .map(|(printer, seconds)| PrinterPrintHours { printer: printer.to_string(), hours: (seconds / 36.0).round() / 100.0 })
+            .collect(),
+        fastest_eta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::{datetime, offset};
+
+    fn job(printer: &str, result: JobResult, ended_at: OffsetDateTime, elapsed_seconds: u64) -> JobRecord {
+        JobRecord { printer: printer.to_string(), file: "cube.gx".to_string(), result, ended_at, elapsed_seconds: Some(elapsed_seconds) }
+    }
+
+    #[test]
+    fn counts_today_and_this_week_in_the_timezone() {
+        // Wednesday 01:30 at +02:00, the week started on Monday at 22:00 UTC the day before
+        let now = datetime!(2026-10-13 23:30 UTC);
+        let printers = [
+            PrinterNow { name: "main".to_string(), online: true, job: Some(("benchy.gx".to_string(), Some(1800), Some(600))) },
+            PrinterNow { name: "second".to_string(), online: true, job: Some(("cube.gx".to_string(), None, Some(300))) },
+            PrinterNow { name: "offline".to_string(), online: false, job: None }
+        ];
+        let jobs = [
+            // Before the week, and one that started before it
+            job("main", JobResult::Completed, datetime!(2026-10-11 21:00 UTC), 3600),
+            job("main", JobResult::Completed, datetime!(2026-10-11 23:00 UTC), 7200),
+            job("main", JobResult::Failed, datetime!(2026-10-13 21:00 UTC), 900),
+            job("main", JobResult::Completed, datetime!(2026-10-13 22:30 UTC), 1800),
+            job("removed", JobResult::Cancelled, datetime!(2026-10-12 12:00 UTC), 3600)
+        ];
+        let stats = farm_stats(&printers, &jobs, now, offset!(+2));
+        assert_eq!((stats.today_since, stats.week_since), (datetime!(2026-10-14 00:00 +2), datetime!(2026-10-12 00:00 +2)));
+        assert_eq!(stats.printers, PrinterCounts { total: 3, online: 2, printing: 2 });
+        assert_eq!(stats.jobs, JobCounts { completed_today: 1, completed_this_week: 2, failed_today: 0, failed_this_week: 1, cancelled_this_week: 1 });
+        // 1h of the print that started before the week, 15m, 30m and 30m of the one it is on
+        let hours: Vec<(&str, f64)> = stats.print_hours_this_week.iter().map(|printer| (printer.printer.as_str(), printer.hours)).collect();
+        assert_eq!(hours, [("main", 2.25), ("offline", 0.0), ("removed", 1.0), ("second", 0.0)]);
+        let fastest = stats.fastest_eta.unwrap();
+        assert_eq!((fastest.printer.as_str(), fastest.remaining_seconds, fastest.eta), ("second", 300, datetime!(2026-10-13 23:35 UTC)));
+    }
+}
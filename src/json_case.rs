@@ -0,0 +1,122 @@
+//! Renames the fields of JSON responses to camelCase, for http.json_case = "camel"
+use std::io::Cursor;
+use log::warn;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::{Request, Response};
+use serde_json::Value;
+
+/// Rewrites the keys of every JSON object in API responses, errors included. Grafana's and Moonraker's routes
+/// answer in the formats of those protocols and are left as is
+pub struct CamelCaseJson;
+
+#[rocket::async_trait]
+impl Fairing for CamelCaseJson {
+    fn info(&self) -> Info {
+        Info { name: "camelCase JSON", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let path = request.uri().path();
+        if !path.starts_with("/api/") || path.starts_with("/api/grafana/") {
+            return;
+        }
+        if response.content_type() != Some(ContentType::JSON) || response.body().preset_size().is_none() {
+            return;
+        }
+        let body = match response.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("could not read response to rename: {}", e);
+                return;
+            }
+        };
+        let body = match serde_json::from_slice::<Value>(&body) {
+            Ok(mut value) => {
+                rename_keys(&mut value);
+                serde_json::to_vec(&value).unwrap_or(body)
+            },
+            Err(_) => body
+        };
+        response.set_sized_body(body.len(), Cursor::new(body));
+    }
+}
+
+fn rename_keys(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            *object = std::mem::take(object).into_iter()
+                .map(|(key, mut value)| {
+                    rename_keys(&mut value);
+                    (to_camel_case(&key), value)
+                })
+                .collect();
+        },
+        Value::Array(entries) => entries.iter_mut().for_each(rename_keys),
+        _ => {}
+    }
+}
+
+/// firmware_version -> firmwareVersion, keys without underscores such as T0 are unchanged
+pub fn to_camel_case(key: &str) -> String {
+    let mut camel = String::with_capacity(key.len());
+    let mut upper_next = false;
+    for c in key.chars() {
+        if c == '_' && !camel.is_empty() {
+            upper_next = true;
+        } else if upper_next {
+            camel.extend(c.to_uppercase());
+            upper_next = false;
+        } else {
+            camel.push(c);
+        }
+    }
+    camel
+}
+
+/// firmwareVersion -> firmware_version, so ?fields= takes the names in either case
+pub fn to_snake_case(key: &str) -> String {
+    let mut snake = String::with_capacity(key.len() + 4);
+    let mut previous_lower = false;
+    for c in key.chars() {
+        if c.is_ascii_uppercase() && previous_lower {
+            snake.push('_');
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
+        }
+        previous_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_nested_keys() {
+        let mut value = serde_json::json!({
+            "firmware_version": "v1.3.7",
+            "build_volume": {"x": 150},
+            "extruders": [{"current_c": 210.0}],
+            "T0": {"current": 210.0}
+        });
+        rename_keys(&mut value);
+        assert_eq!(value, serde_json::json!({
+            "firmwareVersion": "v1.3.7",
+            "buildVolume": {"x": 150},
+            "extruders": [{"currentC": 210.0}],
+            "T0": {"current": 210.0}
+        }));
+    }
+
+    #[test]
+    fn converts_between_cases() {
+        assert_eq!(to_camel_case("same_serial_as"), "sameSerialAs");
+        assert_eq!(to_camel_case("_private"), "_private");
+        assert_eq!(to_snake_case("sameSerialAs"), "same_serial_as");
+        assert_eq!(to_snake_case("T0"), "T0");
+        assert_eq!(to_snake_case("current_file"), "current_file");
+    }
+}
@@ -0,0 +1,152 @@
+//! How long each printer takes to answer, by command, from the write of the request to the end of its response
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::warn;
+use crate::models::ExchangeLatency;
+
+/// Exchanges older than this are left out of the percentiles
+pub const LATENCY_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Samples kept per command, so a client hammering a route doesn't grow the window without bound
+const MAX_SAMPLES: usize = 1000;
+/// Exchanges slower than this are logged until [Latencies::set_slow_after]
+const DEFAULT_SLOW_AFTER: Duration = Duration::from_secs(2);
+
+/// Kept by the command task for each printer
+pub struct Latencies {
+    printer: String,
+    /// None never logs
+    slow_after: Mutex<Option<Duration>>,
+    commands: Mutex<BTreeMap<String, CommandSamples>>
+}
+
+#[derive(Default)]
+struct CommandSamples {
+    /// Oldest first, with when the exchange ended
+    recent: VecDeque<(Instant, Duration)>,
+    /// Since startup, for the Prometheus sum and count
+    count: u64,
+    seconds: f64
+}
+
+/// The window of a command along with its totals since startup
+pub struct CommandLatency {
+    pub window: ExchangeLatency,
+    pub count: u64,
+    pub seconds: f64
+}
+
+impl Latencies {
+    pub fn new(printer: String) -> Self {
+        Self { printer, slow_after: Mutex::new(Some(DEFAULT_SLOW_AFTER)), commands: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// From printers.<id>.slow_exchange_ms, zero never logs
+    pub fn set_slow_after(&self, slow_after: Duration) {
+        *self.slow_after.lock().unwrap() = (!slow_after.is_zero()).then_some(slow_after);
+    }
+
+    /// Records an answered exchange, logging it if it was slow. `gcode` is the request as sent, such as "~M104 S200 T0"
+    pub fn record(&self, gcode: &str, took: Duration) {
+        let command = command_of(gcode);
+        if self.slow_after.lock().unwrap().is_some_and(|slow_after| took >= slow_after) {
+            warn!("printer/{} took {}ms to answer {}, check its network connection", self.printer, took.as_millis(), command);
+        }
+        let now = Instant::now();
+        let mut commands = self.commands.lock().unwrap();
+        let samples = commands.entry(command).or_default();
+        samples.prune(now);
+        if samples.recent.len() == MAX_SAMPLES {
+            samples.recent.pop_front();
+        }
+        samples.recent.push_back((now, took));
+        samples.count += 1;
+        samples.seconds += took.as_secs_f64();
+    }
+
+    /// Every command sent since startup, the window empty for those not sent in the last [LATENCY_WINDOW]
+    pub fn commands(&self) -> Vec<CommandLatency> {
+        let now = Instant::now();
+        let mut commands = self.commands.lock().unwrap();
+        commands.iter_mut().map(|(command, samples)| {
+            samples.prune(now);
+            let mut recent: Vec<Duration> = samples.recent.iter().map(|(_, took)| *took).collect();
+            recent.sort();
+            CommandLatency {
+                window: ExchangeLatency {
+                    command: command.clone(),
+                    samples: recent.len(),
+                    p50_ms: percentile(&recent, 0.5),
+                    p95_ms: percentile(&recent, 0.95),
+                    max_ms: recent.last().map(|took| millis(*took))
+                },
+                count: samples.count,
+                seconds: samples.seconds
+            }
+        }).collect()
+    }
+
+    /// The commands sent in the last [LATENCY_WINDOW]
+    pub fn recent(&self) -> Vec<ExchangeLatency> {
+        self.commands().into_iter().map(|command| command.window).filter(|window| window.samples > 0).collect()
+    }
+}
+
+impl CommandSamples {
+    fn prune(&mut self, now: Instant) {
+        while self.recent.front().is_some_and(|(at, _)| now.duration_since(*at) > LATENCY_WINDOW) {
+            self.recent.pop_front();
+        }
+    }
+}
+
+/// The G-code without its parameters, "M104" for "~M104 S200 T0"
+fn command_of(gcode: &str) -> String {
+    gcode.trim().trim_start_matches('~').split_whitespace().next().unwrap_or_default().to_ascii_uppercase()
+}
+
+/// Nearest rank of the sorted samples
+fn percentile(sorted: &[Duration], quantile: f64) -> Option<f64> {
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.max(1) - 1).map(|took| millis(*took))
+}
+
+/// Rounded to a tenth, answers on a LAN take a few milliseconds
+fn millis(took: Duration) -> f64 {
+    (took.as_secs_f64() * 10_000.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_kept_per_command() {
+        let latencies = Latencies::new("main".to_string());
+        for ms in 1..=100 {
+            latencies.record("~M119", Duration::from_millis(ms));
+        }
+        latencies.record("~M104 S200 T0", Duration::from_millis(40));
+        latencies.record("~M104 S0 T0", Duration::from_micros(12_340));
+
+        let recent = latencies.recent();
+        assert_eq!(recent, vec![
+            ExchangeLatency { command: "M104".to_string(), samples: 2, p50_ms: Some(12.3), p95_ms: Some(40.0), max_ms: Some(40.0) },
+            ExchangeLatency { command: "M119".to_string(), samples: 100, p50_ms: Some(50.0), p95_ms: Some(95.0), max_ms: Some(100.0) }
+        ]);
+        let totals = latencies.commands();
+        assert_eq!(totals[1].count, 100);
+        assert!((totals[1].seconds - 5.05).abs() < 1e-9);
+    }
+
+    #[test]
+    fn old_exchanges_leave_the_window() {
+        let latencies = Latencies::new("main".to_string());
+        latencies.record("~M119", Duration::from_millis(5));
+        latencies.commands.lock().unwrap().get_mut("M119").unwrap().prune(Instant::now() + LATENCY_WINDOW + Duration::from_secs(1));
+        assert!(latencies.recent().is_empty());
+        // Still counted since startup
+        let totals = latencies.commands();
+        assert_eq!((totals[0].window.samples, totals[0].window.max_ms, totals[0].count), (0, None, 1));
+    }
+}
@@ -0,0 +1,223 @@
+use std::time::Instant;
+use log::debug;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::route::{Handler, Outcome};
+use rocket::{Data, Request, Response, Route};
+use serde_json::{Map, Value};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::field::{Empty, Field, Visit};
+use tracing::span::Record;
+use tracing::{info_span, Event, Instrument, Span, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::field::RecordFields;
+use crate::config::LogFormat;
+use crate::metrics;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Sets up the global subscriber, filtered by RUST_LOG and defaulting to info for this crate
+pub fn init(format: LogFormat) {
+    let filter = tracing_subscriber::filter::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| format!("{}=info", env!("CARGO_CRATE_NAME")).into());
+    let registry = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Text => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry.with(tracing_subscriber::fmt::layer().fmt_fields(JsonFields).event_format(JsonFormat)).init()
+    }
+}
+
+/// One JSON object per line with the event's fields and those of the spans it happened in
+struct JsonFormat;
+
+/// Keeps span fields as a JSON object, so [JsonFormat] doesn't have to parse the text format
+struct JsonFields;
+
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl JsonVisitor {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // Metadata of events from the log crate, already used for the event's own target
+        if !field.name().starts_with("log.") {
+            self.0.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+}
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'writer>, fields: R) -> std::fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        fields.record(&mut visitor);
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    fn add_fields(&self, current: &'writer mut FormattedFields<Self>, fields: &Record<'_>) -> std::fmt::Result {
+        let mut visitor = JsonVisitor(serde_json::from_str(&current.fields).unwrap_or_default());
+        fields.record(&mut visitor);
+        current.fields = Value::Object(visitor.0).to_string();
+        Ok(())
+    }
+}
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat where S: Subscriber + for<'a> LookupSpan<'a> {
+    fn format_event(&self, ctx: &FmtContext<'_, S, JsonFields>, mut writer: Writer<'_>, event: &Event<'_>) -> std::fmt::Result {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+
+        let mut line = Map::new();
+        line.insert("timestamp".to_string(), OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default().into());
+        line.insert("level".to_string(), metadata.level().as_str().into());
+        line.insert("target".to_string(), metadata.target().into());
+        line.insert("fields".to_string(), Value::Object(fields.0));
+        let spans: Vec<Value> = ctx.event_scope().into_iter().flat_map(|scope| scope.from_root()).map(|span| {
+            let extensions = span.extensions();
+            let fields = extensions.get::<FormattedFields<JsonFields>>()
+                .and_then(|fields| serde_json::from_str(&fields.fields).ok())
+                .unwrap_or(Value::Object(Map::new()));
+            serde_json::json!({ "name": span.name(), "fields": fields })
+        }).collect();
+        line.insert("spans".to_string(), spans.into());
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+/// Span of the request, created by [RequestTracing]
+struct RequestSpan {
+    id: String,
+    span: Span,
+    started: Instant
+}
+
+impl RequestSpan {
+    /// For requests that never went through [RequestTracing::on_request]
+    fn none() -> Self {
+        Self { id: String::new(), span: Span::none(), started: Instant::now() }
+    }
+}
+
+/// Opens a span for every request with a request id, which is returned in the X-Request-Id header.
+/// An id sent by a proxy in the same header is kept
+pub struct RequestTracing;
+
+#[rocket::async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info { name: "Request tracing", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let id = request.headers().get_one(REQUEST_ID_HEADER)
+            .filter(|id| !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_graphic()))
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+        let span = info_span!("request", id = %id, method = %request.method(), path = %request.uri().path(),
+            printer_id = Empty, status = Empty, duration_ms = Empty);
+        request.local_cache(|| RequestSpan { id, span, started: Instant::now() });
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_span = request.local_cache(RequestSpan::none);
+        if request_span.id.is_empty() {
+            return;
+        }
+        response.set_raw_header(REQUEST_ID_HEADER, request_span.id.clone());
+        let span = &request_span.span;
+        span.record("status", response.status().code);
+        span.record("duration_ms", request_span.started.elapsed().as_millis() as u64);
+        span.in_scope(|| debug!("request finished"));
+    }
+}
+
+/// Runs the route's handler in the request's span, recording the printer id of /<printer_id>/ routes
+#[derive(Clone)]
+struct Traced {
+    handler: Box<dyn Handler>,
+    /// Index of the <printer_id> segment after the mount point
+    printer_segment: Option<usize>
+}
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let span = request.local_cache(RequestSpan::none).span.clone();
+        if let Some(Ok(printer_id)) = self.printer_segment.and_then(|i| request.param::<&str>(i)) {
+            span.record("printer_id", printer_id);
+        }
+        metrics::tracking_printer_requests(request, self.handler.handle(request, data)).instrument(span).await
+    }
+}
+
+/// Wraps the routes so everything their handlers log is attributed to the request, call before mounting
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter().map(|mut route| {
+        let printer_segment = route.uri.path().split('/')
+            .filter(|segment| !segment.is_empty())
+            .position(|segment| segment == "<printer_id>");
+        route.handler = Box::new(Traced { handler: route.handler.clone(), printer_segment });
+        route
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use rocket::{get, routes};
+
+    #[get("/<printer_id>/status")]
+    fn status(printer_id: &str) -> String {
+        printer_id.to_string()
+    }
+
+    #[tokio::test]
+    async fn responses_have_a_request_id() {
+        let rocket = rocket::build().mount("/api/printers", traced(routes![status])).attach(RequestTracing);
+        let client = Client::untracked(rocket).await.unwrap();
+
+        let response = client.get("/api/printers/main/status").dispatch().await;
+        let id = response.headers().get_one(REQUEST_ID_HEADER).unwrap().to_string();
+        assert_eq!(id.len(), 16);
+        assert_eq!(response.into_string().await.unwrap(), "main");
+
+        let response = client.get("/api/printers/main/status").dispatch().await;
+        assert_ne!(response.headers().get_one(REQUEST_ID_HEADER), Some(id.as_str()));
+
+        let response = client.get("/missing").header(Header::new(REQUEST_ID_HEADER, "from-proxy")).dispatch().await;
+        assert_eq!(response.headers().get_one(REQUEST_ID_HEADER), Some("from-proxy"));
+    }
+}
@@ -0,0 +1,259 @@
+//! The SMTP session emails are sent through. Nothing is connected while the config is loaded, so a mail server that
+//! is down at the same time doesn't keep the API from starting: a background task connects, retrying with a backoff,
+//! and sends and the self-test connect on their own when there is no session
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, error, info, warn};
+use mail_send::mail_builder::MessageBuilder;
+use mail_send::{Credentials, SmtpClient, SmtpClientBuilder};
+use time::OffsetDateTime;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::client::TlsStream;
+use crate::config::{EmailConfig, EmailEncryption};
+use crate::models::{SmtpState, SmtpStatus};
+
+/// Wait before the first retry, doubled after every failure
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+type Client = SmtpClient<TlsStream<TcpStream>>;
+
+pub struct Mailer {
+    smtp: EmailConfig,
+    /// None while there is no working session
+    client: Mutex<Option<Client>>,
+    status: std::sync::Mutex<SmtpStatus>,
+    /// The background task is already retrying
+    retrying: AtomicBool
+}
+
+/// Why a connection failed, and whether trying again can help
+struct ConnectError {
+    message: String,
+    permanent: bool
+}
+
+impl Mailer {
+    pub fn new(smtp: EmailConfig) -> Self {
+        Self {
+            smtp,
+            client: Mutex::new(None),
+            status: std::sync::Mutex::new(SmtpStatus { state: SmtpState::Connecting, error: None, failed_attempts: 0, next_attempt_at: None }),
+            retrying: AtomicBool::new(false)
+        }
+    }
+
+    pub fn status(&self) -> SmtpStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Connects in a background task, retrying failures that can pass from [RETRY_BACKOFF] up to [MAX_RETRY_BACKOFF]
+    pub fn connect_in_background(self: &Arc<Self>) {
+        if self.retrying.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let mailer = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = RETRY_BACKOFF;
+            // Started by an email or the self-test that just failed, so the first attempt waits too
+            let mut wait = mailer.status().state == SmtpState::Retrying;
+            loop {
+                if wait {
+                    mailer.status.lock().unwrap().next_attempt_at = Some(OffsetDateTime::now_utc() + backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+                }
+                wait = true;
+                {
+                    let mut client = mailer.client.lock().await;
+                    // An email or the self-test connected in the meantime
+                    if client.is_some() {
+                        break;
+                    }
+                    match mailer.connect().await {
+                        Ok(connected) => {
+                            *client = Some(connected);
+                            break;
+                        },
+                        Err(e) if e.permanent => break,
+                        Err(_) => {}
+                    }
+                }
+            }
+            mailer.retrying.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Sends the message, connecting first if there is no session. Servers close idle sessions of the long-lived
+    /// client, so on failure it reconnects and retries once
+    pub async fn send(self: &Arc<Self>, message: MessageBuilder<'_>) -> Result<(), String> {
+        if let Some(error) = self.disabled() {
+            return Err(format!("email notifications are disabled: {}", error));
+        }
+        let mut client = self.client.lock().await;
+        if let Some(connected) = client.as_mut() {
+            match connected.send(message.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("Failed to send email, reconnecting to SMTP server: {}", e)
+            }
+        }
+        *client = None;
+        let mut connected = self.connect_or_retry().await?;
+        let result = connected.send(message).await.map_err(|e| e.to_string());
+        *client = Some(connected);
+        result
+    }
+
+    /// Sends a NOOP on the session, connecting a new one when there is none or it was closed. Also tried when
+    /// disabled, so the self-test shows what the server answers
+    pub async fn noop(self: &Arc<Self>) -> Result<(), String> {
+        let mut client = self.client.lock().await;
+        if let Some(connected) = client.as_mut() {
+            if connected.noop().await.is_ok() {
+                return Ok(());
+            }
+        }
+        *client = None;
+        let mut connected = self.connect_or_retry().await?;
+        let result = connected.noop().await.map_err(|e| format!("SMTP: NOOP failed: {}", e));
+        *client = Some(connected);
+        result
+    }
+
+    /// Ends the session, if there is a working one
+    pub async fn close(&self) {
+        if let Some(client) = self.client.lock().await.take() {
+            if let Err(e) = client.quit().await {
+                debug!("SMTP: quit failed: {}", e);
+            }
+        }
+    }
+
+    /// The error that disabled emails, if the server refused the config
+    fn disabled(&self) -> Option<String> {
+        let status = self.status.lock().unwrap();
+        (status.state == SmtpState::Disabled).then(|| status.error.clone().unwrap_or_default())
+    }
+
+    /// Connects, leaving it to the background task to keep trying when that fails for a reason that can pass
+    async fn connect_or_retry(self: &Arc<Self>) -> Result<Client, String> {
+        self.connect().await.map_err(|e| {
+            if !e.permanent {
+                self.connect_in_background();
+            }
+            e.message
+        })
+    }
+
+    /// Opens a session, updating the status with the result
+    async fn connect(&self) -> Result<Client, ConnectError> {
+        let smtp = &self.smtp;
+        let result = SmtpClientBuilder::new(smtp.host.as_str(), smtp.port)
+            .implicit_tls(smtp.encryption == EmailEncryption::Tls)
+            .credentials(Credentials::new(smtp.user.as_str(), smtp.password.as_str()))
+            // Without it a server that doesn't answer holds the connection for an hour
+            .timeout(smtp.timeout())
+            .connect()
+            .await
+            .map_err(|e| ConnectError {
+                permanent: is_permanent(&e),
+                message: format!("SMTP: Could not connect to {}:{}: {}", smtp.host, smtp.port, e)
+            });
+        let mut status = self.status.lock().unwrap();
+        status.next_attempt_at = None;
+        match &result {
+            Ok(_) => {
+                if status.state != SmtpState::Connected {
+                    info!("SMTP: connected to {}:{}", smtp.host, smtp.port);
+                }
+                *status = SmtpStatus { state: SmtpState::Connected, error: None, failed_attempts: 0, next_attempt_at: None };
+            },
+            Err(e) if e.permanent => {
+                error!("{}. Email notifications are DISABLED until [smtp] is fixed and the server is restarted", e.message);
+                status.state = SmtpState::Disabled;
+                status.error = Some(e.message.clone());
+                status.failed_attempts += 1;
+            },
+            Err(e) => {
+                warn!("{}, retrying in the background", e.message);
+                status.state = SmtpState::Retrying;
+                status.error = Some(e.message.clone());
+                status.failed_attempts += 1;
+            }
+        }
+        result
+    }
+}
+
+/// Errors that retrying won't fix: rejected credentials, TLS that can't be set up and 5xx replies
+fn is_permanent(error: &mail_send::Error) -> bool {
+    match error {
+        mail_send::Error::AuthenticationFailed(response) | mail_send::Error::UnexpectedReply(response) => response.code >= 500,
+        mail_send::Error::Auth(_) | mail_send::Error::Tls(_) | mail_send::Error::InvalidTLSName | mail_send::Error::MissingCredentials
+            | mail_send::Error::UnsupportedAuthMechanism | mail_send::Error::MissingStartTls => true,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{install_crypto_provider, unused_port};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    fn config(port: u16) -> EmailConfig {
+        install_crypto_provider();
+        EmailConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            encryption: EmailEncryption::None,
+            user: "printer@example.com".to_string(),
+            password: "secret".to_string(),
+            timeout_seconds: 2
+        }
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_server_is_retried_in_the_background() {
+        let mailer = Arc::new(Mailer::new(config(unused_port().await)));
+        assert_eq!(mailer.status().state, SmtpState::Connecting);
+        mailer.connect_in_background();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while mailer.status().next_attempt_at.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        let status = mailer.status();
+        assert_eq!((status.state, status.failed_attempts), (SmtpState::Retrying, 1));
+        assert!(status.error.unwrap().contains("Could not connect to 127.0.0.1"));
+        // Sends still try on their own
+        let error = mailer.noop().await.unwrap_err();
+        assert!(error.contains("Could not connect"), "{}", error);
+        assert_eq!(mailer.status().failed_attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn a_server_refusing_service_disables_emails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else { return };
+                stream.write_all(b"554 5.7.1 No service for you\r\n").await.ok();
+                BufReader::new(stream).lines().next_line().await.ok();
+            }
+        });
+        let mailer = Arc::new(Mailer::new(config(port)));
+        let error = mailer.noop().await.unwrap_err();
+        assert!(error.contains("No service for you"), "{}", error);
+        assert_eq!(mailer.status().state, SmtpState::Disabled);
+        // Not retried, and emails are refused without connecting
+        assert!(!mailer.retrying.load(Ordering::SeqCst));
+        let error = mailer.send(MessageBuilder::new().from("printer@example.com").to("me@example.com").text_body("done")).await.unwrap_err();
+        assert!(error.starts_with("email notifications are disabled"), "{}", error);
+        assert_eq!(mailer.status().failed_attempts, 1);
+    }
+}
@@ -1,72 +1,365 @@
+mod cli;
 mod models;
-mod socket;
 mod printer;
 mod util;
 mod config;
 mod manager;
+mod mqtt;
+mod notifications;
+mod discovery;
+mod moonraker;
+mod history;
+mod filament;
+mod jobs;
+mod gcode;
+mod thumbnails;
+mod audit;
+mod state;
+mod logging;
+mod rate_limit;
+mod compression;
+mod json_case;
+mod metrics;
 mod routes;
+mod selftest;
+mod version;
+mod console;
+mod latency;
+mod errors;
+mod validated;
+mod access_log;
+#[cfg(feature = "smtp")]
+mod mailer;
+#[cfg(feature = "camera")]
+mod camera;
+#[cfg(unix)]
+mod unix_socket;
+#[cfg(test)]
+mod test_support;
 
 use std::sync::{Arc};
-use log::{info};
-use rocket::{catch, catchers, launch, routes, serde::json::Json};
+use std::time::Duration;
+use log::{error, info, warn};
+use rocket::{catch, catchers, routes, serde::json::Json, Build, Request, Rocket};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::figment::Figment;
+use rocket::figment::Profile;
+use rocket::figment::providers::{Env, Format, Toml};
 use tokio::sync::Mutex;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use crate::config::{ConfigManager};
+use crate::config::{ConfigManager, JsonCase};
 use crate::models::{GenericError};
-use crate::manager::Printers;
+use crate::errors::ErrorCode;
+use crate::manager::{PrinterManager, Printers, PROGRESS_CHECK_INTERVAL};
+use crate::logging::{traced, RequestTracing};
+use crate::rate_limit::{limited, RateLimiter};
+use crate::compression::Compression;
+use crate::json_case::CamelCaseJson;
+use crate::metrics::{Metrics, RequestMetrics};
+use crate::audit::{AuditLog, AuditLogger};
+use crate::access_log::AccessLogger;
 use crate::routes::api;
+use crate::util::{host_port, AuthLimiter, PrinterBusy, RetryAfter, TooManyRequests};
+
+#[catch(400)]
+fn error_400(request: &Request) -> Json<GenericError> {
+    Json(validated::rejected_body(request).unwrap_or_else(|| ErrorCode::BadRequest.with_message("The request could not be parsed")))
+}
+
+#[catch(401)]
+fn error_401() -> Json<GenericError> {
+    Json(ErrorCode::PasswordRequired.with_message("Authorization required"))
+}
+
+#[catch(403)]
+fn error_403() -> Json<GenericError> {
+    Json(ErrorCode::Forbidden.with_message("Forbidden"))
+}
 
 #[catch(404)]
 fn error_404() -> Json<GenericError> {
-    Json(GenericError {
-        error: "NOT_FOUND".to_string(),
-        message: Some("Route not found".to_string()),
-    })
+    Json(ErrorCode::NotFound.with_message("Route not found"))
 }
 
-#[launch]
-async fn rocket() -> _ {
+#[catch(413)]
+fn error_413(request: &Request) -> Json<GenericError> {
+    Json(validated::rejected_body(request).unwrap_or_else(|| ErrorCode::PayloadTooLarge.with_message("The request body is too large")))
+}
+
+#[catch(422)]
+fn error_422(request: &Request) -> Json<GenericError> {
+    Json(validated::rejected_body(request).unwrap_or_else(|| ErrorCode::UnprocessableRequest.with_message("The request body or a query parameter is missing fields or has the wrong type")))
+}
+
+#[catch(500)]
+fn error_500() -> Json<GenericError> {
+    Json(ErrorCode::InternalError.with_message("Internal server error"))
+}
+
+#[catch(429)]
+fn error_429(request: &Request) -> TooManyRequests {
+    TooManyRequests(*request.local_cache(|| RetryAfter::AuthLockout(Duration::ZERO)))
+}
+
+#[catch(503)]
+fn error_503(request: &Request) -> PrinterBusy {
+    PrinterBusy(request.local_cache(|| None).clone())
+}
+
+/// Any other status, so clients never get Rocket's HTML pages
+#[catch(default)]
+fn error_default(status: Status, _: &Request) -> (Status, Json<GenericError>) {
+    ErrorCode::HttpError.response_with(status, status.reason_lossy())
+}
+
+#[rocket::main]
+async fn main() {
     tokio_rustls::rustls::crypto::ring::default_provider().install_default().unwrap();
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::filter::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| format!("{}=info", env!("CARGO_CRATE_NAME")).into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    let config = Arc::new(ConfigManager::load().await);
+    // Before logging is set up, so only the subcommand's output is on stdout
+    cli::run_if_requested().await;
+    logging::init(ConfigManager::logging().format);
+    info!("{}", version::info());
+
+    if std::env::args().any(|arg| arg == "--check-config") {
+        ConfigManager::check_config();
+    }
+
+    let config = Arc::new(ConfigManager::load());
+    log_startup_summary(&config);
     let mut printers = Printers::new(config.clone());
     for (id, printer_config) in config.printers() {
-        printers.add_printer(id.to_string(), printer_config.ip)
+        // Config::validate already rejects printers without an address or with the same host
+        let Some(host) = printer_config.host() else {
+            error!("printers.{:?}: ip or host is required", id);
+            continue;
+        };
+        if let Err(e) = printers.add_printer(id.to_string(), host, printer_config.api_port, printer_config.idle_timeout()) {
+            error!("printers.{:?}: {}", id, e);
+        }
     }
     let printers = Arc::new(Mutex::new(printers));
     Printers::start_watch_thread(printers.clone()).await;
+    Printers::start_digest(printers.clone()).await;
+    if config.discovery().auto_add {
+        tokio::spawn(Printers::add_discovered_printers(printers.clone()));
+    }
 
-    let rk_config = rocket::Config {
-        address: std::net::Ipv4Addr::new(0, 0, 0, 0).into(),
-        port: 8080,
-        ..Default::default()
-    };
+    #[cfg(unix)]
+    let unix_socket = config.http().unix_socket.as_ref().map(|path| {
+        unix_socket::UnixSocket::bind(path, config.http()).unwrap_or_else(|e| {
+            error!("http.unix_socket: {}", e);
+            std::process::exit(1);
+        })
+    });
+
+    // Same layering as rocket::Config::figment(), with [http] from config.toml under Rocket.toml and ROCKET_* env vars.
+    // The port is not listened on when only the unix socket is
+    let mut figment = Figment::from(rocket::Config::default())
+        .merge(("address", config.http().address))
+        .merge(("port", config.http().port().unwrap_or_default()))
+        .merge(("limits.json", config.http().max_json_kb * 1024));
+    if let Some(tls) = &config.http().tls {
+        figment = figment
+            .merge(("tls.certs", &tls.cert_path))
+            .merge(("tls.key", &tls.key_path));
+    }
+    let figment = figment
+        .merge(Toml::file(Env::var_or("ROCKET_CONFIG", "Rocket.toml")).nested())
+        .merge(Env::prefixed("ROCKET_").ignore(&["PROFILE"]).global())
+        .select(Profile::from_env_or("ROCKET_PROFILE", rocket::Config::DEFAULT_PROFILE));
+
+    let serves_tcp = config.http().port().is_some();
+    let rocket = app(figment, config, printers.clone());
+    #[cfg(unix)]
+    if let Some(unix_socket) = unix_socket {
+        unix_socket.serve(rocket, serves_tcp).await;
+        Printers::shutdown(printers).await;
+        return;
+    }
+    // Dropping the error logs it, as #[launch] does
+    let _ = rocket.launch().await;
+}
+
+/// What was loaded, as fields so they are kept apart with logging.format = "json"
+fn log_startup_summary(config: &ConfigManager) {
+    let path = config.path().map(|path| std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())).unwrap_or_default();
+    let mut printers: Vec<_> = config.printers().iter().collect();
+    printers.sort_by_key(|(id, _)| *id);
+    tracing::info!(
+        config = %path.display(),
+        printers = printers.len(),
+        poll_interval_secs = PROGRESS_CHECK_INTERVAL.as_secs(),
+        smtp = config.smtp().is_some(),
+        webhooks = config.webhooks().len(),
+        auth = config.auth().is_some(),
+        camera = cfg!(feature = "camera"),
+        "Config loaded"
+    );
+    for (id, printer) in printers {
+        tracing::info!(printer = %id, host = %printer.host().unwrap_or_default(), port = printer.api_port, "Printer configured");
+    }
+    match config.auth() {
+        None => warn!("No [auth] is configured, anyone who can reach the server can control the printers"),
+        Some(auth) if !auth.password_for_read && !auth.password_for_write =>
+            warn!("auth.password_for_read and auth.password_for_write are off, anyone who can reach the server can control the printers"),
+        Some(_) => {}
+    }
+}
+
+/// Mounts every route, separate from [rocket] so tests get the same server without the startup work
+pub(crate) fn app(figment: Figment, config: Arc<ConfigManager>, printers: PrinterManager) -> Rocket<Build> {
+    let (max_failures, failure_window) = config.auth()
+        .map(|auth| (auth.max_failures, Duration::from_secs(auth.failure_window_secs)))
+        .unwrap_or((u32::MAX, Duration::ZERO));
+    let limiter = Arc::new(AuthLimiter::new(max_failures, failure_window));
+
+    let moonraker_enabled = config.moonraker().is_some();
+    let debug_enabled = config.debug().is_some();
+    let audit_log = config.audit().map(AuditLog::new);
+    let access_log = config.http().access_log.as_ref().map(AccessLogger::new);
+    let shutdown_printers = printers.clone();
+    let rate_limiter = config.http().rate_limit.as_ref().map(RateLimiter::new);
+    let compression = config.http().compression.enabled.then(|| Compression::new(&config.http().compression));
+    let camel_case = config.http().json_case == JsonCase::Camel;
+    #[cfg(feature = "camera")]
+    let placeholder = routes::camera::SnapshotPlaceholder::load(config.camera());
+    let metrics = Arc::new(Metrics::default());
+    let request_metrics = RequestMetrics::new(metrics.clone(), Duration::from_millis(config.http().slow_request_ms));
+    let serves_tcp = config.http().port().is_some();
 
-    let r = rocket::build()
-        .configure(&rk_config)
+    let rocket = rocket::custom(figment)
         .manage(config)
+        .manage(limiter)
         .manage(printers)
-        .mount("/api/printers", routes![
+        .manage(metrics)
+        .mount("/", traced(limited(routes![
+            routes::ui::index,
+            routes::ui::printer,
+            routes::ui::dashboard_js,
+            routes::ui::dashboard_css,
+            routes::metrics::metrics,
+        ])))
+        .mount("/api/printers", traced(limited(routes![
             api::list_printers_names,
             api::list_printers,
+            api::refresh_printers,
+            api::refresh_printer,
+            api::patch_printer,
             api::get_printer_info,
             api::get_printer_temps,
             api::get_printer_progress,
+            api::query_printer,
             api::get_printer_status,
             api::get_printer_head_position,
+            api::get_printer_history,
+            api::get_printer_job,
+            api::get_printer_job_thumbnail,
+            api::wait_for_printer_change,
+            api::printer_events,
+            api::get_printer_health,
+            api::get_printer_notification_state,
+            api::get_printer_console,
+            api::set_printer_maintenance,
             api::set_printer_temp,
-            api::get_printer_snapshot,
-            api::get_printer_camera,
-        ])
-        .register("/", catchers![error_404]);
-    info!("Server ready and listening on :{}", rk_config.port);
-    r
-}
\ No newline at end of file
+            api::set_printer_led,
+            api::set_printer_speed,
+            api::set_printer_flow,
+            routes::macros::run_printer_macro,
+        ])))
+        .mount("/api/grafana", traced(limited(routes![
+            routes::grafana::health,
+            routes::grafana::search,
+            routes::grafana::query,
+        ])))
+        .mount("/", traced(limited(routes![
+            routes::ready::readyz,
+        ])))
+        .mount("/api", traced(limited(routes![
+            routes::version::get_version,
+            routes::macros::list_macros,
+            routes::errors::list_errors,
+        ])))
+        .mount("/api/stats", traced(limited(routes![
+            routes::stats::get_farm_stats,
+            routes::stats::get_filament_stats,
+        ])))
+        .mount("/api/discover", traced(limited(routes![
+            routes::discovery::discover_printers,
+        ])))
+        .mount("/api/admin", traced(limited(routes![
+            routes::admin::self_test,
+        ])))
+        .mount("/api/notifications", traced(limited(routes![
+            routes::notifications::list_deliveries,
+            routes::notifications::list_destinations,
+            routes::notifications::set_destination_enabled,
+            routes::notifications::get_history,
+            routes::notifications::send_test_notification,
+        ])))
+        .register("/", catchers![error_400, error_401, error_403, error_404, error_413, error_422, error_429, error_500, error_503,
+            error_default])
+        .attach(RequestTracing)
+        .attach(request_metrics)
+        .attach(AdHoc::on_shutdown("Release printers", |_| Box::pin(async move {
+            Printers::shutdown(shutdown_printers).await;
+        })))
+        .attach(AdHoc::on_liftoff("Log address", move |rocket| Box::pin(async move {
+            // The unix socket logs its own path
+            if !serves_tcp {
+                return;
+            }
+            let config = rocket.config();
+            let scheme = if config.tls_enabled() { "https" } else { "http" };
+            info!("Server ready and listening on {}://{}", scheme, host_port(&config.address.to_string(), config.port));
+        })));
+    #[cfg(feature = "camera")]
+    let rocket = rocket.manage(placeholder)
+        .mount("/api/printers", traced(limited(routes![
+            routes::camera::get_printer_snapshot,
+            routes::camera::get_printer_snapshot_gif,
+            routes::camera::get_printer_camera,
+        ])));
+    // Renamed before compressing
+    let rocket = if camel_case { rocket.attach(CamelCaseJson) } else { rocket };
+    let rocket = match compression {
+        Some(compression) => rocket.attach(compression),
+        None => rocket
+    };
+    // Routes only check the budget when the limiter is managed
+    let rocket = match rate_limiter {
+        Some(rate_limiter) => rocket.manage(rate_limiter),
+        None => rocket
+    };
+    // Off by default, so the extra endpoints are only exposed to those that want them
+    let rocket = if moonraker_enabled {
+        rocket.mount("/", traced(limited(routes![
+            routes::moonraker::server_info,
+            routes::moonraker::printer_info,
+            routes::moonraker::list_objects,
+            routes::moonraker::query_objects,
+        ])))
+    } else {
+        rocket
+    };
+    let rocket = match audit_log {
+        Some(audit_log) => rocket.attach(AuditLogger::new(audit_log.clone()))
+            .manage(audit_log)
+            .mount("/api/admin", traced(limited(routes![
+                routes::admin::get_audit_log,
+            ]))),
+        None => rocket
+    };
+    // Attached last, so it sees the responses as they are sent
+    let rocket = match access_log {
+        Some(access_log) => rocket.attach(access_log),
+        None => rocket
+    };
+    if debug_enabled {
+        rocket.mount("/api/printers", traced(limited(routes![
+            routes::debug::get_raw_response,
+            routes::debug::record_responses,
+        ])))
+    } else {
+        rocket
+    }
+}
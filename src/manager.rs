@@ -1,206 +1,568 @@
-use crate::config::{ConfigManager};
-use crate::printer::Printer;
-
-use log::{debug, error, trace};
-use serde_json::json;
-use std::collections::HashMap;
-use std::fmt::Write;
-use std::net::IpAddr;
+use crate::config::{default_idle_timeout_secs, ConfigManager, ThermalConfig};
+use crate::discovery;
+use crate::filament::FilamentLog;
+use crate::history::History;
+use crate::jobs::JobLog;
+use crate::models::{DiscoveredPrinter, JobRecord, JobResult, MachineStatus, MaintenanceMode, PrinterEvent, PrinterLabels, PrinterNotificationState, PrinterTemperature, WebhookDelivery};
+use crate::mqtt::MqttClient;
+use crate::notifications::{digest, CapturedState, EndedJob, NotificationJob, NotificationQueue, NotificationType, Notifier, Snapshot};
+use crate::printer::{Printer, PRINTER_API_PORT};
+use crate::state::{NotifiedFile, SavedPrinter, SavedState};
+use crate::thumbnails::Thumbnails;
+use crate::util::host_port;
+
+use log::{debug, error, info, trace, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc};
 use std::time::Duration;
-use mail_send::mail_builder::MessageBuilder;
-use mail_send::mail_builder::mime::BodyPart;
-use reqwest::multipart::Part;
+use time::OffsetDateTime;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
-static PROGRESS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the watcher thread polls every printer
+pub static PROGRESS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 pub type PrinterManager = Arc<Mutex<Printers>>;
 
-#[derive(Debug, Clone, Copy)]
-pub enum NotificationType {
-    PrintComplete
+/// Printers are handles to their own command task, so they are shared without a lock
+pub type PrinterContainer = Arc<Printer>;
+
+pub struct Printers {
+    printers: HashMap<String, PrinterContainer>,
+    config: Arc<ConfigManager>,
+    notification_sent: HashMap<String, NotifiedFile>, // If printer (key) has value, then a print done notification has been submitted for file (value)
+    error_notified: HashMap<String, String>, // If printer (key) has value, then an error notification has been submitted for machine status (value)
+    thermal_state: HashMap<String, HashMap<String, ThermalState>>, // Per printer (key), the state of each temperature sensor (inner key)
+    /// What the last poll of each printer (key) reported, to publish a [PrinterEvent] when it changes
+    polled_flags: HashMap<String, PolledFlags>,
+    /// The print of each printer (key) being followed, to tell how it ended
+    watched_jobs: HashMap<String, WatchedJob>,
+    notifier: Arc<Notifier>,
+    notification_queue: Arc<NotificationQueue>,
+    mqtt: Option<MqttClient>,
+    history: Option<History>,
+    filament: Option<FilamentLog>,
+    jobs: JobLog,
+    thumbnails: Option<Thumbnails>,
+    /// Printer state saved before the last restart, handed to the printer when it is added
+    saved_printers: HashMap<String, SavedPrinter>,
+    /// Last state written to state.path, so it is only written when something changed
+    saved_state: SavedState,
+    watch_task: Option<JoinHandle<()>>,
+    digest_task: Option<JoinHandle<()>>,
+}
+
+/// The parts of a status poll that are published as events when they change
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PolledFlags {
+    led: Option<bool>,
+    filament_runout: Option<bool>
 }
 
-impl NotificationType {
-    pub fn get_subject(&self, printer: &Printer) -> String {
-        match self {
-            NotificationType::PrintComplete => format!("Print complete on {}", printer.name()),
-            #[allow(unreachable_patterns)]
-            _ => printer.name().to_string()
+impl PolledFlags {
+    fn of(printer: &Printer) -> Self {
+        Self { led: printer.led(), filament_runout: printer.filament_runout() }
+    }
+
+    /// Events from the previous poll to this one, none for the first poll or flags the printer doesn't report
+    fn changes(previous: Option<&PolledFlags>, current: &PolledFlags) -> Vec<PrinterEvent> {
+        let Some(previous) = previous else { return Vec::new() };
+        let changed = |previous: Option<bool>, current: Option<bool>| match (previous, current) {
+            (Some(previous), Some(current)) if previous != current => Some(current),
+            _ => None
+        };
+        let mut events = Vec::new();
+        if let Some(led) = changed(previous.led, current.led) {
+            events.push(PrinterEvent::Led(led));
+        }
+        if let Some(runout) = changed(previous.filament_runout, current.filament_runout) {
+            events.push(PrinterEvent::FilamentRunout(runout));
+        }
+        events
+    }
+}
+
+/// Prints left at this percentage or more count as completed, the last poll can be up to a minute before the end
+const COMPLETED_PERCENT: u8 = 99;
+/// Prints first seen below this are announced as started
+const STARTED_PERCENT: u8 = 5;
+
+/// A print followed by the watcher thread from poll to poll
+#[derive(Debug, Clone, PartialEq)]
+struct WatchedJob {
+    file: String,
+    progress_percent: u8,
+    elapsed_seconds: Option<u64>,
+    /// The printer reported an error state during the print
+    errored: bool,
+    /// The printer did not answer the last poll
+    unreachable: bool
+}
+
+/// What a poll that the printer answered reported
+struct JobPoll {
+    file: Option<String>,
+    machine_status: Option<MachineStatus>,
+    progress_percent: Option<u8>,
+    elapsed_seconds: Option<u64>
+}
+
+impl JobPoll {
+    fn of(printer: &Printer) -> Self {
+        Self {
+            file: printer.current_file(),
+            machine_status: printer.machine_status(),
+            progress_percent: printer.progress_percent(),
+            elapsed_seconds: printer.job().and_then(|job| job.elapsed_seconds)
         }
     }
+}
+
+#[derive(Debug, PartialEq)]
+enum JobOutcome {
+    Completed,
+    Cancelled,
+    /// With a note when the printer was unreachable right before, such as after a power cycle
+    Failed(Option<String>)
+}
 
-    pub fn get_message(&self, printer: &Printer) -> String {
+impl JobOutcome {
+    fn result(&self) -> JobResult {
         match self {
-            NotificationType::PrintComplete => {
-                let status = printer.get_status().unwrap();
-                let mut str = String::new();
-                writeln!(str, "File: {}", status.current_file.unwrap_or("(None)".to_string())).unwrap();
-                writeln!(str, "IP: {}", printer.ip()).unwrap();
-                // TODO: more data?
-                str
+            JobOutcome::Completed => JobResult::Completed,
+            JobOutcome::Cancelled => JobResult::Cancelled,
+            JobOutcome::Failed(_) => JobResult::Failed
+        }
+    }
+}
+
+impl WatchedJob {
+    /// Follows the printer's print from the previous poll to this one, None when the printer did not answer.
+    /// Returns the print that ended since and how, completed_file is the file a completion was already sent for
+    fn follow(watched: &mut Option<WatchedJob>, poll: Option<&JobPoll>, completed_file: Option<&str>) -> Option<(WatchedJob, JobOutcome)> {
+        let Some(poll) = poll else {
+            if let Some(job) = watched {
+                job.unreachable = true;
+            }
+            return None;
+        };
+        let idle = poll.machine_status.as_ref().is_some_and(|status| status.is_idle());
+        let errored = poll.machine_status.as_ref().is_some_and(|status| status.is_error());
+        let mut ended = None;
+        if let Some(mut job) = watched.take() {
+            if poll.file.as_deref() == Some(job.file.as_str()) {
+                job.progress_percent = poll.progress_percent.unwrap_or(job.progress_percent);
+                job.elapsed_seconds = poll.elapsed_seconds.or(job.elapsed_seconds);
             }
-            #[allow(unreachable_patterns)]
-            _ => "".to_string()
+            if poll.file.as_deref() == Some(job.file.as_str()) && !idle {
+                job.errored |= errored;
+                job.unreachable = false;
+                *watched = Some(job);
+            } else {
+                let outcome = job.outcome(poll.machine_status.as_ref(), completed_file);
+                ended = Some((job, outcome));
+            }
+        }
+        if let (None, Some(file), false) = (&watched, &poll.file, idle) {
+            *watched = Some(WatchedJob {
+                file: file.clone(),
+                progress_percent: poll.progress_percent.unwrap_or_default(),
+                elapsed_seconds: poll.elapsed_seconds,
+                errored,
+                unreachable: false
+            });
+        }
+        ended
+    }
+
+    /// How the print ended, from the status of the first poll without it
+    fn outcome(&self, machine_status: Option<&MachineStatus>, completed_file: Option<&str>) -> JobOutcome {
+        if self.errored || machine_status.is_some_and(|status| status.is_error()) {
+            JobOutcome::Failed(None)
+        } else if completed_file == Some(self.file.as_str()) || machine_status == Some(&MachineStatus::BuildingCompleted)
+            || self.progress_percent >= COMPLETED_PERCENT {
+            JobOutcome::Completed
+        } else if self.unreachable {
+            let status = machine_status.map(|status| status.to_string()).unwrap_or_else(|| "without a status".to_string());
+            JobOutcome::Failed(Some(format!("The printer was unreachable at {}% and came back {}, it was likely restarted", self.progress_percent, status)))
+        } else {
+            JobOutcome::Cancelled
         }
     }
+
+    /// Prints first seen further along were already running, such as when the server restarted
+    fn just_started(&self) -> bool {
+        self.progress_percent < STARTED_PERCENT
+    }
+
+    fn ended(self) -> EndedJob {
+        EndedJob { file: self.file, progress_percent: self.progress_percent, elapsed_seconds: self.elapsed_seconds }
+    }
 }
 
-pub type PrinterContainer = Arc<Mutex<Printer>>;
+#[derive(Debug, Clone, Default)]
+struct ThermalState {
+    /// Number of consecutive polls the sensor has deviated from its target
+    deviation_samples: u32,
+    /// An alert was sent and the sensor has not recovered yet
+    alerted: bool
+}
 
-pub struct Printers {
-    printers: HashMap<String, PrinterContainer>,
-    config: Arc<ConfigManager>,
-    notification_sent: HashMap<String, String>, // If printer (key) has value, then a print done notification has been submitted for file (value
+/// Sends the event to the printer's event stream and MQTT
+fn publish_event(printer: &Printer, mqtt: Option<&MqttClient>, event: PrinterEvent) {
+    debug!("printer/{} event {:?}", printer.name(), event);
+    if let Some(mqtt) = mqtt {
+        mqtt.publish_event(printer, &event);
+    }
+    printer.publish_event(event);
+}
+
+/// Evaluates a printer's temperatures against the thermal limits, updating the per sensor state.
+/// Returns the alert or recovery notifications that should be sent
+fn check_thermal(config: &ThermalConfig, temps: &PrinterTemperature, states: &mut HashMap<String, ThermalState>) -> Vec<NotificationType> {
+    let mut notifications = Vec::new();
+    for (sensor, measurement) in &temps.0 {
+        let state = states.entry(sensor.clone()).or_default();
+        let max = match sensor.chars().next() {
+            Some('B') => config.max_bed,
+            Some('T') => config.max_nozzle,
+            Some('C') => config.max_chamber,
+            _ => None
+        };
+        // Heaters that are off (target of 0) are only checked against the absolute limit
+        let deviation = (measurement.current - measurement.target).abs();
+        if measurement.target > 0.0 && deviation > config.deviation_celsius {
+            state.deviation_samples += 1;
+        } else {
+            state.deviation_samples = 0;
+        }
+        let reason = if let Some(max) = max.filter(|max| measurement.current > *max) {
+            Some(format!("current {:.1}°C exceeds the limit of {:.1}°C", measurement.current, max))
+        } else if state.deviation_samples >= config.consecutive_samples {
+            Some(format!("current {:.1}°C is {:.1}°C off target {:.1}°C for {} polls", measurement.current, deviation, measurement.target, state.deviation_samples))
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) if !state.alerted => {
+                state.alerted = true;
+                notifications.push(NotificationType::TemperatureAlert { sensor: sensor.clone(), reason, measurement: measurement.clone() });
+            },
+            None if state.alerted && state.deviation_samples == 0 => {
+                state.alerted = false;
+                notifications.push(NotificationType::TemperatureRecovered { sensor: sensor.clone(), measurement: measurement.clone() });
+            },
+            _ => {}
+        }
+    }
+    notifications
 }
 
 impl Printers {
     pub fn new(config: Arc<ConfigManager>) -> Printers {
+        let mqtt = config.mqtt()
+            .map(|mqtt| MqttClient::start(mqtt.clone(), config.printers().keys().cloned().collect()));
+        let notifier = Arc::new(Notifier::new(config.clone()));
+        let history = config.history().map(|history| History::start(history.clone()));
+        let gcode_dir = config.gcode().map(|gcode| gcode.dir.as_path());
+        let filament = config.filament().map(|filament| FilamentLog::new(filament, gcode_dir));
+        let thumbnails = config.gcode().map(Thumbnails::new);
+        let saved = config.state().map(|state| SavedState::load(&state.path)).unwrap_or_default();
         Self {
             printers: HashMap::new(),
+            notification_queue: Arc::new(NotificationQueue::start(notifier.clone())),
+            notifier,
             config,
-            notification_sent: HashMap::new()
+            notification_sent: saved.notification_sent.clone(),
+            error_notified: saved.error_notified.clone(),
+            thermal_state: HashMap::new(),
+            polled_flags: HashMap::new(),
+            watched_jobs: HashMap::new(),
+            mqtt,
+            history,
+            filament,
+            jobs: JobLog::new(saved.jobs.clone()),
+            thumbnails,
+            saved_printers: saved.printers.clone(),
+            saved_state: saved,
+            watch_task: None,
+            digest_task: None
         }
     }
 
     pub async fn start_watch_thread(manager: PrinterManager) {
         debug!("Starting watch thread at interval {:?}", PROGRESS_CHECK_INTERVAL);
-        tokio::task::spawn(async move {
+        let watch_manager = manager.clone();
+        let task = tokio::task::spawn(async move {
+            let manager = watch_manager;
+            // Serial numbers already warned about, until they are no longer shared
+            let mut warned_serials = HashSet::new();
             tokio::time::sleep(PROGRESS_CHECK_INTERVAL).await;
             loop {
                 // Grab list of printers
                 trace!("Getting list of printers");
-                let sent_notifications = {
-                    let manager = manager.lock().await;
-                    let (printers, mut sent_notifications) = {
-                        let lock = &manager;
-                        (lock.printers(), lock.notification_sent.clone())
-                    };
+                // Only cloned out of the manager, so requests are not blocked while printers are polled
+                let (printers, config, mqtt, history, filament, jobs, thumbnails, queue, mut sent_notifications, mut error_notified, mut thermal_state, mut polled_flags, mut watched_jobs) = {
+                    let lock = manager.lock().await;
+                    (lock.printers(), lock.config.clone(), lock.mqtt.clone(), lock.history.clone(), lock.filament.clone(), lock.jobs.clone(), lock.thumbnails.clone(), lock.notification_queue.clone(),
+                     lock.notification_sent.clone(), lock.error_notified.clone(), lock.thermal_state.clone(), lock.polled_flags.clone(),
+                     lock.watched_jobs.clone())
+                };
 
-                    trace!("Checking printers");
-                    for printer in printers {
-                        let mut printer = printer.lock().await;
-                        if printer.refresh_status().is_ok() {
-                            if printer.current_file().is_none() { continue; }
-                            let prog = printer.get_progress().unwrap();
-                            // Check if progress is 100%
-                            trace!("printer {} layer={:?} byte={:?}", printer.name(), prog.layer, prog.byte);
-                            if prog.layer.0 >= prog.layer.1 {
-                                // Get current file from status
-                                let status = printer.get_status().unwrap();
-                                if status.current_file.is_none() {
-                                    continue;
+                trace!("Checking printers");
+                for container in printers {
+                    let printer = container.as_ref();
+                    if printer.in_maintenance() {
+                        trace!("skipping printer {}, it is in maintenance", printer.name());
+                        continue;
+                    }
+                    let online = printer.refresh_status().await.is_ok();
+                    // So the GIF of a print error has the frames before it
+                    #[cfg(feature = "camera")]
+                    if config.attach_gif() && printer.capabilities().camera {
+                        printer.camera().record(online && printer.current_file().is_some());
+                    }
+                    if let Some(flags) = online.then(|| PolledFlags::of(printer)) {
+                        for event in PolledFlags::changes(polled_flags.get(printer.name()), &flags) {
+                            publish_event(printer, mqtt.as_ref(), event);
+                        }
+                        polled_flags.insert(printer.name().to_string(), flags);
+                    }
+                    let mut watched = watched_jobs.remove(printer.name());
+                    let watched_file = watched.as_ref().map(|job| job.file.clone());
+                    let poll = online.then(|| JobPoll::of(printer));
+                    let completed_file = sent_notifications.get(printer.name()).map(|sent| sent.file.as_str());
+                    if let Some((job, outcome)) = WatchedJob::follow(&mut watched, poll.as_ref(), completed_file) {
+                        info!("printer/{} print of {} ended: {:?}", printer.name(), job.file, outcome);
+                        jobs.record(JobRecord {
+                            printer: printer.name().to_string(),
+                            file: job.file.clone(),
+                            result: outcome.result(),
+                            ended_at: OffsetDateTime::now_utc(),
+                            elapsed_seconds: job.elapsed_seconds
+                        });
+                        if let Some(filament) = &filament {
+                            // The last poll of a completed print can be a few percent before the end
+                            let progress_percent = if outcome == JobOutcome::Completed { 100 } else { job.progress_percent };
+                            filament.job_ended(printer.name(), &job.file, progress_percent).await;
+                        }
+                        match outcome {
+                            JobOutcome::Completed if completed_file != Some(job.file.as_str()) => {
+                                queue.enqueue(NotificationJob::new(&container, NotificationType::PrintComplete));
+                                sent_notifications.insert(printer.name().to_string(), NotifiedFile::now(job.file));
+                            },
+                            JobOutcome::Completed => {},
+                            JobOutcome::Cancelled => queue.enqueue(NotificationJob::new(&container, NotificationType::PrintCancelled(job.ended()))),
+                            JobOutcome::Failed(note) => queue.enqueue(NotificationJob::new(&container, NotificationType::PrintFailed { job: job.ended(), note }))
+                        }
+                    }
+                    if let Some(watched) = watched {
+                        if watched_file.as_deref() != Some(watched.file.as_str()) {
+                            if let Some(filament) = &filament {
+                                filament.job_started(printer.name(), &watched.file, printer.progress().map(|progress| progress.byte.total)).await;
+                            }
+                            if watched.just_started() {
+                                let mut job = NotificationJob::new(&container, NotificationType::PrintStarted);
+                                // What is being printed, instead of a camera frame of the bed
+                                if let Some(thumbnails) = &thumbnails {
+                                    job.snapshot = thumbnails.of(&watched.file).await.ok().map(Snapshot::thumbnail);
                                 }
-                                // Check if we have already sent a notification
-                                let current_file = status.current_file.unwrap();
-                                let has_notified = sent_notifications.get(printer.name()).unwrap_or(&"".to_string()) == &current_file;
-
-                                if !has_notified {
-                                    debug!("will notify for printer {}", printer.name());
-                                    manager.send_notification(&mut printer, NotificationType::PrintComplete).await;
-                                    // has_sent.insert(id.clone(), current_file);
+                                queue.enqueue(job);
+                            }
+                        }
+                        watched_jobs.insert(printer.name().to_string(), watched);
+                    }
+                    if online && config.watch_head_position() {
+                        if let Err(e) = printer.refresh_head_position().await {
+                            debug!("printer/{} head position poll failed: {}", printer.name(), e);
+                        }
+                    }
+                    if online && printer.passed_first_layers(config.watch_first_layers()) {
+                        publish_event(printer, mqtt.as_ref(), PrinterEvent::FirstLayersComplete(config.watch_first_layers()));
+                    }
+                    let thermal_config = config.thermal();
+                    let temps = if online && (thermal_config.is_some() || mqtt.is_some() || history.is_some()) {
+                        printer.get_temperatures().await.ok()
+                    } else {
+                        None
+                    };
+                    if let Some(mqtt) = &mqtt {
+                        mqtt.publish_printer(printer, online, temps.as_ref());
+                    }
+                    if let (Some(history), true) = (&history, online) {
+                        let layer = printer.progress().map(|progress| progress.layer.current);
+                        history.record(printer.name(), temps.as_ref().map(|t| t.normalize()).as_ref(), printer.progress_percent(), layer);
+                    }
+                    if online {
+                        let machine_status = printer.machine_status();
+                        let is_error = machine_status.as_ref().is_some_and(|status| status.is_error());
+                        if !is_error {
+                            error_notified.remove(printer.name());
+                        }
+                        if let (Some(thermal_config), Some(temps)) = (thermal_config, &temps) {
+                            let states = thermal_state.entry(printer.name().to_string()).or_default();
+                            for notification in check_thermal(thermal_config, temps, states) {
+                                debug!("will notify thermal for printer {}: {:?}", printer.name(), notification);
+                                queue.enqueue(NotificationJob::new(&container, notification));
+                            }
+                        }
+                        if printer.current_file().is_none() { continue; }
+                        // Check if printer went into an error state mid-print, only notifying once per state
+                        if let Some(machine_status) = machine_status.as_ref().filter(|_| is_error).map(|status| status.to_string()) {
+                            if error_notified.get(printer.name()) != Some(&machine_status) {
+                                debug!("will notify error for printer {} status={}", printer.name(), machine_status);
+                                // Reported as they were when the error was seen, not once the job is sent
+                                let mut job = NotificationJob::new(&container, NotificationType::PrintError);
+                                job.state = Some(CapturedState::of(printer));
+                                queue.enqueue(job);
+                                error_notified.insert(printer.name().to_string(), machine_status);
+                            }
+                            continue;
+                        }
+                        // A paused or busy print is not done, even if all layers were sent
+                        if machine_status.as_ref().is_some_and(|status| !status.is_printing() && !status.is_idle()) {
+                            continue;
+                        }
+                        let Some(prog) = printer.progress() else { continue };
+                        // Check if progress is 100%
+                        trace!("printer {} layer={:?} byte={:?}", printer.name(), prog.layer, prog.byte);
+                        // Models that only report bytes have no layer total
+                        let done = if prog.layer.total > 0 { prog.layer.is_done() } else { prog.byte.is_done() };
+                        if done {
+                            // Get current file from status
+                            let Ok(status) = printer.get_status().await else { continue };
+                            let Some(current_file) = status.current_file else { continue };
+                            // Check if we have already sent a notification
+                            let has_notified = sent_notifications.get(printer.name()).is_some_and(|sent| sent.file == current_file);
 
-                                    let current_file = printer.current_file().as_ref().unwrap().clone();
-                                    sent_notifications.insert(printer.name().to_string(), current_file);
-                                }
+                            if !has_notified {
+                                debug!("will notify for printer {}", printer.name());
+                                queue.enqueue(NotificationJob::new(&container, NotificationType::PrintComplete));
+                                sent_notifications.insert(printer.name().to_string(), NotifiedFile::now(current_file));
                             }
                         }
                     }
-                    sent_notifications
-                };
+                }
                 {
                     let mut manager = manager.lock().await;
                     manager.notification_sent = sent_notifications;
+                    manager.error_notified = error_notified;
+                    manager.thermal_state = thermal_state;
+                    manager.polled_flags = polled_flags;
+                    manager.watched_jobs = watched_jobs;
+                    manager.save_state();
+                    let duplicates = manager.duplicate_serials();
+                    for (sn, ids) in &duplicates {
+                        if warned_serials.insert(sn.clone()) {
+                            error!("printers {} all report serial number {}, check their addresses for a copy-paste mistake or a DHCP collision", ids.join(", "), sn);
+                        }
+                    }
+                    warned_serials.retain(|sn| duplicates.contains_key(sn));
                 }
                 tokio::time::sleep(PROGRESS_CHECK_INTERVAL).await;
             }
         });
+        manager.lock().await.watch_task = Some(task);
     }
 
-    pub async fn send_notification(&self, printer: &mut Printer, notification_type: NotificationType) {
-        if let Some(notification) = self.config.get_notification_destinations(&notification_type) {
-            // Fetch latest image
-            printer.get_camera_snapshot().await.ok();
-
-            debug!("Sending notification: {:?}", notification_type);
-            if let Some(emails) = &notification.emails {
-                debug!("have emails, sending emails");
-                self.send_email_notifications(printer, notification_type, emails.iter().map(|s| s.as_str()).collect()).await
+    /// Sends the digest every day at notifications.digest.time, when configured.
+    /// The first one covers the time since startup
+    pub async fn start_digest(manager: PrinterManager) {
+        let (config, notifier) = {
+            let lock = manager.lock().await;
+            (lock.config.clone(), lock.notifier.clone())
+        };
+        let Some(digest) = config.digest() else { return };
+        // Both are checked by Config::validate
+        let (Some(time), Some(offset)) = (digest.time(), digest.offset()) else { return };
+        let digest_manager = manager.clone();
+        let task = tokio::task::spawn(async move {
+            let mut since = OffsetDateTime::now_utc();
+            loop {
+                let now = OffsetDateTime::now_utc();
+                let run = digest::next_run(now, time, offset);
+                debug!("sending the next digest at {}", run);
+                tokio::time::sleep((run - now).unsigned_abs()).await;
+                let printers = digest_manager.lock().await.printers();
+                notifier.send_digest(&printers, since, run).await;
+                since = run;
             }
-            if let Some(urls) = &notification.webhooks {
-                debug!("have webhooks, sending webhooks");
-                self.send_webhook_notifications(printer, notification_type, urls.iter().map(|s| s.as_str()).collect()).await
+        });
+        manager.lock().await.digest_task = Some(task);
+    }
+
+    /// Stops watching and saves the state, then sends the pending notifications, releases every printer
+    /// and closes the mailer, giving up on those after shutdown.grace_seconds
+    pub async fn shutdown(manager: PrinterManager) {
+        let (printers, queue, config) = {
+            let mut lock = manager.lock().await;
+            for task in [lock.watch_task.take(), lock.digest_task.take()].into_iter().flatten() {
+                task.abort();
             }
+            lock.save_state();
+            (lock.printers(), lock.notification_queue.clone(), lock.config.clone())
+        };
+        let grace = config.shutdown().grace();
+        let finished = tokio::time::timeout(grace, async {
+            queue.shutdown().await;
+            futures::future::join_all(printers.iter().map(|printer| printer.shutdown())).await;
+            #[cfg(feature = "smtp")]
+            config.close_mailer().await;
+        }).await;
+        if finished.is_err() {
+            warn!("shutdown did not finish within {:?}, exiting anyway", grace);
         }
     }
-    async fn send_email_notifications(&self, printer: &mut Printer, notification_type: NotificationType, emails: Vec<&str>) {
-        let Some(mailer) = self.config.mailer() else { return; };
-        let mut mailer = mailer.lock().await;
 
-        let send_user = &self.config.smtp().unwrap().user;
-        let subject = notification_type.get_subject(printer);
-        let body = notification_type.get_message(printer);
-
-        trace!("smtp configured, sending from {}", send_user);
-        let mut builder = MessageBuilder::new()
-            .from(send_user.as_str())
-            .text_body(body)
-            .subject(subject);
-        if let Some(last_img) = printer.last_image() {
-            builder = builder.attachment("image/jpeg", "printer_image.jpg", BodyPart::from(last_img));
+    /// Writes the sent notifications and each printer's last known state to state.path if it changed since
+    /// the last save, when configured
+    fn save_state(&mut self) {
+        let Some(state_config) = self.config.state() else { return };
+        let state = SavedState {
+            notification_sent: self.notification_sent.clone(),
+            error_notified: self.error_notified.clone(),
+            printers: self.printers.iter()
+                .map(|(id, printer)| (id.clone(), printer.saved_state()))
+                .collect(),
+            jobs: self.jobs.records()
+        };
+        if state == self.saved_state {
+            return;
         }
-        for to_email in emails {
-            builder = builder.bcc(to_email);
+        match state.save(&state_config.path) {
+            Ok(()) => self.saved_state = state,
+            Err(e) => warn!("failed to save state to {}: {}", state_config.path.display(), e)
         }
-        mailer.send(builder).await.expect("failed to send email");
-        trace!("Sent notification {:?} for printer {}", notification_type, printer);
     }
 
-    async fn send_webhook_notifications(&self, printer: &mut Printer, notification_type: NotificationType, urls: Vec<&str>) {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(5))
-            .user_agent(format!("jackzmc/{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")))
-            .build().expect("failed to create reqwest client for webhooks");
-        // TODO: proper struct? probably going to make it templated so eh
-        trace!("created webhook client");
-        let body = json!({
-            "username": printer.name(),
-            "embeds": [
-                {
-                    "title": notification_type.get_subject(&*printer),
-                    "description": notification_type.get_message(&*printer),
-                    "image": {
-                        "url": "attachment://printer_image.jpg"
-                    }
-                }
-            ]
-        });
-        for url in urls {
-            trace!("POST {}", url);
-            let mut form_data = reqwest::multipart::Form::new()
-                .text("payload_json", body.to_string());
-            if let Some(image) = printer.last_image() {
-                let part = Part::bytes(image)
-                    .file_name("printer_image.jpg")
-                    .mime_str("image/jpeg")
-                    .unwrap();
-                form_data = form_data.part("file1", part);
-            }
-            let request = client
-                .post(url)
-                .multipart(form_data);
-            match request.send().await {
-                Ok(response) => {
-                    if let Err(err) = response.error_for_status() {
-                        error!("Failed to send webhook: \n{}", err);
-                    }
-                },
-                Err(err) => {
-                    error!("Failed to send webhook to \"{}\":\n{}", url, err);
-                }
-            }
-        }
+    pub fn config(&self) -> Arc<ConfigManager> {
+        self.config.clone()
+    }
+
+    pub fn history(&self) -> Option<History> {
+        self.history.clone()
+    }
+
+    pub fn filament(&self) -> Option<FilamentLog> {
+        self.filament.clone()
+    }
+
+    pub fn jobs(&self) -> JobLog {
+        self.jobs.clone()
+    }
+
+    pub fn thumbnails(&self) -> Option<Thumbnails> {
+        self.thumbnails.clone()
+    }
+
+    pub fn notifier(&self) -> Arc<Notifier> {
+        self.notifier.clone()
+    }
+
+    /// Returns the last delivery attempt of every webhook destination that has been sent to
+    pub fn get_deliveries(&self) -> Vec<WebhookDelivery> {
+        self.notifier.get_deliveries()
     }
 
     pub fn get_printer_names(&self) -> Vec<String> {
@@ -211,16 +573,211 @@ impl Printers {
         self.printers.values().cloned().collect()
     }
 
+    /// Looks up the printer by id, case insensitively, or else by the serial number it reported
     pub fn get_printer(&self, id: &str) -> Option<PrinterContainer> {
-        self.printers.get(id).cloned()
+        self.printers.get(&id.to_lowercase())
+            .or_else(|| self.printers.values().find(|printer| printer.info().is_some_and(|info| info.sn.eq_ignore_ascii_case(id))))
+            .cloned()
+    }
+
+    /// A discovered printer is known if one is configured with the same host or reported the same serial number
+    pub fn is_known(&self, discovered: &DiscoveredPrinter) -> bool {
+        self.printers.values().any(|printer| printer.host() == discovered.host
+            || discovered.sn.as_ref().is_some_and(|sn| printer.info().is_some_and(|info| info.sn == *sn)))
+    }
+
+    /// Runs discovery once and adds every printer that is not known yet, using its machine name as the id
+    pub async fn add_discovered_printers(manager: PrinterManager) {
+        let config = manager.lock().await.config.clone();
+        let discovery = config.discovery();
+        let discovered = match discovery::discover(&discovery.broadcast_addresses, discovery.timeout()).await {
+            Ok(discovered) => discovered,
+            Err(e) => {
+                warn!("printer discovery failed: {}", e);
+                return;
+            }
+        };
+        let mut manager = manager.lock().await;
+        for printer in discovered {
+            if manager.is_known(&printer) {
+                continue;
+            }
+            // Printers of the same model have the same name by default
+            let base_id = discovery::printer_id(&printer.name);
+            let mut id = base_id.clone();
+            let mut n = 2;
+            while manager.printers.contains_key(&id) {
+                id = format!("{}_{}", base_id, n);
+                n += 1;
+            }
+            info!("adding discovered printer {} ({}) as {}", printer.name, printer.host, id);
+            if let Err(e) = manager.add_printer(id, printer.host, PRINTER_API_PORT, Duration::from_secs(default_idle_timeout_secs())) {
+                warn!("not adding discovered printer {}: {}", printer.name, e);
+            }
+        }
+    }
+
+    /// Adds an already created printer, such as one pointed at a mock
+    #[cfg(test)]
+    pub fn insert_printer(&mut self, printer: Printer) {
+        #[cfg(feature = "camera")]
+        self.configure_camera(&printer);
+        self.printers.insert(printer.name().to_string(), Arc::new(printer));
+    }
+
+    /// Applies [camera] and the printer's camera config, before anyone subscribed
+    #[cfg(feature = "camera")]
+    fn configure_camera(&self, printer: &Printer) {
+        printer.camera().set_buffered_frames(self.config.camera().buffered_frames);
+        printer.camera().set_gif(self.config.camera().gif());
+        if let Some(config) = self.config.printers().get(printer.name()) {
+            printer.camera().set_transform(config.camera_transform());
+        }
     }
 
-    pub fn add_printer(&mut self, id: String, ip: IpAddr) {
-        debug!("adding printer {} with ip {}", id, ip);
-        let mut printer = Printer::new(id.clone(), ip);
-        printer.get_meta();
-        let container = Arc::new(Mutex::new(printer));
-        self.printers.insert(id, container);
+    /// Serial numbers reported by more than one printer, with the sorted ids of those printers
+    pub fn duplicate_serials(&self) -> BTreeMap<String, Vec<String>> {
+        let mut by_serial: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (id, printer) in &self.printers {
+            if let Some(info) = printer.info().filter(|info| !info.sn.is_empty()) {
+                by_serial.entry(info.sn).or_default().push(id.clone());
+            }
+        }
+        by_serial.retain(|_, ids| {
+            ids.sort();
+            ids.len() > 1
+        });
+        by_serial
+    }
+
+    /// What the watcher thread last notified for the printer, which decides whether it notifies again
+    pub fn notification_state(&self, printer: &Printer) -> PrinterNotificationState {
+        let sent = self.notification_sent.get(printer.name());
+        let current_file = printer.current_file();
+        PrinterNotificationState {
+            completed_file: sent.map(|sent| sent.file.clone()),
+            completed_sent_at: sent.and_then(|sent| sent.sent_at),
+            already_notified: sent.is_some_and(|sent| current_file.as_ref() == Some(&sent.file)),
+            current_file,
+            error_notified: self.error_notified.get(printer.name()).cloned()
+        }
+    }
+
+    /// Ids of the other printers reporting the same serial number as the printer
+    pub fn same_serial_as(&self, printer_id: &str) -> Vec<String> {
+        self.duplicate_serials().into_values()
+            .find(|ids| ids.iter().any(|id| id == printer_id))
+            .map(|ids| ids.into_iter().filter(|id| id != printer_id).collect())
+            .unwrap_or_default()
+    }
+
+    /// Adds the printer and polls it in the background, so unreachable printers don't hold up startup.
+    /// Fails if another printer already has the same host and port, they would show each other's state
+    pub fn add_printer(&mut self, id: String, host: String, api_port: u16, idle_timeout: Duration) -> Result<(), String> {
+        // IP addresses are compared parsed, fd00:0::50 and fd00::50 are the same printer
+        let address = host.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, api_port));
+        let same_address = |printer: &&PrinterContainer| match address {
+            Some(address) => printer.socket_addr() == Some(address),
+            None => printer.host().eq_ignore_ascii_case(&host) && printer.api_port() == api_port
+        };
+        if let Some(other) = self.printers.values().find(same_address) {
+            return Err(format!("{} is already used by printer {}", host_port(&host, api_port), other.name()));
+        }
+        debug!("adding printer {} with host {}", id, host_port(&host, api_port));
+        let printer = Arc::new(Printer::new(id.clone(), host, api_port, idle_timeout));
+        #[cfg(feature = "camera")]
+        self.configure_camera(&printer);
+        if let Some(saved) = self.saved_printers.remove(&id) {
+            printer.restore(saved);
+        }
+        if let Some(config) = self.config.printers().get(&id) {
+            printer.set_require_control(config.require_control);
+            printer.set_labels(PrinterLabels { display_name: config.display_name.clone(), location: config.location.clone() });
+            printer.console().set_capture(config.console.kept_lines());
+            printer.set_connection_limits(config.connections.gcode, config.connections.camera, config.connections.wait());
+            printer.latencies().set_slow_after(config.slow_exchange());
+        }
+        if self.config.printers().get(&id).is_some_and(|config| config.maintenance) {
+            printer.set_maintenance(MaintenanceMode { enabled: true, until: None });
+            self.printers.insert(id, printer);
+            return Ok(());
+        }
+        let initial_poll = printer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = initial_poll.refresh_status().await {
+                debug!("printer/{} initial poll failed: {}", initial_poll.name(), e);
+            }
+        });
+        self.printers.insert(id, printer);
+        Ok(())
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flapping_flags_are_one_event_per_change() {
+        let polls = [true, true, false, false, false, true, false];
+        let mut previous = None;
+        let mut events = Vec::new();
+        for led in polls {
+            let flags = PolledFlags { led: Some(led), filament_runout: Some(false) };
+            events.extend(PolledFlags::changes(previous.as_ref(), &flags));
+            previous = Some(flags);
+        }
+        assert_eq!(events, vec![PrinterEvent::Led(false), PrinterEvent::Led(true), PrinterEvent::Led(false)]);
+        assert_eq!(serde_json::to_string(&events[0]).unwrap(), r#"{"event":"led","value":false}"#);
+
+        let runout = PolledFlags { led: None, filament_runout: Some(true) };
+        assert_eq!(PolledFlags::changes(previous.as_ref(), &runout), vec![PrinterEvent::FilamentRunout(true)]);
+        assert_eq!(serde_json::to_string(&PrinterEvent::FilamentRunout(true)).unwrap(), r#"{"event":"filament_runout","value":true}"#);
+    }
+
+    #[test]
+    fn ended_prints_are_completed_cancelled_or_failed() {
+        fn poll(file: Option<&str>, status: MachineStatus, percent: u8) -> Option<JobPoll> {
+            Some(JobPoll { file: file.map(str::to_string), machine_status: Some(status), progress_percent: Some(percent), elapsed_seconds: None })
+        }
+        fn outcome(polls: Vec<Option<JobPoll>>, completed_file: Option<&str>) -> Option<JobOutcome> {
+            let mut watched = None;
+            let mut ended = None;
+            for poll in polls {
+                if let Some((_, outcome)) = WatchedJob::follow(&mut watched, poll.as_ref(), completed_file) {
+                    assert!(ended.is_none(), "only one print was started");
+                    ended = Some(outcome);
+                }
+            }
+            ended
+        }
+        let printing = |percent| poll(Some("cube.gx"), MachineStatus::Building, percent);
+
+        // Finished with the file still selected, or cleared right after the last poll
+        assert_eq!(outcome(vec![printing(40), printing(100), poll(Some("cube.gx"), MachineStatus::BuildingCompleted, 100)], None), Some(JobOutcome::Completed));
+        assert_eq!(outcome(vec![printing(40), printing(99), poll(None, MachineStatus::Ready, 0)], None), Some(JobOutcome::Completed));
+        assert_eq!(outcome(vec![printing(40), poll(None, MachineStatus::Ready, 0)], Some("cube.gx")), Some(JobOutcome::Completed));
+
+        assert_eq!(outcome(vec![printing(10), printing(40), poll(None, MachineStatus::Ready, 0)], None), Some(JobOutcome::Cancelled));
+        assert_eq!(outcome(vec![printing(40), poll(Some("cube.gx"), MachineStatus::Error("THERMAL_ERROR".to_string()), 40), poll(None, MachineStatus::Ready, 0)], None), Some(JobOutcome::Failed(None)));
+
+        let Some(JobOutcome::Failed(Some(note))) = outcome(vec![printing(40), None, None, poll(None, MachineStatus::Ready, 0)], None) else { panic!("expected a failure with a note") };
+        assert_eq!(note, "The printer was unreachable at 40% and came back READY, it was likely restarted");
+        // Back online mid-print is the same print
+        assert_eq!(outcome(vec![printing(40), None, printing(45)], None), None);
+    }
+    #[test]
+    fn only_prints_first_seen_near_their_start_are_announced() {
+        let first_seen = |percent| {
+            let mut watched = None;
+            let poll = JobPoll { file: Some("cube.gx".to_string()), machine_status: Some(MachineStatus::Building), progress_percent: Some(percent), elapsed_seconds: None };
+            WatchedJob::follow(&mut watched, Some(&poll), None);
+            watched.unwrap().just_started()
+        };
+        assert!(first_seen(0));
+        assert!(first_seen(4));
+        // Already running when the server started
+        assert!(!first_seen(40));
+    }
+}
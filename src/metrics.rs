@@ -0,0 +1,268 @@
+//! Request counts and latencies of every route and of each printer's answers, exposed in the Prometheus text format
+//! at /metrics
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::warn;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+use flashforge_protocol::PrinterRequest;
+use crate::latency::{CommandLatency, LATENCY_WINDOW};
+use crate::util::routed_printer_id;
+
+/// Upper bounds in seconds of the latency histogram buckets
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Routes that wait on the camera, whose latencies are expected to be large
+const CAMERA_ROUTES: [&str; 2] = ["/snapshot", "/camera"];
+/// Routes that wait for a change on purpose
+const LONG_POLL_ROUTES: [&str; 2] = ["/wait", "/events"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RouteKind {
+    Api,
+    Camera,
+    LongPoll
+}
+
+impl RouteKind {
+    fn of(path: &str) -> Self {
+        if CAMERA_ROUTES.iter().any(|suffix| path.ends_with(suffix)) {
+            RouteKind::Camera
+        } else if LONG_POLL_ROUTES.iter().any(|suffix| path.ends_with(suffix)) {
+            RouteKind::LongPoll
+        } else {
+            RouteKind::Api
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            RouteKind::Api => "api",
+            RouteKind::Camera => "camera",
+            RouteKind::LongPoll => "long_poll"
+        }
+    }
+
+    /// Slow requests are only logged for API routes, the others are slow by design
+    fn expected_slow(self) -> bool {
+        self != RouteKind::Api
+    }
+}
+
+#[derive(Default)]
+struct RouteMetrics {
+    statuses: BTreeMap<u16, u64>,
+    /// Requests at or under each of [BUCKETS], not cumulative
+    buckets: [u64; BUCKETS.len()],
+    seconds: f64,
+    count: u64
+}
+
+/// Metrics of every route by its mounted path, requests that matched no route are under "unmatched"
+#[derive(Default)]
+pub struct Metrics {
+    routes: Mutex<BTreeMap<(String, RouteKind), RouteMetrics>>
+}
+
+impl Metrics {
+    pub fn record(&self, route: &str, kind: RouteKind, status: u16, latency: Duration) {
+        let mut routes = self.routes.lock().unwrap();
+        let metrics = routes.entry((route.to_string(), kind)).or_default();
+        *metrics.statuses.entry(status).or_default() += 1;
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            metrics.buckets[bucket] += 1;
+        }
+        metrics.seconds += seconds;
+        metrics.count += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let routes = self.routes.lock().unwrap();
+        let mut text = String::new();
+        writeln!(text, "# HELP http_requests_total Requests handled, by route and status code").unwrap();
+        writeln!(text, "# TYPE http_requests_total counter").unwrap();
+        for ((route, kind), metrics) in routes.iter() {
+            for (status, count) in &metrics.statuses {
+                writeln!(text, "http_requests_total{{route=\"{}\",kind=\"{}\",status=\"{}\"}} {}", escape(route), kind.label(), status, count).unwrap();
+            }
+        }
+        writeln!(text, "# HELP http_request_duration_seconds Time from receiving the request to sending its response").unwrap();
+        writeln!(text, "# TYPE http_request_duration_seconds histogram").unwrap();
+        for ((route, kind), metrics) in routes.iter() {
+            let labels = format!("route=\"{}\",kind=\"{}\"", escape(route), kind.label());
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(metrics.buckets) {
+                cumulative += count;
+                writeln!(text, "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, cumulative).unwrap();
+            }
+            writeln!(text, "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, metrics.count).unwrap();
+            writeln!(text, "http_request_duration_seconds_sum{{{}}} {}", labels, metrics.seconds).unwrap();
+            writeln!(text, "http_request_duration_seconds_count{{{}}} {}", labels, metrics.count).unwrap();
+        }
+        text
+    }
+}
+
+/// Summaries of how long each printer took to answer each command, the quantiles over the last [LATENCY_WINDOW]
+pub fn render_exchanges(printers: &[(String, Vec<CommandLatency>)]) -> String {
+    let mut text = String::new();
+    writeln!(text, "# HELP printer_exchange_duration_seconds Time from sending a command to the printer to the end of its answer, \
+        quantiles over the last {} minutes", LATENCY_WINDOW.as_secs() / 60).unwrap();
+    writeln!(text, "# TYPE printer_exchange_duration_seconds summary").unwrap();
+    for (printer, commands) in printers {
+        for command in commands {
+            let labels = format!("printer=\"{}\",command=\"{}\"", escape(printer), escape(&command.window.command));
+            for (quantile, ms) in [("0.5", command.window.p50_ms), ("0.95", command.window.p95_ms), ("1", command.window.max_ms)] {
+                let seconds = ms.map_or("NaN".to_string(), |ms| (ms / 1000.0).to_string());
+                writeln!(text, "printer_exchange_duration_seconds{{{},quantile=\"{}\"}} {}", labels, quantile, seconds).unwrap();
+            }
+            writeln!(text, "printer_exchange_duration_seconds_sum{{{}}} {}", labels, command.seconds).unwrap();
+            writeln!(text, "printer_exchange_duration_seconds_count{{{}}} {}", labels, command.count).unwrap();
+        }
+    }
+    text
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The last request a route sent to a printer, to tell slow printers from a slow server
+#[derive(Clone)]
+struct SentPrinterRequest {
+    printer: String,
+    gcode: String,
+    answered: bool
+}
+
+/// Shared by the route's handler and [RequestMetrics], through the request's local cache
+#[derive(Clone, Default)]
+struct PrinterRequests(Arc<Mutex<Option<SentPrinterRequest>>>);
+
+tokio::task_local! {
+    static PRINTER_REQUESTS: PrinterRequests;
+}
+
+/// Runs the handler so the printer requests it sends are known to [RequestMetrics]
+pub async fn tracking_printer_requests<F: Future>(request: &Request<'_>, handler: F) -> F::Output {
+    let requests = request.local_cache(PrinterRequests::default).clone();
+    PRINTER_REQUESTS.scope(requests, handler).await
+}
+
+/// Called by [crate::printer::Printer] before sending a request, does nothing outside of a route
+pub fn printer_request_sent(printer: &str, request: &PrinterRequest) {
+    PRINTER_REQUESTS.try_with(|requests| {
+        *requests.0.lock().unwrap() = Some(SentPrinterRequest { printer: printer.to_string(), gcode: request.get_gcode(), answered: false });
+    }).ok();
+}
+
+/// Called by [crate::printer::Printer] once the printer answered or the request failed
+pub fn printer_request_answered() {
+    PRINTER_REQUESTS.try_with(|requests| {
+        if let Some(sent) = requests.0.lock().unwrap().as_mut() {
+            sent.answered = true;
+        }
+    }).ok();
+}
+
+struct RequestStart(Option<Instant>);
+
+/// Records every response in [Metrics], and logs API requests slower than slow_after
+pub struct RequestMetrics {
+    metrics: Arc<Metrics>,
+    slow_after: Option<Duration>
+}
+
+impl RequestMetrics {
+    /// A slow_after of zero never logs
+    pub fn new(metrics: Arc<Metrics>, slow_after: Duration) -> Self {
+        Self { metrics, slow_after: (!slow_after.is_zero()).then_some(slow_after) }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info { name: "Request metrics", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(started) = request.local_cache(|| RequestStart(None)).0 else {
+            return;
+        };
+        let latency = started.elapsed();
+        let path = request.route().map(|route| route.uri.path()).unwrap_or("unmatched");
+        let kind = RouteKind::of(path);
+        self.metrics.record(path, kind, response.status().code, latency);
+
+        if kind.expected_slow() || self.slow_after.is_none_or(|slow_after| latency < slow_after) {
+            return;
+        }
+        let printer_id = routed_printer_id(request);
+        let sent = request.local_cache(PrinterRequests::default).0.lock().unwrap().clone();
+        let printer_request = match sent {
+            Some(SentPrinterRequest { printer, gcode, answered: false }) => format!("waiting on {} for {}", printer, gcode),
+            Some(SentPrinterRequest { printer, gcode, answered: true }) => format!("last sent {} to {}", gcode, printer),
+            None => "no printer requests".to_string()
+        };
+        warn!("Slow request: {} {} took {}ms (printer {}, {})", request.method(), request.uri().path(), latency.as_millis(),
+            printer_id.unwrap_or("-"), printer_request);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ExchangeLatency;
+
+    #[test]
+    fn renders_counts_and_cumulative_buckets() {
+        let metrics = Metrics::default();
+        metrics.record("/api/printers/<printer_id>/status", RouteKind::Api, 200, Duration::from_millis(20));
+        metrics.record("/api/printers/<printer_id>/status", RouteKind::Api, 200, Duration::from_millis(300));
+        metrics.record("/api/printers/<printer_id>/status", RouteKind::Api, 503, Duration::from_secs(20));
+        metrics.record("/api/printers/<printer_id>/snapshot", RouteKind::Camera, 200, Duration::from_millis(750));
+
+        let text = metrics.render();
+        assert!(text.contains("http_requests_total{route=\"/api/printers/<printer_id>/status\",kind=\"api\",status=\"200\"} 2\n"));
+        assert!(text.contains("http_requests_total{route=\"/api/printers/<printer_id>/status\",kind=\"api\",status=\"503\"} 1\n"));
+        assert!(text.contains("http_requests_total{route=\"/api/printers/<printer_id>/snapshot\",kind=\"camera\",status=\"200\"} 1\n"));
+        let status = "route=\"/api/printers/<printer_id>/status\",kind=\"api\"";
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{},le=\"0.01\"}} 0\n", status)));
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{},le=\"0.025\"}} 1\n", status)));
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{},le=\"10\"}} 2\n", status)));
+        assert!(text.contains(&format!("http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 3\n", status)));
+        assert!(text.contains(&format!("http_request_duration_seconds_count{{{}}} 3\n", status)));
+    }
+
+    #[test]
+    fn printer_answers_are_summaries_by_command() {
+        let window = |samples, ms| ExchangeLatency { command: "M119".to_string(), samples, p50_ms: ms, p95_ms: ms, max_ms: ms };
+        let text = render_exchanges(&[
+            ("main".to_string(), vec![CommandLatency { window: window(2, Some(12.5)), count: 2, seconds: 0.025 }]),
+            ("side".to_string(), vec![CommandLatency { window: window(0, None), count: 1, seconds: 0.5 }])
+        ]);
+        assert!(text.contains("# TYPE printer_exchange_duration_seconds summary\n"));
+        assert!(text.contains("printer_exchange_duration_seconds{printer=\"main\",command=\"M119\",quantile=\"0.95\"} 0.0125\n"));
+        assert!(text.contains("printer_exchange_duration_seconds_count{printer=\"main\",command=\"M119\"} 2\n"));
+        // Not sent in the window, the totals since startup remain
+        assert!(text.contains("printer_exchange_duration_seconds{printer=\"side\",command=\"M119\",quantile=\"0.5\"} NaN\n"));
+        assert!(text.contains("printer_exchange_duration_seconds_sum{printer=\"side\",command=\"M119\"} 0.5\n"));
+    }
+
+    #[test]
+    fn camera_and_long_poll_routes_are_labelled_separately() {
+        assert_eq!(RouteKind::of("/api/printers/<printer_id>/snapshot"), RouteKind::Camera);
+        assert_eq!(RouteKind::of("/api/printers/<printer_id>/camera"), RouteKind::Camera);
+        assert_eq!(RouteKind::of("/api/printers/<printer_id>/status"), RouteKind::Api);
+        assert_eq!(RouteKind::of("/api/printers/<printer_id>/wait"), RouteKind::LongPoll);
+    }
+}
@@ -1,79 +1,869 @@
-use std::collections::HashMap;
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use time::OffsetDateTime;
 
-#[derive(Serialize)]
+// What the printer reports, shared with other tools through the protocol crate
+pub use flashforge_protocol::models::{ControlSuccess, EndStopPosition, MachineStatus, NormalizedTemperature, PrinterHeadPosition, PrinterInfo,
+    PrinterProgress, PrinterStatus, PrinterTemperature, Progress, TemperatureMeasurement, TemperatureUnit};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct GenericError {
     pub error: String,
-    pub message: Option<String>
+    pub message: Option<String>,
+    /// The fields of a rejected body that are wrong, left out of other errors
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<FieldError>
 }
 
-#[derive(Serialize, Clone)]
-pub struct Position {
-    pub x: i32,
-    pub y: i32,
-    pub z: i32
+/// A field of a rejected body and what is wrong with it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FieldError {
+    /// None when the body itself is wrong, such as an array instead of an object
+    pub field: Option<String>,
+    pub message: String
 }
 
-#[derive(Serialize, Clone)]
-pub struct EndStopPosition {
-    pub x_max: i32,
-    pub y_max: i32,
-    pub z_min: i32
+/// An entry of GET /api/errors
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ErrorCodeInfo {
+    /// As in the `error` field of [GenericError]
+    pub code: String,
+    /// The status it is usually returned with
+    pub status: u16,
+    pub description: String
 }
 
-#[derive(Serialize, Clone)]
-pub struct TemperatureMeasurement {
-    pub target: f32,
-    pub current: f32
+/// Temperatures with the unit they were converted to
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TemperaturesInUnit<T> {
+    #[serde(flatten)]
+    pub temperatures: T,
+    pub unit: TemperatureUnit
 }
 
-#[derive(Serialize, Clone)]
-pub struct ControlSuccess {
-    pub success: bool
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CachedPrinterInfo {
+    pub name: String,
+    /// printers.<id>.display_name, or the name set on the printer once its info was fetched, or the id
+    pub display_name: String,
+    pub location: Option<String>,
+    pub state: PrinterAvailability,
+    pub is_online: bool,
+    pub is_printing: bool,
+    pub current_file: Option<String>,
+    pub progress_percent: Option<u8>,
+    pub firmware_version: Option<String>,
+    pub model_name: Option<String>,
+    /// Serial number, to tell printers of the same model apart
+    pub sn: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_seen: Option<OffsetDateTime>,
+    pub health: HealthSummary,
+    pub maintenance: bool,
+    pub connection: ConnectionStats,
+    #[serde(flatten)]
+    pub freshness: Freshness
 }
 
-#[derive(Serialize, Clone)]
-pub struct PrinterInfo {
-    pub name: String,
-    pub firmware_version: String,
-    pub sn: String,
-    pub tool_count: u8,
-    pub model_name: String,
-    pub mac_addr: String,
-    pub position: Position
+/// How old the cached values of a response are, they are only as recent as the watcher thread's last poll
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Freshness {
+    /// When the printer was last polled, whether or not it answered
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_polled_at: Option<OffsetDateTime>,
+    /// The values are from over two poll intervals ago, or the printer never answered a poll
+    pub stale: bool
 }
 
-#[derive(Serialize, Clone)]
-pub struct CachedPrinterInfo {
+/// Head position from the watcher thread's last poll, see watch.head_position
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CachedHeadPosition {
+    #[serde(flatten)]
+    pub position: PrinterHeadPosition,
+    /// Endstops of the status poll, None until the printer answered one
+    pub end_stop: Option<EndStopPosition>,
+    pub age_seconds: u64
+}
+
+/// The cached state watched by /wait, sent to subscribers whenever any of it changes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterStateUpdate {
+    pub machine_status: Option<MachineStatus>,
+    pub current_file: Option<String>,
+    pub progress: Option<PrinterProgress>
+}
+
+/// A change between two polls of the watcher thread, such as the light being turned off on the touchscreen
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "event", content = "value", rename_all = "snake_case")]
+pub enum PrinterEvent {
+    Led(bool),
+    /// The filament sensor ran out (true) or has filament again, from the F flag of M119
+    FilamentRunout(bool),
+    /// A line the printer sent on its own, while printers.<id>.console.capture is on
+    Console(String),
+    /// The print passed its first watch.first_layers layers, once per job
+    FirstLayersComplete(u32)
+}
+
+/// Returned by GET /api/printers/<id>/console, oldest first
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConsoleLine {
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+    pub line: String
+}
+
+/// While enabled the watcher thread doesn't poll the printer or send its notifications, requests to it still work
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaintenanceMode {
+    pub enabled: bool,
+    /// Maintenance ends by itself at this time, if set
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub until: Option<OffsetDateTime>
+}
+
+/// What GET /api/printers/<id>/info returns, the printer's info along with the names of the config
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LabeledPrinterInfo {
+    #[serde(flatten)]
+    pub info: PrinterInfo,
+    /// printers.<id>.display_name, or the name set on the printer
+    pub display_name: String,
+    pub location: Option<String>
+}
+
+/// printers.<id>.display_name and location, None when not set
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct PrinterLabels {
+    pub display_name: Option<String>,
+    pub location: Option<String>
+}
+
+/// Body of PATCH /api/printers/<id>, fields left out are kept and null or "" clears them
+#[derive(Deserialize, Default, Debug, PartialEq)]
+pub struct PrinterPatch {
+    #[serde(default, deserialize_with = "present")]
+    pub display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub location: Option<Option<String>>
+}
+
+/// Tells a null field apart from a missing one
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<String>>, D::Error> {
+    Option::deserialize(deserializer).map(Some)
+}
+
+impl PrinterPatch {
+    /// The labels with the patched fields replaced
+    pub fn apply(&self, labels: PrinterLabels) -> PrinterLabels {
+        let replace = |patched: &Option<Option<String>>, current: Option<String>| match patched {
+            Some(value) => value.as_deref().map(str::trim).filter(|value| !value.is_empty()).map(str::to_string),
+            None => current
+        };
+        PrinterLabels {
+            display_name: replace(&self.display_name, labels.display_name),
+            location: replace(&self.location, labels.location)
+        }
+    }
+}
+
+/// Body of PUT /api/printers/<id>/speed and /flow
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrintFactor {
+    pub percent: u16
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterJob {
+    pub file: String,
+    pub machine_status: Option<MachineStatus>,
+    /// Of the file's bytes
+    pub progress_percent: Option<u8>,
+    pub layer_percent: Option<u8>,
+    /// Current and total layer
+    pub layer: Progress,
+    /// Null if the job was already running when the server started
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub started_at: Option<OffsetDateTime>,
+    pub elapsed_seconds: Option<u64>,
+    /// From the printing rate over the last few polls, null until there are enough
+    pub remaining_seconds_estimate: Option<u64>,
+    /// Set with PUT /api/printers/<id>/speed during this job, null if not changed as printers don't report it
+    pub speed_percent: Option<u16>,
+    /// Set with PUT /api/printers/<id>/flow during this job, like speed_percent
+    pub flow_percent: Option<u16>,
+    /// Median of the last polled Z positions in mm, null without watch.head_position
+    pub z_height: Option<f32>,
+    /// z_height divided by the current layer, null until both are known
+    pub layer_height_estimate: Option<f32>,
+    #[serde(flatten)]
+    pub freshness: Freshness
+}
+
+/// A response exactly as the printer sent it, for bug reports
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RawPrinterResponse {
+    pub command: String,
+    pub gcode: String,
+    pub raw: String,
+    /// Null if the response could not be parsed, see error
+    pub parsed: Option<serde_json::Value>,
+    pub error: Option<String>,
+    /// Lines that were skipped while parsing
+    pub warnings: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PrinterRecording {
+    /// File the responses were saved to
+    pub path: String,
+    pub responses: Vec<RawPrinterResponse>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryPoint {
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    /// Average of the samples in the bucket starting at time
+    pub value: f32
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterHistory {
+    pub metric: String,
+    pub resolution_secs: u64,
+    pub points: Vec<HistoryPoint>
+}
+
+/// A printer that answered the discovery broadcast
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DiscoveredPrinter {
+    /// Machine name the printer reported
     pub name: String,
-    pub is_online: bool,
+    pub host: String,
+    /// From a follow up M115, None if the printer did not answer it
+    pub sn: Option<String>,
+    pub model_name: Option<String>
+}
+
+/// Pending until the printer has been reached once, the first poll runs in the background after startup
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PrinterAvailability {
+    Pending,
+    Online,
+    Offline
+}
+
+/// Offline after several failed requests in a row or if the printer never answered,
+/// degraded while requests are failing or shortly after one did
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthSummary {
+    Ok,
+    Degraded,
+    Offline
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterHealth {
+    pub health: HealthSummary,
+    /// Requests that failed since the last one that succeeded
+    pub consecutive_failures: u32,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_success: Option<OffsetDateTime>,
+    pub last_error: Option<LastPrinterError>,
+    pub connection: ConnectionStats,
+    /// Connections to the TCP API, printers.<id>.connections.gcode
+    pub gcode_connections: ConnectionPermits,
+    /// Other printers reporting the same serial number, from a copy-pasted ip or a DHCP collision
+    pub same_serial_as: Vec<String>,
+    pub in_flight: Option<InFlightCommand>,
+    /// Answered commands sent in the last 10 minutes
+    pub latency: Vec<ExchangeLatency>,
+    /// Null when compiled without the camera feature
+    pub camera: Option<CameraHealth>
+}
+
+/// Returned by GET /api/printers/<id>/notifications/state, to tell why a notification was or wasn't sent
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterNotificationState {
+    /// File the last print complete notification was sent for
+    pub completed_file: Option<String>,
+    /// Null if it was sent before the time was saved
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub completed_sent_at: Option<OffsetDateTime>,
     pub current_file: Option<String>,
-    pub firmware_version: Option<String>
-}
-
-#[derive(Serialize, Clone)]
-pub struct PrinterHeadPosition {
-    pub x: f32,
-    pub y: f32,
-    pub z: f32,
-    pub a: f32,
-    pub b: u32
-}
-
-#[derive(Serialize, Clone)]
-pub struct PrinterTemperature(pub HashMap<String, TemperatureMeasurement>);
-
-#[derive(Serialize, Clone, Debug)]
-pub struct PrinterProgress {
-    pub layer: (u32, u32),
-    pub byte: (u32, u32)
-}
-#[derive(Serialize, Clone)]
-pub struct PrinterStatus {
-    pub end_stop: EndStopPosition,
-    pub machine_status: String, // "READY",
-    pub move_mode: String, // "READY"
-    // status: Option<>, // S:1, L:0, J:0, F:0
-    pub led: bool,
-    pub current_file: Option<String>
-}
\ No newline at end of file
+    /// The current file is the one already notified, so finishing it again sends nothing
+    pub already_notified: bool,
+    /// Machine status an error notification was sent for, until the printer leaves the error state
+    pub error_notified: Option<String>
+}
+
+/// Whether the camera task is streaming, to tell a black stream from a wedged task
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(not(feature = "camera"), allow(dead_code))]
+pub struct CameraHealth {
+    /// The task is connected or connecting to the camera, it only runs while there are subscribers
+    pub running: bool,
+    pub subscribers: usize,
+    pub frames_last_minute: usize,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_frame_at: Option<OffsetDateTime>,
+    /// Why the task last stopped or failed to connect
+    pub last_error: Option<String>,
+    /// Connections to the camera, printers.<id>.connections.camera
+    pub connections: ConnectionPermits
+}
+
+/// The command the printer is being sent or is answering right now
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InFlightCommand {
+    /// Such as ~M104
+    pub command: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub started_at: OffsetDateTime,
+    pub elapsed_ms: u64
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LastPrinterError {
+    /// Same as the error of the API response, such as PRINTER_UNREACHABLE
+    pub kind: String,
+    pub message: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime
+}
+
+/// How often requests went over an already open connection vs needed a new one
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ConnectionStats {
+    /// Port the TCP API is reached on, printers.<id>.api_port
+    pub api_port: u16,
+    pub reused: u64,
+    pub reopened: u64
+}
+
+/// How long the printer took to answer a command over the last 10 minutes, from sending it to the end of the response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExchangeLatency {
+    /// The G-code without its parameters, such as M119
+    pub command: String,
+    pub samples: usize,
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    pub max_ms: Option<f64>
+}
+
+/// Connections open to one of the printer's ports out of how many are allowed at once
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConnectionPermits {
+    pub limit: usize,
+    pub in_use: usize,
+    /// Connections waiting for one of those in use to close
+    pub waiting: usize
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WebhookDelivery {
+    pub url: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub attempts: u32,
+    pub success: bool,
+    pub status: Option<u16>,
+    pub error: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DestinationKind {
+    Email,
+    Webhook
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationResultStatus {
+    Sent,
+    Failed,
+    DryRun
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NotificationResult {
+    pub kind: DestinationKind,
+    pub destination: String,
+    pub status: NotificationResultStatus,
+    pub error: Option<String>,
+    /// Rendered subject, only set for dry runs
+    pub subject: Option<String>,
+    /// Rendered body or webhook payload, only set for dry runs
+    pub body: Option<String>
+}
+
+/// A notification that was sent, and how each destination took it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NotificationRecord {
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    /// None for the digest, which covers every printer
+    pub printer: Option<String>,
+    /// Name of the notification type, such as print_complete, or digest
+    #[serde(rename = "type")]
+    pub notification_type: String,
+    pub results: Vec<NotificationResult>
+}
+
+/// Where the filament of a print was estimated from
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilamentSource {
+    /// The slicer's estimate in the comments of the file in gcode.dir
+    Slicer,
+    /// The size of the file and filament.grams_per_megabyte
+    FileSize
+}
+
+/// A print that ended and the filament it used, kept in filament.path
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FilamentRecord {
+    pub printer: String,
+    pub file: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub ended_at: OffsetDateTime,
+    /// Of the whole file's estimate, prints that were stopped only count up to where they stopped
+    pub grams: f64,
+    pub progress_percent: u8,
+    pub source: FilamentSource
+}
+
+/// Returned by GET /api/stats/filament
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FilamentStats {
+    #[serde(with = "time::serde::rfc3339")]
+    pub since: OffsetDateTime,
+    pub total_grams: f64,
+    /// By printer id, only printers that printed since
+    pub printers: Vec<PrinterFilament>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterFilament {
+    pub printer: String,
+    pub jobs: u32,
+    pub grams: f64,
+    /// Jobs estimated from the file size, their grams are rougher
+    pub estimated_from_file_size: u32
+}
+
+/// How a print ended
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobResult {
+    Completed,
+    Failed,
+    Cancelled
+}
+
+/// A print that ended, kept for a week for GET /api/stats and saved to state.path
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JobRecord {
+    pub printer: String,
+    pub file: String,
+    pub result: JobResult,
+    #[serde(with = "time::serde::rfc3339")]
+    pub ended_at: OffsetDateTime,
+    /// None if the printer was never polled with the job's start time
+    pub elapsed_seconds: Option<u64>
+}
+
+/// Returned by GET /api/stats, today and this week are in the timezone of notifications.digest
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FarmStats {
+    /// Midnight of today
+    #[serde(with = "time::serde::rfc3339")]
+    pub today_since: OffsetDateTime,
+    /// Midnight of this week's Monday
+    #[serde(with = "time::serde::rfc3339")]
+    pub week_since: OffsetDateTime,
+    pub printers: PrinterCounts,
+    pub jobs: JobCounts,
+    /// Of every printer, including the time of the print it is on
+    pub print_hours_this_week: Vec<PrinterPrintHours>,
+    /// The print that is expected to finish first, null when none has a remaining_seconds_estimate
+    pub fastest_eta: Option<JobEta>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterCounts {
+    pub total: usize,
+    pub online: usize,
+    pub printing: usize
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JobCounts {
+    pub completed_today: u32,
+    pub completed_this_week: u32,
+    pub failed_today: u32,
+    pub failed_this_week: u32,
+    pub cancelled_this_week: u32
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrinterPrintHours {
+    pub printer: String,
+    pub hours: f64
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JobEta {
+    pub printer: String,
+    pub file: String,
+    pub remaining_seconds: u64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub eta: OffsetDateTime
+}
+
+/// An email or webhook of the config, as listed by GET /api/notifications/destinations
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NotificationDestination {
+    /// Used by PUT /api/notifications/destinations/<index>/enabled, stays the same until the config is edited
+    pub index: usize,
+    /// Key of the [notifications] table it is in, such as on_done or digest
+    pub notification: String,
+    pub kind: DestinationKind,
+    /// Address or url
+    pub target: String,
+    pub enabled: bool,
+    /// Last delivery attempt of a webhook, None for emails and webhooks not sent to yet
+    pub last_delivery: Option<WebhookDelivery>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DestinationEnabled {
+    pub enabled: bool
+}
+
+#[derive(Deserialize)]
+pub struct TestNotificationRequest {
+    pub printer: String,
+    #[serde(rename = "type")]
+    pub notification_type: String,
+    #[serde(default)]
+    pub dry_run: bool
+}
+
+/// Body of the Grafana JSON datasource /search request
+#[derive(Deserialize, Default)]
+pub struct GrafanaSearch {
+    #[serde(default)]
+    pub target: String
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GrafanaQuery {
+    pub range: GrafanaRange,
+    pub interval_ms: Option<u64>,
+    pub max_data_points: Option<u64>,
+    pub targets: Vec<GrafanaTarget>
+}
+
+#[derive(Deserialize)]
+pub struct GrafanaRange {
+    #[serde(with = "time::serde::rfc3339")]
+    pub from: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub to: OffsetDateTime
+}
+
+#[derive(Deserialize)]
+pub struct GrafanaTarget {
+    /// Series as <printer id>.<metric>, from /search
+    pub target: String
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct GrafanaTimeseries {
+    pub target: String,
+    /// [value, unix timestamp in milliseconds]
+    pub datapoints: Vec<(f32, i64)>
+}
+
+/// A macro of the config, as listed by GET /api/macros
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MacroInfo {
+    pub name: String,
+    pub printer_commands: Vec<String>
+}
+
+/// A command of a macro that ran, with the printer's response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MacroStep {
+    pub command: String,
+    pub response: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MacroRun {
+    pub name: String,
+    pub steps: Vec<MacroStep>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTestKind {
+    PrinterApi,
+    Camera,
+    Smtp,
+    Webhook
+}
+
+/// One check of GET /api/admin/selftest
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SelfTestProbe {
+    pub kind: SelfTestKind,
+    /// The printer's id for printer_api and camera, host:port for smtp and the url for webhooks
+    pub target: String,
+    pub ok: bool,
+    pub latency_ms: u64,
+    pub error: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SelfTestReport {
+    /// Every probe is ok
+    pub ok: bool,
+    pub probes: Vec<SelfTestProbe>
+}
+
+/// Where the SMTP session is, nothing is connected until the first attempt in the background
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpState {
+    /// No [smtp] section, or compiled without the smtp feature
+    NotConfigured,
+    Connecting,
+    Connected,
+    /// The last attempt failed, another is made at next_attempt_at or on the next email
+    Retrying,
+    /// The server refused the credentials or TLS, emails are not sent until the config is fixed and the server restarted
+    Disabled
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SmtpStatus {
+    pub state: SmtpState,
+    /// Of the last failed attempt
+    pub error: Option<String>,
+    /// Failed attempts in a row
+    pub failed_attempts: u32,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub next_attempt_at: Option<OffsetDateTime>
+}
+
+/// Returned by GET /readyz. The API serves whatever the state of the SMTP server is, which is reported here
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Readiness {
+    pub ready: bool,
+    pub smtp: SmtpStatus
+}
+
+/// A write request, as a line of the audit log
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AuditEntry {
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    pub client_ip: Option<std::net::IpAddr>,
+    /// "password" or "token" when one was given and accepted, "none" otherwise
+    pub auth: String,
+    /// Name of the token that authorized the request
+    pub token: Option<String>,
+    pub method: String,
+    /// Mounted path of the route, like the metrics route label
+    pub route: String,
+    /// Path and query of the request
+    pub uri: String,
+    pub printer_id: Option<String>,
+    /// The request body, as JSON if it is, cut off after the first few kilobytes
+    pub payload: Option<serde_json::Value>,
+    /// Status code of the response
+    pub status: u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn round_trip<T: Serialize + for<'de> Deserialize<'de> + PartialEq + std::fmt::Debug>(value: T) {
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<T>(&json).unwrap(), value, "{}", json);
+    }
+
+    #[test]
+    fn patches_only_change_the_fields_sent() {
+        let labels = PrinterLabels { display_name: Some("Left Adventurer".to_string()), location: Some("Garage".to_string()) };
+        let patch: PrinterPatch = serde_json::from_str(r#"{"location": null}"#).unwrap();
+        assert_eq!(patch.apply(labels.clone()), PrinterLabels { display_name: Some("Left Adventurer".to_string()), location: None });
+        let patch: PrinterPatch = serde_json::from_str(r#"{"display_name": " Right Adventurer ", "location": ""}"#).unwrap();
+        assert_eq!(patch.apply(labels), PrinterLabels { display_name: Some("Right Adventurer".to_string()), location: None });
+    }
+
+    #[test]
+    fn responses_round_trip_through_json() {
+        let at = datetime!(2024-06-01 12:00:00 UTC);
+        let connection = ConnectionStats { api_port: 8899, reused: 10, reopened: 2 };
+        round_trip(GenericError { error: "UNKNOWN_PRINTER".to_string(), message: Some("unknown printer x".to_string()), details: Vec::new() });
+        round_trip(ErrorCodeInfo { code: "UNKNOWN_PRINTER".to_string(), status: 404, description: "No printer has the id or serial number".to_string() });
+        round_trip(TemperaturesInUnit {
+            temperatures: NormalizedTemperature { extruders: vec![TemperatureMeasurement { target: 410.0, current: 401.0 }], ..Default::default() },
+            unit: TemperatureUnit::Fahrenheit
+        });
+        round_trip(CachedPrinterInfo {
+            name: "main".to_string(),
+            display_name: "Left Adventurer".to_string(),
+            location: Some("Garage".to_string()),
+            state: PrinterAvailability::Online,
+            is_online: true,
+            is_printing: true,
+            current_file: Some("cube.gx".to_string()),
+            progress_percent: Some(20),
+            firmware_version: Some("v1.3.7".to_string()),
+            model_name: None,
+            sn: None,
+            last_seen: Some(at),
+            health: HealthSummary::Degraded,
+            maintenance: false,
+            connection: connection.clone(),
+            freshness: Freshness { last_polled_at: Some(at), stale: false }
+        });
+        round_trip(CachedHeadPosition {
+            position: PrinterHeadPosition { x: 10.5, y: -20.0, z: 0.2, a: 1.5, b: 0 },
+            end_stop: Some(EndStopPosition { x_max: 0, y_max: 0, z_min: 1 }),
+            age_seconds: 4
+        });
+        round_trip(PrinterStateUpdate {
+            machine_status: Some(MachineStatus::Building),
+            current_file: Some("cube.gx".to_string()),
+            progress: Some(PrinterProgress { layer: Progress { current: 12, total: 60 }, byte: Progress { current: 2400, total: 12000 } })
+        });
+        round_trip(PrinterEvent::Led(false));
+        round_trip(PrinterEvent::Console("Nozzle heating".to_string()));
+        round_trip(PrinterEvent::FirstLayersComplete(3));
+        round_trip(ConsoleLine { at, line: "Nozzle heating".to_string() });
+        round_trip(MaintenanceMode { enabled: true, until: Some(at) });
+        round_trip(PrintFactor { percent: 110 });
+        round_trip(PrinterJob {
+            file: "cube.gx".to_string(),
+            machine_status: Some(MachineStatus::Building),
+            progress_percent: Some(20),
+            layer_percent: Some(20),
+            layer: Progress { current: 12, total: 60 },
+            started_at: None,
+            elapsed_seconds: None,
+            remaining_seconds_estimate: Some(800),
+            speed_percent: Some(110),
+            flow_percent: None,
+            z_height: Some(2.5),
+            layer_height_estimate: Some(0.25),
+            freshness: Freshness { last_polled_at: None, stale: true }
+        });
+        round_trip(PrinterRecording {
+            path: "recordings/main.txt".to_string(),
+            responses: vec![RawPrinterResponse {
+                command: "status".to_string(),
+                gcode: "~M119".to_string(),
+                raw: "CMD M119 Received.\r\nok\r\n".to_string(),
+                parsed: None,
+                error: Some("could not parse the response".to_string()),
+                warnings: Vec::new()
+            }]
+        });
+        round_trip(PrinterHistory { metric: "nozzle_temp".to_string(), resolution_secs: 60, points: vec![HistoryPoint { time: at, value: 210.5 }] });
+        round_trip(DiscoveredPrinter { name: "My Printer".to_string(), host: "10.0.0.50".to_string(), sn: None, model_name: None });
+        round_trip(PrinterHealth {
+            health: HealthSummary::Ok,
+            consecutive_failures: 0,
+            last_success: Some(at),
+            last_error: Some(LastPrinterError { kind: "PRINTER_TIMEOUT".to_string(), message: "timed out".to_string(), at }),
+            connection,
+            gcode_connections: ConnectionPermits { limit: 1, in_use: 1, waiting: 0 },
+            same_serial_as: vec!["backup".to_string()],
+            in_flight: Some(InFlightCommand { command: "~M119".to_string(), started_at: at, elapsed_ms: 250 }),
+            latency: vec![ExchangeLatency { command: "M119".to_string(), samples: 10, p50_ms: Some(12.5), p95_ms: Some(40.0), max_ms: Some(41.2) }],
+            camera: Some(CameraHealth { running: true, subscribers: 1, frames_last_minute: 600, last_frame_at: Some(at), last_error: None,
+                connections: ConnectionPermits { limit: 1, in_use: 1, waiting: 2 } })
+        });
+        round_trip(PrinterNotificationState {
+            completed_file: Some("benchy.gx".to_string()),
+            completed_sent_at: Some(at),
+            current_file: None,
+            already_notified: false,
+            error_notified: None
+        });
+        round_trip(WebhookDelivery { url: "https://example.com".to_string(), timestamp: at, attempts: 2, success: false, status: Some(500), error: None });
+        round_trip(NotificationResult {
+            kind: DestinationKind::Webhook,
+            destination: "https://example.com".to_string(),
+            status: NotificationResultStatus::DryRun,
+            error: None,
+            subject: Some("Print done".to_string()),
+            body: Some("{}".to_string())
+        });
+        round_trip(NotificationDestination {
+            index: 2,
+            notification: "digest".to_string(),
+            kind: DestinationKind::Webhook,
+            target: "https://example.com/hook".to_string(),
+            enabled: false,
+            last_delivery: Some(WebhookDelivery {
+                url: "https://example.com/hook".to_string(),
+                timestamp: at,
+                attempts: 1,
+                success: true,
+                status: Some(204),
+                error: None
+            })
+        });
+        round_trip(FilamentRecord {
+            printer: "main".to_string(),
+            file: "benchy.gx".to_string(),
+            ended_at: at,
+            grams: 12.5,
+            progress_percent: 100,
+            source: FilamentSource::Slicer
+        });
+        round_trip(FilamentStats {
+            since: at,
+            total_grams: 12.5,
+            printers: vec![PrinterFilament { printer: "main".to_string(), jobs: 1, grams: 12.5, estimated_from_file_size: 0 }]
+        });
+        round_trip(NotificationRecord {
+            time: at,
+            printer: Some("main".to_string()),
+            notification_type: "print_failed".to_string(),
+            results: vec![NotificationResult {
+                kind: DestinationKind::Email,
+                destination: "me@example.com".to_string(),
+                status: NotificationResultStatus::Failed,
+                error: Some("SMTP is not configured".to_string()),
+                subject: None,
+                body: None
+            }]
+        });
+        round_trip(SelfTestReport {
+            ok: false,
+            probes: vec![SelfTestProbe { kind: SelfTestKind::PrinterApi, target: "main".to_string(), ok: false, latency_ms: 3000, error: Some("no answer within 3 seconds".to_string()) }]
+        });
+        round_trip(Readiness {
+            ready: true,
+            smtp: SmtpStatus { state: SmtpState::Retrying, error: Some("Connection timeout".to_string()), failed_attempts: 2, next_attempt_at: Some(at) }
+        });
+        round_trip(MacroRun { name: "preheat_pla".to_string(), steps: vec![MacroStep { command: "~M140 S60".to_string(), response: "CMD M140 Received.\r\nok".to_string() }] });
+        round_trip(GrafanaTimeseries { target: "main.layer".to_string(), datapoints: vec![(1.5, 1717243200000)] });
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use time::OffsetDateTime;
+use crate::models::{MachineStatus, NormalizedTemperature, TemperatureMeasurement};
+use crate::printer::Printer;
+
+/// Objects that can be queried, a read only subset of Klipper's
+pub const OBJECTS: [&str; 4] = ["extruder", "heater_bed", "print_stats", "display_status"];
+
+/// Moonraker wraps every successful response in "result"
+#[derive(Serialize)]
+pub struct MoonrakerResponse<T> {
+    pub result: T
+}
+
+#[derive(Serialize)]
+pub struct ServerInfo {
+    pub klippy_connected: bool,
+    pub klippy_state: &'static str,
+    pub components: Vec<String>,
+    pub failed_components: Vec<String>,
+    pub registered_directories: Vec<String>,
+    pub warnings: Vec<String>,
+    pub websocket_count: u32,
+    pub moonraker_version: String,
+    pub api_version: [u32; 3],
+    pub api_version_string: String
+}
+
+#[derive(Serialize)]
+pub struct PrinterInfo {
+    pub state: &'static str,
+    pub state_message: String,
+    pub hostname: String,
+    pub software_version: String,
+    pub cpu_info: String
+}
+
+#[derive(Serialize)]
+pub struct ObjectList {
+    pub objects: Vec<&'static str>
+}
+
+#[derive(Serialize)]
+pub struct ObjectQuery {
+    pub eventtime: f64,
+    pub status: HashMap<String, Map<String, Value>>
+}
+
+impl ServerInfo {
+    pub fn new(printer: &Printer) -> Self {
+        ServerInfo {
+            klippy_connected: printer.online(),
+            klippy_state: klippy_state(printer),
+            components: Vec::new(),
+            failed_components: Vec::new(),
+            registered_directories: Vec::new(),
+            warnings: Vec::new(),
+            websocket_count: 0,
+            moonraker_version: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            api_version: [1, 0, 0],
+            api_version_string: "1.0.0".to_string(),
+        }
+    }
+}
+
+impl PrinterInfo {
+    pub fn new(printer: &Printer) -> Self {
+        let info = printer.info();
+        let state_message = match printer.machine_status() {
+            _ if !printer.online() => "Printer is offline".to_string(),
+            Some(MachineStatus::Error(status)) => format!("Printer reported {}", status),
+            _ => "Printer is ready".to_string()
+        };
+        PrinterInfo {
+            state: klippy_state(printer),
+            state_message,
+            hostname: printer.name().to_string(),
+            software_version: info.as_ref().map(|info| info.firmware_version.clone()).unwrap_or_default(),
+            cpu_info: info.map(|info| info.model_name).unwrap_or_default(),
+        }
+    }
+}
+
+fn klippy_state(printer: &Printer) -> &'static str {
+    match printer.machine_status() {
+        _ if !printer.online() => "shutdown",
+        Some(MachineStatus::Error(_)) => "error",
+        _ => "ready"
+    }
+}
+
+/// print_stats.state for the machine status
+fn print_state(status: Option<&MachineStatus>) -> &'static str {
+    match status {
+        Some(MachineStatus::Building | MachineStatus::BuildingFromSd) => "printing",
+        Some(MachineStatus::Paused) => "paused",
+        Some(MachineStatus::BuildingCompleted) => "complete",
+        Some(MachineStatus::Error(_)) => "error",
+        _ => "standby"
+    }
+}
+
+fn heater(measurement: Option<&TemperatureMeasurement>) -> Map<String, Value> {
+    let (temperature, target) = measurement.map(|m| (m.current, m.target)).unwrap_or_default();
+    Map::from_iter([
+        ("temperature".to_string(), Value::from(temperature)),
+        ("target".to_string(), Value::from(target)),
+        // Not reported by FlashForge printers
+        ("power".to_string(), Value::from(0.0)),
+    ])
+}
+
+/// Builds the requested objects from the printer's cached state and temperatures.
+/// Each object is mapped to the attributes to return, None returns all. Unknown objects are left out like Moonraker does
+pub fn query_objects(printer: &Printer, temps: Option<&NormalizedTemperature>, query: &[(String, Option<Vec<String>>)]) -> ObjectQuery {
+    let mut status = HashMap::new();
+    for (object, attributes) in query {
+        let extruder = object.strip_prefix("extruder")
+            .and_then(|index| if index.is_empty() { Some(0) } else { index.parse::<usize>().ok() });
+        let mut value = match object.as_str() {
+            _ if extruder.is_some() => {
+                let measurement = temps.and_then(|t| t.extruders.get(extruder.unwrap()));
+                if temps.is_some() && measurement.is_none() {
+                    continue;
+                }
+                heater(measurement)
+            },
+            "heater_bed" => heater(temps.and_then(|t| t.bed.as_ref())),
+            "print_stats" => {
+                let machine_status = printer.machine_status();
+                Map::from_iter([
+                    ("filename".to_string(), Value::from(printer.current_file().unwrap_or_default())),
+                    ("state".to_string(), Value::from(print_state(machine_status.as_ref()))),
+                    ("message".to_string(), Value::from(match &machine_status {
+                        Some(MachineStatus::Error(status)) => status.clone(),
+                        _ => String::new()
+                    })),
+                    // FlashForge printers don't report durations or filament usage
+                    ("print_duration".to_string(), Value::from(0.0)),
+                    ("total_duration".to_string(), Value::from(0.0)),
+                    ("filament_used".to_string(), Value::from(0.0)),
+                ])
+            },
+            "display_status" => Map::from_iter([
+                ("progress".to_string(), Value::from(printer.progress_percent().unwrap_or(0) as f64 / 100.0)),
+                ("message".to_string(), Value::Null),
+            ]),
+            _ => continue
+        };
+        if let Some(attributes) = attributes {
+            value.retain(|key, _| attributes.contains(key));
+        }
+        status.insert(object.clone(), value);
+    }
+    ObjectQuery {
+        eventtime: OffsetDateTime::now_utc().unix_timestamp_nanos() as f64 / 1e9,
+        status
+    }
+}
@@ -0,0 +1,362 @@
+use crate::config::MqttConfig;
+use crate::models::{PrinterEvent, PrinterTemperature};
+use crate::printer::Printer;
+use log::{debug, info, trace, warn};
+use serde_json::json;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// How often the broker expects to hear from us, a PINGREQ is sent at half this interval
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Messages queued while the broker is unreachable, older states are not worth keeping as they are republished every poll
+const QUEUE_SIZE: usize = 256;
+/// The most the remaining length of a packet can encode, in four bytes
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+struct MqttMessage {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool
+}
+
+/// Publishes printer state to an MQTT broker. The connection is owned by a background task that
+/// reconnects with backoff, publishing is fire and forget (QoS 0)
+#[derive(Clone)]
+pub struct MqttClient {
+    base_topic: String,
+    tx: mpsc::Sender<MqttMessage>
+}
+
+impl MqttClient {
+    pub fn start(config: MqttConfig, printer_ids: Vec<String>) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let base_topic = config.base_topic.clone();
+        tokio::spawn(run(config, printer_ids, rx));
+        Self { base_topic, tx }
+    }
+
+    fn publish(&self, topic: String, payload: Vec<u8>, retain: bool) {
+        if self.tx.try_send(MqttMessage { topic, payload, retain }).is_err() {
+            trace!("mqtt queue full or closed, dropping message");
+        }
+    }
+
+    /// Publishes the retained state and availability of a printer
+    pub fn publish_printer(&self, printer: &Printer, online: bool, temperatures: Option<&PrinterTemperature>) {
+        let topic = format!("{}/{}", self.base_topic, printer.name());
+        let availability = if online { "online" } else { "offline" };
+        self.publish(format!("{}/availability", topic), availability.as_bytes().to_vec(), true);
+        if !online {
+            return;
+        }
+        let progress = printer.progress_percent();
+        let state = json!({
+            "machine_status": printer.machine_status(),
+            "current_file": printer.current_file(),
+            "printing": printer.is_printing(),
+            "progress_percent": progress.unwrap_or(0),
+            "temperatures": temperatures
+        });
+        self.publish(format!("{}/state", topic), state.to_string().into_bytes(), true);
+    }
+
+    /// Publishes the event to <base_topic>/<printer id>/event, not retained as it is only news when it happens
+    pub fn publish_event(&self, printer: &Printer, event: &PrinterEvent) {
+        let payload = serde_json::to_vec(event).unwrap_or_default();
+        self.publish(format!("{}/{}/event", self.base_topic, printer.name()), payload, false);
+    }
+}
+
+async fn run(config: MqttConfig, printer_ids: Vec<String>, mut rx: mpsc::Receiver<MqttMessage>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match connect(&config).await {
+            Ok(stream) => {
+                info!("Connected to MQTT broker {}:{}", config.host, config.port);
+                backoff = MIN_BACKOFF;
+                match session(stream, &config, &printer_ids, &mut rx).await {
+                    Ok(()) => return,
+                    Err(e) => warn!("MQTT connection lost: {}", e)
+                }
+            },
+            Err(e) => warn!("Failed to connect to MQTT broker {}:{}: {}", config.host, config.port, e)
+        }
+        debug!("reconnecting to MQTT broker in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn connect(config: &MqttConfig) -> Result<TcpStream, String> {
+    let mut stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((config.host.as_str(), config.port))).await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+    stream.write_all(&encode_connect(config)?).await.map_err(|e| e.to_string())?;
+    let mut connack = [0u8; 4];
+    tokio::time::timeout(CONNECT_TIMEOUT, stream.read_exact(&mut connack)).await
+        .map_err(|_| "timed out waiting for CONNACK".to_string())?
+        .map_err(|e| e.to_string())?;
+    if connack[0] != 0x20 {
+        return Err(format!("expected CONNACK, got packet type {:#x}", connack[0]));
+    }
+    match connack[3] {
+        0 => Ok(stream),
+        4 | 5 => Err("broker refused credentials".to_string()),
+        code => Err(format!("broker refused connection (code {})", code))
+    }
+}
+
+/// Runs a connected session until the broker disconnects (Err) or the client is dropped (Ok)
+async fn session(stream: TcpStream, config: &MqttConfig, printer_ids: &[String], rx: &mut mpsc::Receiver<MqttMessage>) -> Result<(), String> {
+    let (mut reader, mut writer) = stream.into_split();
+    write(&mut writer, &encode_publish(&status_topic(config), b"online", true)?).await?;
+    for id in printer_ids {
+        for (topic, payload) in discovery_configs(config, id) {
+            match encode_publish(&topic, payload.to_string().as_bytes(), true) {
+                Ok(packet) => write(&mut writer, &packet).await?,
+                Err(e) => warn!("Not publishing discovery config {}: {}", topic, e)
+            }
+        }
+    }
+
+    let mut ping = tokio::time::interval(KEEP_ALIVE / 2);
+    let mut buf = [0u8; 1024];
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(message) => match encode_publish(&message.topic, &message.payload, message.retain) {
+                    Ok(packet) => write(&mut writer, &packet).await?,
+                    // Dropped like a full queue would, the connection is fine
+                    Err(e) => warn!("Not publishing to {}: {}", message.topic, e)
+                },
+                None => {
+                    write(&mut writer, &[0xE0, 0x00]).await.ok();
+                    return Ok(());
+                }
+            },
+            _ = ping.tick() => write(&mut writer, &[0xC0, 0x00]).await?,
+            // Only PINGRESP is expected from the broker as we do not subscribe to anything
+            read = reader.read(&mut buf) => match read {
+                Ok(0) => return Err("connection closed by broker".to_string()),
+                Ok(_) => {},
+                Err(e) => return Err(e.to_string())
+            }
+        }
+    }
+}
+
+async fn write(writer: &mut OwnedWriteHalf, packet: &[u8]) -> Result<(), String> {
+    writer.write_all(packet).await.map_err(|e| e.to_string())
+}
+
+fn status_topic(config: &MqttConfig) -> String {
+    format!("{}/status", config.base_topic)
+}
+
+/// Home assistant MQTT discovery configs for a printer, as (topic, payload)
+fn discovery_configs(config: &MqttConfig, printer_id: &str) -> Vec<(String, serde_json::Value)> {
+    let state_topic = format!("{}/{}/state", config.base_topic, printer_id);
+    let device = json!({
+        "identifiers": [format!("flashforge_{}", printer_id)],
+        "name": printer_id,
+        "manufacturer": "FlashForge"
+    });
+    // Entities are unavailable if either the server or the printer is offline
+    let availability = json!([
+        { "topic": status_topic(config) },
+        { "topic": format!("{}/{}/availability", config.base_topic, printer_id) }
+    ]);
+    let entities = [
+        ("sensor", "nozzle_temperature", "Nozzle temperature", "{{ value_json.temperatures.T0.current }}", Some("°C"), Some("temperature")),
+        ("sensor", "bed_temperature", "Bed temperature", "{{ value_json.temperatures.B.current }}", Some("°C"), Some("temperature")),
+        ("sensor", "progress", "Progress", "{{ value_json.progress_percent }}", Some("%"), None),
+        ("binary_sensor", "printing", "Printing", "{{ 'ON' if value_json.printing else 'OFF' }}", None, Some("running")),
+    ];
+    entities.into_iter().map(|(component, object_id, name, value_template, unit, device_class)| {
+        let unique_id = format!("flashforge_{}_{}", printer_id, object_id);
+        let mut payload = json!({
+            "name": name,
+            "unique_id": unique_id,
+            "state_topic": state_topic,
+            "value_template": value_template,
+            "availability": availability,
+            "availability_mode": "all",
+            "device": device
+        });
+        if let Some(unit) = unit {
+            payload["unit_of_measurement"] = json!(unit);
+        }
+        if let Some(device_class) = device_class {
+            payload["device_class"] = json!(device_class);
+        }
+        (format!("{}/{}/{}/config", config.discovery_prefix, component, unique_id), payload)
+    }).collect()
+}
+
+fn encode_remaining_length(packet: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+/// Writes the value prefixed by its length, which MQTT limits to two bytes
+fn encode_string(buf: &mut Vec<u8>, value: &[u8]) -> Result<(), String> {
+    let len = u16::try_from(value.len()).map_err(|_| format!("{} bytes is longer than the {} MQTT allows for a string", value.len(), u16::MAX))?;
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(value);
+    Ok(())
+}
+
+/// Prefixes the body with the packet type and its remaining length
+fn encode_packet(header: u8, body: Vec<u8>) -> Result<Vec<u8>, String> {
+    if body.len() > MAX_REMAINING_LENGTH {
+        return Err(format!("{} bytes is larger than the {} MQTT allows for a packet", body.len(), MAX_REMAINING_LENGTH));
+    }
+    let mut packet = vec![header];
+    encode_remaining_length(&mut packet, body.len());
+    packet.extend(body);
+    Ok(packet)
+}
+
+fn encode_connect(config: &MqttConfig) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+    encode_string(&mut body, b"MQTT")?;
+    body.push(4); // protocol level 3.1.1
+    // clean session, with a retained will marking the server offline
+    let mut flags = 0x02 | 0x04 | 0x20;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    let client_id = format!("{}-{}", env!("CARGO_PKG_NAME"), std::process::id());
+    encode_string(&mut body, client_id.as_bytes())?;
+    encode_string(&mut body, status_topic(config).as_bytes()).map_err(|e| format!("base_topic: {}", e))?;
+    encode_string(&mut body, b"offline")?;
+    if let Some(username) = &config.username {
+        encode_string(&mut body, username.as_bytes()).map_err(|e| format!("username: {}", e))?;
+    }
+    if let Some(password) = &config.password {
+        encode_string(&mut body, password.as_bytes()).map_err(|e| format!("password: {}", e))?;
+    }
+    encode_packet(0x10, body)
+}
+
+fn encode_publish(topic: &str, payload: &[u8], retain: bool) -> Result<Vec<u8>, String> {
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+    encode_string(&mut body, topic.as_bytes())?;
+    body.extend_from_slice(payload);
+    encode_packet(if retain { 0x31 } else { 0x30 }, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(username: Option<&str>, password: Option<&str>) -> MqttConfig {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            username: username.map(str::to_string),
+            password: password.map(str::to_string),
+            base_topic: "ff".to_string(),
+            discovery_prefix: "ha".to_string()
+        }
+    }
+
+    fn remaining_length(len: usize) -> Vec<u8> {
+        let mut packet = Vec::new();
+        encode_remaining_length(&mut packet, len);
+        packet
+    }
+
+    #[test]
+    fn remaining_length_is_variable() {
+        assert_eq!(remaining_length(0), [0x00]);
+        assert_eq!(remaining_length(127), [0x7F]);
+        assert_eq!(remaining_length(128), [0x80, 0x01]);
+        assert_eq!(remaining_length(16_383), [0xFF, 0x7F]);
+        assert_eq!(remaining_length(16_384), [0x80, 0x80, 0x01]);
+        assert_eq!(remaining_length(MAX_REMAINING_LENGTH), [0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn publish_is_encoded() {
+        assert_eq!(encode_publish("a/b", b"on", true).unwrap(), [0x31, 0x07, 0x00, 0x03, b'a', b'/', b'b', b'o', b'n']);
+        assert_eq!(encode_publish("t", b"", false).unwrap(), [0x30, 0x03, 0x00, 0x01, b't']);
+
+        let payload = vec![b'x'; 200];
+        let packet = encode_publish("t", &payload, false).unwrap();
+        assert_eq!(packet[..6], [0x30, 0xCB, 0x01, 0x00, 0x01, b't']);
+        assert_eq!(packet.len(), 3 + 203);
+    }
+
+    #[test]
+    fn oversized_strings_and_packets_are_rejected() {
+        let topic = "t".repeat(u16::MAX as usize + 1);
+        assert!(encode_publish(&topic, b"", false).is_err());
+        assert!(encode_publish(&topic[1..], b"", false).is_ok());
+        assert!(encode_publish("t", &vec![0; MAX_REMAINING_LENGTH], false).is_err());
+
+        let password = "p".repeat(u16::MAX as usize + 1);
+        assert!(encode_connect(&config(None, Some(&password))).unwrap_err().starts_with("password: "));
+    }
+
+    #[test]
+    fn connect_is_encoded() {
+        let client_id = format!("{}-{}", env!("CARGO_PKG_NAME"), std::process::id());
+        let mut body = vec![0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0xE6, 0x00, 0x3C];
+        for value in [client_id.as_bytes(), b"ff/status", b"offline", b"user", b"secret"] {
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(value);
+        }
+        let mut packet = vec![0x10, body.len() as u8];
+        packet.extend(body);
+        assert_eq!(encode_connect(&config(Some("user"), Some("secret"))).unwrap(), packet);
+
+        // Without credentials only clean session and the retained will are set
+        let packet = encode_connect(&config(None, None)).unwrap();
+        assert_eq!(packet[9], 0x26);
+        assert!(packet.ends_with(b"\x00\x09ff/status\x00\x07offline"));
+    }
+
+    #[test]
+    fn discovery_is_configured_per_printer() {
+        let configs = discovery_configs(&config(None, None), "main");
+        let topics: Vec<&str> = configs.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(topics, [
+            "ha/sensor/flashforge_main_nozzle_temperature/config",
+            "ha/sensor/flashforge_main_bed_temperature/config",
+            "ha/sensor/flashforge_main_progress/config",
+            "ha/binary_sensor/flashforge_main_printing/config"
+        ]);
+        let (_, nozzle) = &configs[0];
+        assert_eq!(nozzle["state_topic"], "ff/main/state");
+        assert_eq!(nozzle["unit_of_measurement"], "°C");
+        assert_eq!(nozzle["device_class"], "temperature");
+        assert_eq!(nozzle["device"]["identifiers"], json!(["flashforge_main"]));
+        assert_eq!(nozzle["availability"], json!([{ "topic": "ff/status" }, { "topic": "ff/main/availability" }]));
+        let (_, printing) = &configs[3];
+        assert_eq!(printing["device_class"], "running");
+        assert!(printing.get("unit_of_measurement").is_none());
+        let (_, progress) = &configs[2];
+        assert!(progress.get("device_class").is_none());
+    }
+}
@@ -0,0 +1,203 @@
+//! The daily summary of [crate::config::DigestConfig]. There are no job records, so prints are counted
+//! from the notifications sent since the last digest
+use super::format::format_duration;
+use super::RenderedNotification;
+use crate::manager::PrinterContainer;
+use crate::models::HealthSummary;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use time::macros::format_description;
+use time::{Duration, OffsetDateTime, Time, UtcOffset};
+
+/// A notification that was sent, kept until the next digest
+struct SentNotification {
+    at: OffsetDateTime,
+    printer: String,
+    notification_type: &'static str,
+    elapsed_seconds: Option<u64>
+}
+
+#[derive(Default)]
+pub struct DigestLog {
+    sent: Mutex<Vec<SentNotification>>
+}
+
+impl DigestLog {
+    pub fn record(&self, printer: &str, notification_type: &'static str, elapsed_seconds: Option<u64>) {
+        self.sent.lock().unwrap().push(SentNotification {
+            at: OffsetDateTime::now_utc(),
+            printer: printer.to_string(),
+            notification_type,
+            elapsed_seconds
+        });
+    }
+
+    /// Removes and returns the notifications sent before until
+    fn take(&self, until: OffsetDateTime) -> Vec<SentNotification> {
+        let mut sent = self.sent.lock().unwrap();
+        let (taken, kept) = std::mem::take(&mut *sent).into_iter().partition(|notification| notification.at < until);
+        *sent = kept;
+        taken
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct PrinterDigest {
+    pub name: String,
+    pub completed: u32,
+    pub failed: u32,
+    pub cancelled: u32,
+    pub temperature_alerts: u32,
+    /// Time the completed, failed and cancelled prints took
+    pub print_seconds: u64,
+    pub offline: bool,
+    pub printing: bool
+}
+
+impl PrinterDigest {
+    fn new(printer: &PrinterContainer) -> Self {
+        Self {
+            name: printer.name().to_string(),
+            offline: printer.health_summary() == HealthSummary::Offline,
+            printing: printer.is_printing(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Counts the notifications of each printer, printers that were removed since are still listed
+fn summarize(sent: &[SentNotification], printers: Vec<PrinterDigest>) -> Vec<PrinterDigest> {
+    let mut printers: HashMap<String, PrinterDigest> = printers.into_iter().map(|printer| (printer.name.clone(), printer)).collect();
+    for notification in sent {
+        let printer = printers.entry(notification.printer.clone())
+            .or_insert_with(|| PrinterDigest { name: notification.printer.clone(), ..Default::default() });
+        match notification.notification_type {
+            "print_complete" => printer.completed += 1,
+            // print_error is sent mid-print, print_failed follows once the job ended
+            "print_failed" => printer.failed += 1,
+            "print_cancelled" => printer.cancelled += 1,
+            "temperature_alert" => printer.temperature_alerts += 1,
+            _ => continue
+        }
+        printer.print_seconds += notification.elapsed_seconds.unwrap_or_default();
+    }
+    let mut printers: Vec<PrinterDigest> = printers.into_values().collect();
+    printers.sort_by(|a, b| a.name.cmp(&b.name));
+    printers
+}
+
+/// The subject and message of the digest of since until until, dated in the digest's timezone
+fn render(printers: &[PrinterDigest], since: OffsetDateTime, offset: UtcOffset) -> RenderedNotification {
+    let date = since.to_offset(offset).date().format(format_description!("[year]-[month]-[day]")).unwrap_or_default();
+    let subject = format!("Daily summary for {}", date);
+    let (completed, failed) = printers.iter().fold((0, 0), |(completed, failed), printer| (completed + printer.completed, failed + printer.failed));
+    let print_seconds = printers.iter().map(|printer| printer.print_seconds).sum();
+
+    let mut message = String::new();
+    writeln!(message, "Completed prints: {}, failed: {}, printing for {}", completed, failed, format_duration(print_seconds)).unwrap();
+    writeln!(message).unwrap();
+    for printer in printers {
+        write!(message, "{}: {} completed, {} failed, printing for {}", printer.name, printer.completed, printer.failed, format_duration(printer.print_seconds)).unwrap();
+        if printer.cancelled > 0 {
+            write!(message, ", {} cancelled", printer.cancelled).unwrap();
+        }
+        if printer.temperature_alerts > 0 {
+            write!(message, ", {} temperature alerts", printer.temperature_alerts).unwrap();
+        }
+        match (printer.offline, printer.printing) {
+            (true, _) => write!(message, " (offline)").unwrap(),
+            (false, true) => write!(message, " (printing)").unwrap(),
+            _ => {}
+        }
+        writeln!(message).unwrap();
+    }
+    let offline: Vec<&str> = printers.iter().filter(|printer| printer.offline).map(|printer| printer.name.as_str()).collect();
+    if !offline.is_empty() {
+        writeln!(message, "\nOffline: {}", offline.join(", ")).unwrap();
+    }
+
+    RenderedNotification {
+        printer_name: env!("CARGO_PKG_NAME").to_string(),
+        notification_type: "digest",
+        host: String::new(),
+        template_vars: HashMap::from([
+            ("notification.type", "digest".to_string()),
+            ("digest.subject", subject.clone()),
+            ("digest.summary", message.clone()),
+            ("digest.completed", completed.to_string()),
+            ("digest.failed", failed.to_string()),
+            ("digest.cancelled", printers.iter().map(|printer| printer.cancelled).sum::<u32>().to_string()),
+            ("digest.offline", offline.join(", ")),
+        ]),
+        subject,
+        message,
+        file: None,
+        status: None,
+        progress_percent: None,
+        elapsed_seconds: Some(print_seconds),
+        image_name: super::CAMERA_IMAGE_NAME.to_string()
+    }
+}
+
+/// Counts the notifications sent before until and renders the digest, taking them out of the log
+pub(super) fn build(log: &DigestLog, printers: &[PrinterContainer], since: OffsetDateTime, until: OffsetDateTime, offset: UtcOffset) -> RenderedNotification {
+    let printers = summarize(&log.take(until), printers.iter().map(PrinterDigest::new).collect());
+    render(&printers, since, offset)
+}
+
+/// The next time the clock reads time in the offset, after now
+pub fn next_run(now: OffsetDateTime, time: Time, offset: UtcOffset) -> OffsetDateTime {
+    let local = now.to_offset(offset);
+    let run = local.replace_time(time);
+    if run <= local { run + Duration::days(1) } else { run }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::{datetime, offset, time};
+
+    fn sent(printer: &str, notification_type: &'static str, elapsed_seconds: Option<u64>) -> SentNotification {
+        SentNotification { at: OffsetDateTime::now_utc(), printer: printer.to_string(), notification_type, elapsed_seconds }
+    }
+
+    #[test]
+    fn summarizes_the_notifications_sent() {
+        let printers = vec![
+            PrinterDigest { name: "main".to_string(), printing: true, ..Default::default() },
+            PrinterDigest { name: "second".to_string(), offline: true, ..Default::default() },
+        ];
+        let sent = [
+            sent("main", "print_complete", Some(3600)),
+            sent("main", "print_complete", Some(125)),
+            sent("main", "temperature_recovered", None),
+            sent("second", "print_error", None),
+            sent("second", "print_failed", Some(600)),
+            sent("removed", "print_cancelled", Some(60)),
+            sent("second", "temperature_alert", None),
+            sent("removed", "print_complete", None),
+        ];
+        let printers = summarize(&sent, printers);
+        assert_eq!(printers[0], PrinterDigest { name: "main".to_string(), completed: 2, print_seconds: 3725, printing: true, ..Default::default() });
+        assert_eq!(printers.iter().map(|printer| printer.name.as_str()).collect::<Vec<_>>(), ["main", "removed", "second"]);
+
+        let digest = render(&printers, datetime!(2024-06-01 23:30 UTC), offset!(+2));
+        assert_eq!(digest.subject, "Daily summary for 2024-06-02");
+        assert_eq!(digest.message, "Completed prints: 3, failed: 1, printing for 1h 13m\n\n\
+            main: 2 completed, 0 failed, printing for 1h 2m (printing)\n\
+            removed: 1 completed, 0 failed, printing for 1m, 1 cancelled\n\
+            second: 0 completed, 1 failed, printing for 10m, 1 temperature alerts (offline)\n\
+            \nOffline: second\n");
+    }
+
+    #[test]
+    fn runs_at_the_next_time_of_day() {
+        let now = datetime!(2024-06-01 05:00 UTC);
+        assert_eq!(next_run(now, time!(08:00), UtcOffset::UTC), datetime!(2024-06-01 08:00 UTC));
+        // It is 07:00 at +02:00
+        assert_eq!(next_run(now, time!(08:00), offset!(+2)), datetime!(2024-06-01 06:00 UTC));
+        assert_eq!(next_run(now, time!(06:00), offset!(+2)), datetime!(2024-06-02 04:00 UTC));
+        assert_eq!(next_run(now, time!(05:00), UtcOffset::UTC), datetime!(2024-06-02 05:00 UTC));
+    }
+}
@@ -0,0 +1,126 @@
+//! Payloads of webhooks without a template, one function per [WebhookFormat]
+use super::RenderedNotification;
+use crate::config::WebhookFormat;
+use serde_json::{json, Value};
+
+pub fn payload(format: WebhookFormat, notification: &RenderedNotification) -> Value {
+    match format {
+        WebhookFormat::Discord => discord(notification),
+        WebhookFormat::Slack => slack(notification),
+        WebhookFormat::Generic => generic(notification)
+    }
+}
+
+/// An embed, with the snapshot attached as printer_image.jpg (or .png for a thumbnail)
+pub fn discord(notification: &RenderedNotification) -> Value {
+    json!({
+        "username": notification.printer_name,
+        "embeds": [
+            {
+                "title": notification.subject,
+                "description": notification.message,
+                "image": {
+                    "url": format!("attachment://{}", notification.image_name)
+                }
+            }
+        ]
+    })
+}
+
+/// Block Kit message with a header and the printer, file and duration as fields.
+/// Incoming webhooks can't attach files, the snapshot is uploaded separately
+pub fn slack(notification: &RenderedNotification) -> Value {
+    let field = |name: &str, value: &str| json!({ "type": "mrkdwn", "text": format!("*{}*\n{}", name, value) });
+    let mut fields = vec![field("Printer", &notification.printer_name)];
+    if let Some(file) = &notification.file {
+        fields.push(field("File", file));
+    }
+    if let Some(elapsed) = notification.elapsed_seconds {
+        fields.push(field("Duration", &format_duration(elapsed)));
+    }
+    json!({
+        // Shown in notifications and by clients without Block Kit
+        "text": notification.subject,
+        "blocks": [
+            { "type": "header", "text": { "type": "plain_text", "text": notification.subject } },
+            { "type": "section", "text": { "type": "plain_text", "text": notification.message.trim_end() } },
+            { "type": "section", "fields": fields }
+        ]
+    })
+}
+
+/// The notification's fields as they are, for receivers that do their own formatting
+pub fn generic(notification: &RenderedNotification) -> Value {
+    json!({
+        "type": notification.notification_type,
+        "printer": notification.printer_name,
+        "host": notification.host,
+        "subject": notification.subject,
+        "message": notification.message,
+        "file": notification.file,
+        "status": notification.status,
+        "progress_percent": notification.progress_percent,
+        "elapsed_seconds": notification.elapsed_seconds
+    })
+}
+
+/// 3725 seconds is "1h 2m"
+pub(super) fn format_duration(seconds: u64) -> String {
+    match (seconds / 3600, seconds / 60 % 60) {
+        (0, 0) => format!("{}s", seconds),
+        (0, minutes) => format!("{}m", minutes),
+        (hours, minutes) => format!("{}h {}m", hours, minutes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::tests::rendered as notification;
+
+    #[test]
+    fn discord_payload() {
+        assert_eq!(discord(&notification()), json!({
+            "username": "main",
+            "embeds": [{
+                "title": "Print complete on main",
+                "description": "File: benchy.gx\nAddress: 192.168.1.89\n",
+                "image": { "url": "attachment://printer_image.jpg" }
+            }]
+        }));
+    }
+
+    #[test]
+    fn slack_payload() {
+        assert_eq!(slack(&notification()), json!({
+            "text": "Print complete on main",
+            "blocks": [
+                { "type": "header", "text": { "type": "plain_text", "text": "Print complete on main" } },
+                { "type": "section", "text": { "type": "plain_text", "text": "File: benchy.gx\nAddress: 192.168.1.89" } },
+                { "type": "section", "fields": [
+                    { "type": "mrkdwn", "text": "*Printer*\nmain" },
+                    { "type": "mrkdwn", "text": "*File*\nbenchy.gx" },
+                    { "type": "mrkdwn", "text": "*Duration*\n1h 2m" }
+                ] }
+            ]
+        }));
+        // Fields the printer doesn't know are left out
+        let idle = RenderedNotification { file: None, elapsed_seconds: None, ..notification() };
+        assert_eq!(slack(&idle)["blocks"][2]["fields"], json!([{ "type": "mrkdwn", "text": "*Printer*\nmain" }]));
+    }
+
+    #[test]
+    fn generic_payload() {
+        assert_eq!(generic(&notification()), json!({
+            "type": "print_complete",
+            "printer": "main",
+            "host": "192.168.1.89",
+            "subject": "Print complete on main",
+            "message": "File: benchy.gx\nAddress: 192.168.1.89\n",
+            "file": "benchy.gx",
+            "status": "READY",
+            "progress_percent": 100,
+            "elapsed_seconds": 3725
+        }));
+    }
+}
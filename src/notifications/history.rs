@@ -0,0 +1,139 @@
+//! The notifications sent and their results, kept in memory and, with [notifications.history] path, in a JSON lines file
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use log::warn;
+use crate::config::NotificationHistoryConfig;
+use crate::models::NotificationRecord;
+
+/// Shared by the notifier and the history route
+#[derive(Clone)]
+pub struct NotificationHistory(Arc<HistoryLog>);
+
+struct HistoryLog {
+    records: Mutex<Records>,
+    max_entries: usize,
+    path: Option<PathBuf>
+}
+
+struct Records {
+    /// Oldest first
+    kept: VecDeque<NotificationRecord>,
+    /// Once the file has twice max_entries lines it is rewritten with the kept records, so it stays bounded too
+    lines_in_file: usize
+}
+
+impl NotificationHistory {
+    /// Loads the last records of the file, if there is one
+    pub fn new(config: Option<&NotificationHistoryConfig>) -> Self {
+        let default = NotificationHistoryConfig::default();
+        let config = config.unwrap_or(&default);
+        let mut kept = VecDeque::new();
+        let mut lines_in_file = 0;
+        if let Some(path) = &config.path {
+            match read_records(path) {
+                Ok((records, lines)) => {
+                    kept.extend(records);
+                    lines_in_file = lines;
+                },
+                Err(e) => warn!("Could not read notification history {}: {}", path.display(), e)
+            }
+        }
+        while kept.len() > config.max_entries {
+            kept.pop_front();
+        }
+        Self(Arc::new(HistoryLog {
+            records: Mutex::new(Records { kept, lines_in_file }),
+            max_entries: config.max_entries,
+            path: config.path.clone()
+        }))
+    }
+
+    pub async fn record(&self, record: NotificationRecord) {
+        let log = self.0.clone();
+        match tokio::task::spawn_blocking(move || log.push(record)).await {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => warn!("Could not save notification history: {}", e),
+            Err(e) => warn!("Could not save notification history: {}", e)
+        }
+    }
+
+    /// Newest first, only those of the printer when set
+    pub fn recent(&self, printer: Option<&str>, limit: usize) -> Vec<NotificationRecord> {
+        self.0.records.lock().unwrap().kept.iter().rev()
+            .filter(|record| printer.is_none_or(|printer| record.printer.as_deref().is_some_and(|id| id.eq_ignore_ascii_case(printer))))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+impl HistoryLog {
+    fn push(&self, record: NotificationRecord) -> Result<(), String> {
+        let mut records = self.records.lock().unwrap();
+        let line = serde_json::to_string(&record).map_err(|e| e.to_string())?;
+        records.kept.push_back(record);
+        while records.kept.len() > self.max_entries {
+            records.kept.pop_front();
+        }
+        let Some(path) = &self.path else { return Ok(()) };
+        if records.lines_in_file >= 2 * self.max_entries {
+            let mut contents = String::new();
+            for record in &records.kept {
+                contents.push_str(&serde_json::to_string(record).map_err(|e| e.to_string())?);
+                contents.push('\n');
+            }
+            std::fs::write(path, contents).map_err(|e| e.to_string())?;
+            records.lines_in_file = records.kept.len();
+        } else {
+            let mut file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| e.to_string())?;
+            writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+            records.lines_in_file += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Every record and the number of lines, skipping lines that can't be parsed (such as one cut off by a crash)
+fn read_records(path: &Path) -> Result<(Vec<NotificationRecord>, usize), String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e.to_string())
+    };
+    let lines: Vec<String> = BufReader::new(file).lines().map_while(Result::ok).collect();
+    let records = lines.iter().filter_map(|line| serde_json::from_str(line).ok()).collect();
+    Ok((records, lines.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::OffsetDateTime;
+
+    fn record(printer: &str, n: usize) -> NotificationRecord {
+        NotificationRecord { time: OffsetDateTime::now_utc(), printer: Some(printer.to_string()), notification_type: format!("type_{}", n), results: Vec::new() }
+    }
+
+    #[tokio::test]
+    async fn keeps_the_newest_records_across_restarts() {
+        let path = std::env::temp_dir().join(format!("flashforge-notification-history-{}.jsonl", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let config = NotificationHistoryConfig { path: Some(path.clone()), max_entries: 3 };
+        let history = NotificationHistory::new(Some(&config));
+        for n in 0..8 {
+            history.record(record(if n % 2 == 0 { "main" } else { "side" }, n)).await;
+        }
+        let types = |records: Vec<NotificationRecord>| records.into_iter().map(|record| record.notification_type).collect::<Vec<_>>();
+        assert_eq!(types(history.recent(None, 10)), ["type_7", "type_6", "type_5"]);
+        assert_eq!(types(history.recent(Some("MAIN"), 10)), ["type_6"]);
+
+        let reloaded = NotificationHistory::new(Some(&config));
+        assert_eq!(types(reloaded.recent(None, 2)), ["type_7", "type_6"]);
+        // Rewritten once it reached twice max_entries lines
+        assert!(std::fs::read_to_string(&path).unwrap().lines().count() <= 2 * config.max_entries);
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,1012 @@
+use crate::config::{ConfigManager, NotificationDestinations, SlackUploadConfig, WebhookConfig, WebhookFormat};
+use crate::gcode::Thumbnail;
+use crate::manager::PrinterContainer;
+use crate::models::{DestinationKind, MachineStatus, NotificationRecord, NotificationResult, NotificationResultStatus, TemperatureMeasurement, WebhookDelivery};
+use crate::printer::Printer;
+use crate::util::{render_template, ImageFormat};
+use crate::version;
+use bytes::Bytes;
+use flashforge_protocol::models::PrinterProgress;
+use digest::DigestLog;
+use history::NotificationHistory;
+use templates::Templates;
+
+use log::{debug, error, trace, warn};
+#[cfg(feature = "smtp")]
+use mail_send::mail_builder::mime::BodyPart;
+#[cfg(feature = "smtp")]
+use mail_send::mail_builder::MessageBuilder;
+use rand::distributions::{Alphanumeric, DistString};
+use reqwest::header::CONTENT_TYPE;
+use ring::hmac;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+pub mod digest;
+mod format;
+pub mod history;
+mod templates;
+
+#[cfg(feature = "camera")]
+static SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Header with the HMAC of the body, for webhooks with a secret
+const SIGNATURE_HEADER: &str = "X-Flashforge-Signature";
+/// Attachment name of camera frames, and of the image Discord embeds refer to when there is none
+const CAMERA_IMAGE_NAME: &str = "printer_image.jpg";
+/// Jobs waiting to be sent, further notifications are dropped when full
+const QUEUE_SIZE: usize = 32;
+
+/// A print that is no longer on the printer, as last seen by the watcher thread
+#[derive(Debug, Clone, PartialEq)]
+pub struct EndedJob {
+    pub file: String,
+    pub progress_percent: u8,
+    pub elapsed_seconds: Option<u64>
+}
+
+#[derive(Debug, Clone)]
+pub enum NotificationType {
+    /// A print was first seen near its start, with the thumbnail of its file when there is one
+    PrintStarted,
+    PrintComplete,
+    /// Sent as soon as the printer reports an error state, [NotificationType::PrintFailed] follows once the job ended
+    PrintError,
+    /// The file was cleared or the printer went idle before the print was done
+    PrintCancelled(EndedJob),
+    /// The job ended in an error state, or the printer came back idle after being unreachable (note)
+    PrintFailed { job: EndedJob, note: Option<String> },
+    TemperatureAlert { sensor: String, reason: String, measurement: TemperatureMeasurement },
+    TemperatureRecovered { sensor: String, measurement: TemperatureMeasurement }
+}
+
+impl NotificationType {
+    /// Every [NotificationType::name]
+    pub const NAMES: [&'static str; 7] = ["print_started", "print_complete", "print_error", "print_cancelled", "print_failed",
+        "temperature_alert", "temperature_recovered"];
+
+    /// Creates a notification from its name, used for sending test notifications.
+    /// Temperature notifications are given placeholder measurements
+    pub fn from_name(name: &str) -> Option<NotificationType> {
+        let measurement = TemperatureMeasurement { target: 0.0, current: 0.0 };
+        let job = EndedJob { file: "test.gx".to_string(), progress_percent: 50, elapsed_seconds: Some(3600) };
+        match name {
+            "print_started" => Some(NotificationType::PrintStarted),
+            "print_complete" => Some(NotificationType::PrintComplete),
+            "print_error" => Some(NotificationType::PrintError),
+            "print_cancelled" => Some(NotificationType::PrintCancelled(job)),
+            "print_failed" => Some(NotificationType::PrintFailed { job, note: Some("Test notification".to_string()) }),
+            "temperature_alert" => Some(NotificationType::TemperatureAlert { sensor: "T0".to_string(), reason: "Test notification".to_string(), measurement }),
+            "temperature_recovered" => Some(NotificationType::TemperatureRecovered { sensor: "T0".to_string(), measurement }),
+            _ => None
+        }
+    }
+
+    /// Name of the notification type, as used in templates
+    pub fn name(&self) -> &'static str {
+        match self {
+            NotificationType::PrintStarted => "print_started",
+            NotificationType::PrintComplete => "print_complete",
+            NotificationType::PrintError => "print_error",
+            NotificationType::PrintCancelled(_) => "print_cancelled",
+            NotificationType::PrintFailed { .. } => "print_failed",
+            NotificationType::TemperatureAlert { .. } => "temperature_alert",
+            NotificationType::TemperatureRecovered { .. } => "temperature_recovered"
+        }
+    }
+
+    /// The job the notification is about when it already left the printer
+    fn ended_job(&self) -> Option<&EndedJob> {
+        match self {
+            NotificationType::PrintCancelled(job) | NotificationType::PrintFailed { job, .. } => Some(job),
+            _ => None
+        }
+    }
+
+    fn elapsed_seconds(&self, printer: &Printer) -> Option<u64> {
+        match self.ended_job() {
+            Some(job) => job.elapsed_seconds,
+            None => printer.job().and_then(|job| job.elapsed_seconds)
+        }
+    }
+
+    /// Returns the variables available to webhook and notification templates, those that don't apply to the type are empty
+    pub fn get_template_vars(&self, printer: &Printer, state: &CapturedState) -> HashMap<&'static str, String> {
+        let percent = self.ended_job().map(|job| job.progress_percent).or(state.progress_percent()).map(|percent| percent.to_string());
+        let file = self.ended_job().map(|job| job.file.clone()).or(printer.current_file());
+        let elapsed = self.elapsed_seconds(printer);
+        let progress = state.progress.as_ref();
+        let progress_var = |value: fn(&PrinterProgress) -> u32| progress.map(value).map(|value| value.to_string()).unwrap_or_default();
+        let (sensor, reason, measurement) = match self {
+            NotificationType::TemperatureAlert { sensor, reason, measurement } => (Some(sensor), Some(reason), Some(measurement)),
+            NotificationType::TemperatureRecovered { sensor, measurement } => (Some(sensor), None, Some(measurement)),
+            _ => (None, None, None)
+        };
+        let note = match self {
+            NotificationType::PrintFailed { note, .. } => note.clone(),
+            _ => None
+        };
+        HashMap::from([
+            ("printer.name", printer.name().to_string()),
+            ("printer.display_name", printer.display_name()),
+            ("printer.location", printer.labels().location.unwrap_or_default()),
+            ("printer.host", printer.host().to_string()),
+            // Kept for templates written before hostnames were supported, same as printer.host
+            ("printer.ip", printer.host().to_string()),
+            ("file", file.unwrap_or_default()),
+            ("status", state.machine_status.as_ref().map(|s| s.to_string()).unwrap_or_default()),
+            ("progress.percent", percent.unwrap_or_default()),
+            ("progress.layer.current", progress_var(|progress| progress.layer.current)),
+            ("progress.layer.total", progress_var(|progress| progress.layer.total)),
+            ("progress.byte.current", progress_var(|progress| progress.byte.current)),
+            ("progress.byte.total", progress_var(|progress| progress.byte.total)),
+            ("elapsed", elapsed.map(format::format_duration).unwrap_or_default()),
+            ("elapsed.seconds", elapsed.map(|seconds| seconds.to_string()).unwrap_or_default()),
+            ("note", note.unwrap_or_default()),
+            ("sensor", sensor.cloned().unwrap_or_default()),
+            ("reason", reason.cloned().unwrap_or_default()),
+            ("temperature.current", measurement.map(|m| format!("{:.1}", m.current)).unwrap_or_default()),
+            ("temperature.target", measurement.map(|m| format!("{:.1}", m.target)).unwrap_or_default()),
+            ("notification.type", self.name().to_string()),
+        ])
+    }
+
+    pub fn get_subject(&self, printer: &Printer, state: &CapturedState, templates: &Templates) -> String {
+        templates.subject(self.name(), &self.get_template_vars(printer, state))
+    }
+
+    pub fn get_message(&self, printer: &Printer, state: &CapturedState, templates: &Templates) -> String {
+        templates.body(self.name(), &self.get_template_vars(printer, state))
+    }
+}
+
+/// A notification that has been queued to be sent by the notification worker
+pub struct NotificationJob {
+    pub printer_id: String,
+    pub printer: PrinterContainer,
+    pub notification_type: NotificationType,
+    /// Image to attach, if None a fresh snapshot is taken when the job is processed
+    pub snapshot: Option<Snapshot>,
+    /// Status and progress to report, if None they are read from the printer when the job is processed
+    pub state: Option<CapturedState>
+}
+
+/// The machine status and progress of a printer at one point in time
+#[derive(Debug, Clone, Default)]
+pub struct CapturedState {
+    pub machine_status: Option<MachineStatus>,
+    pub progress: Option<PrinterProgress>
+}
+
+impl CapturedState {
+    /// The last status and progress polled by the watch thread
+    pub fn of(printer: &Printer) -> Self {
+        Self { machine_status: printer.machine_status(), progress: printer.progress() }
+    }
+
+    fn progress_percent(&self) -> Option<u8> {
+        self.progress.as_ref().and_then(|progress| progress.byte.percent())
+    }
+}
+
+/// A camera frame or thumbnail to attach, with when it was received
+pub struct Snapshot {
+    /// Shared by every destination, which only copy it into a body when they have to
+    pub image: Bytes,
+    pub format: ImageFormat,
+    pub captured: Instant
+}
+
+impl Snapshot {
+    /// The thumbnail of the file being printed
+    pub fn thumbnail(thumbnail: Thumbnail) -> Self {
+        Self { image: thumbnail.image, format: thumbnail.format, captured: Instant::now() }
+    }
+
+    /// Name of the attachment, which Discord embeds refer to
+    pub fn file_name(&self) -> String {
+        format!("printer_image.{}", self.format.extension())
+    }
+}
+
+impl NotificationJob {
+    pub fn new(printer: &PrinterContainer, notification_type: NotificationType) -> Self {
+        Self {
+            printer_id: printer.name().to_string(),
+            printer: printer.clone(),
+            notification_type,
+            snapshot: None,
+            state: None
+        }
+    }
+}
+
+/// Everything needed from the printer to send a notification, so the printer does not stay locked while sending
+struct RenderedNotification {
+    printer_name: String,
+    notification_type: &'static str,
+    host: String,
+    subject: String,
+    message: String,
+    file: Option<String>,
+    status: Option<String>,
+    progress_percent: Option<u8>,
+    elapsed_seconds: Option<u64>,
+    template_vars: HashMap<&'static str, String>,
+    /// Name of the attached image
+    image_name: String
+}
+
+impl RenderedNotification {
+    fn new(printer: &Printer, notification_type: &NotificationType, state: &CapturedState, templates: &Templates) -> Self {
+        let ended_job = notification_type.ended_job();
+        Self {
+            printer_name: printer.name().to_string(),
+            notification_type: notification_type.name(),
+            host: printer.host().to_string(),
+            subject: notification_type.get_subject(printer, state, templates),
+            message: notification_type.get_message(printer, state, templates),
+            file: ended_job.map(|job| job.file.clone()).or(printer.current_file()),
+            status: state.machine_status.as_ref().map(|status| status.to_string()),
+            progress_percent: ended_job.map(|job| job.progress_percent).or(state.progress_percent()),
+            elapsed_seconds: notification_type.elapsed_seconds(printer),
+            template_vars: notification_type.get_template_vars(printer, state),
+            image_name: CAMERA_IMAGE_NAME.to_string()
+        }
+    }
+}
+
+/// Delivers notifications to the destinations configured for their type
+pub struct Notifier {
+    config: Arc<ConfigManager>,
+    /// Shared by every webhook delivery, so connections and TLS sessions are reused
+    client: reqwest::Client,
+    deliveries: std::sync::Mutex<HashMap<String, WebhookDelivery>>, // Last delivery attempt per webhook url (key)
+    /// Notifications sent since the last digest, when one is configured
+    digest_log: DigestLog,
+    history: NotificationHistory,
+    /// Subjects and bodies of the notifications, read once at startup
+    templates: Templates
+}
+
+impl Notifier {
+    pub fn new(config: Arc<ConfigManager>) -> Self {
+        Self {
+            client: webhook_client(&config),
+            history: NotificationHistory::new(config.notification_history()),
+            templates: config.notification_templates_path().map(Templates::load).unwrap_or_default(),
+            config,
+            deliveries: std::sync::Mutex::new(HashMap::new()),
+            digest_log: DigestLog::default()
+        }
+    }
+
+    /// Sends the notification to all its configured destinations, returning the result of each destination.
+    /// The printer's current status and progress are reported unless state is given.
+    /// When dry_run is set, nothing is sent and the rendered subjects and bodies are returned instead
+    pub async fn send_notification(&self, printer: &PrinterContainer, notification_type: NotificationType, snapshot: Option<Snapshot>, state: Option<CapturedState>, dry_run: bool) -> Vec<NotificationResult> {
+        let mut results = Vec::new();
+        if let Some(notification) = self.config.get_notification_destinations(&notification_type) {
+            let image = match snapshot {
+                Some(snapshot) => Some(snapshot),
+                None if dry_run => None,
+                None => self.image_for(printer, &notification_type).await
+            }.filter(|snapshot| self.is_recent(printer, snapshot));
+            let state = state.unwrap_or_else(|| CapturedState::of(printer));
+            let mut rendered = RenderedNotification::new(printer, &notification_type, &state, &self.templates);
+            if let Some(image) = &image {
+                rendered.image_name = image.file_name();
+            }
+            if image.is_none() && !dry_run && cfg!(feature = "camera") && printer.capabilities().camera {
+                rendered.message.push_str("Camera unavailable, no image attached\n");
+            }
+
+            debug!("Sending notification: {:?}", notification_type);
+            results = self.send_to(notification, &rendered, image.as_ref(), dry_run).await;
+            if !dry_run {
+                self.record(Some(printer.name()), notification_type.name(), &results).await;
+            }
+        }
+        if !dry_run && self.config.digest().is_some() {
+            self.digest_log.record(printer.name(), notification_type.name(), notification_type.elapsed_seconds(printer));
+        }
+        results
+    }
+
+    /// Sends the digest of the notifications sent from since until until, with the snapshot of a printer that is still printing
+    pub async fn send_digest(&self, printers: &[PrinterContainer], since: OffsetDateTime, until: OffsetDateTime) -> Vec<NotificationResult> {
+        let Some(config) = self.config.digest() else { return Vec::new() };
+        let mut rendered = digest::build(&self.digest_log, printers, since, until, config.offset().unwrap_or(time::UtcOffset::UTC));
+        let mut image = None;
+        for printer in printers.iter().filter(|printer| printer.is_printing()) {
+            image = self.latest_image(printer).await.filter(|snapshot| self.is_recent(printer, snapshot));
+            if image.is_some() {
+                break;
+            }
+        }
+        if let Some(image) = &image {
+            rendered.image_name = image.file_name();
+        }
+        debug!("Sending digest: {}", rendered.subject);
+        let results = self.send_to(&config.destinations, &rendered, image.as_ref(), false).await;
+        self.record(None, "digest", &results).await;
+        results
+    }
+
+    async fn record(&self, printer: Option<&str>, notification_type: &str, results: &[NotificationResult]) {
+        self.history.record(NotificationRecord {
+            time: OffsetDateTime::now_utc(),
+            printer: printer.map(str::to_string),
+            notification_type: notification_type.to_string(),
+            results: results.to_vec()
+        }).await;
+    }
+
+    pub fn history(&self) -> &NotificationHistory {
+        &self.history
+    }
+
+    /// A fresh snapshot, or the last frame received if the camera did not answer in time
+    #[cfg(feature = "camera")]
+    async fn latest_image(&self, printer: &Printer) -> Option<Snapshot> {
+        if !printer.capabilities().camera {
+            return None;
+        }
+        // The camera task never replies if the camera is unreachable
+        match tokio::time::timeout(SNAPSHOT_TIMEOUT, printer.camera().snapshot()).await {
+            Ok(Ok(image)) => return Some(Snapshot { image: image.into(), format: ImageFormat::Jpeg, captured: Instant::now() }),
+            Ok(Err(e)) => debug!("printer/{} snapshot for notification failed: {}", printer.name(), e),
+            Err(_) => debug!("printer/{} snapshot for notification timed out", printer.name())
+        }
+        printer.camera().last_image().map(|(image, captured)| Snapshot { image: image.into(), format: ImageFormat::Jpeg, captured })
+    }
+
+    /// With notifications.attach_gif, print errors attach the GIF of the last frames instead of a still
+    #[cfg(feature = "camera")]
+    async fn image_for(&self, printer: &Printer, notification_type: &NotificationType) -> Option<Snapshot> {
+        if self.config.attach_gif() && matches!(notification_type, NotificationType::PrintError) && printer.capabilities().camera {
+            match printer.camera().gif().await {
+                Ok((image, captured)) => return Some(Snapshot { image: image.into(), format: ImageFormat::Gif, captured }),
+                Err(e) => debug!("printer/{} GIF for notification failed, attaching a still instead: {}", printer.name(), e)
+            }
+        }
+        self.latest_image(printer).await
+    }
+
+    /// Frames older than camera.max_image_age_secs would show an earlier print
+    fn is_recent(&self, printer: &Printer, snapshot: &Snapshot) -> bool {
+        let age = snapshot.captured.elapsed();
+        if age > self.config.camera().max_image_age() {
+            debug!("printer/{} last frame is {}s old, not attaching it", printer.name(), age.as_secs());
+            return false;
+        }
+        true
+    }
+
+    /// Without the camera feature notifications are sent without an image
+    #[cfg(not(feature = "camera"))]
+    async fn latest_image(&self, _printer: &Printer) -> Option<Snapshot> {
+        None
+    }
+
+    #[cfg(not(feature = "camera"))]
+    async fn image_for(&self, _printer: &Printer, _notification_type: &NotificationType) -> Option<Snapshot> {
+        None
+    }
+
+    /// Sends to the emails and every webhook at the same time, the results are in the order of the destinations
+    async fn send_to(&self, destinations: &NotificationDestinations, rendered: &RenderedNotification, image: Option<&Snapshot>, dry_run: bool) -> Vec<NotificationResult> {
+        // Disabled destinations are left out as if they were not configured
+        let emails: Vec<&str> = destinations.emails.iter().flatten()
+            .filter(|email| email.enabled.get())
+            .map(|email| email.address.as_str())
+            .collect();
+        let webhooks: Vec<&WebhookConfig> = destinations.webhooks.iter().flatten()
+            .filter(|webhook| webhook.enabled.get())
+            .collect();
+        debug!("sending to {} emails and {} webhooks", emails.len(), webhooks.len());
+        let send_emails = async {
+            if emails.is_empty() { None } else { Some(self.send_email_notifications(rendered, image, emails, dry_run).await) }
+        };
+        let (email_result, webhook_results) = futures::join!(send_emails, self.send_webhook_notifications(rendered, image, &webhooks, dry_run));
+        email_result.into_iter().chain(webhook_results).collect()
+    }
+
+    async fn send_email_notifications(&self, rendered: &RenderedNotification, image: Option<&Snapshot>, emails: Vec<&str>, dry_run: bool) -> NotificationResult {
+        let subject = rendered.subject.clone();
+        let body = rendered.message.clone();
+        let mut result = NotificationResult {
+            kind: DestinationKind::Email,
+            destination: emails.join(", "),
+            status: NotificationResultStatus::DryRun,
+            error: None,
+            subject: None,
+            body: None,
+        };
+        if dry_run {
+            result.subject = Some(subject);
+            result.body = Some(body);
+            return result;
+        }
+        let timeout = self.config.smtp().map(|smtp| smtp.timeout()).unwrap_or_default();
+        let sent = tokio::time::timeout(timeout, self.send_email(subject, body, image, emails)).await
+            .unwrap_or_else(|_| Err(format!("timed out after {}s", timeout.as_secs())));
+        match sent {
+            Ok(()) => {
+                trace!("Sent notification {} for printer {}", rendered.notification_type, rendered.printer_name);
+                result.status = NotificationResultStatus::Sent;
+            },
+            Err(e) => {
+                error!("Failed to send notification {} for printer {} by email: {}", rendered.notification_type, rendered.printer_name, e);
+                result.status = NotificationResultStatus::Failed;
+                result.error = Some(e);
+            }
+        }
+        result
+    }
+
+    #[cfg(feature = "smtp")]
+    async fn send_email(&self, subject: String, body: String, image: Option<&Snapshot>, emails: Vec<&str>) -> Result<(), String> {
+        let Some(mailer) = self.config.mailer() else {
+            return Err("SMTP is not configured".to_string());
+        };
+        let send_user = &self.config.smtp().unwrap().user;
+        trace!("smtp configured, sending from {}", send_user);
+        let mut builder = MessageBuilder::new()
+            .from(send_user.as_str())
+            .text_body(body)
+            .subject(subject);
+        if let Some(image) = image {
+            builder = builder.attachment(image.format.content_type(), image.file_name(), BodyPart::from(image.image.to_vec()));
+        }
+        for to_email in emails {
+            builder = builder.bcc(to_email);
+        }
+        mailer.send(builder).await
+    }
+
+    #[cfg(not(feature = "smtp"))]
+    async fn send_email(&self, _subject: String, _body: String, _image: Option<&Snapshot>, _emails: Vec<&str>) -> Result<(), String> {
+        Err("compiled without email support, rebuild with the smtp feature".to_string())
+    }
+
+    async fn send_webhook_notifications(&self, rendered: &RenderedNotification, image: Option<&Snapshot>, webhooks: &[&WebhookConfig], dry_run: bool) -> Vec<NotificationResult> {
+        // Discord webhooks without a template all get the same body, so the image is only copied into it once
+        let discord_form = webhooks.iter().any(|webhook| webhook.template.is_none() && webhook.format == WebhookFormat::Discord)
+            .then(|| discord_form(rendered, image));
+        futures::future::join_all(webhooks.iter()
+            .map(|webhook| self.send_webhook(rendered, image, discord_form.as_ref(), webhook, dry_run))).await
+    }
+
+    /// Posts the notification to the webhook, retrying failed attempts with webhook.backoff_ms
+    async fn send_webhook(&self, rendered: &RenderedNotification, image: Option<&Snapshot>, discord_form: Option<&(String, Bytes)>, webhook: &WebhookConfig, dry_run: bool) -> NotificationResult {
+        let client = &self.client;
+        let settings = self.config.webhook_settings();
+        let url = webhook.url.as_str();
+        let payload = webhook.template.as_ref()
+            .map(|template| render_webhook_template(template, &rendered.template_vars));
+        if dry_run {
+            return NotificationResult {
+                kind: DestinationKind::Webhook,
+                destination: url.to_string(),
+                status: NotificationResultStatus::DryRun,
+                error: None,
+                subject: None,
+                body: Some(payload.map(|(_, body)| body).unwrap_or_else(|| format::payload(webhook.format, rendered).to_string())),
+            };
+        }
+        let request = WebhookRequest::new(webhook, payload, rendered, discord_form);
+        let mut attempts = 0;
+        let delivery = loop {
+            attempts += 1;
+            trace!("POST {} (attempt {})", url, attempts);
+            // Only server errors and network errors are worth retrying, 4xx won't change on retry
+            let (status, error, retryable) = match request.build(client, url).send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    match response.error_for_status() {
+                        Ok(_) => (Some(status.as_u16()), None, false),
+                        Err(err) => (Some(status.as_u16()), Some(err.without_url().to_string()), status.is_server_error())
+                    }
+                },
+                Err(err) => (None, Some(err.without_url().to_string()), true)
+            };
+            if error.is_none() || !retryable || attempts > settings.retries {
+                break WebhookDelivery {
+                    url: url.to_string(),
+                    timestamp: OffsetDateTime::now_utc(),
+                    attempts,
+                    success: error.is_none(),
+                    status,
+                    error,
+                };
+            }
+            let backoff = settings.backoff(attempts);
+            warn!("Failed to send webhook to \"{}\", retrying in {:?}:\n{}", url, backoff, error.unwrap());
+            tokio::time::sleep(backoff).await;
+        };
+        if let Some(err) = &delivery.error {
+            error!("Failed to send webhook to \"{}\" after {} attempts:\n{}", url, attempts, err);
+        }
+        if let (Some(slack), Some(image), true) = (&webhook.slack, image, delivery.success) {
+            if let Err(e) = upload_to_slack(client, slack, &rendered.subject, image).await {
+                error!("Failed to upload snapshot to slack channel {}: {}", slack.channel, e);
+            }
+        }
+        let result = NotificationResult {
+            kind: DestinationKind::Webhook,
+            destination: url.to_string(),
+            status: if delivery.success { NotificationResultStatus::Sent } else { NotificationResultStatus::Failed },
+            error: delivery.error.clone(),
+            subject: None,
+            body: None,
+        };
+        self.deliveries.lock().unwrap().insert(url.to_string(), delivery);
+        result
+    }
+
+    /// Posts a payload without a notification to the webhook, signed and with its headers, without retrying.
+    /// Receivers such as Discord answer 400 to a payload without content, which still shows the url is right
+    pub async fn probe_webhook(&self, webhook: &WebhookConfig) -> Result<(), String> {
+        let body = Bytes::from(json!({ "event": "selftest" }).to_string());
+        let request = WebhookRequest {
+            content_type: "application/json".to_string(),
+            signature: webhook.secret.as_ref().map(|secret| webhook_signature(secret, &body)),
+            body,
+            headers: &webhook.headers,
+            timeout: webhook.timeout()
+        };
+        let response = request.build(&self.client, &webhook.url).send().await.map_err(|e| e.to_string())?;
+        match response.status() {
+            status if status.is_success() || status == reqwest::StatusCode::BAD_REQUEST => Ok(()),
+            status => Err(format!("answered {}", status))
+        }
+    }
+
+    /// Returns the last delivery attempt of every webhook destination that has been sent to
+    pub fn get_deliveries(&self) -> Vec<WebhookDelivery> {
+        self.deliveries.lock().unwrap().values().cloned().collect()
+    }
+
+    /// The last delivery attempt to the webhook url, if it has been sent to
+    pub fn last_delivery(&self, url: &str) -> Option<WebhookDelivery> {
+        self.deliveries.lock().unwrap().get(url).cloned()
+    }
+}
+
+/// The webhook's template with the notification's variables, and its content type. Templates are not required to be
+/// JSON, but those that are once filled in get their values escaped, so a file named with a quote keeps the body valid
+fn render_webhook_template(template: &str, vars: &HashMap<&'static str, String>) -> (&'static str, String) {
+    let escaped: HashMap<&str, String> = vars.iter()
+        .map(|(name, value)| (*name, serde_json::to_string(value).unwrap_or_default().trim_matches('"').to_string()))
+        .collect();
+    let json = render_template(template, &escaped);
+    if serde_json::from_str::<serde_json::Value>(&json).is_ok() {
+        ("application/json", json)
+    } else {
+        ("text/plain", render_template(template, vars))
+    }
+}
+
+/// The client webhooks are sent with, through webhook.proxy when set
+fn webhook_client(config: &ConfigManager) -> reqwest::Client {
+    let settings = config.webhook_settings();
+    let mut builder = reqwest::Client::builder()
+        .timeout(settings.timeout())
+        .user_agent(version::user_agent());
+    if let Some(proxy) = &settings.proxy {
+        match reqwest::Proxy::all(proxy) {
+            Ok(proxy) => builder = builder.proxy(proxy),
+            Err(e) => warn!("Ignoring webhook.proxy \"{}\": {}", proxy, e)
+        }
+    }
+    builder.build().expect("failed to create reqwest client for webhooks")
+}
+
+/// The request to one webhook destination, built once and sent on every attempt
+struct WebhookRequest<'a> {
+    content_type: String,
+    /// Shared with the other attempts, and with the other Discord webhooks
+    body: Bytes,
+    signature: Option<String>,
+    headers: &'a HashMap<String, String>,
+    timeout: Option<Duration>
+}
+
+impl<'a> WebhookRequest<'a> {
+    /// Sends the rendered template if set, otherwise the payload of the webhook's format.
+    /// discord_form is the body of Discord webhooks, built by [discord_form]
+    fn new(webhook: &'a WebhookConfig, payload: Option<(&'static str, String)>, rendered: &RenderedNotification, discord_form: Option<&(String, Bytes)>) -> Self {
+        let (content_type, body) = match (payload, webhook.format, discord_form) {
+            (Some((content_type, payload)), ..) => (content_type.to_string(), Bytes::from(payload)),
+            (None, WebhookFormat::Discord, Some((content_type, body))) => (content_type.clone(), body.clone()),
+            (None, format, _) => ("application/json".to_string(), Bytes::from(format::payload(format, rendered).to_string()))
+        };
+        Self {
+            signature: webhook.secret.as_ref().map(|secret| webhook_signature(secret, &body)),
+            content_type,
+            body,
+            headers: &webhook.headers,
+            timeout: webhook.timeout()
+        }
+    }
+
+    fn build(&self, client: &reqwest::Client, url: &str) -> reqwest::RequestBuilder {
+        let mut request = client.post(url).header(CONTENT_TYPE, &self.content_type);
+        for (name, value) in self.headers {
+            request = request.header(name, value);
+        }
+        if let Some(signature) = &self.signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+        request.body(self.body.clone())
+    }
+}
+
+/// The content type and body of Discord webhooks, the embed with the snapshot attached as file1
+fn discord_form(rendered: &RenderedNotification, image: Option<&Snapshot>) -> (String, Bytes) {
+    let mut form = MultipartForm::new();
+    form.text("payload_json", &format::discord(rendered).to_string());
+    if let Some(image) = image {
+        form.file("file1", &image.file_name(), image.format.content_type(), &image.image);
+    }
+    let (content_type, body) = form.finish();
+    (content_type, Bytes::from(body))
+}
+
+/// A multipart/form-data body, encoded by hand instead of through reqwest so the body is known up front and can be signed
+struct MultipartForm {
+    boundary: String,
+    body: Vec<u8>
+}
+
+impl MultipartForm {
+    fn new() -> Self {
+        Self { boundary: Alphanumeric.sample_string(&mut rand::thread_rng(), 32), body: Vec::new() }
+    }
+
+    fn text(&mut self, name: &str, value: &str) {
+        self.body.extend_from_slice(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", self.boundary, name, value).as_bytes());
+    }
+
+    fn file(&mut self, name: &str, file_name: &str, content_type: &str, contents: &[u8]) {
+        self.body.extend_from_slice(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                                            self.boundary, name, file_name, content_type).as_bytes());
+        self.body.extend_from_slice(contents);
+        self.body.extend_from_slice(b"\r\n");
+    }
+
+    /// Returns the content type and body
+    fn finish(mut self) -> (String, Vec<u8>) {
+        self.body.extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        (format!("multipart/form-data; boundary={}", self.boundary), self.body)
+    }
+}
+
+/// Slack's answer to a web API call, an error is still a 200 but with ok false
+async fn slack_response(response: reqwest::Result<reqwest::Response>) -> Result<serde_json::Value, String> {
+    let response: serde_json::Value = response.and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json().await.map_err(|e| e.to_string())?;
+    if response["ok"] != true {
+        return Err(response["error"].as_str().unwrap_or("unknown error").to_string());
+    }
+    Ok(response)
+}
+
+/// Uploads the snapshot to the channel with Slack's external upload flow: reserve an upload url, send the file to it, then share it
+async fn upload_to_slack(client: &reqwest::Client, slack: &SlackUploadConfig, title: &str, image: &Snapshot) -> Result<(), String> {
+    let length = image.image.len().to_string();
+    let upload = slack_response(client.post("https://slack.com/api/files.getUploadURLExternal")
+        .bearer_auth(&slack.bot_token)
+        .form(&[("filename", image.file_name().as_str()), ("length", &length)])
+        .send().await).await?;
+    let (Some(upload_url), Some(file_id)) = (upload["upload_url"].as_str(), upload["file_id"].as_str()) else {
+        return Err("files.getUploadURLExternal returned no upload url".to_string());
+    };
+    client.post(upload_url).body(image.image.clone()).send().await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    slack_response(client.post("https://slack.com/api/files.completeUploadExternal")
+        .bearer_auth(&slack.bot_token)
+        .json(&json!({ "files": [{ "id": file_id, "title": title }], "channel_id": slack.channel }))
+        .send().await).await?;
+    Ok(())
+}
+
+/// The value of [SIGNATURE_HEADER], `sha256=` and the hex HMAC-SHA256 of the body
+pub fn webhook_signature(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let mut signature = String::from("sha256=");
+    for byte in hmac::sign(&key, body).as_ref() {
+        write!(signature, "{:02x}", byte).ok();
+    }
+    signature
+}
+
+/// Queue of notifications consumed by a dedicated task, so slow destinations don't hold up the printer polling
+pub struct NotificationQueue {
+    tx: std::sync::Mutex<Option<mpsc::Sender<NotificationJob>>>,
+    worker: Mutex<Option<JoinHandle<()>>>
+}
+
+impl NotificationQueue {
+    pub fn start(notifier: Arc<Notifier>) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let worker = tokio::spawn(run_queue(notifier, rx));
+        Self {
+            tx: std::sync::Mutex::new(Some(tx)),
+            worker: Mutex::new(Some(worker))
+        }
+    }
+
+    /// Queues the notification to be sent, dropping it if the queue is full
+    pub fn enqueue(&self, job: NotificationJob) {
+        let tx = self.tx.lock().unwrap();
+        let Some(tx) = tx.as_ref() else {
+            warn!("Notification queue is shut down, dropping {:?} for printer {}", job.notification_type, job.printer_id);
+            return;
+        };
+        if let Err(e) = tx.try_send(job) {
+            let job = e.into_inner();
+            warn!("Notification queue is full, dropping {:?} for printer {}", job.notification_type, job.printer_id);
+        }
+    }
+
+    /// Stops accepting new notifications and waits for the pending ones to be sent
+    pub async fn shutdown(&self) {
+        self.tx.lock().unwrap().take();
+        if let Some(worker) = self.worker.lock().await.take() {
+            debug!("waiting for pending notifications to be sent");
+            worker.await.ok();
+        }
+    }
+}
+
+async fn run_queue(notifier: Arc<Notifier>, mut rx: mpsc::Receiver<NotificationJob>) {
+    while let Some(job) = rx.recv().await {
+        debug!("sending queued notification {:?} for printer {}", job.notification_type, job.printer_id);
+        let printer_id = job.printer_id.clone();
+        let notifier = notifier.clone();
+        // Each job runs in its own task so a panic while sending doesn't stop the queue
+        let task = tokio::spawn(async move {
+            notifier.send_notification(&job.printer, job.notification_type, job.snapshot, job.state, false).await;
+        });
+        if let Err(e) = task.await {
+            error!("Sending notification for printer {} failed: {}", printer_id, e);
+        }
+    }
+    debug!("notification queue closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fixture, mock_webhook, slow_mock_webhook, unused_port, MockPrinter};
+    #[cfg(feature = "camera")]
+    use crate::{camera::GifSettings, test_support::mock_camera};
+
+    /// A print of benchy.gx that finished on printer main after 1h 2m
+    pub fn rendered() -> RenderedNotification {
+        RenderedNotification {
+            printer_name: "main".to_string(),
+            notification_type: "print_complete",
+            host: "192.168.1.89".to_string(),
+            subject: "Print complete on main".to_string(),
+            message: "File: benchy.gx\nAddress: 192.168.1.89\n".to_string(),
+            file: Some("benchy.gx".to_string()),
+            status: Some("READY".to_string()),
+            progress_percent: Some(100),
+            elapsed_seconds: Some(3725),
+            template_vars: HashMap::new(),
+            image_name: CAMERA_IMAGE_NAME.to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn destinations_are_sent_to_at_the_same_time() {
+        let mut urls = Vec::new();
+        for _ in 0..4 {
+            urls.push(slow_mock_webhook(Duration::from_millis(500)).await.0);
+        }
+        let (fast, _) = mock_webhook().await;
+        urls.insert(2, fast.clone());
+        let config = Arc::new(ConfigManager::from_toml(&format!(r#"
+            [notifications.on_done]
+            webhooks = [{}]
+            [printers]
+        "#, urls.iter().map(|url| format!("{{ url = {:?}, format = \"generic\" }}", url)).collect::<Vec<_>>().join(", "))));
+        let notifier = Notifier::new(config);
+        let mock = MockPrinter::start().await;
+        let printer = Arc::new(Printer::with_ports("main".to_string(), "127.0.0.1".to_string(), mock.port, unused_port().await, Duration::from_secs(5)));
+
+        let started = Instant::now();
+        let results = notifier.send_notification(&printer, NotificationType::PrintComplete, None, None, false).await;
+        assert!(started.elapsed() < Duration::from_millis(1500), "took {:?}", started.elapsed());
+        // Results stay in the order of the destinations
+        let destinations: Vec<&str> = results.iter().map(|result| result.destination.as_str()).collect();
+        assert_eq!(destinations, urls);
+        assert!(results.iter().all(|result| matches!(result.status, NotificationResultStatus::Sent)));
+    }
+
+    #[tokio::test]
+    async fn stale_frames_are_not_attached() {
+        let (url, bodies) = mock_webhook().await;
+        let config = Arc::new(ConfigManager::from_toml(&format!(r#"
+            [notifications.on_done]
+            webhooks = ["{}"]
+            [printers]
+        "#, url)));
+        let notifier = Notifier::new(config);
+        let mock = MockPrinter::start().await;
+        // Nothing answers on the camera port, snapshots fail
+        let printer = Arc::new(Printer::with_ports("main".to_string(), "127.0.0.1".to_string(), mock.port, unused_port().await, Duration::from_secs(5)));
+        #[cfg(feature = "camera")]
+        printer.camera().store_image(b"previous print".to_vec(), Instant::now() - Duration::from_secs(3600));
+
+        let results = notifier.send_notification(&printer, NotificationType::PrintComplete, None, None, false).await;
+        assert!(matches!(results[0].status, NotificationResultStatus::Sent), "{:?}", results[0].error);
+        let body = bodies.lock().unwrap().pop().unwrap();
+        assert!(!body.contains("previous print"));
+        assert!(!body.contains("printer_image.jpg\"\r\n"));
+        assert_eq!(body.contains("Camera unavailable, no image attached"), cfg!(feature = "camera"));
+
+        #[cfg(feature = "camera")]
+        {
+            // A fallback frame that is recent enough is still attached
+            printer.camera().store_image(b"this print".to_vec(), Instant::now());
+            notifier.send_notification(&printer, NotificationType::PrintComplete, None, None, false).await;
+            let body = bodies.lock().unwrap().pop().unwrap();
+            assert!(body.contains("filename=\"printer_image.jpg\"\r\nContent-Type: image/jpeg\r\n\r\nthis print\r\n"));
+        }
+    }
+
+    #[cfg(feature = "camera")]
+    #[tokio::test]
+    async fn print_errors_attach_the_gif() {
+        let (url, bodies) = mock_webhook().await;
+        let config = Arc::new(ConfigManager::from_toml(&format!(r#"
+            [notifications]
+            attach_gif = true
+            [notifications.on_error]
+            webhooks = ["{}"]
+            [printers]
+        "#, url)));
+        let notifier = Notifier::new(config);
+        let mock = MockPrinter::start().await;
+        let printer = Arc::new(Printer::with_ports("main".to_string(), "127.0.0.1".to_string(), mock.port, mock_camera(Duration::ZERO).await, Duration::from_secs(5)));
+        printer.camera().set_gif(GifSettings { frames: 3, interval: Duration::ZERO, max_bytes: 1 << 20, width: 640 });
+        printer.camera().snapshot().await.unwrap();
+
+        let results = notifier.send_notification(&printer, NotificationType::PrintError, None, None, false).await;
+        assert!(matches!(results[0].status, NotificationResultStatus::Sent), "{:?}", results[0].error);
+        let body = bodies.lock().unwrap().pop().unwrap();
+        assert!(body.contains("filename=\"printer_image.gif\"\r\nContent-Type: image/gif\r\n\r\nGIF89a"));
+    }
+
+    #[tokio::test]
+    async fn errors_report_the_state_they_were_queued_with() {
+        let (url, bodies) = mock_webhook().await;
+        let config = Arc::new(ConfigManager::from_toml(&format!(r#"
+            [notifications.on_error]
+            webhooks = [{{ url = "{}", format = "generic" }}]
+            [printers]
+        "#, url)));
+        let notifier = Notifier::new(config);
+        let mock = MockPrinter::start().await;
+        mock.respond("M119", &fixture("M119_printing"));
+        let printer = Arc::new(Printer::with_ports("main".to_string(), "127.0.0.1".to_string(), mock.port, unused_port().await, Duration::from_secs(5)));
+        printer.refresh_status().await.unwrap();
+        let state = CapturedState::of(&printer);
+        // The print is cleared before the queue gets to the notification
+        mock.respond("M119", &fixture("M119"));
+        printer.refresh_status().await.unwrap();
+
+        let results = notifier.send_notification(&printer, NotificationType::PrintError, None, Some(state), false).await;
+        assert!(matches!(results[0].status, NotificationResultStatus::Sent), "{:?}", results[0].error);
+        let body = bodies.lock().unwrap().pop().unwrap();
+        assert!(body.contains(r#""status":"BUILDING_FROM_SD""#), "{}", body);
+        assert!(body.contains(r#""progress_percent":20"#), "{}", body);
+
+        notifier.send_notification(&printer, NotificationType::PrintError, None, None, false).await;
+        let body = bodies.lock().unwrap().pop().unwrap();
+        assert!(body.contains(r#""status":"READY""#), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn started_prints_attach_the_thumbnail() {
+        let (url, bodies) = mock_webhook().await;
+        let config = Arc::new(ConfigManager::from_toml(&format!(r#"
+            [notifications.on_started]
+            webhooks = ["{}"]
+            [printers]
+        "#, url)));
+        let notifier = Notifier::new(config);
+        let mock = MockPrinter::start().await;
+        let printer = Arc::new(Printer::with_ports("main".to_string(), "127.0.0.1".to_string(), mock.port, unused_port().await, Duration::from_secs(5)));
+        let thumbnail = Thumbnail { width: 300, height: 300, format: ImageFormat::Png, image: Bytes::from_static(b"\x89PNG\r\n\x1a\nbenchy") };
+
+        let results = notifier.send_notification(&printer, NotificationType::PrintStarted, Some(Snapshot::thumbnail(thumbnail)), None, false).await;
+        assert!(matches!(results[0].status, NotificationResultStatus::Sent), "{:?}", results[0].error);
+        let body = bodies.lock().unwrap().pop().unwrap();
+        assert!(body.contains("Print started on main"));
+        assert!(body.contains("attachment://printer_image.png"));
+        assert!(body.contains("filename=\"printer_image.png\"\r\nContent-Type: image/png\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn templates_are_rendered_from_the_printer() {
+        let mock = MockPrinter::start().await;
+        mock.respond("M119", &fixture("M119_printing").replace("benchy.gx", "\"quoted\" \\ benchy.gx"));
+        let printer = Printer::with_ports("main".to_string(), "127.0.0.1".to_string(), mock.port, unused_port().await, Duration::from_secs(5));
+        printer.refresh_status().await.unwrap();
+        let vars = NotificationType::PrintError.get_template_vars(&printer, &CapturedState::of(&printer));
+
+        let (content_type, body) = render_webhook_template(r#"{"text": "{{printer.name}} at {{printer.ip}} printing {{file}}", "percent": {{progress.percent}}, "status": "{{status}}", "type": "{{notification.type}}"}"#, &vars);
+        assert_eq!(content_type, "application/json");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["text"], "main at 127.0.0.1 printing \"quoted\" \\ benchy.gx");
+        assert_eq!(body["percent"], 20);
+        assert_eq!((body["status"].as_str(), body["type"].as_str()), (Some("BUILDING_FROM_SD"), Some("print_error")));
+
+        // Not JSON, sent as written
+        let (content_type, body) = render_webhook_template("{{printer.name}}: {{file}} {{unknown}}", &vars);
+        assert_eq!((content_type, body.as_str()), ("text/plain", "main: \"quoted\" \\ benchy.gx "));
+    }
+
+    #[test]
+    fn signs_webhook_bodies() {
+        // RFC 4231 test case 2
+        assert_eq!(webhook_signature("Jefe", b"what do ya want for nothing?"),
+                   "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+
+        let webhook = WebhookConfig {
+            url: "http://127.0.0.1/hook".to_string(),
+            enabled: Default::default(),
+            template: None,
+            secret: Some("Jefe".to_string()),
+            headers: HashMap::from([("Authorization".to_string(), "Bearer token".to_string())]),
+            format: WebhookFormat::Discord,
+            slack: None,
+            timeout_seconds: None
+        };
+        let form = discord_form(&rendered(), Some(&Snapshot { image: Bytes::from_static(b"image"), format: ImageFormat::Jpeg, captured: Instant::now() }));
+        let request = WebhookRequest::new(&webhook, None, &rendered(), Some(&form))
+            .build(&reqwest::Client::new(), &webhook.url).build().unwrap();
+        let body = request.body().unwrap().as_bytes().unwrap();
+        assert_eq!(request.headers()[SIGNATURE_HEADER], webhook_signature("Jefe", body));
+        assert_eq!(request.headers()["Authorization"], "Bearer token");
+        let content_type = request.headers()[CONTENT_TYPE].to_str().unwrap();
+        let boundary = content_type.strip_prefix("multipart/form-data; boundary=").unwrap();
+        let body = String::from_utf8_lossy(body);
+        assert!(body.starts_with(&format!("--{}\r\nContent-Disposition: form-data; name=\"payload_json\"\r\n\r\n{}\r\n", boundary, format::discord(&rendered()))));
+        assert!(body.contains("filename=\"printer_image.jpg\"\r\nContent-Type: image/jpeg\r\n\r\nimage\r\n"));
+        assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+
+        let unsigned = WebhookConfig { secret: None, ..webhook };
+        let request = WebhookRequest::new(&unsigned, Some(("text/plain", "done".to_string())), &rendered(), None)
+            .build(&reqwest::Client::new(), &unsigned.url).build().unwrap();
+        assert!(!request.headers().contains_key(SIGNATURE_HEADER));
+        assert_eq!(request.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(request.timeout(), None);
+
+        let slow = WebhookConfig { timeout_seconds: Some(30), ..unsigned.clone() };
+        let request = WebhookRequest::new(&slow, Some(("text/plain", "done".to_string())), &rendered(), None)
+            .build(&reqwest::Client::new(), &slow.url).build().unwrap();
+        assert_eq!(request.timeout(), Some(&Duration::from_secs(30)));
+
+        let slack = WebhookConfig { format: WebhookFormat::Slack, ..unsigned };
+        let request = WebhookRequest::new(&slack, None, &rendered(), Some(&form))
+            .build(&reqwest::Client::new(), &slack.url).build().unwrap();
+        assert_eq!(request.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(request.body().unwrap().as_bytes().unwrap(), format::slack(&rendered()).to_string().as_bytes());
+    }
+}
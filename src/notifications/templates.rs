@@ -0,0 +1,125 @@
+//! Subjects and bodies of notifications. Each type can be replaced by <type>.subject and <type>.body files
+//! in notifications.templates_path, those that are missing use the built-in English ones
+use crate::util::{render_template, render_template_lines};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Subject and body of each notification type, by [super::NotificationType::name]
+const DEFAULTS: [(&str, &str, &str); 7] = [
+    ("print_started", "Print started on {{printer.display_name}}",
+     "File: {{file}}\nAddress: {{printer.host}}\n"),
+    ("print_complete", "Print complete on {{printer.display_name}}",
+     "File: {{file}}\nAddress: {{printer.host}}\n"),
+    ("print_error", "Print error on {{printer.display_name}}",
+     "File: {{file}}\nAddress: {{printer.host}}\nStatus: {{status}}\n\
+      Progress: layer {{progress.layer.current}}/{{progress.layer.total}}, byte {{progress.byte.current}}/{{progress.byte.total}}\n"),
+    ("print_cancelled", "Print cancelled on {{printer.display_name}}",
+     "File: {{file}}\nAddress: {{printer.host}}\nStopped at {{progress.percent}}%\n"),
+    ("print_failed", "Print failed on {{printer.display_name}}",
+     "File: {{file}}\nAddress: {{printer.host}}\nStatus: {{status}}\nStopped at {{progress.percent}}%\nNote: {{note}}\n"),
+    ("temperature_alert", "Temperature alert for {{sensor}} on {{printer.display_name}}",
+     "Sensor: {{sensor}}\nReason: {{reason}}\nCurrent: {{temperature.current}}°C, Target: {{temperature.target}}°C\nAddress: {{printer.host}}\n"),
+    ("temperature_recovered", "Temperature recovered for {{sensor}} on {{printer.display_name}}",
+     "Sensor {{sensor}} is back within limits\nCurrent: {{temperature.current}}°C, Target: {{temperature.target}}°C\nAddress: {{printer.host}}\n"),
+];
+
+/// Templates replacing the defaults, by file name such as "print_complete.subject"
+#[derive(Default)]
+pub struct Templates {
+    overrides: HashMap<String, String>
+}
+
+impl Templates {
+    /// Reads the templates in dir, files that can't be read or are not of a notification type are skipped with a warning
+    pub fn load(dir: &Path) -> Self {
+        let mut overrides = HashMap::new();
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not read notification templates from {}, using the built-in ones: {}", dir.display(), e);
+                return Self::default();
+            }
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let known = name.rsplit_once('.').is_some_and(|(notification_type, part)| {
+                (part == "subject" || part == "body") && default(notification_type).is_some()
+            });
+            if !known {
+                warn!("Ignoring {} in {}, expected <type>.subject or <type>.body for a type such as print_complete", name, dir.display());
+                continue;
+            }
+            match std::fs::read_to_string(entry.path()) {
+                Ok(template) => {
+                    debug!("Using notification template {}", name);
+                    overrides.insert(name, template);
+                }
+                Err(e) => warn!("Could not read notification template {}, using the built-in one: {}", entry.path().display(), e)
+            }
+        }
+        Self { overrides }
+    }
+
+    /// The subject of the notification type, on one line
+    pub fn subject(&self, notification_type: &str, vars: &HashMap<&str, String>) -> String {
+        let template = self.overrides.get(&format!("{}.subject", notification_type)).map(String::as_str)
+            .or(default(notification_type).map(|(_, subject, _)| *subject));
+        render_template(template.unwrap_or_default().trim_end(), vars)
+    }
+
+    /// The body of the notification type, lines whose variables are all empty are left out
+    pub fn body(&self, notification_type: &str, vars: &HashMap<&str, String>) -> String {
+        let template = self.overrides.get(&format!("{}.body", notification_type)).map(String::as_str)
+            .or(default(notification_type).map(|(_, _, body)| *body));
+        render_template_lines(template.unwrap_or_default(), vars)
+    }
+}
+
+fn default(notification_type: &str) -> Option<&'static (&'static str, &'static str, &'static str)> {
+    DEFAULTS.iter().find(|(name, ..)| *name == notification_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::NotificationType;
+
+    fn vars() -> HashMap<&'static str, String> {
+        HashMap::from([
+            ("printer.name", "main".to_string()),
+            ("printer.display_name", "Main".to_string()),
+            ("printer.host", "192.168.1.89".to_string()),
+            ("file", "benchy.gx".to_string()),
+            ("progress.percent", "40".to_string()),
+            ("elapsed", "1h 2m".to_string())
+        ])
+    }
+
+    #[test]
+    fn defaults_leave_out_empty_fields() {
+        let templates = Templates::default();
+        assert_eq!(templates.subject("print_failed", &vars()), "Print failed on Main");
+        assert_eq!(templates.body("print_failed", &vars()), "File: benchy.gx\nAddress: 192.168.1.89\nStopped at 40%\n");
+        for (name, ..) in DEFAULTS {
+            assert!(NotificationType::from_name(name).is_some(), "{} is not a notification type", name);
+        }
+    }
+
+    #[test]
+    fn files_replace_the_defaults() {
+        let dir = std::env::temp_dir().join(format!("flashforge-templates-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("print_cancelled.subject"), "Druck auf {{printer.name}} abgebrochen\n").unwrap();
+        std::fs::write(dir.join("print_cancelled.body"), "Datei: {{file}}\nDauer: {{elapsed}}\nNotiz: {{note}}\n{{missing}} bei {{progress.percent}}%\n").unwrap();
+        std::fs::write(dir.join("print_canceled.body"), "typo").unwrap();
+        let templates = Templates::load(&dir);
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(templates.subject("print_cancelled", &vars()), "Druck auf main abgebrochen");
+        assert_eq!(templates.body("print_cancelled", &vars()), "Datei: benchy.gx\nDauer: 1h 2m\n bei 40%\n");
+        // Types without files keep the built-in templates
+        assert_eq!(templates.subject("print_complete", &vars()), "Print complete on Main");
+        assert_eq!(templates.overrides.len(), 2);
+    }
+}
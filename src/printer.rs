@@ -1,50 +1,407 @@
-use futures::StreamExt;
 use std::fmt::Display;
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
-use log::{trace, warn};
-use multipart_stream::Part;
-use reqwest::Url;
-use tokio::sync::broadcast;
-use tokio::task::JoinHandle;
-use crate::models::{ControlSuccess, PrinterHeadPosition, PrinterInfo, PrinterProgress, PrinterStatus, PrinterTemperature};
-use crate::socket::{PrinterRequest, PrinterResponse};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use log::{debug, info, trace, warn};
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tracing::field::Empty;
+use tracing::{debug_span, Instrument, Span};
+#[cfg(feature = "camera")]
+use crate::camera::Camera;
+use crate::console::Console;
+use crate::latency::Latencies;
+use crate::errors::ErrorCode;
+use crate::manager::PROGRESS_CHECK_INTERVAL;
+use crate::metrics;
+use crate::models::{CachedHeadPosition, CachedPrinterInfo, ConnectionStats, ConnectionPermits, ControlSuccess, EndStopPosition, Freshness, HealthSummary, InFlightCommand, LastPrinterError, MachineStatus, MaintenanceMode, PrinterAvailability, PrinterEvent, PrinterHealth, PrinterJob, PrinterHeadPosition, PrinterInfo, PrinterLabels, PrinterProgress, PrinterStateUpdate, PrinterStatus, PrinterTemperature};
+use flashforge_protocol::{AsyncClient, ClientError, PrinterRequest, PrinterResponse, API_PORT};
+use flashforge_protocol::profile::{Capabilities, ModelProfile, DEFAULT_PROFILE};
+use crate::state::{SavedJob, SavedPrinter};
+use crate::util::host_port;
 
+/// Handle to a printer. Requests are sent to a per printer task that runs them one at a time,
+/// so callers never wait on each other except for the printer's own connection. Cached state and
+/// the camera can be used concurrently with requests.
 pub struct Printer {
-    socket_addr: SocketAddr,
-    info: Option<PrinterInfo>,
     name: String,
+    /// IP address or hostname as configured, hostnames are resolved on every connection
+    host: String,
+    api_port: u16,
+    commands: mpsc::Sender<PrinterCommand>,
+    /// Shared with the command task
+    task: Arc<CommandTaskShared>,
+    /// Whether read-only queries take control with M601 too, see [Printer::set_require_control]
+    require_control: AtomicBool,
+    /// From the config, changed by PATCH /api/printers/<id>
+    labels: RwLock<PrinterLabels>,
+    state: RwLock<PrinterState>,
+    state_changes: broadcast::Sender<PrinterStateUpdate>,
+    events: broadcast::Sender<PrinterEvent>,
+    #[cfg(feature = "camera")]
+    camera: Camera
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrinterError {
+    /// Could not connect or the connection was lost, a reused connection may have been closed by the printer
+    Unreachable(String),
+    /// The printer did not answer in time
+    Timeout(String),
+    /// The printer answered with something unexpected
+    InvalidResponse(String),
+    /// The printer answered a query with nothing, twice
+    EmptyResponse(String),
+    /// The printer refused M601, another client such as FlashPrint has control
+    ControlDenied(String),
+    /// Every connection the printer allows at once stayed in use, see [ConnectionLimit]
+    Busy(String)
+}
+
+impl PrinterError {
+    /// The error code of API responses, so monitoring can tell an offline printer from a bug
+    pub fn code(&self) -> &'static str {
+        self.error_code().as_str()
+    }
+
+    /// Also decides the status of the response, see [crate::util::printer_error]
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            PrinterError::Unreachable(_) => ErrorCode::PrinterUnreachable,
+            PrinterError::Timeout(_) => ErrorCode::PrinterTimeout,
+            PrinterError::InvalidResponse(_) => ErrorCode::PrinterInvalidResponse,
+            PrinterError::EmptyResponse(_) => ErrorCode::PrinterEmptyResponse,
+            PrinterError::ControlDenied(_) => ErrorCode::ControlDenied,
+            PrinterError::Busy(_) => ErrorCode::PrinterBusy
+        }
+    }
+}
+
+impl Display for PrinterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrinterError::Unreachable(e) | PrinterError::Timeout(e) | PrinterError::InvalidResponse(e) | PrinterError::EmptyResponse(e)
+                | PrinterError::ControlDenied(e) | PrinterError::Busy(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl From<ClientError> for PrinterError {
+    fn from(e: ClientError) -> Self {
+        match e {
+            ClientError::WriteTimeout | ClientError::ReadTimeout => PrinterError::Timeout(e.to_string()),
+            ClientError::Io(_) | ClientError::Closed => PrinterError::Unreachable(e.to_string()),
+            ClientError::InvalidResponse(e) => PrinterError::InvalidResponse(e),
+            ClientError::Undecodable(_) => PrinterError::InvalidResponse(e.to_string()),
+            ClientError::EmptyResponse => PrinterError::EmptyResponse(e.to_string())
+        }
+    }
+}
+
+/// Cached state, updated by the watcher thread except for health, which every request updates
+#[derive(Default)]
+struct PrinterState {
+    info: Option<PrinterInfo>,
     is_online: bool,
     current_file: Option<String>,
-    camera_channel: broadcast::Sender<Part>,
-    camera_task: Option<JoinHandle<()>>,
-    last_image: Arc<RwLock<Option<Vec<u8>>>>
-    // camera_stream: Option<Receiver<>>
+    machine_status: Option<MachineStatus>,
+    progress: Option<PrinterProgress>,
+    last_seen: Option<OffsetDateTime>,
+    /// Last poll of the status, answered or not
+    last_polled: Option<OffsetDateTime>,
+    job: Option<JobState>,
+    /// Whether the mismatch between the extruders reported by M105 and the tool count was logged
+    tool_count_warned: bool,
+    health: HealthState,
+    maintenance: Option<MaintenanceMode>,
+    /// Only polled with watch.head_position, with when it was received
+    head_position: Option<(PrinterHeadPosition, Instant)>,
+    end_stop: Option<EndStopPosition>,
+    led: Option<bool>,
+    /// From the status flags, None when the printer doesn't send them
+    filament_runout: Option<bool>,
+}
+
+impl PrinterState {
+    fn freshness(&self, now: OffsetDateTime) -> Freshness {
+        Freshness {
+            last_polled_at: self.last_polled,
+            stale: self.last_seen.is_none_or(|seen| now - seen > PROGRESS_CHECK_INTERVAL * STALE_AFTER_POLLS)
+        }
+    }
+}
+
+/// Outcome of the requests sent by routes and the watcher thread
+#[derive(Default)]
+struct HealthState {
+    consecutive_failures: u32,
+    last_success: Option<OffsetDateTime>,
+    last_error: Option<LastPrinterError>
+}
+
+impl HealthState {
+    fn summary(&self, now: OffsetDateTime) -> HealthSummary {
+        let recently_failed = self.last_error.as_ref().is_some_and(|error| now - error.at < DEGRADED_AFTER_ERROR);
+        if self.last_success.is_none() || self.consecutive_failures >= OFFLINE_AFTER_FAILURES {
+            HealthSummary::Offline
+        } else if self.consecutive_failures > 0 || recently_failed {
+            HealthSummary::Degraded
+        } else {
+            HealthSummary::Ok
+        }
+    }
+}
+
+/// The file being printed, tracked from the polls of the watcher thread
+struct JobState {
+    file: String,
+    /// None if the job was already running when first seen, such as after a restart
+    started_at: Option<OffsetDateTime>,
+    /// Recent (time, bytes printed) samples, used for the printing rate
+    samples: VecDeque<(Instant, u32)>,
+    /// Set through the API, printers don't report them
+    speed_percent: Option<u16>,
+    flow_percent: Option<u16>,
+    /// Recent Z positions of the head, with watch.head_position
+    z_samples: VecDeque<f32>,
+    /// Lowest layer polled, to only report passing the first layers if they were seen
+    lowest_layer: Option<u32>,
+    first_layers_passed: bool
+}
+
+impl JobState {
+    fn new(file: String, started_at: Option<OffsetDateTime>) -> Self {
+        Self { file, started_at, samples: VecDeque::with_capacity(JOB_SAMPLE_WINDOW), speed_percent: None, flow_percent: None,
+            z_samples: VecDeque::with_capacity(Z_SAMPLE_WINDOW), lowest_layer: None, first_layers_passed: false }
+    }
+
+    /// Median of the recent Z positions, so the head briefly moving for probing or a retraction hop is ignored
+    fn z_height(&self) -> Option<f32> {
+        let mut samples: Vec<f32> = self.z_samples.iter().copied().collect();
+        samples.sort_by(f32::total_cmp);
+        samples.get(samples.len() / 2).copied()
+    }
+
+    /// Average height of the layers printed so far, from the Z height and the current layer
+    fn layer_height(&self, layer: u32) -> Option<f32> {
+        let z = self.z_height()?;
+        (layer > 0 && z > 0.0).then(|| z / layer as f32)
+    }
+
+    /// Seconds left at the byte rate of the recent samples, None until there are two samples with progress
+    fn remaining_seconds(&self, progress: &PrinterProgress) -> Option<u64> {
+        let (first_time, first_bytes) = self.samples.front()?;
+        let (last_time, last_bytes) = self.samples.back()?;
+        let elapsed = last_time.duration_since(*first_time).as_secs_f64();
+        let printed = last_bytes.checked_sub(*first_bytes)? as f64;
+        if elapsed <= 0.0 || printed <= 0.0 {
+            return None;
+        }
+        let remaining = progress.byte.total.saturating_sub(progress.byte.current) as f64;
+        Some((remaining / (printed / elapsed)).round() as u64)
+    }
+}
+
+/// What [Printer::set_print_factor] changes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrintFactorKind {
+    Speed,
+    Flow
+}
+
+/// The command being run by the command task, with when it started
+struct InFlight {
+    command: String,
+    started: Instant,
+    started_at: OffsetDateTime
+}
+
+#[derive(Default)]
+struct ConnectionCounters {
+    reused: AtomicU64,
+    reopened: AtomicU64
+}
+
+/// Caps the connections open to one of the printer's ports at once, FlashForge firmware gets flaky above two.
+/// Each connection holds a permit until it is closed
+pub struct ConnectionLimit {
+    semaphore: Arc<Semaphore>,
+    /// Permits and how long to wait for one, changed by [ConnectionLimit::set]
+    settings: Mutex<(usize, Duration)>,
+    waiting: AtomicUsize
+}
+
+/// Decrements the waiting count when done, including when the wait is cancelled
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnectionLimit {
+    pub fn new(limit: usize, wait: Duration) -> Self {
+        Self { semaphore: Arc::new(Semaphore::new(limit)), settings: Mutex::new((limit, wait)), waiting: AtomicUsize::new(0) }
+    }
+
+    /// Changes the limit, from printers.<id>.connections. Permits in use are only taken away once released
+    pub fn set(&self, limit: usize, wait: Duration) {
+        let mut settings = self.settings.lock().unwrap();
+        if limit > settings.0 {
+            self.semaphore.add_permits(limit - settings.0);
+            settings.0 = limit;
+        } else {
+            settings.0 -= self.semaphore.forget_permits(settings.0 - limit);
+        }
+        settings.1 = wait;
+    }
+
+    /// Waits for a free connection, failing with [PrinterError::Busy] if none was freed in time. `port` names the
+    /// port in the error
+    pub async fn acquire(&self, port: &str) -> Result<OwnedSemaphorePermit, PrinterError> {
+        let (limit, wait) = *self.settings.lock().unwrap();
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(&self.waiting);
+        match tokio::time::timeout(wait, self.semaphore.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            // The semaphore is never closed
+            Ok(Err(e)) => Err(PrinterError::Busy(e.to_string())),
+            Err(_) => Err(PrinterError::Busy(format!("all {} connections to the {} stayed in use for {}ms", limit, port, wait.as_millis())))
+        }
+    }
+
+    /// A free connection, without waiting
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.semaphore.clone().try_acquire_owned().ok()
+    }
+
+    pub fn usage(&self) -> ConnectionPermits {
+        let limit = self.settings.lock().unwrap().0;
+        ConnectionPermits {
+            limit,
+            in_use: limit.saturating_sub(self.semaphore.available_permits()),
+            waiting: self.waiting.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// What the command task updates and the printer reads
+struct CommandTaskShared {
+    stats: ConnectionCounters,
+    /// Connections to the TCP API, held by the command task's session and the self-test
+    connections: ConnectionLimit,
+    latencies: Latencies,
+    /// Set while the command task waits on the printer
+    in_flight: Mutex<Option<InFlight>>,
+    console: Console
+}
+
+/// A response as the printer sent it, along with the result of parsing it
+pub struct RawResponse {
+    pub text: String,
+    pub parsed: Result<PrinterResponse, PrinterError>
+}
+
+enum PrinterCommand {
+    Request {
+        request: PrinterRequest,
+        reply: oneshot::Sender<Result<RawResponse, PrinterError>>,
+        /// Profile of the printer's model the response is parsed with
+        profile: &'static ModelProfile,
+        /// Take control for read-only queries too, see [Printer::set_require_control]
+        require_control: bool,
+        /// Span of the caller, such as the HTTP request, the exchange with the printer is recorded under
+        span: Span
+    },
+    /// Requests run in order over the same connection, without other requests in between. Stops at the first that
+    /// fails, replying with its index and error
+    Batch {
+        requests: Vec<PrinterRequest>,
+        reply: oneshot::Sender<Result<Vec<RawResponse>, (usize, PrinterError)>>,
+        profile: &'static ModelProfile,
+        require_control: bool,
+        span: Span
+    },
+    /// Close the open connection with M602 now instead of when idle, replying once done
+    Release(oneshot::Sender<()>)
 }
 
 // The port the TCP API is on
-pub const PRINTER_API_PORT: u16 = 8899;
+pub const PRINTER_API_PORT: u16 = API_PORT;
 pub const PRINTER_CAM_PORT: u16 = 8080;
-pub const PRINTER_CAM_STREAM_PATH: &str = "/?action=stream";
+/// Commands waiting for the printer, further requests wait for a free slot
+const COMMAND_QUEUE_SIZE: usize = 16;
+/// State changes kept for subscribers that are behind, older ones are dropped
+const STATE_CHANGES_SIZE: usize = 16;
+/// How long a request can take, including the time spent queued behind other requests
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(20);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(3);
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Progress samples the remaining time is estimated from, polls are every 60 seconds
+const JOB_SAMPLE_WINDOW: usize = 10;
+/// Failed requests in a row after which the printer is considered offline
+const OFFLINE_AFTER_FAILURES: u32 = 3;
+/// How long the printer is degraded after a failed request, so drops show up between polls
+const DEGRADED_AFTER_ERROR: time::Duration = time::Duration::minutes(5);
+/// Head positions the Z height of the job is the median of
+const Z_SAMPLE_WINDOW: usize = 5;
+/// Polls that can be missed before the cached values are reported as stale
+const STALE_AFTER_POLLS: u32 = 2;
+/// Connections allowed to each port until [Printer::set_connection_limits]
+pub const DEFAULT_CONNECTIONS: usize = 1;
+pub const DEFAULT_CONNECTION_WAIT: Duration = Duration::from_secs(5);
+/// A connection to the printer, which has control once M601 was answered
+struct Session {
+    client: AsyncClient<TcpStream>,
+    controlled: bool,
+    /// Released when the session is dropped
+    _permit: OwnedSemaphorePermit
+}
+
 impl Display for Printer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.name)
     }
 }
 impl Printer {
-    pub fn new(name: String, ip_addr: IpAddr) -> Self {
-        let (tx, _) = broadcast::channel(1024);
+    /// api_port is usually [PRINTER_API_PORT], unless it is forwarded
+    pub fn new(name: String, host: String, api_port: u16, idle_timeout: Duration) -> Self {
+        Self::with_ports(name, host, api_port, PRINTER_CAM_PORT, idle_timeout)
+    }
+
+    /// Creates a printer and spawns its command task, must be called from within the tokio runtime.
+    /// cam_port is unused without the camera feature
+    #[cfg_attr(not(feature = "camera"), allow(unused_variables))]
+    pub fn with_ports(name: String, host: String, api_port: u16, cam_port: u16, idle_timeout: Duration) -> Self {
+        let (commands, commands_rx) = mpsc::channel(COMMAND_QUEUE_SIZE);
+        let events = broadcast::channel(STATE_CHANGES_SIZE).0;
+        let task = Arc::new(CommandTaskShared {
+            stats: ConnectionCounters::default(),
+            connections: ConnectionLimit::new(DEFAULT_CONNECTIONS, DEFAULT_CONNECTION_WAIT),
+            latencies: Latencies::new(name.clone()),
+            in_flight: Mutex::new(None),
+            console: Console::new(events.clone())
+        });
+        tokio::spawn(run_commands(name.clone(), host.clone(), api_port, idle_timeout, task.clone(), commands_rx));
         Printer {
-            socket_addr: SocketAddr::new(ip_addr, PRINTER_API_PORT),
-            info: None,
+            #[cfg(feature = "camera")]
+            camera: Camera::new(name.clone(), &host, cam_port),
             name,
-            is_online: false,
-            current_file: None,
-            camera_channel: tx,
-            camera_task: None,
-            last_image: Arc::new(RwLock::new(None)),
+            host,
+            api_port,
+            commands,
+            task,
+            require_control: AtomicBool::new(true),
+            labels: RwLock::new(PrinterLabels::default()),
+            state: RwLock::new(PrinterState::default()),
+            state_changes: broadcast::channel(STATE_CHANGES_SIZE).0,
+            events,
         }
     }
 
@@ -52,164 +409,1039 @@ impl Printer {
         &self.name
     }
 
-    pub fn ip(&self) -> IpAddr {
-        self.socket_addr.ip()
+    pub fn labels(&self) -> PrinterLabels {
+        self.labels.read().unwrap().clone()
+    }
+
+    pub fn set_labels(&self, labels: PrinterLabels) {
+        *self.labels.write().unwrap() = labels;
+    }
+
+    /// printers.<id>.display_name, or the name set on the printer once its info was fetched, or the id
+    pub fn display_name(&self) -> String {
+        self.labels.read().unwrap().display_name.clone()
+            .or_else(|| self.info().map(|info| info.name))
+            .unwrap_or_else(|| self.name.clone())
+    }
+
+    /// With false, read-only queries are sent without taking control so they are answered while FlashPrint is
+    /// connected, and only commands that change something send M601. On by default
+    pub fn set_require_control(&self, require_control: bool) {
+        self.require_control.store(require_control, Ordering::Relaxed);
+    }
+
+    /// Connections allowed at once to the TCP API and the camera, and how long one waits for another to close,
+    /// from printers.<id>.connections
+    #[cfg_attr(not(feature = "camera"), allow(unused_variables))]
+    pub fn set_connection_limits(&self, gcode: usize, camera: usize, wait: Duration) {
+        self.task.connections.set(gcode, wait);
+        #[cfg(feature = "camera")]
+        self.camera.connections().set(camera, wait);
+    }
+
+    /// How long the printer takes to answer each command
+    pub fn latencies(&self) -> &Latencies {
+        &self.task.latencies
+    }
+
+    /// Checks that the TCP API accepts connections, for the self-test. A session that is already open answered
+    /// its last request, so it counts instead of opening another connection than the limit allows
+    pub async fn probe_api(&self) -> Result<(), String> {
+        let Some(_permit) = self.task.connections.try_acquire() else {
+            return Ok(());
+        };
+        TcpStream::connect((self.host.as_str(), self.api_port)).await.map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    /// Lines the printer sent on its own, only kept once capture is turned on with [Console::set_capture]
+    pub fn console(&self) -> &Console {
+        &self.task.console
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn api_port(&self) -> u16 {
+        self.api_port
+    }
+
+    /// Address of the TCP API when the host is an IP address, hostnames are resolved on every connection instead
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.host.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, self.api_port))
+    }
+
+    #[cfg(feature = "camera")]
+    pub fn camera(&self) -> &Camera {
+        &self.camera
     }
 
     // Only updated by watcher thread
-    pub fn online(&self) -> bool { self.is_online }
+    pub fn online(&self) -> bool { self.state.read().unwrap().is_online }
+
+    pub fn current_file(&self) -> Option<String> { self.state.read().unwrap().current_file.clone() }
+
+    // Only updated by watcher thread
+    pub fn machine_status(&self) -> Option<MachineStatus> { self.state.read().unwrap().machine_status.clone() }
+
+    /// Printer is actively printing, from the cached machine status
+    pub fn is_printing(&self) -> bool {
+        self.state.read().unwrap().machine_status.as_ref().is_some_and(|status| status.is_printing())
+    }
 
-    pub fn current_file(&self) -> &Option<String> { &self.current_file }
+    /// Progress of the current print, only updated by watcher thread
+    pub fn progress(&self) -> Option<PrinterProgress> { self.state.read().unwrap().progress.clone() }
 
+    /// Polls the head position into the cache read by [Printer::cached_head_position]
+    pub async fn refresh_head_position(&self) -> Result<(), PrinterError> {
+        let position = self.get_head_position().await?;
+        let mut state = self.state.write().unwrap();
+        if let Some(job) = state.job.as_mut() {
+            if job.z_samples.len() == Z_SAMPLE_WINDOW {
+                job.z_samples.pop_front();
+            }
+            job.z_samples.push_back(position.z);
+        }
+        state.head_position = Some((position, Instant::now()));
+        Ok(())
+    }
+
+    /// True once per job, on the first poll past the first `layers` layers. Jobs first seen past them don't count
+    pub fn passed_first_layers(&self, layers: u32) -> bool {
+        let mut state = self.state.write().unwrap();
+        let Some(current) = state.progress.as_ref().map(|progress| progress.layer.current) else { return false };
+        let Some(job) = state.job.as_mut() else { return false };
+        if job.first_layers_passed || current <= layers || job.lowest_layer.is_none_or(|lowest| lowest > layers) {
+            return false;
+        }
+        job.first_layers_passed = true;
+        true
+    }
+
+    /// The last polled head position and endstops, None if the head position was never polled
+    pub fn cached_head_position(&self) -> Option<CachedHeadPosition> {
+        let state = self.state.read().unwrap();
+        let (position, received) = state.head_position.as_ref()?;
+        Some(CachedHeadPosition {
+            position: position.clone(),
+            end_stop: state.end_stop.clone(),
+            age_seconds: received.elapsed().as_secs()
+        })
+    }
+
+    /// The cached machine status, file and progress
+    pub fn state_update(&self) -> PrinterStateUpdate {
+        let state = self.state.read().unwrap();
+        PrinterStateUpdate {
+            machine_status: state.machine_status.clone(),
+            current_file: state.current_file.clone(),
+            progress: state.progress.clone()
+        }
+    }
+
+    /// Receives the new [Printer::state_update] whenever a poll changes it
+    pub fn subscribe_state(&self) -> broadcast::Receiver<PrinterStateUpdate> {
+        self.state_changes.subscribe()
+    }
+
+    /// Events published by the watcher thread, see [crate::manager::Printers::start_watch_thread]
+    pub fn subscribe_events(&self) -> broadcast::Receiver<PrinterEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn publish_event(&self, event: PrinterEvent) {
+        // Fails only when nobody is subscribed
+        self.events.send(event).ok();
+    }
 
-    pub fn get_meta(&mut self) -> Option<PrinterInfo> {
-        if self.info.is_none() {
-            match self.get_info() {
-                Ok(info) => self.info = Some(info),
+    /// Whether the light was on at the last status poll, None for models without one
+    pub fn led(&self) -> Option<bool> {
+        self.state.read().unwrap().led
+    }
+
+    /// Whether the filament sensor found no filament at the last status poll, None if the printer sends no flags
+    pub fn filament_runout(&self) -> Option<bool> {
+        self.state.read().unwrap().filament_runout
+    }
+
+    /// Profile of the printer's model, [DEFAULT_PROFILE] until its info was fetched
+    pub fn profile(&self) -> &'static ModelProfile {
+        self.state.read().unwrap().info.as_ref()
+            .map(|info| ModelProfile::for_machine_type(&info.model_name))
+            .unwrap_or(&DEFAULT_PROFILE)
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.profile().capabilities
+    }
+
+    /// Percentage of the current file's bytes printed, from the cached progress
+    pub fn progress_percent(&self) -> Option<u8> {
+        self.state.read().unwrap().progress.as_ref().and_then(|prog| prog.byte.percent())
+    }
+
+    pub fn availability(&self) -> PrinterAvailability {
+        let state = self.state.read().unwrap();
+        match (state.last_seen, state.is_online) {
+            (None, _) => PrinterAvailability::Pending,
+            (Some(_), true) => PrinterAvailability::Online,
+            (Some(_), false) => PrinterAvailability::Offline
+        }
+    }
+
+    /// When the printer last responded to the watcher thread
+    pub fn last_seen(&self) -> Option<OffsetDateTime> { self.state.read().unwrap().last_seen }
+
+    /// Info cached by [get_meta], None if the printer has not been reachable yet
+    pub fn info(&self) -> Option<PrinterInfo> { self.state.read().unwrap().info.clone() }
+
+    /// The current job from the cached state, None if the printer is not printing a file
+    pub fn job(&self) -> Option<PrinterJob> {
+        let state = self.state.read().unwrap();
+        let job = state.job.as_ref()?;
+        let progress = state.progress.as_ref()?;
+        Some(PrinterJob {
+            file: job.file.clone(),
+            machine_status: state.machine_status.clone(),
+            progress_percent: progress.byte.percent(),
+            layer_percent: progress.layer.percent(),
+            layer: progress.layer,
+            started_at: job.started_at,
+            elapsed_seconds: job.started_at.map(|start| (OffsetDateTime::now_utc() - start).whole_seconds().max(0) as u64),
+            remaining_seconds_estimate: job.remaining_seconds(progress),
+            speed_percent: job.speed_percent,
+            flow_percent: job.flow_percent,
+            z_height: job.z_height(),
+            layer_height_estimate: job.layer_height(progress.layer.current),
+            freshness: state.freshness(OffsetDateTime::now_utc())
+        })
+    }
+
+    /// Number of requests sent over an already open connection vs ones that had to reconnect
+    pub fn connection_stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            api_port: self.api_port,
+            reused: self.task.stats.reused.load(Ordering::Relaxed),
+            reopened: self.task.stats.reopened.load(Ordering::Relaxed),
+        }
+    }
+
+    pub async fn get_meta(&self) -> Option<PrinterInfo> {
+        if self.info().is_none() {
+            match self.get_info().await {
+                Ok(info) => self.state.write().unwrap().info = Some(info),
                 Err(e) => {
                     warn!("printer/{} get_meta error: {}", self.name, e);
                 }
             }
         }
-        self.info.clone()
+        self.info()
+    }
+
+    /// Queues the request on the printer's command task and waits for the response
+    pub async fn send_request(&self, printer_request: PrinterRequest) -> Result<PrinterResponse, PrinterError> {
+        let response = self.send_raw(printer_request).await.and_then(|response| response.parsed);
+        self.record_outcome(response.as_ref().err(), OffsetDateTime::now_utc());
+        response
+    }
+
+    fn record_outcome(&self, error: Option<&PrinterError>, now: OffsetDateTime) {
+        let health = &mut self.state.write().unwrap().health;
+        match error {
+            None => {
+                health.consecutive_failures = 0;
+                health.last_success = Some(now);
+            },
+            Some(e) => {
+                health.consecutive_failures += 1;
+                health.last_error = Some(LastPrinterError { kind: e.code().to_string(), message: e.to_string(), at: now });
+            }
+        }
+    }
+
+    pub fn health_summary(&self) -> HealthSummary {
+        self.state.read().unwrap().health.summary(OffsetDateTime::now_utc())
+    }
+
+    pub fn health(&self) -> PrinterHealth {
+        let state = self.state.read().unwrap();
+        PrinterHealth {
+            health: state.health.summary(OffsetDateTime::now_utc()),
+            consecutive_failures: state.health.consecutive_failures,
+            last_success: state.health.last_success,
+            last_error: state.health.last_error.clone(),
+            connection: self.connection_stats(),
+            gcode_connections: self.task.connections.usage(),
+            same_serial_as: Vec::new(),
+            in_flight: self.in_flight(),
+            latency: self.task.latencies.recent(),
+            #[cfg(feature = "camera")]
+            camera: Some(self.camera.health()),
+            #[cfg(not(feature = "camera"))]
+            camera: None
+        }
+    }
+
+    /// The command the printer is busy with, if any
+    pub fn in_flight(&self) -> Option<InFlightCommand> {
+        self.task.in_flight.lock().unwrap().as_ref().map(|in_flight| InFlightCommand {
+            command: in_flight.command.clone(),
+            started_at: in_flight.started_at,
+            elapsed_ms: in_flight.started.elapsed().as_millis() as u64
+        })
+    }
+
+    /// Like [Printer::send_request], but keeps the printer's response text. Only fails if no response was received
+    pub async fn send_raw(&self, printer_request: PrinterRequest) -> Result<RawResponse, PrinterError> {
+        let (reply, response) = oneshot::channel();
+        metrics::printer_request_sent(&self.name, &printer_request);
+        let command = PrinterCommand::Request { request: printer_request, reply, profile: self.profile(),
+            require_control: self.require_control.load(Ordering::Relaxed), span: Span::current() };
+        let response = tokio::time::timeout(COMMAND_TIMEOUT, async {
+            self.commands.send(command).await.map_err(|_| PrinterError::Unreachable("printer task stopped".to_string()))?;
+            response.await.map_err(|_| PrinterError::Unreachable("printer task dropped the request".to_string()))?
+        }).await.unwrap_or_else(|_| Err(PrinterError::Timeout("timed out waiting for the printer".to_string())));
+        metrics::printer_request_answered();
+        response
+    }
+
+    /// Sends the requests one after the other, with no other request in between, stopping at the first that fails.
+    /// The error has the index of the request that failed
+    pub async fn send_batch(&self, requests: Vec<PrinterRequest>) -> Result<Vec<RawResponse>, (usize, PrinterError)> {
+        let (reply, responses) = oneshot::channel();
+        // Every request can take up to the read timeout, on top of the time spent queued
+        let timeout = COMMAND_TIMEOUT + READ_TIMEOUT * requests.len() as u32;
+        let command = PrinterCommand::Batch { requests, reply, profile: self.profile(),
+            require_control: self.require_control.load(Ordering::Relaxed), span: Span::current() };
+        let responses = tokio::time::timeout(timeout, async {
+            self.commands.send(command).await.map_err(|_| (0, PrinterError::Unreachable("printer task stopped".to_string())))?;
+            responses.await.map_err(|_| (0, PrinterError::Unreachable("printer task dropped the request".to_string())))?
+        }).await.unwrap_or_else(|_| Err((0, PrinterError::Timeout("timed out waiting for the printer".to_string()))));
+        self.record_outcome(responses.as_ref().err().map(|(_, e)| e), OffsetDateTime::now_utc());
+        responses
     }
 
-    fn process_requests(&self, requests: &[PrinterRequest]) -> Result<PrinterResponse, String> {
-        trace!("connecting to {:?}", self.socket_addr);
-        let mut conn = TcpStream::connect(self.socket_addr).map_err(|e| e.to_string())?;
-        conn.set_write_timeout(Some(Duration::from_secs(3))).unwrap();
-        conn.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    /// Stops the camera task and releases control of the printer. Requests still work afterwards,
+    /// they open a new connection
+    pub async fn shutdown(&self) {
+        #[cfg(feature = "camera")]
+        self.camera.stop();
+        let (done, released) = oneshot::channel();
+        if self.commands.send(PrinterCommand::Release(done)).await.is_ok() {
+            released.await.ok();
+        }
+    }
 
-        // let mut results: Vec<String> = Vec::with_capacity(requests.len());
-        let mut buf = [0; 1024];
-        let mut result: Option<PrinterResponse> = None;
-        if requests.is_empty() {
-            panic!("No requests given")
+    /// The last known file, status and job, to be saved across restarts
+    pub fn saved_state(&self) -> SavedPrinter {
+        let state = self.state.read().unwrap();
+        SavedPrinter {
+            current_file: state.current_file.clone(),
+            machine_status: state.machine_status.as_ref().map(|status| status.to_string()),
+            job: state.job.as_ref().map(|job| SavedJob { file: job.file.clone(), started_at: job.started_at })
         }
-        for request in requests {
-            let req_str = request.get_instruction();
-            conn.write_all(req_str.as_bytes()).map_err(|e| e.to_string())?;
-            let n = conn.read(&mut buf).map_err(|e| e.to_string())?;
-            let str = String::from_utf8_lossy(&buf[..n]);
-            result = Some(request.parse_response(&str)?);
+    }
+
+    /// Restores the state saved before a restart, shown until the printer is reached. If the printer is
+    /// still printing the same file on the first poll, the job is continued with its original start time
+    pub fn restore(&self, saved: SavedPrinter) {
+        let mut state = self.state.write().unwrap();
+        state.current_file = saved.current_file;
+        state.machine_status = saved.machine_status.as_deref().map(MachineStatus::parse);
+        state.job = saved.job.map(|job| JobState::new(job.file, job.started_at));
+    }
+
+    /// Polls the printer outside the watcher thread's schedule, fetching its info again as well
+    pub async fn refresh(&self) -> Result<(), PrinterError> {
+        self.refresh_status().await?;
+        let info = self.get_info().await?;
+        self.state.write().unwrap().info = Some(info);
+        Ok(())
+    }
+
+    pub fn set_maintenance(&self, maintenance: MaintenanceMode) {
+        self.state.write().unwrap().maintenance = maintenance.enabled.then_some(maintenance);
+    }
+
+    /// Whether the printer is in maintenance, ending it once its until time has passed
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance().enabled
+    }
+
+    pub fn maintenance(&self) -> MaintenanceMode {
+        let mut state = self.state.write().unwrap();
+        if let Some(until) = state.maintenance.as_ref().and_then(|maintenance| maintenance.until) {
+            if until <= OffsetDateTime::now_utc() {
+                info!("printer/{} maintenance ended", self.name);
+                state.maintenance = None;
+            }
         }
-        Ok(result.unwrap())
+        state.maintenance.clone().unwrap_or(MaintenanceMode { enabled: false, until: None })
     }
 
-    pub fn send_request(&self, printer_request: PrinterRequest) -> Result<PrinterResponse, String> {
-        let requests = vec![
-            PrinterRequest::ControlMessage,
-            printer_request
-        ];
-        self.process_requests(&requests)
+    /// The cached state listed by /api/printers
+    pub fn cached_info(&self) -> CachedPrinterInfo {
+        let info = self.info();
+        CachedPrinterInfo {
+            name: self.name.clone(),
+            display_name: self.display_name(),
+            location: self.labels().location,
+            state: self.availability(),
+            is_online: self.online(),
+            is_printing: self.is_printing(),
+            current_file: self.current_file(),
+            progress_percent: self.progress_percent(),
+            firmware_version: info.as_ref().map(|info| info.firmware_version.clone()),
+            model_name: info.as_ref().map(|info| info.model_name.clone()),
+            sn: info.as_ref().map(|info| info.sn.clone()),
+            last_seen: self.last_seen(),
+            health: self.health_summary(),
+            maintenance: self.in_maintenance(),
+            connection: self.connection_stats(),
+            freshness: self.state.read().unwrap().freshness(OffsetDateTime::now_utc()),
+        }
     }
 
-    pub fn refresh_status(&mut self) -> Result<(), String> {
-        if let Ok(status) = self.get_status() {
-            self.current_file = status.current_file;
-            self.is_online = true;
+    pub async fn refresh_status(&self) -> Result<(), PrinterError> {
+        let status = self.get_status().await;
+        self.state.write().unwrap().last_polled = Some(OffsetDateTime::now_utc());
+        if let Ok(status) = status {
+            let progress = match status.current_file {
+                Some(_) => self.get_progress().await.ok(),
+                None => None
+            };
+            let previous = self.state_update();
+            {
+                let mut state = self.state.write().unwrap();
+                let now = OffsetDateTime::now_utc();
+                match (&status.current_file, &progress) {
+                    (Some(file), Some(progress)) => {
+                        if state.job.as_ref().is_none_or(|job| job.file != *file) {
+                            // A job that is already underway on the first poll started before we were watching
+                            let seen_start = state.last_seen.is_some() || progress.byte.current == 0;
+                            state.job = Some(JobState::new(file.clone(), seen_start.then_some(now)));
+                        }
+                        let job = state.job.as_mut().unwrap();
+                        if job.samples.len() == JOB_SAMPLE_WINDOW {
+                            job.samples.pop_front();
+                        }
+                        job.samples.push_back((Instant::now(), progress.byte.current));
+                        if progress.layer.total > 0 {
+                            job.lowest_layer = Some(job.lowest_layer.map_or(progress.layer.current, |lowest| lowest.min(progress.layer.current)));
+                        }
+                    },
+                    (None, _) => state.job = None,
+                    _ => {}
+                }
+                state.current_file = status.current_file;
+                state.machine_status = Some(status.machine_status);
+                state.end_stop = Some(status.end_stop);
+                state.led = status.led;
+                state.filament_runout = status.flags.map(|flags| flags.filament_runout);
+                state.progress = progress;
+                state.is_online = true;
+                state.last_seen = Some(now);
+            }
+            let update = self.state_update();
+            if update != previous {
+                // Fails only when nobody is subscribed
+                self.state_changes.send(update).ok();
+            }
+            // Printers offline at startup have no info yet
+            self.get_meta().await;
         } else {
-            self.is_online = false;
-            // TODO: own error enum
-            return Err("Printer unreachable or offline".to_string());
+            self.state.write().unwrap().is_online = false;
+            return status.map(|_| ());
         }
         Ok(())
     }
 
-    pub fn get_info(&self) -> Result<PrinterInfo, String> {
-        match self.send_request(PrinterRequest::GetInfo) {
+    pub async fn get_info(&self) -> Result<PrinterInfo, PrinterError> {
+        match self.send_request(PrinterRequest::GetInfo).await {
             Ok(PrinterResponse::PrinterInfo(info)) => Ok(info),
             Ok(_) => panic!("got wrong response from request"),
             Err(e) => Err(e)
         }
     }
 
-    pub fn get_status(&self) -> Result<PrinterStatus, String> {
-        match self.send_request(PrinterRequest::GetStatus) {
+    pub async fn get_status(&self) -> Result<PrinterStatus, PrinterError> {
+        match self.send_request(PrinterRequest::GetStatus).await {
             Ok(PrinterResponse::PrinterStatus(v)) => Ok(v),
             Ok(_) => panic!("got wrong response from request"),
             Err(e) => Err(e)
         }
     }
 
-    pub fn get_temperatures(&self) -> Result<PrinterTemperature, String> {
-        match self.send_request(PrinterRequest::GetTemperature) {
-            Ok(PrinterResponse::PrinterTemperature(t)) => Ok(t),
+    pub async fn get_temperatures(&self) -> Result<PrinterTemperature, PrinterError> {
+        match self.send_request(PrinterRequest::GetTemperature).await {
+            Ok(PrinterResponse::PrinterTemperature(t)) => {
+                self.check_tool_count(&t);
+                Ok(t)
+            },
             Ok(_) => panic!("got wrong response from request"),
             Err(e) => Err(e)
         }
     }
 
-    pub fn get_progress(&self) -> Result<PrinterProgress, String> {
-        match self.send_request(PrinterRequest::GetProgress) {
+    /// Logs once when the printer reports temperatures for a different number of extruders than its tool count
+    fn check_tool_count(&self, temps: &PrinterTemperature) {
+        let mut state = self.state.write().unwrap();
+        let Some(tool_count) = state.info.as_ref().map(|info| info.tool_count as usize) else { return };
+        let extruders = temps.normalize().extruders.len();
+        if extruders != tool_count && !state.tool_count_warned {
+            warn!("printer/{} reports temperatures for {} extruders, but has {} tools", self.name, extruders, tool_count);
+            state.tool_count_warned = true;
+        }
+    }
+
+    pub async fn get_progress(&self) -> Result<PrinterProgress, PrinterError> {
+        match self.send_request(PrinterRequest::GetProgress).await {
             Ok(PrinterResponse::PrinterProgress(t)) => Ok(t),
             Ok(_) => panic!("got wrong response from request"),
             Err(e) => Err(e)
         }
     }
 
-    pub fn get_head_position(&self) -> Result<PrinterHeadPosition, String> {
-        match self.send_request(PrinterRequest::GetHeadPosition) {
+    pub async fn get_head_position(&self) -> Result<PrinterHeadPosition, PrinterError> {
+        match self.send_request(PrinterRequest::GetHeadPosition).await {
             Ok(PrinterResponse::PrinterHeadPosition(t)) => Ok(t),
             Ok(_) => panic!("got wrong response from request"),
             Err(e) => Err(e)
         }
     }
 
-    pub fn set_temperature(&self, temp_index: u8, temperature_c: f32) -> Result<ControlSuccess, String> {
-        match self.send_request(PrinterRequest::SetTemperature(temp_index, temperature_c)) {
+    pub async fn set_temperature(&self, temp_index: u8, temperature_c: f32) -> Result<ControlSuccess, PrinterError> {
+        match self.send_request(PrinterRequest::SetTemperature(temp_index, temperature_c)).await {
+            Ok(PrinterResponse::ControlSuccess(res)) => Ok(res),
+            Ok(_) => panic!("got wrong response from request"),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Changes the movement speed (M220) or flow (M221) of the current print, kept for [Printer::job] until it ends
+    pub async fn set_print_factor(&self, factor: PrintFactorKind, percent: u16) -> Result<ControlSuccess, PrinterError> {
+        let request = match factor {
+            PrintFactorKind::Speed => PrinterRequest::SetSpeedFactor(percent),
+            PrintFactorKind::Flow => PrinterRequest::SetFlowFactor(percent)
+        };
+        match self.send_request(request).await {
+            Ok(PrinterResponse::ControlSuccess(res)) => {
+                if let Some(job) = self.state.write().unwrap().job.as_mut() {
+                    match factor {
+                        PrintFactorKind::Speed => job.speed_percent = Some(percent),
+                        PrintFactorKind::Flow => job.flow_percent = Some(percent)
+                    }
+                }
+                Ok(res)
+            },
+            Ok(_) => panic!("got wrong response from request"),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub async fn set_led(&self, on: bool) -> Result<ControlSuccess, PrinterError> {
+        match self.send_request(PrinterRequest::SetLed(on)).await {
             Ok(PrinterResponse::ControlSuccess(res)) => Ok(res),
             Ok(_) => panic!("got wrong response from request"),
             Err(e) => Err(e)
         }
     }
+}
 
-    /// Returns the last received image, if any. Call [get_camera_snapshot] for a live
-    pub fn last_image(&self) -> Option<Vec<u8>> {
-        let read = self.last_image.read().expect("poisoned");
-        read.clone()
-    }
-
-    /// Gets a fresh camera snapshot, by internally calling [subscribe_camera]()
-    pub async fn get_camera_snapshot(&mut self) -> Result<Vec<u8>, String> {
-        let mut rx = self.subscribe_camera().map_err(|e| e.to_string())?;
-        trace!("subscribed, now waiting for image");
-        let part = rx.recv().await.map_err(|e| e.to_string())?;
-        trace!("returning image");
-        Ok(part.body.to_vec())
-    }
-
-    /// Returns a receiver that returns Part (header and image body from multipart/x-mixed-replace)
-    /// If there is not already a connection to printer's camera, a new one will be created.
-    /// Image is JPEG, size is provided in header `Content-length`
-    pub fn subscribe_camera(&mut self) -> Result<broadcast::Receiver<Part>, String> {
-        let sub = self.camera_channel.subscribe();
-        let image_store = self.last_image.clone();
-        if self.camera_task.is_none() || self.camera_task.as_ref().unwrap().is_finished() {
-            let stream_url = format!("http://{}:{}{}", self.ip(), PRINTER_CAM_PORT, PRINTER_CAM_STREAM_PATH);
-            let stream_url = Url::parse(&stream_url).map_err(|e| e.to_string())?;
-            trace!("starting new camera task. stream url = {:?}", stream_url);
-
-            let tx = self.camera_channel.clone();
-            let task = tokio::spawn(async move {
-                trace!("starting reqwest");
-                // TODO: better handling of offline printer
-                let res = reqwest::get(stream_url).await.expect("failed to fetch stream");
-                let bytes_stream = res.bytes_stream();
-                trace!("starting read loop");
-                let image_store = image_store;
-                let mut chunk_stream = multipart_stream::parse(bytes_stream, "boundarydonotcross");
-                while let Ok(part) = chunk_stream.next().await.unwrap() {
-                    let mut write = image_store.write().unwrap();
-                    *write = Some(part.body.to_vec());
-                    if tx.send(part).is_err() {
-                        trace!("no more subscribers, stopping task");
-                        break;
+/// Runs the printer's commands one at a time until the printer is dropped. One connection is kept
+/// open between commands and closed with M602 after being idle for `idle_timeout`, unless the console is
+/// captured, which reads what the printer sends in between instead
+async fn run_commands(name: String, host: String, port: u16, idle_timeout: Duration, shared: Arc<CommandTaskShared>,
+                      mut commands: mpsc::Receiver<PrinterCommand>) {
+    let mut session: Option<Session> = None;
+    // One request, recorded as the in flight command and under its own span
+    let exchange = async |session: &mut Option<Session>, request: PrinterRequest, profile: &ModelProfile, require_control: bool, first: bool, span: &Span| {
+        let exchange = debug_span!(parent: span, "printer_exchange", printer = %name, gcode = request.get_instruction().trim(),
+            bytes_received = Empty, duration_ms = Empty);
+        let started = Instant::now();
+        *shared.in_flight.lock().unwrap() = Some(InFlight { command: request.get_gcode(), started, started_at: OffsetDateTime::now_utc() });
+        let options = SendOptions { needs_control: require_control || !request.is_read_only(), first };
+        let result = run_command(&host, port, &shared, session, request, profile, options).instrument(exchange.clone()).await;
+        *shared.in_flight.lock().unwrap() = None;
+        // Lines before the response's echo, which were not part of it
+        if let Some(conn) = session {
+            shared.console.record(&name, conn.client.take_unsolicited());
+        }
+        exchange.record("duration_ms", started.elapsed().as_millis() as u64);
+        exchange.in_scope(|| trace!("exchange finished, ok={}", result.is_ok()));
+        result
+    };
+    loop {
+        let command = match session.as_mut() {
+            Some(conn) if shared.console.capturing() => tokio::select! {
+                command = commands.recv() => command,
+                read = conn.client.read_unsolicited() => {
+                    match read {
+                        Ok(()) => shared.console.record(&name, conn.client.take_unsolicited()),
+                        Err(e) => {
+                            debug!("connection to {} lost while reading the console ({}), reconnecting on the next command", host_port(&host, port), e);
+                            session = None;
+                        }
                     }
+                    continue;
+                }
+            },
+            Some(_) => match tokio::time::timeout(idle_timeout, commands.recv()).await {
+                Ok(command) => command,
+                Err(_) => {
+                    trace!("closing idle connection to {}", host_port(&host, port));
+                    close_session(session.take().unwrap()).await;
+                    continue;
+                }
+            },
+            None => commands.recv().await
+        };
+        match command {
+            Some(PrinterCommand::Request { request, reply, profile, require_control, span }) => {
+                let result = exchange(&mut session, request, profile, require_control, true, &span).await;
+                reply.send(result).ok();
+            },
+            Some(PrinterCommand::Batch { requests, reply, profile, require_control, span }) => {
+                let mut responses = Vec::with_capacity(requests.len());
+                let mut result = Ok(());
+                for (i, request) in requests.into_iter().enumerate() {
+                    match exchange(&mut session, request, profile, require_control, i == 0, &span).await {
+                        Ok(response) => responses.push(response),
+                        Err(e) => {
+                            result = Err((i, e));
+                            break;
+                        }
+                    }
+                }
+                reply.send(result.map(|_| responses)).ok();
+            },
+            Some(PrinterCommand::Release(done)) => {
+                if let Some(conn) = session.take() {
+                    close_session(conn).await;
+                }
+                done.send(()).ok();
+            },
+            None => break
+        }
+    }
+    if let Some(conn) = session {
+        close_session(conn).await;
+    }
+}
+
+/// How [run_command] sends a request
+#[derive(Clone, Copy)]
+struct SendOptions {
+    /// Take control first if the session has none yet
+    needs_control: bool,
+    /// Nothing of the batch was sent before, so the request can be sent again if the printer never answered it
+    first: bool
+}
+
+/// Sends the request over the open session. Printers close idle connections without telling, so if the session turns
+/// out to be dead it reconnects and sends the request once more, unless the printer may have acted on it already.
+/// Queries answered with nothing, as by printers that just booted, are also sent once more
+async fn run_command(host: &str, port: u16, shared: &CommandTaskShared, session: &mut Option<Session>, request: PrinterRequest,
+                     profile: &ModelProfile, options: SendOptions) -> Result<RawResponse, PrinterError> {
+    let mut resent = false;
+    if let Some(mut conn) = session.take() {
+        shared.stats.reused.fetch_add(1, Ordering::Relaxed);
+        match send_over(&mut conn, &request, options.needs_control, &shared.latencies).await {
+            Ok(text) => {
+                *session = Some(conn);
+                return Ok(parse_response(&request, text, profile));
+            },
+            // The printer answered, so the connection is still good for queries
+            Err(e @ PrinterError::ControlDenied(_)) => {
+                *session = Some(conn);
+                return Err(e);
+            },
+            Err(e) => {
+                // Writing failed or the connection closed before any of the response, so the printer never saw it
+                let unanswered = matches!(e, PrinterError::Unreachable(_)) && !conn.client.was_answered();
+                let resend = request.is_idempotent() || (unanswered && options.first);
+                if !resend {
+                    debug!("connection to {} lost after sending {} ({}), not sending it again", host_port(host, port), request.get_gcode(), e);
+                    return Err(e);
                 }
-            });
-            self.camera_task = Some(task);
+                info!("connection to {} lost ({}), reconnecting to send {} again", host_port(host, port), e, request.get_gcode());
+                resent = true;
+            }
         }
-        Ok(sub)
     }
-}
\ No newline at end of file
+    let permit = shared.connections.acquire("TCP API").await?;
+    shared.stats.reopened.fetch_add(1, Ordering::Relaxed);
+    let mut conn = open_session(host, port, permit).await?;
+    let mut sent = send_over(&mut conn, &request, options.needs_control, &shared.latencies).await;
+    if !resent && matches!(sent, Err(PrinterError::EmptyResponse(_))) {
+        info!("{} answered {} with nothing, sending it again", host_port(host, port), request.get_gcode());
+        sent = send_over(&mut conn, &request, options.needs_control, &shared.latencies).await;
+    }
+    // The whole response was read unless the connection failed
+    if matches!(sent, Ok(_) | Err(PrinterError::ControlDenied(_) | PrinterError::EmptyResponse(_) | PrinterError::InvalidResponse(_))) {
+        *session = Some(conn);
+    }
+    Ok(parse_response(&request, sent?, profile))
+}
+
+/// Sends the request, first taking control of the printer if the request needs it and the session has none yet
+async fn send_over(session: &mut Session, request: &PrinterRequest, needs_control: bool, latencies: &Latencies) -> Result<String, PrinterError> {
+    if needs_control && !session.controlled {
+        let text = send_timed(&mut session.client, &PrinterRequest::ControlMessage, latencies).await?;
+        if let Ok(PrinterResponse::ControlSuccess(ControlSuccess { success: false })) = PrinterRequest::ControlMessage.parse_response(&text) {
+            return Err(PrinterError::ControlDenied("printer refused control (M601), FlashPrint or another client is probably holding the connection. \
+                Close it, or set require_control = false to query the printer without control".to_string()));
+        }
+        session.controlled = true;
+    }
+    send_timed(&mut session.client, request, latencies).await
+}
+
+/// [send_request], recording how long the printer took to answer
+async fn send_timed(client: &mut AsyncClient<TcpStream>, request: &PrinterRequest, latencies: &Latencies) -> Result<String, PrinterError> {
+    let started = Instant::now();
+    let response = send_request(client, request).await?;
+    latencies.record(&request.get_gcode(), started.elapsed());
+    Ok(response)
+}
+
+/// The whole response was read, so the connection can be used again even if it can't be parsed
+fn parse_response(request: &PrinterRequest, text: String, profile: &ModelProfile) -> RawResponse {
+    // Parsing an unexpected response can panic, which should only fail this request
+    let parsed = request.parse_response_for(&text, profile).map_err(PrinterError::InvalidResponse);
+    RawResponse { text, parsed }
+}
+
+async fn open_session(host: &str, port: u16, permit: OwnedSemaphorePermit) -> Result<Session, PrinterError> {
+    trace!("connecting to {}", host_port(host, port));
+    // Resolved on every connect so DHCP lease changes of hostnames are picked up
+    let conn = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await
+        .map_err(|_| PrinterError::Timeout("connection timed out".to_string()))?
+        .map_err(|e| PrinterError::Unreachable(e.to_string()))?;
+    Ok(Session { client: AsyncClient::new(conn).with_timeouts(WRITE_TIMEOUT, READ_TIMEOUT), controlled: false, _permit: permit })
+}
+
+async fn close_session(mut session: Session) {
+    if session.controlled {
+        if let Err(e) = tokio::time::timeout(WRITE_TIMEOUT, send_request(&mut session.client, &PrinterRequest::ReleaseControl)).await {
+            trace!("releasing control failed: {}", e);
+        }
+    }
+    session.client.into_inner().shutdown().await.ok();
+}
+
+/// Sends the request and reads the response up to the final "ok"
+async fn send_request(client: &mut AsyncClient<TcpStream>, request: &PrinterRequest) -> Result<String, PrinterError> {
+    let response = client.send(request).await?;
+    Span::current().record("bytes_received", response.len());
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "camera")]
+    use crate::test_support::{mock_camera, CAMERA_IMAGE};
+    use crate::test_support::{fixture, unused_port, MockPrinter};
+    use std::time::Instant;
+    use crate::models::{ConnectionPermits, Progress};
+
+    const IDLE: Duration = Duration::from_secs(30);
+
+    #[tokio::test]
+    async fn ipv6_addresses_are_bracketed_in_urls() {
+        let printer = Printer::new("v6".to_string(), "fd00::50".to_string(), PRINTER_API_PORT, IDLE);
+        assert_eq!(printer.socket_addr(), Some("[fd00::50]:8899".parse().unwrap()));
+        #[cfg(feature = "camera")]
+        assert_eq!(printer.camera().stream_url(), "http://[fd00::50]:8080/?action=stream");
+
+        let printer = Printer::new("v4".to_string(), "10.0.0.50".to_string(), PRINTER_API_PORT, IDLE);
+        assert_eq!(printer.socket_addr(), Some("10.0.0.50:8899".parse().unwrap()));
+        #[cfg(feature = "camera")]
+        assert_eq!(printer.camera().stream_url(), "http://10.0.0.50:8080/?action=stream");
+        let printer = Printer::new("named".to_string(), "printer.lan".to_string(), PRINTER_API_PORT, IDLE);
+        assert_eq!(printer.socket_addr(), None);
+    }
+
+    #[test]
+    fn health_summary_follows_failures() {
+        let now = OffsetDateTime::now_utc();
+        let error = |at| Some(LastPrinterError { kind: "PRINTER_TIMEOUT".to_string(), message: String::new(), at });
+        let health = |consecutive_failures, last_success, last_error| HealthState { consecutive_failures, last_success, last_error }.summary(now);
+        assert_eq!(health(0, None, None), HealthSummary::Offline);
+        assert_eq!(health(0, Some(now), None), HealthSummary::Ok);
+        assert_eq!(health(1, Some(now), error(now)), HealthSummary::Degraded);
+        assert_eq!(health(OFFLINE_AFTER_FAILURES, Some(now), error(now)), HealthSummary::Offline);
+        // Recovered, but dropped recently
+        assert_eq!(health(0, Some(now), error(now - time::Duration::minutes(1))), HealthSummary::Degraded);
+        assert_eq!(health(0, Some(now), error(now - DEGRADED_AFTER_ERROR)), HealthSummary::Ok);
+    }
+
+    #[cfg(feature = "camera")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn status_is_not_blocked_by_snapshots() {
+        let camera_delay = Duration::from_secs(2);
+        let mock = MockPrinter::start().await;
+        let printer = Arc::new(Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock_camera(camera_delay).await, IDLE));
+        let start = Instant::now();
+
+        let snapshots: Vec<_> = (0..5).map(|_| {
+            let printer = printer.clone();
+            tokio::spawn(async move { printer.camera().snapshot().await })
+        }).collect();
+        let statuses: Vec<_> = (0..50).map(|_| {
+            let printer = printer.clone();
+            tokio::spawn(async move { printer.get_status().await })
+        }).collect();
+
+        for status in statuses {
+            let status = status.await.unwrap().expect("status request failed");
+            assert_eq!(status.machine_status, MachineStatus::Ready);
+        }
+        assert!(start.elapsed() < camera_delay, "status requests waited on the camera ({:?})", start.elapsed());
+        // Each has its own limit, the camera's only connection is still waiting for a frame
+        assert_eq!(printer.health().camera.unwrap().connections.in_use, 1);
+        assert_eq!(printer.health().gcode_connections.in_use, 1);
+
+        for snapshot in snapshots {
+            assert_eq!(snapshot.await.unwrap().unwrap(), CAMERA_IMAGE);
+        }
+        assert!(start.elapsed() >= camera_delay);
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_fail_as_busy() {
+        let mock = MockPrinter::start().await;
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, IDLE);
+        printer.set_connection_limits(1, 1, Duration::from_millis(100));
+        printer.get_status().await.unwrap();
+        assert_eq!(printer.health().gcode_connections, ConnectionPermits { limit: 1, in_use: 1, waiting: 0 });
+        // The open session is probed instead of opening a second connection
+        printer.probe_api().await.unwrap();
+        assert_eq!(mock.received().len(), 1);
+
+        printer.shutdown().await;
+        let held = printer.task.connections.try_acquire().unwrap();
+        let busy = printer.get_status().await.unwrap_err();
+        assert_eq!(busy.code(), "PRINTER_BUSY");
+        assert_eq!(busy.to_string(), "all 1 connections to the TCP API stayed in use for 100ms");
+        drop(held);
+        printer.get_status().await.unwrap();
+
+        printer.set_connection_limits(2, 1, Duration::from_millis(100));
+        assert_eq!(printer.health().gcode_connections, ConnectionPermits { limit: 2, in_use: 1, waiting: 0 });
+        printer.set_connection_limits(1, 1, Duration::from_millis(100));
+        assert_eq!(printer.health().gcode_connections.limit, 1);
+    }
+
+    #[tokio::test]
+    async fn request_fails_when_printer_is_unreachable() {
+        let port = unused_port().await;
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), port, port, IDLE);
+        assert!(printer.get_status().await.is_err());
+        // The command task keeps running after a failed request
+        assert!(printer.refresh_status().await.is_err());
+        assert!(!printer.online());
+    }
+
+    #[tokio::test]
+    async fn reuses_connection_between_requests() {
+        let mock = MockPrinter::start().await;
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, IDLE);
+        for _ in 0..3 {
+            printer.get_status().await.unwrap();
+        }
+        let stats = printer.connection_stats();
+        assert_eq!((stats.reused, stats.reopened), (2, 1));
+        assert_eq!(mock.received(), vec![vec!["~M601 S1", "~M119", "~M119", "~M119"]]);
+    }
+
+    #[tokio::test]
+    async fn reconnects_when_printer_closes_connection() {
+        // Printer hangs up after taking control and answering one request
+        let mock = MockPrinter::start_with(2).await;
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, IDLE);
+        for _ in 0..3 {
+            assert_eq!(printer.get_status().await.unwrap().machine_status, MachineStatus::Ready);
+        }
+        let stats = printer.connection_stats();
+        assert_eq!((stats.reused, stats.reopened), (2, 3));
+        assert_eq!(mock.received().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn commands_are_only_sent_again_if_the_printer_never_saw_them() {
+        // Hangs up after taking control and answering the status, as printers do with idle connections
+        let mock = MockPrinter::start_with(2).await;
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, IDLE);
+        printer.get_status().await.unwrap();
+        printer.set_led(true).await.unwrap();
+        assert_eq!(mock.received(), vec![vec!["~M601 S1", "~M119"], vec!["~M601 S1", "~M146 r255 g255 b255 F0"]]);
+
+        // Hangs up halfway through the answer, so the light may be off already
+        let mock = MockPrinter::start_with(3).await;
+        mock.respond("M146", "CMD M146 Received.\n");
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, IDLE);
+        printer.get_status().await.unwrap();
+        assert_eq!(printer.set_led(false).await.unwrap_err().code(), "PRINTER_UNREACHABLE");
+        assert_eq!(mock.received().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn empty_answers_to_queries_are_asked_again_once() {
+        let mock = MockPrinter::start().await;
+        mock.respond("M105", &fixture("M105_empty"));
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, IDLE);
+        assert_eq!(printer.get_temperatures().await.unwrap_err().code(), "PRINTER_EMPTY_RESPONSE");
+        assert_eq!(mock.received(), vec![vec!["~M601 S1", "~M105", "~M105"]]);
+        // The connection is still good for the next request
+        printer.get_status().await.unwrap();
+        assert_eq!(mock.received().len(), 1);
+        // Commands can be answered with nothing
+        printer.set_led(true).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn batches_stop_at_the_first_failure() {
+        let mock = MockPrinter::start().await;
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, IDLE);
+        let batch = || vec![PrinterRequest::Raw("~M104 S200 T0".to_string()), PrinterRequest::Raw("~M140 S60".to_string()), PrinterRequest::SetLed(true)];
+        let responses = printer.send_batch(batch()).await.unwrap();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[1].text, "CMD M140 Received.\r\nok\r\n");
+        assert_eq!(mock.received(), vec![vec!["~M601 S1", "~M104 S200 T0", "~M140 S60", "~M146 r255 g255 b255 F0"]]);
+
+        // Hangs up after taking control and answering one request, so M140 is not sent again after M104 was applied
+        let mock = MockPrinter::start_with(2).await;
+        mock.respond("M140", "CMD M140 Received.\n");
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, IDLE);
+        let (step, _) = printer.send_batch(batch()).await.err().unwrap();
+        assert_eq!(step, 1);
+        assert!(!mock.received().concat().iter().any(|line| line.starts_with("~M146")));
+    }
+
+    #[tokio::test]
+    async fn queries_skip_control_unless_required() {
+        let mock = MockPrinter::start().await;
+        mock.respond("M601", &fixture("M601_denied"));
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, IDLE);
+        let denied = printer.get_status().await.unwrap_err();
+        assert_eq!(denied.code(), "CONTROL_DENIED");
+
+        // Queries work while FlashPrint has control, commands still need it
+        printer.set_require_control(false);
+        printer.get_status().await.unwrap();
+        assert_eq!(printer.set_led(true).await.unwrap_err().code(), "CONTROL_DENIED");
+        mock.respond("M601", &fixture("M601"));
+        printer.set_led(true).await.unwrap();
+        printer.get_status().await.unwrap();
+        assert_eq!(mock.received(), vec![vec!["~M601 S1", "~M119", "~M601 S1", "~M601 S1", "~M146 r255 g255 b255 F0", "~M119"]]);
+    }
+
+    #[tokio::test]
+    async fn releases_control_when_idle() {
+        let mock = MockPrinter::start().await;
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, Duration::from_millis(50));
+        printer.get_status().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        printer.get_status().await.unwrap();
+        let stats = printer.connection_stats();
+        assert_eq!((stats.reused, stats.reopened), (0, 2));
+        assert_eq!(mock.received()[0], vec!["~M601 S1", "~M119", "~M602"]);
+    }
+
+    #[tokio::test]
+    async fn captured_console_lines_are_kept_out_of_responses() {
+        let mock = MockPrinter::start().await;
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, Duration::from_millis(50));
+        printer.console().set_capture(10);
+        let mut events = printer.subscribe_events();
+        mock.respond("M119", &format!("Nozzle heating\r\n{}", fixture("M119")));
+        printer.get_status().await.unwrap();
+
+        // The connection stays open past the idle timeout to receive lines in between
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        mock.push("Print paused by user\n");
+        let pushed = tokio::time::timeout(Duration::from_secs(5), async {
+            while events.recv().await != Ok(PrinterEvent::Console("Print paused by user".to_string())) {}
+        }).await;
+        assert!(pushed.is_ok(), "pushed line was not published");
+        let lines: Vec<String> = printer.console().last(200).unwrap().into_iter().map(|line| line.line).collect();
+        assert_eq!(lines, ["Nozzle heating", "Print paused by user"]);
+
+        printer.get_status().await.unwrap();
+        assert_eq!(mock.received(), vec![vec!["~M601 S1", "~M119", "~M119"]]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_releases_control() {
+        let mock = MockPrinter::start().await;
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, Duration::from_secs(60));
+        printer.get_status().await.unwrap();
+        printer.shutdown().await;
+        assert_eq!(mock.received()[0], vec!["~M601 S1", "~M119", "~M602"]);
+    }
+
+    #[tokio::test]
+    async fn first_layers_are_passed_once_per_job() {
+        let mock = MockPrinter::start().await;
+        let printer = Printer::with_ports("test".to_string(), "127.0.0.1".to_string(), mock.port, mock.port, IDLE);
+        mock.respond("M119", &fixture("M119_printing"));
+        let poll = async |layer: u32, z: f32| {
+            mock.respond("M27", &format!("CMD M27 Received.\nSD printing byte 2400/12000\nLayer: {}/60\nok\n", layer));
+            mock.respond("M114", &format!("CMD M114 Received.\nX:10.5 Y:-20 Z:{} A:123 B:0\nok\n", z));
+            printer.refresh_status().await.unwrap();
+            printer.refresh_head_position().await.unwrap();
+            printer.passed_first_layers(3)
+        };
+        assert!(!poll(2, 0.5).await);
+        assert!(!poll(3, 0.75).await);
+        // A probing move is outvoted by the other samples
+        assert!(poll(4, 1.0).await);
+        assert!(!poll(10, 40.0).await);
+        let job = printer.job().unwrap();
+        assert_eq!(job.z_height, Some(1.0));
+        assert_eq!(job.layer_height_estimate, Some(0.1));
+        assert!(!poll(11, 2.75).await);
+    }
+
+    #[tokio::test]
+    async fn jobs_first_seen_past_the_first_layers_are_not_reported() {
+        let mut job = JobState::new("test.gx".to_string(), None);
+        job.z_samples.extend([0.2, 0.4]);
+        assert_eq!(job.z_height(), Some(0.4));
+        assert_eq!(job.layer_height(2), Some(0.2));
+        assert_eq!(job.layer_height(0), None);
+
+        let state = PrinterState { job: Some(JobState { lowest_layer: Some(20), ..job }), progress: Some(PrinterProgress {
+            byte: Progress { current: 2400, total: 12000 }, layer: Progress { current: 21, total: 60 } }), ..Default::default() };
+        let printer = Printer::new("test".to_string(), "127.0.0.1".to_string(), PRINTER_API_PORT, IDLE);
+        *printer.state.write().unwrap() = state;
+        assert!(!printer.passed_first_layers(3));
+    }
+
+    #[test]
+    fn estimates_remaining_time_from_recent_rate() {
+        let start = Instant::now();
+        let job = JobState {
+            samples: VecDeque::from([(start, 1000), (start + Duration::from_secs(60), 1600), (start + Duration::from_secs(120), 2200)]),
+            ..JobState::new("test.gx".to_string(), None)
+        };
+        // 10 bytes a second with 8000 bytes to go
+        let progress = PrinterProgress { byte: Progress { current: 2200, total: 10200 }, layer: Progress { current: 5, total: 50 } };
+        assert_eq!(job.remaining_seconds(&progress), Some(800));
+
+        let stalled = JobState { samples: VecDeque::from([(start, 1000), (start + Duration::from_secs(60), 1000)]), ..job };
+        assert_eq!(stalled.remaining_seconds(&progress), None);
+    }
+
+    #[test]
+    fn cached_values_go_stale_after_two_missed_polls() {
+        let polled = OffsetDateTime::now_utc();
+        let mut state = PrinterState { last_polled: Some(polled), ..Default::default() };
+        assert_eq!(state.freshness(polled), Freshness { last_polled_at: Some(polled), stale: true });
+        state.last_seen = Some(polled);
+        assert!(!state.freshness(polled + PROGRESS_CHECK_INTERVAL * 2).stale);
+        assert!(state.freshness(polled + PROGRESS_CHECK_INTERVAL * 2 + Duration::from_secs(1)).stale);
+    }
+}
@@ -0,0 +1,179 @@
+//! Token bucket rate limiting per client ip, with a separate budget for the routes that make the printer do work
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::debug;
+use rocket::http::Status;
+use rocket::route::{Handler, Outcome};
+use rocket::{Data, Request, Route};
+use crate::config::RateLimitConfig;
+use crate::util::RetryAfter;
+
+/// Routes that fetch from the camera or send several commands to the printer
+const EXPENSIVE_ROUTES: [&str; 4] = ["/snapshot", "/camera", "/debug/raw", "/debug/record"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cost {
+    Cheap,
+    Expensive
+}
+
+impl Cost {
+    fn of(route: &Route) -> Self {
+        let path = route.uri.path();
+        if EXPENSIVE_ROUTES.iter().any(|suffix| path.ends_with(suffix)) { Cost::Expensive } else { Cost::Cheap }
+    }
+}
+
+struct Budget {
+    per_second: f64,
+    burst: f64
+}
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+impl Budget {
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.per_second).min(self.burst)
+    }
+}
+
+pub struct RateLimiter {
+    cheap: Budget,
+    expensive: Budget,
+    max_clients: usize,
+    /// Buckets of each client, cheap then expensive
+    clients: Mutex<HashMap<IpAddr, [Bucket; 2]>>
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            cheap: Budget { per_second: config.requests_per_second, burst: config.burst as f64 },
+            expensive: Budget { per_second: config.expensive_requests_per_second, burst: config.expensive_burst as f64 },
+            max_clients: config.max_clients,
+            clients: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Takes a token for the request, returning how long the client has to wait if there are none left
+    pub fn check(&self, ip: IpAddr, cost: Cost) -> Result<(), Duration> {
+        self.check_at(ip, cost, Instant::now())
+    }
+
+    fn budget(&self, cost: Cost) -> &Budget {
+        match cost {
+            Cost::Cheap => &self.cheap,
+            Cost::Expensive => &self.expensive
+        }
+    }
+
+    fn check_at(&self, ip: IpAddr, cost: Cost, now: Instant) -> Result<(), Duration> {
+        let mut clients = self.clients.lock().unwrap();
+        if !clients.contains_key(&ip) && clients.len() >= self.max_clients {
+            // Clients whose buckets refilled are the same as new ones, after that the longest idle one is dropped
+            clients.retain(|_, [cheap, expensive]| {
+                self.cheap.refill(cheap, now) < self.cheap.burst || self.expensive.refill(expensive, now) < self.expensive.burst
+            });
+            if clients.len() >= self.max_clients {
+                if let Some(idlest) = clients.iter().min_by_key(|(_, buckets)| buckets[0].updated.max(buckets[1].updated)).map(|(ip, _)| *ip) {
+                    clients.remove(&idlest);
+                }
+            }
+        }
+        let buckets = clients.entry(ip).or_insert([
+            Bucket { tokens: self.cheap.burst, updated: now },
+            Bucket { tokens: self.expensive.burst, updated: now }
+        ]);
+        let budget = self.budget(cost);
+        let bucket = &mut buckets[cost as usize];
+        bucket.tokens = budget.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / budget.per_second))
+        }
+    }
+}
+
+/// Rejects requests over the client's budget with a 429, when a [RateLimiter] is managed
+#[derive(Clone)]
+struct Limited {
+    handler: Box<dyn Handler>,
+    cost: Cost
+}
+
+#[rocket::async_trait]
+impl Handler for Limited {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        if let (Some(limiter), Some(ip)) = (request.rocket().state::<RateLimiter>(), request.client_ip()) {
+            if let Err(retry_after) = limiter.check(ip, self.cost) {
+                debug!("{} is rate limited for {:?}", ip, retry_after);
+                // Picked up by the 429 catcher to set the Retry-After header
+                request.local_cache(|| RetryAfter::RateLimited(retry_after));
+                return Outcome::Error(Status::TooManyRequests);
+            }
+        }
+        self.handler.handle(request, data).await
+    }
+}
+
+/// Wraps the routes so they count against the client's budget, call before mounting
+pub fn limited(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter().map(|mut route| {
+        let cost = Cost::of(&route);
+        route.handler = Box::new(Limited { handler: route.handler.clone(), cost });
+        route
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_clients: usize) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            requests_per_second: 10.0,
+            burst: 5,
+            expensive_requests_per_second: 0.5,
+            expensive_burst: 2,
+            max_clients
+        })
+    }
+
+    #[test]
+    fn limits_each_budget_separately() {
+        let limiter = limiter(16);
+        let ip: IpAddr = "192.168.1.2".parse().unwrap();
+        let now = Instant::now();
+        assert!(limiter.check_at(ip, Cost::Expensive, now).is_ok());
+        assert!(limiter.check_at(ip, Cost::Expensive, now).is_ok());
+        assert_eq!(limiter.check_at(ip, Cost::Expensive, now), Err(Duration::from_secs(2)));
+        // Other requests and clients still have their budget
+        assert!(limiter.check_at(ip, Cost::Cheap, now).is_ok());
+        assert!(limiter.check_at("192.168.1.3".parse().unwrap(), Cost::Expensive, now).is_ok());
+        // Refilled at 0.5 tokens per second
+        assert!(limiter.check_at(ip, Cost::Expensive, now + Duration::from_secs(2)).is_ok());
+        assert!(limiter.check_at(ip, Cost::Expensive, now + Duration::from_secs(2)).is_err());
+    }
+
+    #[test]
+    fn tracks_a_bounded_number_of_clients() {
+        let limiter = limiter(2);
+        let now = Instant::now();
+        for i in 0..100u8 {
+            let ip = IpAddr::from([10, 0, 0, i]);
+            assert!(limiter.check_at(ip, Cost::Cheap, now + Duration::from_millis(i as u64)).is_ok());
+        }
+        let clients = limiter.clients.lock().unwrap();
+        assert_eq!(clients.len(), 2);
+        assert!(clients.contains_key(&IpAddr::from([10, 0, 0, 99])));
+    }
+}
@@ -0,0 +1,33 @@
+//! Server administration, needs the password or an admin token whenever [auth] is configured
+use crate::errors::ErrorCode;
+use crate::audit::AuditLog;
+use crate::manager::PrinterManager;
+use crate::models::{AuditEntry, GenericError, SelfTestReport};
+use crate::selftest;
+use crate::util::{AccessType, AuthGuard};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, State};
+
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+const MAX_AUDIT_LIMIT: usize = 1000;
+
+/// The most recent write requests, newest first. Only mounted when [audit] is configured
+#[get("/audit?<limit>")]
+pub async fn get_audit_log(auth: AuthGuard, audit: &State<AuditLog>, limit: Option<usize>) -> Result<Json<Vec<AuditEntry>>, (Status, Json<GenericError>)> {
+    auth.check_auth(AccessType::Admin)?;
+    audit.recent(limit.unwrap_or(DEFAULT_AUDIT_LIMIT).min(MAX_AUDIT_LIMIT)).await
+        .map(Json)
+        .map_err(|e| ErrorCode::AuditError.response(e))
+}
+
+/// Checks every printer's API port and camera, the SMTP server and with include_webhooks every webhook, all at once
+#[get("/selftest?<include_webhooks>")]
+pub async fn self_test(auth: AuthGuard, manager: &State<PrinterManager>, include_webhooks: Option<bool>) -> Result<Json<SelfTestReport>, (Status, Json<GenericError>)> {
+    auth.check_auth(AccessType::Admin)?;
+    let (printers, config, notifier) = {
+        let manager = manager.lock().await;
+        (manager.printers(), manager.config(), manager.notifier())
+    };
+    Ok(Json(selftest::run(&printers, &config, &notifier, include_webhooks.unwrap_or(false)).await))
+}
@@ -1,17 +1,30 @@
+use crate::errors::ErrorCode;
 use crate::manager::{PrinterManager};
-use crate::models::{CachedPrinterInfo, ControlSuccess, GenericError, PrinterHeadPosition, PrinterInfo, PrinterProgress, PrinterStatus, PrinterTemperature};
-use base64::prelude::BASE64_STANDARD;
-use base64::Engine;
-use log::trace;
-use rocket::futures::Stream;
-use rocket::response::stream::{stream, ByteStream};
-use rocket::response::{Responder};
+use crate::history::{self, HistoryMetric};
+use crate::models::{CachedHeadPosition, CachedPrinterInfo, ConsoleLine, ControlSuccess, GenericError, LabeledPrinterInfo, MaintenanceMode, PrintFactor, PrinterHeadPosition, PrinterHealth, PrinterHistory, PrinterJob, PrinterNotificationState, PrinterPatch, TemperatureUnit, TemperaturesInUnit};
+use crate::config::{ConfigManager};
+use crate::printer::PrintFactorKind;
+use crate::validated;
+use flashforge_protocol::{PrinterRequest, PrinterResponse};
+use log::{debug, info};
 use rocket::serde::json::Json;
-use rocket::{get, post, Either, State};
-use std::io::Write;
-use std::pin::Pin;
-use rocket::http::Status;
-use crate::util::{try_printer_json, AccessType, AuthGuard};
+use rocket::response::status::NoContent;
+use rocket::response::stream::{Event, EventStream};
+use rocket::{get, patch, post, put, Either, Shutdown, State};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::broadcast::error::RecvError;
+use rocket::http::{Status};
+use crate::util::{not_supported_by_model, printer_error, select_fields, try_printer, try_printer_json, unknown_printer, AccessType, AuthGuard, ETagged, NotBusy};
+
+/// Seconds /wait waits for a change, unless the request's timeout says otherwise
+const DEFAULT_WAIT_SECS: u64 = 30;
+const MAX_WAIT_SECS: u64 = 300;
+/// Console lines returned without ?lines
+const DEFAULT_CONSOLE_LINES: usize = 200;
 
 #[get("/names")]
 pub async fn list_printers_names(printers: &State<PrinterManager>) -> Json<Vec<String>> {
@@ -19,136 +32,345 @@ pub async fn list_printers_names(printers: &State<PrinterManager>) -> Json<Vec<S
     Json(printers.get_printer_names())
 }
 
-#[get("/")]
-pub async fn list_printers(manager: &State<PrinterManager>) -> Json<Vec<CachedPrinterInfo>> {
+/// `?fields=name,state` lists only those fields of each printer
+#[get("/?<fields>")]
+pub async fn list_printers(manager: &State<PrinterManager>, config: &State<Arc<ConfigManager>>, fields: Option<&str>) -> Result<Json<Value>, (Status, Json<GenericError>)> {
     let printers = {
         let lock = manager.lock().await;
         lock.printers()
     };
-    let mut printers_info = Vec::new();
-    for printer in printers {
-        let printer = printer.lock().await;
-        let info = CachedPrinterInfo {
-            name: printer.name().to_string(),
-            is_online: printer.online(),
-            current_file: printer.current_file().as_ref().map(|file| file.to_string()),
-            firmware_version: None,
-        };
-        printers_info.push(info);
+    let printers: Vec<CachedPrinterInfo> = printers.iter().map(|printer| printer.cached_info()).collect();
+    select_fields(&printers, fields, &config.privacy().hide_fields).map(Json)
+}
+
+/// Polls every printer right away, the printers that could not be reached are listed as offline
+#[post("/refresh")]
+pub async fn refresh_printers(auth: AuthGuard, manager: &State<PrinterManager>, config: &State<Arc<ConfigManager>>) -> Result<Json<Value>, (Status, Json<GenericError>)> {
+    auth.check_auth(AccessType::Write)?;
+    let printers = manager.lock().await.printers();
+    futures::future::join_all(printers.iter().map(|printer| printer.refresh())).await;
+    let printers: Vec<CachedPrinterInfo> = printers.iter().map(|printer| printer.cached_info()).collect();
+    select_fields(&printers, None, &config.privacy().hide_fields).map(Json)
+}
+
+/// Polls the printer right away instead of waiting for the watcher thread
+#[post("/<printer_id>/refresh")]
+pub async fn refresh_printer(auth: AuthGuard, manager: &State<PrinterManager>, config: &State<Arc<ConfigManager>>, printer_id: &str) -> Result<Json<Value>, (Status, Json<GenericError>)> {
+    auth.check_auth(AccessType::Write)?;
+    let printer = manager.lock().await.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    if let Err(e) = printer.refresh().await {
+        debug!("printer/{} refresh failed: {}", printer.name(), e);
     }
-    Json(printers_info)
+    select_fields(&printer.cached_info(), None, &config.privacy().hide_fields).map(Json)
 }
 
-#[get("/<printer_id>/info")]
-pub async fn get_printer_info(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str)
-    -> Result<Json<PrinterInfo>, (Status, Json<GenericError>)>
+#[get("/<printer_id>/info?<fields>")]
+pub async fn get_printer_info(auth: AuthGuard, printers: &State<PrinterManager>, config: &State<Arc<ConfigManager>>, printer_id: &str, fields: Option<&str>)
+    -> Result<Json<Value>, (Status, Json<GenericError>)>
 {
     auth.check_auth(AccessType::Read)?;
-    try_printer_json(printers, printer_id, |printer| printer.get_info()).await
+    let info = try_printer(printers, printer_id, async |printer| {
+        let labels = printer.labels();
+        printer.get_info().await.map(|info| LabeledPrinterInfo {
+            display_name: labels.display_name.unwrap_or_else(|| info.name.clone()),
+            location: labels.location,
+            info
+        })
+    }).await?;
+    select_fields(&info, fields, &config.privacy().hide_fields).map(Json)
+}
+
+/// Changes the printer's display_name and location, and saves them to config.toml so they stay after a restart
+#[patch("/<printer_id>", data = "<patch>")]
+pub async fn patch_printer(auth: AuthGuard, manager: &State<PrinterManager>, config: &State<Arc<ConfigManager>>, printer_id: &str, patch: validated::Json<PrinterPatch>)
+    -> Result<Json<Value>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Write)?;
+    let printer = manager.lock().await.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    let labels = patch.apply(printer.labels());
+    config.set_printer_labels(printer.name(), &labels).map_err(|e| ErrorCode::ConfigWriteFailed.response(format!("could not save the change: {}", e)))?;
+    printer.set_labels(labels);
+    select_fields(&printer.cached_info(), None, &config.privacy().hide_fields).map(Json)
 }
 
-#[get("/<printer_id>/status")]
-pub async fn get_printer_status(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str)
-    -> Result<Json<PrinterStatus>, (Status, Json<GenericError>)>
+#[get("/<printer_id>/status?<fields>")]
+pub async fn get_printer_status(auth: AuthGuard, printers: &State<PrinterManager>, config: &State<Arc<ConfigManager>>, printer_id: &str, fields: Option<&str>)
+    -> Result<ETagged, (Status, Json<GenericError>)>
 {
     auth.check_auth(AccessType::Read)?;
-    try_printer_json(printers, printer_id, |printer| printer.get_status()).await
+    let status = try_printer(printers, printer_id, async |printer| printer.get_status().await).await?;
+    select_fields(&status, fields, &config.privacy().hide_fields).map(|status| ETagged::json(&status))
 }
 
-#[get("/<printer_id>/temperatures")]
-pub async fn get_printer_temps(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str)
-    -> Result<Json<PrinterTemperature>, (Status, Json<GenericError>)>
+/// Temperatures in Celsius, or Fahrenheit with `?unit=f`
+#[get("/<printer_id>/temperatures?<normalized>&<unit>")]
+pub async fn get_printer_temps(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str, normalized: Option<bool>, unit: Option<&str>)
+    -> Result<ETagged, (Status, Json<GenericError>)>
 {
     auth.check_auth(AccessType::Read)?;
-    try_printer_json(printers, printer_id, |printer| printer.get_temperatures()).await
+    let unit = match unit {
+        Some(unit) => TemperatureUnit::from_name(unit).ok_or_else(|| ErrorCode::InvalidUnit.response(format!("unknown unit {}, expected c or f", unit)))?,
+        None => TemperatureUnit::Celsius
+    };
+    let temps = try_printer(printers, printer_id, async |printer| printer.get_temperatures().await).await?.in_unit(unit);
+    if normalized.unwrap_or(false) {
+        Ok(ETagged::json(&TemperaturesInUnit { temperatures: temps.normalize(), unit }))
+    } else {
+        Ok(ETagged::json(&TemperaturesInUnit { temperatures: temps, unit }))
+    }
+}
+
+/// The answer to one query in its tagged form, such as `{"status": {...}}` for `?what=status`, so generic clients can
+/// send every kind through one path. `what` is the tag of the response
+#[get("/<printer_id>/query?<what>")]
+pub async fn query_printer(auth: AuthGuard, printers: &State<PrinterManager>, config: &State<Arc<ConfigManager>>, printer_id: &str, what: &str)
+    -> Result<Json<Value>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let request = PrinterRequest::query_for_tag(what)
+        .ok_or_else(|| ErrorCode::UnknownQuery.response(format!("unknown query {}, expected one of {}", what, PrinterRequest::QUERY_TAGS.join(", "))))?;
+    let response = try_printer(printers, printer_id, async |printer| match request {
+        // Through the method of the route, which also checks the tool count
+        PrinterRequest::GetTemperature => printer.get_temperatures().await.map(PrinterResponse::PrinterTemperature),
+        request => printer.send_request(request).await
+    }).await?;
+    let tag = response.tag();
+    let mut tagged = serde_json::to_value(&response).map_err(|e| ErrorCode::SerializationFailed.response(e.to_string()))?;
+    let inner = select_fields(&tagged[tag].take(), None, &config.privacy().hide_fields)?;
+    Ok(Json(serde_json::json!({ tag: inner })))
 }
 
 #[get("/<printer_id>/progress")]
 pub async fn get_printer_progress(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str)
-    -> Result<Json<PrinterProgress>, (Status, Json<GenericError>)>
+    -> Result<ETagged, (Status, Json<GenericError>)>
 {
     auth.check_auth(AccessType::Read)?;
-    try_printer_json(printers, printer_id, |printer| printer.get_progress()).await
+    let progress = try_printer(printers, printer_id, async |printer| printer.get_progress().await).await?;
+    Ok(ETagged::json(&progress))
 }
 
-#[get("/<printer_id>/head-position")]
-pub async fn get_printer_head_position(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str)
-    -> Result<Json<PrinterHeadPosition>, (Status, Json<GenericError>)>
+/// Long poll for clients without SSE: answers with the cached machine status, file and progress once a poll changes
+/// them, or 204 No Content after `timeout` seconds. `since` is the ETag of the previous answer, so a change between
+/// two polls is answered right away instead of being missed
+#[get("/<printer_id>/wait?<timeout>&<since>")]
+pub async fn wait_for_printer_change(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str, timeout: Option<u64>, since: Option<&str>)
+    -> Result<Either<ETagged, NoContent>, (Status, Json<GenericError>)>
 {
     auth.check_auth(AccessType::Read)?;
-    try_printer_json(printers, printer_id, |printer| printer.get_head_position()).await
+    let printer = printers.lock().await.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    // Subscribed before comparing, a change right after is still received
+    let mut changes = printer.subscribe_state();
+    let current = ETagged::json(&printer.state_update());
+    if since.is_some_and(|since| !current.has_token(since)) {
+        return Ok(Either::Left(current));
+    }
+    let timeout = Duration::from_secs(timeout.unwrap_or(DEFAULT_WAIT_SECS).min(MAX_WAIT_SECS));
+    match tokio::time::timeout(timeout, changes.recv()).await {
+        Ok(Ok(update)) => Ok(Either::Left(ETagged::json(&update))),
+        // Missed some changes, the cached state is the latest
+        Ok(Err(RecvError::Lagged(_))) => Ok(Either::Left(ETagged::json(&printer.state_update()))),
+        Ok(Err(RecvError::Closed)) | Err(_) => Ok(Either::Right(NoContent))
+    }
 }
 
-#[post("/<printer_id>/set-temperature/<temp_index>/<temperature>")]
-pub async fn set_printer_temp(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str, temp_index: u8, temperature: f32)
-    -> Result<Json<ControlSuccess>, (Status, Json<GenericError>)>
+/// Server-sent events of changes the watcher thread noticed between polls, such as `{"event":"led","value":false}`
+/// when the light is turned off on the touchscreen
+#[get("/<printer_id>/events")]
+pub async fn printer_events(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str, mut shutdown: Shutdown)
+    -> Result<EventStream![], (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let printer = printers.lock().await.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    let mut events = printer.subscribe_events();
+    Ok(EventStream! {
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => event,
+                    // Events are news, the missed ones are not worth sending late
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break
+                },
+                _ = &mut shutdown => break
+            };
+            yield Event::json(&event);
+        }
+    })
+}
+
+/// The current job from the cached state, 404 if nothing is printing
+#[get("/<printer_id>/job")]
+pub async fn get_printer_job(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str)
+    -> Result<Json<PrinterJob>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let printer = printers.lock().await.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    printer.job().map(Json).ok_or(ErrorCode::NoActiveJob.response(format!("printer {} is not printing", printer_id)))
+}
+
+/// The largest thumbnail the slicer embedded in the file being printed, from the file with its name in gcode.dir
+#[get("/<printer_id>/job/thumbnail")]
+pub async fn get_printer_job_thumbnail(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str)
+    -> Result<ETagged, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let (printer, thumbnails) = {
+        let lock = printers.lock().await;
+        (lock.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?, lock.thumbnails())
+    };
+    let no_thumbnail = |message: String| ErrorCode::NoThumbnail.response(message);
+    let file = printer.current_file().ok_or_else(|| no_thumbnail(format!("printer {} is not printing", printer_id)))?;
+    let thumbnails = thumbnails.ok_or_else(|| no_thumbnail("gcode.dir is not configured".to_string()))?;
+    let thumbnail = thumbnails.of(&file).await.map_err(no_thumbnail)?;
+    Ok(ETagged::image(thumbnail.format, thumbnail.image.to_vec()))
+}
+
+/// Turns maintenance mode on or off, the watcher thread leaves the printer alone while it is on
+#[put("/<printer_id>/maintenance", data = "<maintenance>")]
+pub async fn set_printer_maintenance(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str, maintenance: validated::Json<MaintenanceMode>)
+    -> Result<Json<MaintenanceMode>, (Status, Json<GenericError>)>
 {
     auth.check_auth(AccessType::Write)?;
-    try_printer_json(printers, printer_id, |printer| printer.set_temperature(temp_index, temperature)).await
-}
-
-#[derive(Responder)]
-#[response(content_type = "image/jpeg")]
-pub struct JpegImage(Vec<u8>);
-
-// This is just a "No image" fallback embeded in base64
-const NO_IMAGE_BASE64: &[u8] = b"iVBORw0KGgoAAAANSUhEUgAAAlgAAAGQCAYAAAByNR6YAAAACXBIWXMAAAsTAAALEwEAmpwYAAAgAElEQVR4nO3d5ZIjaZIF0H3/NxhmZmZmZh5/llq7ZRZj0V9HCBK6vLzOD7ed7UpQXj8ZugqFlP9XVS+MDBhggAEGGGCAgXqyDP5PmH6hGGCAAQYYYICBetIMFCy/VH6pGGCAAQYYYKAULAgcCBhggAEGGGDgRecMnMFqsAQjAwYYYIABBmpUBgpWgyUYGTDAAAMMMFCjMlCwGizByIABBhhggIEalYGC1WAJRgYMMMAAAwzUqAwUrAZLMDJggAEGGGCgRmWgYDVYgpEBAwwwwAADNSoDBavBEowMGGCAAQYYqFEZKFgNlmBkwAADDDDAQI3KQMFqsAQjAwYYYIABBmpUBgpWgyUYGTDAAAMMMFCjMlCwGizByIABBhhggIEalYGC1WAJRgYMMMAAAwzUqAwUrAZLMDJggAEGGGCgRmWgYDVYgpEBAwwwwAADNSoDBavBEowMGGCAAQYYqFEZKFgNlmBkwAADDDDAQI3KQMFqsAQjAwYYYIABBmpUBgpWgyUYGTDAAAMMMFCjMlCwGizByIABBhhggIEalYGC1WAJRgYMMMAAAwzUqAwUrAZLMDJggAEGGGCgRmWgYDVYgpEBAwwwwAADNSoDBavBEowMGGCAAQYYqFEZKFgNlmBkwAADDDDAQI3KQMFqsAQjAwYYYIABBmpUBgpWgyUYGTDAAAMMMFCjMlCwGizByIABBhhggIEalYGC1WAJRgYMMMAAAwzUqAwUrAZLMDJggAEGGGCgRmWgYDVYgpEBAwwwwAADNSoDBavBEowMGGCAAQYYqFEZKFgNlmBkwAADDDDAQI3KQMFqsAQjAwYYYIABBmpUBgpWgyUYGTDAAAMMMFCjMlCwGizByIABBhhggIEalYGC1WAJRgYMMMAAAwzUqAwUrAZLMDJggAEGGGBglgEFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCODV23gv//974vf/va3L37/+9+//N+v+vaY1z+Df/7zny9+/etfv/jrX//6tn/797///eLHP/7x/+YnP/nJk3//f/zjH2/5Hj//+c8f/LX+9Kc/veVr5XflVedrqn0GCtYbODm4/eIXv3jxla985cXHP/7xFx/84AdfvPe97335fz/2sY+9/O85GOXjXvVtNe+Mhzh417ve9XI+/elPv/jPf/4je/4ebCAF5H3ve99LT+9+97tffP/733/Lv//tb3/7n7dMjj9PnXceLOy/x0c+8pEHf63c/v3X+vKXv+z3w+/Hi2sZKFhvEJLcaf7gBz948f73v/8tB4uzSeH60Y9+5IzG8FnvPDLZ+6u+Xeb1zWBf2LeSlTNa278rWK9+R6aePQMF6w2B9ve///1tB71b5zOf+cxbDo5mVgZf//rX37bzb3/726/8dpnXN4MPfOADbzP15z//+X//rmC9+h2Z589AwXoDoOUaiJyNOipPeWT5oQ996GX5yv/N/3/0cbmW4lX/HOb5ns5ZTfzhD3+QN3MPNvC1r33tLaZy6cH+2j4Fy/Gs3oAMFKw37Pqa9em/9cxUPj7XX+0/J2c4XvXPYZ43g1/+8pcvz1R+9rOfVaZ5e5LLEXIW9JOf/OSLr371qy/PoO//XcFyTKs3IAMFa/h885vffFu5ykXM//rXvy5+Xh5tpoDl813w/Or3aGQwyYCC9ep3YOrZM1CwBkPL2am8Omdfrj784Q8/26sD8/2uFbdb55ZSlxL42LcUyPd5qrclyM9/y9dK/rd+7OswXm36vE43Lx32/FRuH1qw8r1zG24xd8urCG/dzWNeRfgUxylTr2UGCtbg+d73vve2s1e/+tWvnuzr56LVnOHK9RX7a7fyKsUcgP7yl7+cfu6nPvWpl09D5imE7T1lckD8/Oc//79SmKcxv/vd777t2o1vfOMbb7leLP8713zk367d5nytn/70py+fCtu/mjLf60tf+tLL23D2ufm+uc2Z7WXn+Xp5KmT7Wu95z3ve9n47KZ35+DwFty+8uf05m5in5+7NPplttyWTPVz7nHyf5L19Tt7PZ7t9+6+VuXSHkL0mi9xh5efdfu587fycR3d+8bB97eTwxz/+8aLb7WO/+MUvnpbtfJ/8+/axX/jCF27OL872P2/e5+jsZ91/XNzs/z139tu/ZZfb18l1j3lqfe80D25i5dqDkHzNZJDfkS3fLeN8/9/85jdv+5ztaf3MJz7xiZe/N9cyyO/B3sPqMPnmLHa+5+o2n/ezn/3s9Gvvb8+Rz3sKVq7/jJ9kuT5YvJTnWcHK05XbMWT7efJv+Vpnxe2egrUdYz73uc8dHmN+97vf3f37buq1zEDBGjw54D/V+8DsJwe0HGDOLojfHzTPysP+gJ07u7x9xNnX2w5mOdBeeouJ/Nuli7Nz5/fRj3706qsmc83IUcHI7dx/r3zM/r9tsxWXTO6gbnlbjHzPe3aQO4L1615688Pc1v0dVLLe3gAyd+jr7Tn6+bcyeW3vuSNZi+p3vvOdt3xMitHR7cxtWb9+3rPt6GOT8/7j7rlWML8L+889K9YpguvPtr6Z5XobcrvWM8f7icH1mqQt3xSrS5979irP3I41t0sPcOJn/33yv/dnyVKezl4Ys+7xyErc7z9uLb+3FKw8gEuRu3YbUrSOftajgpXj0fb+XGdf6+iB2q0FK7c5Dziv3ea816BLL2r8KFhDJwfQ9YD7FBer52B6S0nZHzj3L8/eZn1EfO3rXLvT2h8gjw5cuaM8OrCmpBy9pDwHwPVrrGUqd3JHt2E7S/itb33r5pwyKZmPeXuFSyUtZz32H5uzSNu/3Vqwcqdy9irU/ZmWo72vRWUrqOv3yCP/9Xuc3Znlzn3/cfe8u/ZzFaxbjGZydmP9XtnfPV6S1f7zU2JWn2c//5rzPuMf/vCHd92Oo7Onjy1YsXNrlpkck9bf+7Vgxegtx5qjM7i3FKzc5qMHUznuHJVVb1Za40fBGjo5O3HpzMpjZn/HngNHHnXntHceReYRYkrO/vvmtPj6NY4OnrmDyRmoHKhykD/6mBwgU1xyx5c5uoh/veNJ2VxvUw5u+0eqR4+W17emWAvW/mCdHFI0cpu3R9P7tz/Ix+bny9fM90pe69fLwfme65lyRm49kJ89Kl7L0f5pzFsK1nq2KKU0u94+Lt83O9tnkjz3X2PdwdHThGtp2nJZb0/+//2dWW7PPde5PFfB2hfY5BML2fVafjLr05LZyb5w5PcsXvI1Ymk9I5089z9zPnb997OfP7dv/7H7p61yu7Y95v/m9zc/S9wmp9VSiktyeMqClZ9rfyYopSd/Tif7ODs+rMe3tWBtk9+7PODIMfLo9/Doa10rWPG/PvCM5fUYk6d9n+uSDVPtMlCwhs7RweUh1/ocTQ4mucYjB7mjQrDeKeVAuN75XTs4ZvI91p8hTzVdu1NeD37rI/LcUR3dGedOYn+71uttjg7EOfAfnaHb32nmTMLR32PLbVjv6O99v7G1FB79vbX16aC1jFwrWNn3+gj86Dqgo7N2++KyluH1z6fk+5yd2VwL0Or73qdYn7NgrT/Xtuv1qaOjj8vvQYwfPYWYPa572JfUfI+1xB5d75M7/X22KQbrx8Rhdnl2bdrqbv1bgk/xFGF+Z2Lm7CnitfSsZwWPjoFn16blc9ff60vfaz3GrA9Aks/RMSa/a/sHBjkW3ePW1GuVgYL1hrx55Dv9aGm9IHUtGGvBOrruIWez1p/hqKisT3esB631fcAuXWS6f5omj8z3BfLojNPRHeE9k4ttr93pXprcse0/P7fxWj7rUzrXClaK+aV895M8zp6mSu77f1sL7P4MTArQ/k48d/aXyve9tp+zYJ19z/Up5aOnoa9NziZdOlu75pIXf1x78cu9T00ffY3V1FMUrHvP0ueYc6lgXTqjt9rM7C+ev1awrp39PnsGIEXXq3Br7ChYQ2d9+ujoYPycsx5w1ovPbylY60E4B6Nbri/aP/pcL5rO/87BL59zNOt1MPuzU2vBOjqbdu+sB+61SFybHJz315atf/Mtsz61tF4QfK1grSUwdy5n+WX212PtL2bP19yfgYmB/VOa++xTDPaFJGd/znxdemq0U8FaS8fZhf6X5tpZwBTc9RW965mU/VNZ2dVD3gJiLe1rWXwnClbs779GHDz0jz2vX2t9IHapYK3Xu+Z/p/Cf/X6s73J/6RW1pl7rDBSsoXN04H+KQnA0OYCnQOXpqTwdlzvG9amM9Q7sloK1ng05K1jr2bp9wToqmvfM/navBeshhTWPunNGKE8p5GzDWn7uLViZ9YC9/0PN69NBR2efrhWso+uHbp31LNVaYPdPNe7NpASvZzC3s5c5s7D/mR5SVDoUrFveViIFP17yufGyPnA5OuO5Ot1fGrCWjqPrI48mv4v5OjljmtuxPqW2ntF5joKVIpPf9fze5azbWjbXr3FPwcqsL4LZ53apYOUBy2OOMfe8OMPUa5WBgjV41qfpcoHlU33t3AGnUK3v7XRLUXknC9bZha63zv4pwIcWrNxJ54B8y8veH1Kw1hKwv7h8fcro6L2LrhWslKSH5rdeG7VeiL39vPs95Y5u+/77zFLe899yTc61685e54KVMycpjUevbl3nqGDl7MnZ91nL+KU795S77G89jhzNcxWslOntfdFueVuYxxSs9fq1vatLBWs1cu8cXfZgakQGCtbgOXpZ/aULsm+dfI31Duray6FfVcHKbV3vILdXIF6b9cB3b8Ha3gjzLKf8PGsODylYmby55NFBe/90UJ4uOnoq7VrBWn+GnAm9NcP1+pJ83f2Zgm1X+6cD92dV9oVgOxt26Tq517lgpUxcKrPxsr4dxtkF9ftSlM/J115feXlWOPJxyfhSoVndPkfBSsG59J5V6214bMFaC+3+OqpLBWu9FiwZP/QYY2pUBgrW4FnPFpy9/849kwPCetDL0045ZZ8DxvZy7fXlyK+qYK1PJz3motJ7C9bRy+pToHJ787Pljuyx12CdvYopj/rXcpBrqY4+91rBWp+KeehtPLpQO/vIjvavsNufOdgb3srUvjQ+1HO3gpW81xdj5OdM8cwZrc3LeqH82Ysi1jd2jdX1eHD2uWuhzs8cO/n8/J7mduRM6HMWrP1bVmy7z+3Kz5EHTXGQ2/FUBevoGqz9g9F7r8F6qj8ZZuq1zkDBGj5H7yp867VYuePNgXJ/sFjPim1P26zTpWAdnd05e9n3Uxas9ZV3OTNx9LLtpypY61sxpNytTw+enb28VrDWO+ajl/XfM+ud5/6VkOsZqfWMV+7Y93dm69sDPPT34uxVX+9UwVoLckrokZdbC9Z67V3Kyf5s4NGLIY5KSZ5uPnpA8twFaz0Dd3Qh+FMWrPVp1f3T1Ee/p+vPux7vLv0ZIVNvTAYK1vDJHcfRqf4cINY3B9xPLj7eDnI5eGwH2fWR/9mrt9ZS8yoL1npwzJ3rpbNYueM5+tMb9xSs9azP2XVC68vdH3N2aH3qbH/Qv3T93bWClazWp08u/ez53NwhXvobgvunuvZnpI7eZmJ/RmV/luesJNwy61NxZw861lfLPVfByqvwbnmfsWuvItzP/kL03O59zmcvDFh/V84eQK1vD/KUBWv9tzO7eeB3T8HK8ezszWjXN15df55rBWt9r71kfe0Y8xSXa5hqnYGC9QbM2Z90yQEpd2i5k88j6BwkcgBfn6rYvwx7fbfitTjloLJeSPuqC9bR3+3LGZ71AJf/P+9Rs13rst7mxxSso+KU0vVU12Bdu6D/0m295Z3c1zuY5BMv+xKV/51H7tvZoaOydJblpTNS6xmv/Q4fmtV6di9nLPYXfCeT7GJ9cPJOFayzNyFdb8+lgrWeRb2lwK17PnqvrqO/CfqcBSuZr2UlH7O+ovJawdrODO5Lecyuf3Lq6Hh1rWAdvQlsbt/6Rq154Jbvt12n6hWENXoUrDdgcmd59Cdlbp0cOLYysj5FmDMbuSPKNVg5cJy9Uu5VFqxLZ/JyXVQ+/uhviK1PhT3mKcJ872SXnFJ4z/6I7WOvbzoqx9f+BM8tBSv//3pN2Xanlu+ZrI7yPXuqZD0DcumM1Hod3bWzK7dMbK0XjG/Wz36W7d/fiacIc9tyRnLzcrTXawVrfd+xvfmzzzkqJTnbldtx9LYi78RThNvvdB4I5ufN9zp65fItBWvLNr9/OTN2dBH90V8FuOVvEeY4dOQmP8vZMWb9c0emRmWgYL1Bk0Jwy0u/95OnUvavdLnlj7DmoLGeMn/VBevsjNHZ5I7pMWewji5aPjrQ5451f+B9bME6+kO91/7I961/7Dkfd89bNuRM5tmdx/oGsNfOSB193yMzj81qnfWM7nMVrKO/l3lUIGJuXwyvvfP/0dnrFJVLn3Ntx9lbvu/+bPZTF6y1cJ4dm/Znn64VrHz8teNfPuboqe1bCtb2wOqeY8ylvyph6rXPQMF6wyYH8jwSvXTnvz11ePa3C1Noju4M8mgwd0jbS8L3FxJ3KFiZlMWzR8CZ/Fx5tH50xufeVxHmzvfoqbD8HLk+ZjsruD+7+NiClezXn+3s78ndW7Ay+e/5udenivd2cme6vnP/0axnQy5dsL5eC5Vr/J7zQUd+vs3/vqQ859s05Hfg6AxRvOQs0vZAZ38W+VrByuesr3C79uedYihPpR2djcnt236X96XjOd6mIQX46AxTzgilgMVifoe2wnmtYOUBVvI4yjgG8vOcub+1YG2Z56nVS8eYfD1/IqfGj4L1Bk8OtHkElVfVbS/jPnrvoqPJgSgHsDwFlMnXWR/55WNyIM0BZ/23/Lf9HB3Y8t/Wjzu6Lbm9+4+55e8D5vbk9udnz+3Pz37t8/Lv++9z6wEyH7tlnFcrHb24IP8tH/fQi7Yv3c5bdnlLzutkt8kt+aWMJM97nu7Iz3rPzvY/11PktP/54zf7yZ6OXrG2fe/1gcA92aW47D/u7EUmuU4nZWD7nTz6Wbfbc8vbAWy/g0e3/1re2evm9mhH2w7X23jtZ73ndzvXi+U25LYc7Sa/y0dfY//f19u+/UWF5HyL21t3t/6M6zHmsWddTb1WGShYDZZgZMAAAwwwwECNykDBarAEIwMGGGCAAQZqVAYKVoMlGBkwwAADDDBQozJQsBoswciAAQYYYICBGpWBgtVgCUYGDDDAAAMM1KgMFKwGSzAyYIABBhhgoEZloGA1WIKRAQMMMMAAAzUqAwWrwRKMDBhggAEGGKhRGShYDZZgZMAAAwwwwECNykDBarAEIwMGGGCAAQZqVAYKVoMlGBkwwAADDDBQozJQsBoswciAAQYYYICBGpWBgtVgCUYGDDDAAAMM1KgMFKwGSzAyYIABBhhgoEZloGA1WIKRAQMMMMAAAzUqAwWrwRKMDBhggAEGGKhRGShYDZZgZMAAAwwwwECNykDBarAEIwMGGGCAAQZqVAYKVoMlGBkwwAADDDAwy4CC1WAJRgYMMMAAAwzUqAwUrAZLMDJggAEGGGCgRmWgYDVYgpEBAwwwwAADNSoDBavBEowMGGCAAQYYqFEZKFgNlmBkwAADDDDAQI3KQMFqsAQjAwYYYIABBmpUBgpWgyUYGTDAAAMMMFCjMlCwGizByIABBhhggIEalYGC1WAJRgYMMMAAAwzUqAwUrAZLMDJggAEGGGCgRmWgYDVYgpEBAwwwwAADNSoDBavBEowMGGCAAQYYqFEZKFgNlmBkwAADDDDAQI3KQMFqsAQjAwYYYIABBmpUBgpWgyUYGTDAAAMMMFCjMlCwGizByIABBhhggIEalYGC1WAJRgYMMMAAAwzUqAwUrAZLMDJggAEGGGCgRmWgYDVYgpEBAwwwwAADNSoDBavBEowMGGCAAQYYqFEZKFgNlmBkwAADDDDAQI3KQMFqsAQjAwYYYIABBmpUBgpWgyUYGTDAAAMMMFCjMlCwGizByIABBhhggIEalYGC1WAJRgYMMMAAAwzUqAwUrAZLMDJggAEGGGCgRmWgYDVYgpEBAzxJzk4AAAiFSURBVAwwwAADNSoDBavBEowMGGCAAQYYqFEZKFgNlmBkwAADDDDAQI3KQMFqsAQjAwYYYIABBmpUBgpWgyUYGTDAAAMMMFCjMlCwGizByIABBhhggIEalYGC1WAJRgYMMMAAAwzUqAwUrAZLMDJggAEGGGCgRmWgYDVYgpEBAwwwwAADNSoDBavBEowMGGCAAQYYqFEZKFgNlmBkwAADDDDAQI3KQMFqsAQjAwYYYIABBmpUBgpWgyUYGTDAAAMMMFCjMlCwGizByIABBhhggIEalYGC1WAJRgYMMMAAAwzMMqBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDBSsBkswMmCAAQYYYKBGZaBgNViCkQEDDDDAAAM1KgMFq8ESjAwYYIABBhioURkoWA2WYGTAAAMMMMBAjcpAwWqwBCMDBhhggAEGalQGClaDJRgZMMAAAwwwUKMyULAaLMHIgAEGGGCAgRqVgYLVYAlGBgwwwAADDNSoDP4feWoPfW7vl3kAAAAASUVORK5CYII=";
-
-#[derive(Responder)]
-#[response(content_type = "multipart/x-mixed-replace;boundary=boundarydonotcross")]
-//header = "Cache-Control': 'no-store, no-cache, must-revalidate, pre-check=0, post-check=0, max-age=0'", header = "Pragma: 'no-cache'", header = "Connection: 'close'"
-pub struct MjpegStream<T>(T);
-
-#[get("/<printer_id>/snapshot")]
-pub async fn get_printer_snapshot(printers: & State<PrinterManager>, printer_id: String) -> Result<JpegImage, Either<JpegImage, String>> {
-    let snapshot = {
-        trace!("acquiring printer");
-        let printer = {
-            let lock = printers.lock().await;
-            let printer = lock.get_printer(&printer_id).ok_or(Either::Right("Unknown printer".to_string()))?;
-            printer.clone()
-        };
-        let mut printer = printer.lock().await;
-        trace!("requesting snapshot {}", printer_id);
-        printer.get_camera_snapshot().await
+    let printer = printers.lock().await.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    info!("printer/{} maintenance {}", printer.name(), if maintenance.enabled { "started" } else { "ended" });
+    printer.set_maintenance(maintenance.into_inner());
+    Ok(Json(printer.maintenance()))
+}
+
+/// Failures of the requests to the printer, from the routes and the watcher thread
+#[get("/<printer_id>/health")]
+pub async fn get_printer_health(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str)
+    -> Result<Json<PrinterHealth>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let lock = printers.lock().await;
+    let printer = lock.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    let mut health = printer.health();
+    health.same_serial_as = lock.same_serial_as(printer.name());
+    Ok(Json(health))
+}
 
+/// The file the last print complete notification was sent for and the error state notified, to tell why no notification was sent
+#[get("/<printer_id>/notifications/state")]
+pub async fn get_printer_notification_state(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str)
+    -> Result<Json<PrinterNotificationState>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let lock = printers.lock().await;
+    let printer = lock.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    Ok(Json(lock.notification_state(&printer)))
+}
+
+/// Lines the printer sent on its own, the last `lines` (200 by default) oldest first. Needs printers.<id>.console.capture
+#[get("/<printer_id>/console?<lines>")]
+pub async fn get_printer_console(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str, lines: Option<usize>)
+    -> Result<Json<Vec<ConsoleLine>>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let printer = printers.lock().await.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    printer.console().last(lines.unwrap_or(DEFAULT_CONSOLE_LINES)).map(Json).ok_or_else(|| ErrorCode::ConsoleDisabled.response(format!("printer {} does not capture its console, set printers.{}.console.capture = true", printer_id, printer_id)))
+}
+
+/// Asks the printer, or with `?cached=true` returns the watcher thread's last poll with its `age_seconds` and
+/// the endstops, without a connection. Cached positions need watch.head_position
+#[get("/<printer_id>/head-position?<cached>")]
+pub async fn get_printer_head_position(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str, cached: Option<bool>)
+    -> Result<Either<Json<PrinterHeadPosition>, Json<CachedHeadPosition>>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    if cached != Some(true) {
+        return try_printer_json(printers, printer_id, async |printer| printer.get_head_position().await).await.map(Either::Left);
+    }
+    let printer = printers.lock().await.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    printer.cached_head_position().map(|position| Either::Right(Json(position))).ok_or_else(|| ErrorCode::NoCachedPosition.response(format!("printer {} has no polled head position, enable watch.head_position", printer_id)))
+}
+
+/// Recorded temperatures and progress, `since` is RFC 3339 or a unix timestamp (default an hour ago) and
+/// `resolution` the bucket size points are averaged over, such as "60s" or "5m" (default 60s)
+#[get("/<printer_id>/history?<metric>&<since>&<resolution>")]
+pub async fn get_printer_history(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str, metric: &str, since: Option<&str>, resolution: Option<&str>)
+    -> Result<Json<PrinterHistory>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let history_metric = HistoryMetric::from_name(metric)
+        .ok_or_else(|| ErrorCode::UnknownMetric.response(format!("unknown metric {}, expected one of {}", metric,
+            HistoryMetric::ALL.map(|metric| metric.name()).join(", "))))?;
+    let since = match since {
+        Some(since) => OffsetDateTime::parse(since, &Rfc3339).ok()
+            .or_else(|| since.parse().ok().and_then(|ts| OffsetDateTime::from_unix_timestamp(ts).ok()))
+            .ok_or_else(|| ErrorCode::InvalidSince.response(format!("{} is not a RFC 3339 date or unix timestamp", since)))?,
+        None => OffsetDateTime::now_utc() - Duration::from_secs(60 * 60)
+    };
+    let resolution = match resolution {
+        Some(resolution) => history::parse_duration(resolution)
+            .ok_or_else(|| ErrorCode::InvalidResolution.response(format!("{} is not a duration such as 60s, 5m or 1h", resolution)))?,
+        None => Duration::from_secs(60)
     };
-    trace!("returning snapshot");
-    snapshot
-        .map(JpegImage)
-        .map_err(|_| Either::Left(JpegImage(BASE64_STANDARD.decode(NO_IMAGE_BASE64).unwrap())))
-}
-
-// TODO: add headers (Connection: close) and (Cache-Control: no-cache ...)
-// Cannot get it to work with rocket, needs Response to set headers but it will not compile
-#[get("/<printer_id>/camera")]
-pub async fn get_printer_camera(printers: & State<PrinterManager>, printer_id: String) -> Result<MjpegStream<ByteStream<Pin<Box<dyn Stream<Item = Vec<u8>> + Send + 'static>>>>, Either<JpegImage, String>> {
-    let mut camera_rx = {
-        trace!("acquiring printer");
-        let printer = {
-            let lock = printers.lock().await;
-            let printer = lock.get_printer(&printer_id).ok_or(Either::Right("Unknown printer".to_string()))?;
-            printer.clone()
-        };
-        let mut printer = printer.lock().await;
-        trace!("requesting snapshot {}", printer_id);
-        printer.subscribe_camera().map_err(|e| Either::Right(format!("Failed to setup camera stream: {}", e)))?
+    let (printer, history) = {
+        let lock = printers.lock().await;
+        let printer = lock.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+        (printer, lock.history().ok_or(ErrorCode::HistoryDisabled.response("history is not configured"))?)
     };
+    let points = history.query(printer.name(), history_metric, since, OffsetDateTime::now_utc(), resolution).await
+        .map_err(|e| ErrorCode::HistoryError.response(e))?;
+    Ok(Json(PrinterHistory {
+        metric: metric.to_string(),
+        resolution_secs: resolution.as_secs(),
+        points
+    }))
+}
 
-    let stream = stream! {
-        while let Ok(part) = camera_rx.recv().await {
-            let len: usize = part.headers.get("content-length").unwrap().to_str().unwrap().parse().unwrap();
-            let mut s = Vec::with_capacity(len+512);
-            writeln!(s, "--boundarydonotcross\r").ok();
-            for header in part.headers.iter() {
-                write!(s, "{}: {}\r\n", header.0, header.1.to_str().unwrap()).ok();
-            }
-            writeln!(s, "\r").ok();
-            s.extend_from_slice(part.body.iter().as_slice());
-            writeln!(s, "\r").ok();
-            yield s;
-        }
+#[post("/<printer_id>/set-temperature/<temp_index>/<temperature>")]
+pub async fn set_printer_temp(auth: AuthGuard, _not_busy: NotBusy, printers: &State<PrinterManager>, printer_id: &str, temp_index: u8, temperature: f32)
+    -> Result<Json<ControlSuccess>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Write)?;
+    try_printer_json(printers, printer_id, async |printer| printer.set_temperature(temp_index, temperature).await).await
+}
+
+/// Movement speed of the current print in percent of the file's, only while printing
+#[put("/<printer_id>/speed", data = "<factor>")]
+pub async fn set_printer_speed(auth: AuthGuard, _not_busy: NotBusy, printers: &State<PrinterManager>, printer_id: &str, factor: validated::Json<PrintFactor>)
+    -> Result<Json<ControlSuccess>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Write)?;
+    set_print_factor(printers, printer_id, PrintFactorKind::Speed, factor.percent).await
+}
+
+/// Flow of the current print in percent of the file's, only while printing
+#[put("/<printer_id>/flow", data = "<factor>")]
+pub async fn set_printer_flow(auth: AuthGuard, _not_busy: NotBusy, printers: &State<PrinterManager>, printer_id: &str, factor: validated::Json<PrintFactor>)
+    -> Result<Json<ControlSuccess>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Write)?;
+    set_print_factor(printers, printer_id, PrintFactorKind::Flow, factor.percent).await
+}
+
+async fn set_print_factor(printers: &State<PrinterManager>, printer_id: &str, factor: PrintFactorKind, percent: u16)
+    -> Result<Json<ControlSuccess>, (Status, Json<GenericError>)>
+{
+    let printer = printers.lock().await.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    // The cached status can be a poll behind, a print that just started or ended would be missed
+    let status = printer.get_status().await.map_err(printer_error)?;
+    if !status.machine_status.is_printing() {
+        return Err(ErrorCode::NotPrinting.response(format!("printer {} is not printing, it is {}", printer_id, status.machine_status)));
+    }
+    printer.set_print_factor(factor, percent).await.map(Json).map_err(printer_error)
+}
+
+#[post("/<printer_id>/led/<on>")]
+pub async fn set_printer_led(auth: AuthGuard, _not_busy: NotBusy, printers: &State<PrinterManager>, printer_id: &str, on: bool)
+    -> Result<Json<ControlSuccess>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Write)?;
+    let printer = {
+        let lock = printers.lock().await;
+        lock.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?
     };
-    let text_stream = ByteStream::from(Box::pin(stream) as Pin<Box<dyn Stream<Item = Vec<u8>> + Send + 'static>>);
-    Ok(MjpegStream(text_stream))
+    // The model is only known once its info was fetched
+    printer.get_meta().await;
+    if !printer.capabilities().led {
+        return Err(not_supported_by_model(&printer, "light"));
+    }
+    printer.set_led(on).await.map(Json).map_err(printer_error)
 }
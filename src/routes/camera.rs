@@ -0,0 +1,163 @@
+//! The camera's snapshot and MJPEG stream, only mounted with the camera feature
+use crate::errors::ErrorCode;
+use crate::manager::PrinterManager;
+use crate::models::GenericError;
+use crate::camera::{next_frame, Transform};
+use crate::config::{CameraConfig, Flip};
+use log::{trace, warn};
+use rocket::futures::Stream;
+use rocket::response::stream::{stream, ByteStream};
+use rocket::response::Responder;
+use rocket::serde::json::Json;
+use rocket::{get, Either, FromForm, State};
+use std::io::Write;
+use std::pin::Pin;
+use time::OffsetDateTime;
+use rocket::http::{Accept, ContentType, Header, Status};
+use crate::util::{not_supported_by_model, unknown_printer, ETagged, ImageFormat};
+
+/// Shown by <img> tags when the camera is unavailable
+const NO_IMAGE: &[u8] = include_bytes!("../../ui/no_image.png");
+
+/// The image returned when a snapshot fails, loaded from camera.placeholder_path
+pub struct SnapshotPlaceholder {
+    image: Vec<u8>,
+    content_type: ContentType
+}
+
+impl SnapshotPlaceholder {
+    pub fn load(config: &CameraConfig) -> Self {
+        let image = config.placeholder_path.as_ref()
+            .and_then(|path| std::fs::read(path)
+                .inspect_err(|e| warn!("could not read camera.placeholder_path {}: {}, using the built in image", path.display(), e))
+                .ok())
+            .unwrap_or_else(|| NO_IMAGE.to_vec());
+        let content_type = if image.starts_with(b"\x89PNG") { ContentType::PNG } else { ContentType::JPEG };
+        Self { image, content_type }
+    }
+}
+
+/// The placeholder image, with a header telling it apart from a real frame
+#[derive(Responder)]
+#[response(status = 502)]
+pub struct PlaceholderImage {
+    image: (ContentType, Vec<u8>),
+    placeholder: Header<'static>
+}
+
+#[derive(Responder)]
+#[response(content_type = "multipart/x-mixed-replace;boundary=boundarydonotcross")]
+//header = "Cache-Control': 'no-store, no-cache, must-revalidate, pre-check=0, post-check=0, max-age=0'", header = "Pragma: 'no-cache'", header = "Connection: 'close'"
+pub struct MjpegStream<T>(T);
+
+/// Query parameters of [get_printer_snapshot] that change the image
+#[derive(FromForm)]
+pub struct SnapshotTransform<'r> {
+    rotate: Option<u16>,
+    flip: Option<&'r str>,
+    width: Option<u32>
+}
+
+/// A fresh frame from the camera. On failure, clients asking for JSON (Accept: application/json or ?on_error=json)
+/// get a CAMERA_UNAVAILABLE error, others the placeholder image so <img> tags show something. Both are a 502.
+/// rotate, flip and width apply to the frame already in the printer's camera config, encoding it again
+#[get("/<printer_id>/snapshot?<on_error>&<transform..>")]
+pub async fn get_printer_snapshot(printers: &State<PrinterManager>, placeholder: &State<SnapshotPlaceholder>, accept: Option<&Accept>, printer_id: String, on_error: Option<&str>,
+    transform: SnapshotTransform<'_>)
+    -> Result<ETagged, Either<PlaceholderImage, (Status, Json<GenericError>)>>
+{
+    let SnapshotTransform { rotate, flip, width } = transform;
+    if width == Some(0) {
+        return Err(invalid_transform("width must be at least 1".to_string()));
+    }
+    let flip = match flip {
+        Some(name) => Some(Flip::from_name(name).ok_or_else(|| invalid_transform(format!("flip must be horizontal or vertical, not {}", name)))?),
+        None => None
+    };
+    let requested = Transform::new(rotate.unwrap_or(0), flip)
+        .ok_or_else(|| invalid_transform("rotate must be 0, 90, 180 or 270".to_string()))?
+        .with_width(width);
+    let snapshot = {
+        trace!("acquiring printer");
+        let printer = {
+            let lock = printers.lock().await;
+            let printer = lock.get_printer(&printer_id).ok_or_else(|| Either::Right(unknown_printer(&printer_id)))?;
+            printer.clone()
+        };
+        if !printer.capabilities().camera {
+            return Err(Either::Right(not_supported_by_model(&printer, "camera")));
+        }
+        trace!("requesting snapshot {}", printer_id);
+        match printer.camera().snapshot().await {
+            Ok(image) => requested.apply_blocking(image).await,
+            Err(e) => Err(e)
+        }
+    };
+    trace!("returning snapshot");
+    snapshot.map(|image| ETagged::jpeg(image, OffsetDateTime::now_utc())).map_err(|e| {
+        if on_error == Some("json") || accept.is_some_and(|accept| accept.preferred().is_json()) {
+            Either::Right(ErrorCode::CameraUnavailable.response(format!("Failed to get a snapshot: {}", e)))
+        } else {
+            Either::Left(PlaceholderImage {
+                image: (placeholder.content_type.clone(), placeholder.image.clone()),
+                placeholder: Header::new("X-Snapshot-Placeholder", "true")
+            })
+        }
+    })
+}
+
+/// The frames kept for camera.gif_frames as an animated GIF, encoded on every request. Frames are kept while the
+/// camera is streamed, so a 503 CAMERA_UNAVAILABLE until someone watched it, or notifications.attach_gif recorded it
+#[get("/<printer_id>/snapshot.gif")]
+pub async fn get_printer_snapshot_gif(printers: &State<PrinterManager>, printer_id: String) -> Result<ETagged, (Status, Json<GenericError>)> {
+    let printer = {
+        let lock = printers.lock().await;
+        lock.get_printer(&printer_id).ok_or_else(|| unknown_printer(&printer_id))?
+    };
+    if !printer.capabilities().camera {
+        return Err(not_supported_by_model(&printer, "camera"));
+    }
+    let (gif, _) = printer.camera().gif().await
+        .map_err(|e| ErrorCode::CameraUnavailable.response_with(Status::ServiceUnavailable, format!("Failed to make the GIF: {}", e)))?;
+    Ok(ETagged::image(ImageFormat::Gif, gif))
+}
+
+fn invalid_transform(message: String) -> Either<PlaceholderImage, (Status, Json<GenericError>)> {
+    Either::Right(ErrorCode::InvalidTransform.response(message))
+}
+
+// TODO: add headers (Connection: close) and (Cache-Control: no-cache ...)
+// Cannot get it to work with rocket, needs Response to set headers but it will not compile
+#[get("/<printer_id>/camera")]
+pub async fn get_printer_camera(printers: & State<PrinterManager>, printer_id: String) -> Result<MjpegStream<ByteStream<Pin<Box<dyn Stream<Item = Vec<u8>> + Send + 'static>>>>, (Status, Json<GenericError>)> {
+    let mut camera_rx = {
+        trace!("acquiring printer");
+        let printer = {
+            let lock = printers.lock().await;
+            let printer = lock.get_printer(&printer_id).ok_or_else(|| unknown_printer(&printer_id))?;
+            printer.clone()
+        };
+        if !printer.capabilities().camera {
+            return Err(not_supported_by_model(&printer, "camera"));
+        }
+        trace!("requesting snapshot {}", printer_id);
+        printer.camera().subscribe().map_err(|e| ErrorCode::CameraUnavailable.response_with(Status::ServiceUnavailable, format!("Failed to setup camera stream: {}", e)))?
+    };
+
+    let stream = stream! {
+        while let Some(part) = next_frame(&mut camera_rx).await {
+            let len: usize = part.headers.get("content-length").unwrap().to_str().unwrap().parse().unwrap();
+            let mut s = Vec::with_capacity(len+512);
+            writeln!(s, "--boundarydonotcross\r").ok();
+            for header in part.headers.iter() {
+                write!(s, "{}: {}\r\n", header.0, header.1.to_str().unwrap()).ok();
+            }
+            writeln!(s, "\r").ok();
+            s.extend_from_slice(part.body.iter().as_slice());
+            writeln!(s, "\r").ok();
+            yield s;
+        }
+    };
+    let text_stream = ByteStream::from(Box::pin(stream) as Pin<Box<dyn Stream<Item = Vec<u8>> + Send + 'static>>);
+    Ok(MjpegStream(text_stream))
+}
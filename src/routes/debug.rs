@@ -0,0 +1,82 @@
+use crate::errors::ErrorCode;
+use crate::config::ConfigManager;
+use crate::manager::PrinterManager;
+use crate::models::{GenericError, PrinterRecording, RawPrinterResponse};
+use crate::printer::{Printer, PrinterError};
+use flashforge_protocol::PrinterRequest;
+use crate::util::{try_printer, try_printer_json, AccessType, AuthGuard};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, post, State};
+use std::fmt::Write;
+use std::sync::Arc;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+/// Commands that can be sent with ?cmd= and are saved by /debug/record
+const COMMANDS: [(&str, PrinterRequest); 5] = [
+    ("info", PrinterRequest::GetInfo),
+    ("status", PrinterRequest::GetStatus),
+    ("temps", PrinterRequest::GetTemperature),
+    ("progress", PrinterRequest::GetProgress),
+    ("position", PrinterRequest::GetHeadPosition),
+];
+
+async fn raw_response(printer: &Printer, command: &str, request: &PrinterRequest) -> Result<RawPrinterResponse, PrinterError> {
+    let response = printer.send_raw(request.clone()).await?;
+    let (parsed, error) = match response.parsed {
+        Ok(parsed) => (serde_json::to_value(parsed).ok(), None),
+        Err(e) => (None, Some(e.to_string()))
+    };
+    Ok(RawPrinterResponse {
+        command: command.to_string(),
+        gcode: request.get_gcode(),
+        warnings: request.parse_warnings(&response.text),
+        raw: response.text,
+        parsed,
+        error
+    })
+}
+
+/// The printer's response to one command, as sent and parsed
+#[get("/<printer_id>/debug/raw?<cmd>")]
+pub async fn get_raw_response(auth: AuthGuard, printers: &State<PrinterManager>, printer_id: &str, cmd: &str)
+    -> Result<Json<RawPrinterResponse>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Write)?;
+    let (command, request) = COMMANDS.iter().find(|(name, _)| *name == cmd)
+        .ok_or_else(|| ErrorCode::UnknownCommand.response(format!("unknown command {}, expected one of {}", cmd, COMMANDS.map(|(name, _)| name).join(", "))))?;
+    try_printer_json(printers, printer_id, async |printer| raw_response(printer, command, request).await).await
+}
+
+/// Saves the responses to every command to a file in debug.record_dir, which can be attached to bug reports
+#[post("/<printer_id>/debug/record")]
+pub async fn record_responses(auth: AuthGuard, printers: &State<PrinterManager>, config: &State<Arc<ConfigManager>>, printer_id: &str)
+    -> Result<Json<PrinterRecording>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Write)?;
+    let responses = try_printer(printers, printer_id, async |printer| {
+        let mut responses = Vec::new();
+        for (command, request) in &COMMANDS {
+            responses.push(raw_response(printer, command, request).await?);
+        }
+        Ok(responses)
+    }).await?;
+
+    let now = OffsetDateTime::now_utc();
+    let mut contents = format!("# Responses of printer {} at {}\n", printer_id, now);
+    for response in &responses {
+        write!(contents, "\n## {} ({})\n{}", response.gcode, response.command, response.raw).ok();
+    }
+    // Routes are only mounted when enabled
+    let dir = &config.debug().expect("debug routes mounted while disabled").record_dir;
+    let timestamp = now.format(format_description!("[year][month][day]-[hour][minute][second]")).unwrap_or_default();
+    let path = dir.join(format!("{}-{}.txt", printer_id, timestamp));
+    std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(&path, contents))
+        .map_err(|e| ErrorCode::RecordingFailed.response(format!("could not write {}: {}", path.display(), e)))?;
+    Ok(Json(PrinterRecording {
+        path: path.display().to_string(),
+        responses
+    }))
+}
@@ -0,0 +1,21 @@
+use crate::errors::ErrorCode;
+use crate::discovery;
+use crate::manager::PrinterManager;
+use crate::models::{DiscoveredPrinter, GenericError};
+use crate::util::{AccessType, AuthGuard};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, State};
+
+/// Printers on the network that are not in the config yet
+#[get("/")]
+pub async fn discover_printers(auth: AuthGuard, manager: &State<PrinterManager>)
+    -> Result<Json<Vec<DiscoveredPrinter>>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let config = manager.lock().await.config();
+    let discovered = discovery::discover(&config.discovery().broadcast_addresses, config.discovery().timeout()).await
+        .map_err(|e| ErrorCode::DiscoveryFailed.response(e))?;
+    let manager = manager.lock().await;
+    Ok(Json(discovered.into_iter().filter(|printer| !manager.is_known(printer)).collect()))
+}
@@ -0,0 +1,10 @@
+use crate::errors::ErrorCode;
+use crate::models::ErrorCodeInfo;
+use rocket::serde::json::Json;
+use rocket::get;
+
+/// Every code the `error` field of responses can have, without auth, for client authors
+#[get("/errors")]
+pub async fn list_errors() -> Json<Vec<ErrorCodeInfo>> {
+    Json(ErrorCode::ALL.iter().map(|code| code.info()).collect())
+}
@@ -0,0 +1,151 @@
+use crate::errors::ErrorCode;
+use crate::history::HistoryMetric;
+use crate::manager::PrinterManager;
+use crate::models::{GenericError, GrafanaQuery, GrafanaSearch, GrafanaTimeseries};
+use crate::util::{AccessType, AuthGuard};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, post, State};
+use std::time::Duration;
+
+// Endpoints of Grafana's JSON datasource, series are named <printer id>.<metric> and backed by the history
+
+/// Used by Grafana to test the datasource
+#[get("/")]
+pub async fn health(auth: AuthGuard) -> Result<&'static str, (Status, Json<GenericError>)> {
+    auth.check_auth(AccessType::Read)?;
+    Ok("OK")
+}
+
+#[post("/search", data = "<search>")]
+pub async fn search(auth: AuthGuard, manager: &State<PrinterManager>, search: Option<Json<GrafanaSearch>>)
+    -> Result<Json<Vec<String>>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let search = search.map(|search| search.into_inner()).unwrap_or_default();
+    let mut names = manager.lock().await.get_printer_names();
+    names.sort();
+    Ok(Json(names.iter()
+        .flat_map(|printer| HistoryMetric::ALL.map(|metric| format!("{}.{}", printer, metric.name())))
+        .filter(|series| series.contains(&search.target))
+        .collect()))
+}
+
+#[post("/query", data = "<query>")]
+pub async fn query(auth: AuthGuard, manager: &State<PrinterManager>, query: Json<GrafanaQuery>)
+    -> Result<Json<Vec<GrafanaTimeseries>>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let history = manager.lock().await.history().ok_or(ErrorCode::HistoryDisabled.response("history is not configured"))?;
+    // Grafana's interval, but never more points than the panel can show
+    let range = (query.range.to - query.range.from).whole_milliseconds().max(0) as u64;
+    let min_interval = query.max_data_points.filter(|points| *points > 0).map(|points| range / points).unwrap_or(0);
+    let resolution = Duration::from_millis(query.interval_ms.unwrap_or(60_000).max(min_interval).max(1000));
+
+    let mut results = Vec::new();
+    for target in &query.targets {
+        let Some((printer, metric)) = target.target.rsplit_once('.')
+            .and_then(|(printer, metric)| Some((printer, HistoryMetric::from_name(metric)?))) else {
+            return Err(ErrorCode::UnknownSeries.response(format!("unknown series {}, expected <printer>.<metric>", target.target)));
+        };
+        let points = history.query(printer, metric, query.range.from, query.range.to, resolution).await
+            .map_err(|e| ErrorCode::HistoryError.response(e))?;
+        results.push(GrafanaTimeseries {
+            target: target.target.clone(),
+            datapoints: points.into_iter()
+                .map(|point| (point.value, (point.time.unix_timestamp_nanos() / 1_000_000) as i64))
+                .collect()
+        });
+    }
+    Ok(Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigManager;
+    use crate::manager::Printers;
+    use crate::util::AuthLimiter;
+    use rocket::http::ContentType;
+    use rocket::local::asynchronous::Client;
+    use rocket::routes;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    /// Sent by Grafana's JSON datasource for a panel with two series over an hour
+    const RECORDED_QUERY: &str = r#"{"app":"dashboard","requestId":"Q101","timezone":"browser","panelId":2,"dashboardId":1,
+        "range":{"from":"2025-03-01T12:00:00.000Z","to":"2025-03-01T13:00:00.000Z","raw":{"from":"now-1h","to":"now"}},
+        "timeInfo":"","interval":"5m","intervalMs":300000,
+        "targets":[{"target":"main.nozzle_temp","refId":"A","type":"timeserie"},{"target":"main.layer","refId":"B","type":"timeserie"}],
+        "maxDataPoints":1166,"scopedVars":{"__interval":{"text":"5m","value":"5m"},"__interval_ms":{"text":"300000","value":300000}},
+        "startTime":1740833999000,"rangeRaw":{"from":"now-1h","to":"now"},"adhocFilters":[]}"#;
+
+    // 2025-03-01T12:00:00Z
+    const START: i64 = 1740830400;
+
+    async fn client(test: &str) -> Client {
+        let path = std::env::temp_dir().join(format!("flashforge-grafana-{}-{}.db", test, std::process::id()));
+        let records = [
+            (START, "main", 200.0, 1),
+            (START + 120, "main", 210.0, 2),
+            (START + 420, "main", 220.0, 3),
+            // After the range
+            (START + 5400, "main", 999.0, 99),
+            (START, "second", 50.0, 0),
+        ];
+        crate::history::seed(&path, &records);
+
+        let config = Arc::new(ConfigManager::from_toml(&format!(r#"
+            [history]
+            db_path = {:?}
+            retention_days = 36500
+            [printers]
+            main = {{ ip = "127.0.0.1" }}
+            second = {{ ip = "127.0.0.2" }}
+        "#, path)));
+        let mut printers = Printers::new(config.clone());
+        for (id, printer) in config.printers() {
+            printers.add_printer(id.clone(), printer.host().unwrap(), printer.api_port, printer.idle_timeout()).unwrap();
+        }
+        let rocket = rocket::build()
+            .manage(config)
+            .manage(Arc::new(AuthLimiter::new(u32::MAX, Duration::ZERO)))
+            .manage(Arc::new(Mutex::new(printers)))
+            .mount("/api/grafana", routes![health, search, query]);
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn replays_recorded_query() {
+        let client = client("query").await;
+        let response = client.post("/api/grafana/query").header(ContentType::JSON).body(RECORDED_QUERY).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let series: serde_json::Value = response.into_json().await.unwrap();
+        let start_ms = START * 1000;
+        assert_eq!(series, serde_json::json!([
+            { "target": "main.nozzle_temp", "datapoints": [[205.0, start_ms], [220.0, start_ms + 300_000]] },
+            { "target": "main.layer", "datapoints": [[1.5, start_ms], [3.0, start_ms + 300_000]] }
+        ]));
+    }
+
+    #[rocket::async_test]
+    async fn rejects_unknown_series() {
+        let client = client("unknown").await;
+        let body = RECORDED_QUERY.replace("main.layer", "main.flow_rate");
+        let response = client.post("/api/grafana/query").header(ContentType::JSON).body(body).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn searches_series() {
+        let client = client("search").await;
+        let response = client.post("/api/grafana/search").header(ContentType::JSON).body(r#"{"target":"second.bed"}"#).dispatch().await;
+        let series: Vec<String> = response.into_json().await.unwrap();
+        assert_eq!(series, vec!["second.bed_temp", "second.bed_target"]);
+
+        let response = client.post("/api/grafana/search").dispatch().await;
+        let series: Vec<String> = response.into_json().await.unwrap();
+        assert_eq!(series.len(), 2 * HistoryMetric::ALL.len());
+        assert!(series.contains(&"main.progress".to_string()));
+    }
+}
@@ -0,0 +1,44 @@
+//! Named sequences of G-code from the [macros] section of the config
+use crate::errors::ErrorCode;
+use crate::config::ConfigManager;
+use crate::manager::PrinterManager;
+use crate::models::{GenericError, MacroInfo, MacroRun, MacroStep};
+use crate::util::{printer_error, unknown_printer, AccessType, AuthGuard, NotBusy};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, post, State};
+use std::sync::Arc;
+
+#[get("/macros")]
+pub async fn list_macros(auth: AuthGuard, config: &State<Arc<ConfigManager>>) -> Result<Json<Vec<MacroInfo>>, (Status, Json<GenericError>)> {
+    auth.check_auth(AccessType::Read)?;
+    Ok(Json(config.macros().iter()
+        .map(|(name, printer_macro)| MacroInfo { name: name.clone(), printer_commands: printer_macro.printer_commands.clone() })
+        .collect()))
+}
+
+/// Sends the macro's commands in order over one connection, with no other requests in between. Stops at the first
+/// command that fails, the error says which
+#[post("/<printer_id>/macros/<name>")]
+pub async fn run_printer_macro(auth: AuthGuard, _not_busy: NotBusy, manager: &State<PrinterManager>, config: &State<Arc<ConfigManager>>, printer_id: &str, name: &str)
+    -> Result<Json<MacroRun>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Write)?;
+    let printer = manager.lock().await.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))?;
+    let printer_macro = config.macros().get(name).ok_or_else(|| ErrorCode::UnknownMacro.response(format!("unknown macro {}", name)))?;
+    let requests = printer_macro.requests();
+    let commands: Vec<String> = requests.iter().map(|request| request.get_gcode()).collect();
+    match printer.send_batch(requests).await {
+        Ok(responses) => Ok(Json(MacroRun {
+            name: name.to_string(),
+            steps: commands.into_iter().zip(responses)
+                .map(|(command, response)| MacroStep { command, response: response.text.trim_end().to_string() })
+                .collect()
+        })),
+        Err((step, e)) => {
+            let (status, error) = printer_error(e);
+            Err(ErrorCode::MacroStepFailed.response_with(status, format!("step {} of {} ({}) failed, the steps after it were not sent: {}",
+                step + 1, name, commands[step], error.message.as_deref().unwrap_or(&error.error))))
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use crate::manager::PrinterManager;
+use crate::metrics::{render_exchanges, Metrics};
+use crate::models::GenericError;
+use crate::util::{AccessType, AuthGuard};
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::Json;
+use rocket::{get, State};
+use std::sync::Arc;
+
+/// Request counts and latencies, and how long each printer takes to answer, in the Prometheus text format
+#[get("/metrics")]
+pub async fn metrics(auth: AuthGuard, metrics: &State<Arc<Metrics>>, printers: &State<PrinterManager>) -> Result<(ContentType, String), (Status, Json<GenericError>)> {
+    auth.check_auth(AccessType::Read)?;
+    let exchanges: Vec<_> = printers.lock().await.printers().iter()
+        .map(|printer| (printer.name().to_string(), printer.latencies().commands()))
+        .collect();
+    Ok((ContentType::new("text", "plain").with_params(("version", "0.0.4")), metrics.render() + &render_exchanges(&exchanges)))
+}
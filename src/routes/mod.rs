@@ -1,2 +1,18 @@
+pub mod admin;
 pub mod api;
-pub mod ui;
\ No newline at end of file
+#[cfg(feature = "camera")]
+pub mod camera;
+pub mod debug;
+pub mod discovery;
+pub mod errors;
+pub mod grafana;
+pub mod macros;
+pub mod metrics;
+pub mod moonraker;
+pub mod notifications;
+pub mod ready;
+pub mod stats;
+pub mod ui;
+pub mod version;
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,75 @@
+use crate::config::ConfigManager;
+use crate::manager::PrinterManager;
+use crate::models::GenericError;
+use crate::moonraker::{self, MoonrakerResponse, ObjectList, ObjectQuery, PrinterInfo, ServerInfo};
+use crate::printer::Printer;
+use crate::util::{unknown_printer, AccessType, AuthGuard};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::Json;
+use rocket::{get, Request, State};
+use std::convert::Infallible;
+use std::sync::Arc;
+
+type MoonrakerResult<T> = Result<Json<MoonrakerResponse<T>>, (Status, Json<GenericError>)>;
+
+/// Objects of /printer/objects/query, given as `?extruder&heater_bed=temperature,target`
+pub struct QueriedObjects(Vec<(String, Option<Vec<String>>)>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for QueriedObjects {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let objects = request.uri().query()
+            .map(|query| query.segments()
+                .map(|(object, attributes)| {
+                    let attributes = (!attributes.is_empty()).then(|| attributes.split(',').map(str::to_string).collect());
+                    (object.to_string(), attributes)
+                })
+                .collect())
+            .unwrap_or_default();
+        Outcome::Success(QueriedObjects(objects))
+    }
+}
+
+async fn moonraker_printer(config: &ConfigManager, manager: &PrinterManager) -> Result<Arc<Printer>, (Status, Json<GenericError>)> {
+    // Routes are only mounted when enabled
+    let printer_id = &config.moonraker().expect("moonraker routes mounted while disabled").printer;
+    manager.lock().await.get_printer(printer_id).ok_or_else(|| unknown_printer(printer_id))
+}
+
+#[get("/server/info")]
+pub async fn server_info(auth: AuthGuard, config: &State<Arc<ConfigManager>>, manager: &State<PrinterManager>) -> MoonrakerResult<ServerInfo> {
+    auth.check_auth(AccessType::Read)?;
+    let printer = moonraker_printer(config, manager).await?;
+    Ok(Json(MoonrakerResponse { result: ServerInfo::new(&printer) }))
+}
+
+#[get("/printer/info")]
+pub async fn printer_info(auth: AuthGuard, config: &State<Arc<ConfigManager>>, manager: &State<PrinterManager>) -> MoonrakerResult<PrinterInfo> {
+    auth.check_auth(AccessType::Read)?;
+    let printer = moonraker_printer(config, manager).await?;
+    Ok(Json(MoonrakerResponse { result: PrinterInfo::new(&printer) }))
+}
+
+#[get("/printer/objects/list")]
+pub async fn list_objects(auth: AuthGuard) -> MoonrakerResult<ObjectList> {
+    auth.check_auth(AccessType::Read)?;
+    Ok(Json(MoonrakerResponse { result: ObjectList { objects: moonraker::OBJECTS.to_vec() } }))
+}
+
+#[get("/printer/objects/query")]
+pub async fn query_objects(auth: AuthGuard, config: &State<Arc<ConfigManager>>, manager: &State<PrinterManager>, objects: QueriedObjects)
+    -> MoonrakerResult<ObjectQuery>
+{
+    auth.check_auth(AccessType::Read)?;
+    let printer = moonraker_printer(config, manager).await?;
+    // Status and progress are cached by the watch thread, temperatures are only fetched if asked for
+    let wants_temps = objects.0.iter().any(|(object, _)| object.starts_with("extruder") || object == "heater_bed");
+    let temps = match wants_temps && printer.online() {
+        true => printer.get_temperatures().await.ok().map(|temps| temps.normalize()),
+        false => None
+    };
+    Ok(Json(MoonrakerResponse { result: moonraker::query_objects(&printer, temps.as_ref(), &objects.0) }))
+}
@@ -0,0 +1,103 @@
+use crate::errors::ErrorCode;
+use crate::manager::PrinterManager;
+use crate::notifications::NotificationType;
+use crate::config::Destination;
+use crate::models::{DestinationEnabled, DestinationKind, GenericError, NotificationDestination, NotificationRecord, NotificationResult, TestNotificationRequest, WebhookDelivery};
+use crate::notifications::Notifier;
+use crate::util::{redact_url, unknown_printer, AccessType, AuthGuard};
+use crate::validated;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, post, put, State};
+
+#[get("/deliveries")]
+pub async fn list_deliveries(auth: AuthGuard, manager: &State<PrinterManager>)
+    -> Result<Json<Vec<WebhookDelivery>>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let manager = manager.lock().await;
+    Ok(Json(manager.get_deliveries().into_iter().map(redacted_delivery).collect()))
+}
+
+/// Webhook urls are secrets, only their host is shown to readers
+fn redacted_delivery(delivery: WebhookDelivery) -> WebhookDelivery {
+    WebhookDelivery { url: redact_url(&delivery.url), ..delivery }
+}
+
+fn destination_info(index: usize, destination: &Destination, notifier: &Notifier) -> NotificationDestination {
+    let (target, last_delivery) = match destination.kind {
+        DestinationKind::Webhook => (redact_url(destination.target), notifier.last_delivery(destination.target).map(redacted_delivery)),
+        DestinationKind::Email => (destination.target.to_string(), None)
+    };
+    NotificationDestination {
+        index,
+        notification: destination.key.to_string(),
+        kind: destination.kind.clone(),
+        target,
+        enabled: destination.enabled.get(),
+        last_delivery
+    }
+}
+
+/// Every email and webhook of every notification type and the digest, with whether it is enabled
+#[get("/destinations")]
+pub async fn list_destinations(auth: AuthGuard, manager: &State<PrinterManager>)
+    -> Result<Json<Vec<NotificationDestination>>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let (config, notifier) = {
+        let manager = manager.lock().await;
+        (manager.config(), manager.notifier())
+    };
+    Ok(Json(config.destinations().iter().enumerate()
+        .map(|(index, destination)| destination_info(index, destination, &notifier))
+        .collect()))
+}
+
+/// Turns a destination on or off, and saves it to config.toml so it stays that way after a restart
+#[put("/destinations/<index>/enabled", data = "<request>")]
+pub async fn set_destination_enabled(auth: AuthGuard, manager: &State<PrinterManager>, index: usize, request: validated::Json<DestinationEnabled>)
+    -> Result<Json<NotificationDestination>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Write)?;
+    let (config, notifier) = {
+        let manager = manager.lock().await;
+        (manager.config(), manager.notifier())
+    };
+    let destinations = config.destinations();
+    let destination = destinations.get(index).ok_or_else(|| ErrorCode::UnknownDestination.response(format!("there is no destination {}, there are {}", index, destinations.len())))?;
+    config.set_destination_enabled(destination, request.enabled).map_err(|e| ErrorCode::ConfigWriteFailed.response(format!("could not save the change: {}", e)))?;
+    Ok(Json(destination_info(index, destination, &notifier)))
+}
+
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+/// The notifications sent, newest first, with the result of each destination. `printer` leaves out the others and the digest
+#[get("/history?<printer>&<limit>")]
+pub async fn get_history(auth: AuthGuard, manager: &State<PrinterManager>, printer: Option<&str>, limit: Option<usize>)
+    -> Result<Json<Vec<NotificationRecord>>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let notifier = manager.lock().await.notifier();
+    let mut records = notifier.history().recent(printer, limit.unwrap_or(DEFAULT_HISTORY_LIMIT));
+    for result in records.iter_mut().flat_map(|record| record.results.iter_mut()).filter(|result| result.kind == DestinationKind::Webhook) {
+        result.destination = redact_url(&result.destination);
+    }
+    Ok(Json(records))
+}
+
+#[post("/test", data = "<request>")]
+pub async fn send_test_notification(auth: AuthGuard, manager: &State<PrinterManager>, request: validated::Json<TestNotificationRequest>)
+    -> Result<Json<Vec<NotificationResult>>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Write)?;
+    // Checked by the body's Validate
+    let notification_type = NotificationType::from_name(&request.notification_type).unwrap();
+    let (printer, notifier) = {
+        let manager = manager.lock().await;
+        let printer = manager.get_printer(&request.printer).ok_or_else(|| unknown_printer(&request.printer))?;
+        (printer, manager.notifier())
+    };
+    // Sent directly instead of through the queue, to be able to return the results
+    Ok(Json(notifier.send_notification(&printer, notification_type, None, None, request.dry_run).await))
+}
@@ -0,0 +1,11 @@
+use crate::config::ConfigManager;
+use crate::models::Readiness;
+use rocket::serde::json::Json;
+use rocket::{get, State};
+use std::sync::Arc;
+
+/// For readiness probes, without auth. The API serves whatever state the SMTP server is in, so it is only reported
+#[get("/readyz")]
+pub async fn readyz(config: &State<Arc<ConfigManager>>) -> Json<Readiness> {
+    Json(Readiness { ready: true, smtp: config.smtp_status() })
+}
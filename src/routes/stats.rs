@@ -0,0 +1,44 @@
+use crate::errors::ErrorCode;
+use crate::history;
+use crate::jobs::{self, PrinterNow};
+use crate::manager::PrinterManager;
+use crate::models::{FarmStats, FilamentStats, GenericError};
+use crate::util::{AccessType, AuthGuard};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, State};
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::{OffsetDateTime, UtcOffset};
+
+/// Counts of the whole farm in one request, for the header of a dashboard
+#[get("/")]
+pub async fn get_farm_stats(auth: AuthGuard, printers: &State<PrinterManager>) -> Result<Json<FarmStats>, (Status, Json<GenericError>)> {
+    auth.check_auth(AccessType::Read)?;
+    let (printers, jobs, config) = {
+        let lock = printers.lock().await;
+        (lock.printers(), lock.jobs(), lock.config())
+    };
+    // Today and this week start when the digest's do
+    let offset = config.digest().and_then(|digest| digest.offset()).unwrap_or(UtcOffset::UTC);
+    let mut printers: Vec<PrinterNow> = printers.iter().map(PrinterNow::of).collect();
+    printers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(jobs::farm_stats(&printers, &jobs.records(), OffsetDateTime::now_utc(), offset)))
+}
+
+/// Filament used by the prints that ended since `since`, a duration ago such as "30d" (the default) or RFC 3339
+#[get("/filament?<since>")]
+pub async fn get_filament_stats(auth: AuthGuard, printers: &State<PrinterManager>, since: Option<&str>)
+    -> Result<Json<FilamentStats>, (Status, Json<GenericError>)>
+{
+    auth.check_auth(AccessType::Read)?;
+    let since = match since {
+        Some(since) => history::parse_duration(since)
+            .and_then(|ago| OffsetDateTime::now_utc().checked_sub(time::Duration::try_from(ago).ok()?))
+            .or_else(|| OffsetDateTime::parse(since, &Rfc3339).ok())
+            .ok_or_else(|| ErrorCode::InvalidSince.response(format!("{} is not a duration such as 30d or a RFC 3339 date", since)))?,
+        None => OffsetDateTime::now_utc() - Duration::from_secs(30 * 24 * 60 * 60)
+    };
+    let filament = printers.lock().await.filament().ok_or(ErrorCode::FilamentDisabled.response("filament is not configured"))?;
+    Ok(Json(filament.totals(since)))
+}
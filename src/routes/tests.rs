@@ -0,0 +1,1275 @@
+//! Every route against the mock printer, through the same server main builds
+use crate::config::ConfigManager;
+use crate::manager::{PrinterManager, Printers};
+use crate::printer::Printer;
+#[cfg(feature = "camera")]
+use crate::test_support::{decode, is_red, mock_camera, CAMERA_IMAGE};
+use crate::test_support::{fixture, mock_webhook, unused_port, MockPrinter};
+use crate::util::redact_url;
+use rocket::figment::Figment;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous::{Client, LocalResponse};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+const IDLE: Duration = Duration::from_secs(30);
+
+struct TestServer {
+    client: Client,
+    /// Printer "main", "offline" has nothing listening on its ports
+    mock: MockPrinter
+}
+
+impl TestServer {
+    /// Starts the server with the given config sections, in addition to the two printers
+    async fn start(config: &str) -> Self {
+        let mock = MockPrinter::start().await;
+        let offline_port = unused_port().await;
+        #[cfg(feature = "camera")]
+        let camera_port = mock_camera(Duration::ZERO).await;
+        #[cfg(not(feature = "camera"))]
+        let camera_port = offline_port;
+        let config = Arc::new(ConfigManager::from_toml(&format!(r#"
+            {}
+            [printers]
+            main = {{ ip = "127.0.0.1" }}
+            offline = {{ ip = "127.0.0.1" }}
+        "#, config)));
+        let mut printers = Printers::new(config.clone());
+        printers.insert_printer(Printer::with_ports("main".to_string(), "127.0.0.1".to_string(), mock.port, camera_port, IDLE));
+        printers.insert_printer(Printer::with_ports("offline".to_string(), "127.0.0.1".to_string(), offline_port, offline_port, IDLE));
+        let printers = Arc::new(Mutex::new(printers));
+        let figment = Figment::from(rocket::Config::debug_default());
+        let client = Client::untracked(crate::app(figment, config, printers)).await.unwrap();
+        TestServer { client, mock }
+    }
+
+    /// Polls the printer like the watch thread does
+    async fn refresh(&self, printer_id: &str) {
+        let manager = self.client.rocket().state::<PrinterManager>().unwrap();
+        let printer = manager.lock().await.get_printer(printer_id).unwrap();
+        printer.refresh_status().await.ok();
+        if self.client.rocket().state::<Arc<ConfigManager>>().unwrap().watch_head_position() {
+            printer.refresh_head_position().await.ok();
+        }
+    }
+}
+
+async fn json(response: LocalResponse<'_>) -> Value {
+    serde_json::from_str(&response.into_string().await.unwrap()).unwrap()
+}
+
+async fn get(server: &TestServer, uri: &str) -> (Status, Value) {
+    let response = server.client.get(uri.to_string()).dispatch().await;
+    (response.status(), json(response).await)
+}
+
+#[tokio::test]
+async fn printer_routes_answer_from_the_printer() {
+    let server = TestServer::start("").await;
+
+    let (status, info) = get(&server, "/api/printers/main/info").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(info["name"], "Adventurer III");
+    assert_eq!(info["sn"], "SNADVA9501234");
+    assert_eq!(info["model_name"], "FlashForge Adventurer III");
+    assert_eq!(info["build_volume"], serde_json::json!({"x": 150, "y": 150, "z": 150}));
+    assert!(info.get("position").is_none());
+    assert_eq!(info["capabilities"], serde_json::json!({"led": true, "camera": true, "layer_progress": true}));
+
+    let (_, status) = get(&server, "/api/printers/main/status").await;
+    assert_eq!(status["machine_status"], "READY");
+    assert_eq!(status["current_file"], Value::Null);
+
+    let (_, temps) = get(&server, "/api/printers/main/temperatures").await;
+    assert_eq!(temps["T0"]["current"], 210.0);
+    assert_eq!(temps["unit"], "C");
+    let (_, temps) = get(&server, "/api/printers/main/temperatures?normalized=true").await;
+    assert_eq!(temps["extruders"][0]["target"], 210.0);
+    assert_eq!(temps["bed"]["current"], 60.0);
+    let (_, temps) = get(&server, "/api/printers/main/temperatures?normalized=true&unit=F").await;
+    assert_eq!(temps["extruders"][0]["target"], 410.0);
+    assert_eq!(temps["bed"]["current"], 140.0);
+    assert_eq!(temps["unit"], "F");
+    let (status, error) = get(&server, "/api/printers/main/temperatures?unit=kelvin").await;
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(error["error"], "INVALID_UNIT");
+
+    let (_, progress) = get(&server, "/api/printers/main/progress").await;
+    assert_eq!(progress["byte"], serde_json::json!({"current": 2400, "total": 12000}));
+    assert_eq!(progress["layer"], serde_json::json!({"current": 12, "total": 60}));
+
+    let (_, position) = get(&server, "/api/printers/main/head-position").await;
+    assert_eq!(position["x"], 10.5);
+    assert_eq!(position["y"], -20.0);
+
+    let response = server.client.post("/api/printers/main/set-temperature/0/200").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json(response).await["success"], true);
+    assert!(server.mock.received()[0].contains(&"~M104 S200 T0".to_string()));
+
+    let response = server.client.post("/api/printers/main/led/false").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert!(server.mock.received().concat().contains(&"~M146 r0 g0 b0 F0".to_string()));
+}
+
+#[tokio::test]
+async fn models_without_a_light_or_camera_refuse_those_routes() {
+    let server = TestServer::start("").await;
+    server.mock.respond("M115", &fixture("finder/M115"));
+    server.mock.respond("M119", &fixture("finder/M119"));
+    server.refresh("main").await;
+
+    let (_, info) = get(&server, "/api/printers/main/info").await;
+    assert_eq!(info["capabilities"], serde_json::json!({"led": false, "camera": false, "layer_progress": true}));
+    // The fixture has an LED line, the Finder has no light
+    let (_, status) = get(&server, "/api/printers/main/status").await;
+    assert_eq!(status["led"], Value::Null);
+
+    let response = server.client.post("/api/printers/main/led/true").dispatch().await;
+    assert_eq!(response.status(), Status::NotImplemented);
+    let error = json(response).await;
+    assert_eq!(error["error"], "NOT_SUPPORTED_BY_MODEL");
+    assert_eq!(error["message"], "printer main (Flashforge Finder) has no light");
+    assert!(!server.mock.received().concat().iter().any(|line| line.starts_with("~M146")));
+
+    #[cfg(feature = "camera")]
+    for uri in ["/api/printers/main/snapshot", "/api/printers/main/camera"] {
+        let (status, error) = get(&server, uri).await;
+        assert_eq!(status, Status::NotImplemented, "{}", uri);
+        assert_eq!(error["error"], "NOT_SUPPORTED_BY_MODEL");
+    }
+}
+
+#[tokio::test]
+async fn json_fields_can_be_camel_case() {
+    let server = TestServer::start("[http]\njson_case = \"camel\"").await;
+    let (_, info) = get(&server, "/api/printers/main/info").await;
+    assert_eq!(info["firmwareVersion"], "v1.3.7");
+    assert_eq!(info["buildVolume"]["x"], 150);
+    assert_eq!(info["capabilities"]["layerProgress"], true);
+    assert!(info.get("firmware_version").is_none());
+
+    let (_, temps) = get(&server, "/api/printers/main/temperatures?normalized=true").await;
+    assert_eq!(temps["extruders"][0]["target"], 210.0);
+    let (_, status) = get(&server, "/api/printers/main/status").await;
+    assert!(status["machineStatus"].is_string());
+
+    let (_, info) = get(&server, "/api/printers/main/info?fields=firmwareVersion,model_name").await;
+    assert_eq!(info, serde_json::json!({"firmwareVersion": "v1.3.7", "modelName": "FlashForge Adventurer III"}));
+    let (status, error) = get(&server, "/api/printers/missing/health").await;
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error["error"], "UNKNOWN_PRINTER");
+}
+
+#[tokio::test]
+async fn lists_printers_with_their_state() {
+    let server = TestServer::start("").await;
+    let (_, names) = get(&server, "/api/printers/names").await;
+    let mut names: Vec<&str> = names.as_array().unwrap().iter().map(|name| name.as_str().unwrap()).collect();
+    names.sort();
+    assert_eq!(names, vec!["main", "offline"]);
+
+    server.refresh("main").await;
+    server.refresh("offline").await;
+    let (status, printers) = get(&server, "/api/printers").await;
+    assert_eq!(status, Status::Ok);
+    let printer = |id: &str| printers.as_array().unwrap().iter().find(|printer| printer["name"] == id).unwrap().clone();
+    assert_eq!(printer("main")["state"], "online");
+    assert_eq!(printer("main")["model_name"], "FlashForge Adventurer III");
+    assert_eq!(printer("offline")["state"], "pending");
+    assert_eq!(printer("offline")["is_online"], false);
+    // Both were polled just now, only main answered
+    assert!(printer("main")["last_polled_at"].is_string() && printer("offline")["last_polled_at"].is_string());
+    assert_eq!((printer("main")["stale"].as_bool(), printer("offline")["stale"].as_bool()), (Some(false), Some(true)));
+}
+
+#[tokio::test]
+async fn printers_can_be_renamed_and_located() {
+    let server = TestServer::start("").await;
+    server.refresh("main").await;
+    let (_, printers) = get(&server, "/api/printers").await;
+    let main = printers.as_array().unwrap().iter().find(|printer| printer["name"] == "main").unwrap();
+    // The machine name until one is set
+    assert_eq!((main["display_name"].as_str(), main["location"].as_str()), (Some("Adventurer III"), None));
+
+    let patch = async |uri: &str, body: &str| {
+        let response = server.client.patch(uri.to_string()).header(ContentType::JSON).body(body).dispatch().await;
+        (response.status(), json(response).await)
+    };
+    let (status, main) = patch("/api/printers/main", r#"{"display_name": " Door ", "location": "Lab 2"}"#).await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!((main["name"].as_str(), main["display_name"].as_str(), main["location"].as_str()), (Some("main"), Some("Door"), Some("Lab 2")));
+    let (_, main) = patch("/api/printers/main", r#"{"location": null}"#).await;
+    assert_eq!((main["display_name"].as_str(), main["location"].as_str()), (Some("Door"), None));
+    let (_, info) = get(&server, "/api/printers/main/info?fields=name,display_name").await;
+    assert_eq!(info, serde_json::json!({"name": "Adventurer III", "display_name": "Door"}));
+
+    let (status, error) = patch("/api/printers/missing", r#"{"location": "Lab 2"}"#).await;
+    assert_eq!((status, error["error"].as_str()), (Status::NotFound, Some("UNKNOWN_PRINTER")));
+}
+
+#[tokio::test]
+async fn queries_keep_the_tag_of_their_response() {
+    let server = TestServer::start(r#"
+        [privacy]
+        hide_fields = ["sn"]
+    "#).await;
+    let (status, response) = get(&server, "/api/printers/main/query?what=status").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(response["status"]["machine_status"], "READY");
+    let (_, response) = get(&server, "/api/printers/main/query?what=temperatures").await;
+    assert_eq!(response["temperatures"]["T0"]["current"], 210.0);
+    let (_, response) = get(&server, "/api/printers/main/query?what=info").await;
+    assert_eq!(response.as_object().unwrap().keys().collect::<Vec<_>>(), ["info"]);
+    assert!(response["info"].get("sn").is_none() && response["info"]["mac_addr"].is_string());
+
+    let (status, error) = get(&server, "/api/printers/main/query?what=success").await;
+    assert_eq!((status, error["error"].as_str()), (Status::BadRequest, Some("UNKNOWN_QUERY")));
+    let (status, _) = get(&server, "/api/printers/offline/query?what=progress").await;
+    assert_eq!(status, Status::ServiceUnavailable);
+}
+
+#[tokio::test]
+async fn fields_are_selected_and_hidden() {
+    let server = TestServer::start(r#"
+        [privacy]
+        hide_fields = ["sn", "mac_addr"]
+    "#).await;
+    let (status, info) = get(&server, "/api/printers/main/info?fields=name,firmware_version").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(info, serde_json::json!({"name": "Adventurer III", "firmware_version": "v1.3.7"}));
+    let (_, info) = get(&server, "/api/printers/main/info").await;
+    assert_eq!(info["model_name"], "FlashForge Adventurer III");
+    assert!(info.get("sn").is_none() && info.get("mac_addr").is_none());
+
+    let (status, error) = get(&server, "/api/printers/main/info?fields=name,sn").await;
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(error["error"], "UNKNOWN_FIELD");
+    let (status, _) = get(&server, "/api/printers/main/status?fields=nope").await;
+    assert_eq!(status, Status::BadRequest);
+
+    let (_, printers) = get(&server, "/api/printers?fields=name,state").await;
+    for printer in printers.as_array().unwrap() {
+        assert_eq!(printer.as_object().unwrap().keys().collect::<Vec<_>>(), ["name", "state"]);
+    }
+}
+
+#[tokio::test]
+async fn refresh_polls_right_away() {
+    let server = TestServer::start("").await;
+    let response = server.client.post("/api/printers/main/refresh").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let printer = json(response).await;
+    assert_eq!(printer["state"], "online");
+    assert_eq!(printer["model_name"], "FlashForge Adventurer III");
+
+    let response = server.client.post("/api/printers/refresh").dispatch().await;
+    let printers = json(response).await;
+    let printer = |id: &str| printers.as_array().unwrap().iter().find(|printer| printer["name"] == id).unwrap().clone();
+    assert_eq!(printer("main")["state"], "online");
+    // Never reached, so still pending
+    assert_eq!(printer("offline")["state"], "pending");
+    assert_eq!(printer("offline")["health"], "offline");
+
+    let response = server.client.post("/api/printers/missing/refresh").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[tokio::test]
+async fn maintenance_mode_is_listed_and_ends_by_itself() {
+    let server = TestServer::start("").await;
+    let maintenance = |body: String| server.client.put("/api/printers/main/maintenance").header(ContentType::JSON).body(body).dispatch();
+    let response = maintenance(r#"{"enabled": true}"#.to_string()).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json(response).await, serde_json::json!({"enabled": true, "until": null}));
+    let (_, printers) = get(&server, "/api/printers").await;
+    assert_eq!(printers.as_array().unwrap().iter().find(|printer| printer["name"] == "main").unwrap()["maintenance"], true);
+    // Requests still go to the printer
+    let (status, _) = get(&server, "/api/printers/main/status").await;
+    assert_eq!(status, Status::Ok);
+
+    let until = |offset: time::Duration| (time::OffsetDateTime::now_utc() + offset).format(&time::format_description::well_known::Rfc3339).unwrap();
+    let response = maintenance(format!(r#"{{"enabled": true, "until": "{}"}}"#, until(time::Duration::minutes(-1)))).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(json(response).await["details"], serde_json::json!([{"field": "until", "message": "is in the past"}]));
+
+    let response = maintenance(format!(r#"{{"enabled": true, "until": "{}"}}"#, until(time::Duration::milliseconds(500)))).await;
+    assert_eq!(response.status(), Status::Ok);
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    let (_, printers) = get(&server, "/api/printers").await;
+    assert_eq!(printers.as_array().unwrap().iter().find(|printer| printer["name"] == "main").unwrap()["maintenance"], false);
+}
+
+#[tokio::test]
+async fn unchanged_responses_are_not_modified() {
+    let server = TestServer::start("").await;
+    let temperatures = |etag: Option<String>| {
+        let mut request = server.client.get("/api/printers/main/temperatures");
+        if let Some(etag) = etag {
+            request = request.header(Header::new("If-None-Match", etag));
+        }
+        request.dispatch()
+    };
+    let response = temperatures(None).await;
+    assert_eq!(response.status(), Status::Ok);
+    let etag = response.headers().get_one("ETag").unwrap().to_string();
+    assert!(etag.starts_with("W/\""));
+
+    let response = temperatures(Some(etag.clone())).await;
+    assert_eq!(response.status(), Status::NotModified);
+    assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+    assert!(response.into_bytes().await.is_none_or(|body| body.is_empty()));
+
+    server.mock.respond("M105", "CMD M105 Received.\nT0:205/210 B:60/60\nok\n");
+    let response = temperatures(Some(etag.clone())).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_ne!(response.headers().get_one("ETag"), Some(etag.as_str()));
+    assert_eq!(json(response).await["T0"]["current"], 205.0);
+
+    let response = server.client.get("/api/printers/main/status").dispatch().await;
+    let etag = response.headers().get_one("ETag").unwrap().to_string();
+    let response = server.client.get("/api/printers/main/status").header(Header::new("If-None-Match", format!("\"other\", {}", etag))).dispatch().await;
+    assert_eq!(response.status(), Status::NotModified);
+}
+
+#[tokio::test]
+async fn large_json_responses_are_compressed() {
+    let server = TestServer::start(r#"
+        [http.compression]
+        min_size = 100
+    "#).await;
+    let plain = server.client.get("/api/printers/main/info").dispatch().await;
+    assert!(plain.headers().get_one("Content-Encoding").is_none());
+    assert_eq!(plain.headers().get_one("Vary"), Some("Accept-Encoding"));
+    let plain = plain.into_bytes().await.unwrap();
+
+    let response = server.client.get("/api/printers/main/info").header(Header::new("Accept-Encoding", "gzip, deflate")).dispatch().await;
+    assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+    assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let mut body = Vec::new();
+    std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&response.into_bytes().await.unwrap()[..]), &mut body).unwrap();
+    assert_eq!(body, plain);
+
+    // Under min_size
+    let response = server.client.get("/api/printers/main/progress").header(Header::new("Accept-Encoding", "gzip")).dispatch().await;
+    assert!(response.headers().get_one("Content-Encoding").is_none());
+    assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+    #[cfg(feature = "camera")]
+    {
+        let response = server.client.get("/api/printers/main/snapshot").header(Header::new("Accept-Encoding", "gzip")).dispatch().await;
+        assert!(response.headers().get_one("Content-Encoding").is_none());
+        assert!(response.headers().get_one("Vary").is_none());
+        assert_eq!(response.into_bytes().await.unwrap(), CAMERA_IMAGE);
+    }
+}
+
+#[tokio::test]
+async fn job_is_tracked_while_printing() {
+    let server = TestServer::start("").await;
+    server.refresh("main").await;
+    let (status, job) = get(&server, "/api/printers/main/job").await;
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(job["error"], "NO_ACTIVE_JOB");
+
+    server.mock.respond("M119", &fixture("M119_printing"));
+    server.refresh("main").await;
+    let (status, job) = get(&server, "/api/printers/main/job").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(job["file"], "benchy.gx");
+    assert_eq!(job["machine_status"], "BUILDING_FROM_SD");
+    assert_eq!(job["progress_percent"], 20);
+    assert_eq!(job["layer"], serde_json::json!({"current": 12, "total": 60}));
+}
+
+#[tokio::test]
+async fn speed_and_flow_are_only_set_while_printing() {
+    let server = TestServer::start("").await;
+    let set = async |path: &str, percent: u16| {
+        let response = server.client.put(format!("/api/printers/main/{}", path)).header(ContentType::JSON)
+            .body(format!(r#"{{"percent": {}}}"#, percent)).dispatch().await;
+        (response.status(), json(response).await)
+    };
+    let (status, error) = set("speed", 110).await;
+    assert_eq!((status, error["error"].as_str()), (Status::Conflict, Some("NOT_PRINTING")));
+
+    server.mock.respond("M119", &fixture("M119_printing"));
+    server.refresh("main").await;
+    let (status, error) = set("speed", 400).await;
+    assert_eq!((status, error["details"][0]["field"].as_str()), (Status::UnprocessableEntity, Some("percent")));
+    assert_eq!(set("speed", 110).await.0, Status::Ok);
+    assert_eq!(set("flow", 95).await.0, Status::Ok);
+    let sent = server.mock.received().concat();
+    assert!(sent.contains(&"~M220 S110".to_string()) && sent.contains(&"~M221 S95".to_string()));
+    assert!(!sent.iter().any(|line| line.starts_with("~M220 S400")));
+
+    let (_, job) = get(&server, "/api/printers/main/job").await;
+    assert_eq!((job["speed_percent"].as_u64(), job["flow_percent"].as_u64()), (Some(110), Some(95)));
+}
+
+#[tokio::test]
+async fn job_thumbnails_come_from_the_sliced_file() {
+    let dir = std::env::temp_dir().join(format!("flashforge-routes-thumbnail-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // "\x89PNG\r\n\x1a\nbenchy" in base64
+    std::fs::write(dir.join("benchy.gx"), "; thumbnail begin 300x300 24\n; iVBORw0KGgpiZW5jaHk=\n; thumbnail end\nG28\n").unwrap();
+    let server = TestServer::start(&format!("[gcode]\ndir = {:?}", dir.display().to_string())).await;
+    let (status, error) = get(&server, "/api/printers/main/job/thumbnail").await;
+    assert_eq!((status, error["error"].as_str()), (Status::NotFound, Some("NO_THUMBNAIL")));
+
+    server.mock.respond("M119", &fixture("M119_printing"));
+    server.refresh("main").await;
+    let response = server.client.get("/api/printers/main/job/thumbnail").dispatch().await;
+    assert_eq!((response.status(), response.content_type()), (Status::Ok, Some(ContentType::PNG)));
+    assert_eq!(response.into_bytes().await.unwrap(), b"\x89PNG\r\n\x1a\nbenchy");
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn head_position_is_cached_by_polls() {
+    let server = TestServer::start("[watch]\nhead_position = true").await;
+    let (status, error) = get(&server, "/api/printers/main/head-position?cached=true").await;
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error["error"], "NO_CACHED_POSITION");
+
+    server.refresh("main").await;
+    let m114_sent = || server.mock.received().concat().iter().filter(|line| line.contains("M114")).count();
+    let (status, position) = get(&server, "/api/printers/main/head-position?cached=true").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(position["age_seconds"], 0);
+    assert_eq!(position["end_stop"]["z_min"], 0);
+    // Only the poll asked the printer
+    assert_eq!(m114_sent(), 1);
+
+    let (_, live) = get(&server, "/api/printers/main/head-position").await;
+    assert_eq!(position["x"], live["x"]);
+    assert!(live.get("age_seconds").is_none());
+    assert_eq!(m114_sent(), 2);
+}
+
+#[tokio::test]
+async fn wait_returns_once_the_state_changes() {
+    let server = TestServer::start("").await;
+    server.refresh("main").await;
+    let response = server.client.get("/api/printers/main/wait?timeout=1").dispatch().await;
+    assert_eq!(response.status(), Status::NoContent);
+
+    // A stale token is answered right away
+    let response = server.client.get("/api/printers/main/wait?since=outdated").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let etag = response.headers().get_one("ETag").unwrap().to_string();
+    assert_eq!(json(response).await["machine_status"], "READY");
+
+    let wait = server.client.get(format!("/api/printers/main/wait?timeout=10&since={}", etag.trim_start_matches("W/").trim_matches('"'))).dispatch();
+    let (response, _) = tokio::join!(wait, async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        server.mock.respond("M119", &fixture("M119_printing"));
+        server.refresh("main").await;
+    });
+    assert_eq!(response.status(), Status::Ok);
+    let update = json(response).await;
+    assert_eq!(update["machine_status"], "BUILDING_FROM_SD");
+    assert_eq!(update["current_file"], "benchy.gx");
+    assert_eq!(update["progress"]["layer"], serde_json::json!({"current": 12, "total": 60}));
+
+    let (status, _) = get(&server, "/api/printers/missing/wait?timeout=1").await;
+    assert_eq!(status, Status::NotFound);
+}
+
+#[tokio::test]
+async fn events_are_sent_as_they_happen() {
+    use tokio::io::AsyncReadExt;
+    let server = TestServer::start("").await;
+    let mut response = server.client.get("/api/printers/main/events").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::EventStream));
+
+    let manager = server.client.rocket().state::<PrinterManager>().unwrap();
+    manager.lock().await.get_printer("main").unwrap().publish_event(crate::models::PrinterEvent::Led(false));
+    let mut event = [0; 64];
+    let read = tokio::time::timeout(Duration::from_secs(5), response.read(&mut event)).await.unwrap().unwrap();
+    assert!(String::from_utf8_lossy(&event[..read]).starts_with("data:{\"event\":\"led\",\"value\":false}\n"));
+
+    let (status, _) = get(&server, "/api/printers/missing/events").await;
+    assert_eq!(status, Status::NotFound);
+}
+
+#[tokio::test]
+async fn changes_are_refused_while_a_command_is_in_flight() {
+    let server = TestServer::start("[http]\nbusy_after_ms = 200").await;
+    server.refresh("main").await;
+    server.mock.delay("M119", Duration::from_millis(1500));
+    let ((), (busy, health)) = tokio::join!(server.refresh("main"), async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let busy = server.client.post("/api/printers/main/set-temperature/0/200").dispatch().await;
+        (busy, get(&server, "/api/printers/main/health").await)
+    });
+    assert_eq!(busy.status(), Status::ServiceUnavailable);
+    let retry_after: u64 = busy.headers().get_one("Retry-After").unwrap().parse().unwrap();
+    assert!((1..=20).contains(&retry_after), "{}", retry_after);
+    assert_eq!(json(busy).await["error"], "PRINTER_BUSY");
+    assert_eq!(health.1["in_flight"]["command"], "~M119");
+    assert!(health.1["in_flight"]["elapsed_ms"].as_u64().unwrap() >= 200);
+
+    // Once answered, changes go through again, the refused one was never sent
+    let response = server.client.post("/api/printers/main/set-temperature/0/200").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(server.mock.received().concat().iter().filter(|line| *line == "~M104 S200 T0").count(), 1);
+    assert_eq!(get(&server, "/api/printers/main/health").await.1["in_flight"], Value::Null);
+}
+
+#[tokio::test]
+async fn offline_and_unknown_printers() {
+    let server = TestServer::start("").await;
+    let (status, error) = get(&server, "/api/printers/offline/status").await;
+    assert_eq!(status, Status::ServiceUnavailable);
+    assert_eq!(error["error"], "PRINTER_UNREACHABLE");
+
+    let (status, error) = get(&server, "/api/printers/missing/status").await;
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error["error"], "UNKNOWN_PRINTER");
+
+    let (status, error) = get(&server, "/api/printers/missing/job").await;
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error["error"], "UNKNOWN_PRINTER");
+
+    let (status, error) = get(&server, "/api/nothing-here").await;
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error["error"], "NOT_FOUND");
+}
+
+#[tokio::test]
+async fn rejected_bodies_get_json_errors() {
+    let server = TestServer::start("").await;
+    let put = async |body: String| {
+        let response = server.client.put("/api/printers/main/speed").header(ContentType::JSON).body(body).dispatch().await;
+        (response.status(), json(response).await)
+    };
+    let (status, error) = put("{\"percent\": ".to_string()).await;
+    assert_eq!((status, error["error"].as_str()), (Status::BadRequest, Some("BAD_REQUEST")));
+    let (status, error) = put(r#"{"percent": "fast"}"#.to_string()).await;
+    assert_eq!((status, error["error"].as_str()), (Status::UnprocessableEntity, Some("UNPROCESSABLE_REQUEST")));
+    assert_eq!(error["details"], serde_json::json!([{"field": "percent", "message": "invalid type: string \"fast\", expected u16"}]));
+    let (status, error) = put(format!(r#"{{"percent": 100, "padding": "{}"}}"#, "x".repeat(2 << 20))).await;
+    assert_eq!((status, error["error"].as_str()), (Status::PayloadTooLarge, Some("PAYLOAD_TOO_LARGE")));
+
+    let (status, codes) = get(&server, "/api/errors").await;
+    assert_eq!(status, Status::Ok);
+    let unknown_printer = codes.as_array().unwrap().iter().find(|code| code["code"] == "UNKNOWN_PRINTER").unwrap();
+    assert_eq!(unknown_printer["status"], 404);
+}
+
+#[tokio::test]
+async fn printers_are_found_by_any_case_or_serial() {
+    let server = TestServer::start("").await;
+    let (status, _) = get(&server, "/api/printers/MAIN/status").await;
+    assert_eq!(status, Status::Ok);
+
+    let (status, _) = get(&server, "/api/printers/SNADVA9501234/status").await;
+    assert_eq!(status, Status::NotFound);
+    // The serial number is known once the printer's info is cached
+    server.refresh("main").await;
+    let (status, _) = get(&server, "/api/printers/snadva9501234/status").await;
+    assert_eq!(status, Status::Ok);
+}
+
+#[tokio::test]
+async fn health_tracks_failed_requests() {
+    let server = TestServer::start("").await;
+    server.refresh("main").await;
+    let (status, health) = get(&server, "/api/printers/main/health").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(health["health"], "ok");
+    assert_eq!(health["consecutive_failures"], 0);
+    assert!(health["last_success"].is_string());
+    assert_eq!(health["last_error"], Value::Null);
+    assert_eq!(health["connection"]["api_port"], server.mock.port);
+    // The session stays open between requests
+    assert_eq!(health["gcode_connections"], serde_json::json!({"limit": 1, "in_use": 1, "waiting": 0}));
+    let latency = health["latency"].as_array().unwrap();
+    let status_latency = latency.iter().find(|command| command["command"] == "M119").unwrap();
+    assert_eq!(status_latency["samples"], 1);
+    assert_eq!(status_latency["p95_ms"], status_latency["max_ms"]);
+
+    get(&server, "/api/printers/offline/status").await;
+    server.refresh("offline").await;
+    let (_, health) = get(&server, "/api/printers/offline/health").await;
+    assert_eq!(health["health"], "offline");
+    assert_eq!(health["consecutive_failures"], 2);
+    assert_eq!(health["last_error"]["kind"], "PRINTER_UNREACHABLE");
+
+    server.mock.respond("M115", "CMD M115 Received.\nsomething unexpected\nok\n");
+    get(&server, "/api/printers/main/info").await;
+    let (_, printers) = get(&server, "/api/printers").await;
+    let health = |id: &str| printers.as_array().unwrap().iter().find(|printer| printer["name"] == id).unwrap()["health"].clone();
+    assert_eq!(health("main"), "degraded");
+    assert_eq!(health("offline"), "offline");
+
+    let (status, _) = get(&server, "/api/printers/missing/health").await;
+    assert_eq!(status, Status::NotFound);
+}
+
+#[tokio::test]
+async fn notification_state_tells_if_the_file_was_notified() {
+    let path = std::env::temp_dir().join(format!("flashforge-notification-state-{}.json", std::process::id()));
+    // Saved before the time was kept
+    std::fs::write(&path, r#"{"notification_sent": {"main": "benchy.gx"}, "error_notified": {"offline": "ERROR"}}"#).unwrap();
+    let server = TestServer::start(&format!("[state]\npath = {:?}", path)).await;
+    server.mock.respond("M119", &fixture("M119_printing"));
+    server.refresh("main").await;
+
+    let (status, state) = get(&server, "/api/printers/main/notifications/state").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(state["completed_file"], "benchy.gx");
+    assert_eq!(state["completed_sent_at"], Value::Null);
+    assert_eq!(state["current_file"], "benchy.gx");
+    assert_eq!(state["already_notified"], true);
+    assert_eq!(state["error_notified"], Value::Null);
+
+    let (_, state) = get(&server, "/api/printers/offline/notifications/state").await;
+    assert_eq!(state["completed_file"], Value::Null);
+    assert_eq!(state["already_notified"], false);
+    assert_eq!(state["error_notified"], "ERROR");
+    assert_eq!(get(&server, "/api/printers/missing/notifications/state").await.0, Status::NotFound);
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn console_returns_the_last_captured_lines() {
+    let server = TestServer::start("").await;
+    let (status, error) = get(&server, "/api/printers/main/console").await;
+    assert_eq!((status, error["error"].as_str()), (Status::NotFound, Some("CONSOLE_DISABLED")));
+
+    let manager = server.client.rocket().state::<PrinterManager>().unwrap();
+    manager.lock().await.get_printer("main").unwrap().console().set_capture(100);
+    server.mock.respond("M119", &format!("Heating done\r\nLevel ok\r\n{}", fixture("M119")));
+    server.refresh("main").await;
+    let (status, lines) = get(&server, "/api/printers/main/console?lines=1").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(lines.as_array().unwrap().len(), 1);
+    assert_eq!(lines[0]["line"], "Level ok");
+    let (_, lines) = get(&server, "/api/printers/main/console").await;
+    assert_eq!(lines[0]["line"], "Heating done");
+    // The lines were not taken for the status
+    let (_, status) = get(&server, "/api/printers/main/status").await;
+    assert_eq!(status["machine_status"], "READY");
+}
+
+#[tokio::test]
+async fn filament_stats_need_the_filament_section() {
+    let server = TestServer::start("").await;
+    let (status, error) = get(&server, "/api/stats/filament").await;
+    assert_eq!((status, error["error"].as_str()), (Status::NotFound, Some("FILAMENT_DISABLED")));
+
+    let server = TestServer::start("[filament]\ngrams_per_megabyte = 12.5").await;
+    let (status, stats) = get(&server, "/api/stats/filament?since=7d").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!((stats["total_grams"].as_f64(), stats["printers"].as_array().map(Vec::len)), (Some(0.0), Some(0)));
+    let (status, error) = get(&server, "/api/stats/filament?since=last%20week").await;
+    assert_eq!((status, error["error"].as_str()), (Status::BadRequest, Some("INVALID_SINCE")));
+}
+
+#[tokio::test]
+async fn farm_stats_count_the_printers() {
+    let server = TestServer::start("").await;
+    server.mock.respond("M119", &fixture("M119_printing"));
+    server.refresh("main").await;
+    let (status, stats) = get(&server, "/api/stats").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(stats["printers"], serde_json::json!({"total": 2, "online": 1, "printing": 1}));
+    assert_eq!(stats["jobs"]["completed_this_week"], 0);
+    let printers: Vec<&str> = stats["print_hours_this_week"].as_array().unwrap().iter().map(|printer| printer["printer"].as_str().unwrap()).collect();
+    assert_eq!(printers, ["main", "offline"]);
+    assert!(stats["today_since"].as_str().unwrap().ends_with("T00:00:00Z"));
+}
+
+#[tokio::test]
+async fn readiness_reports_smtp_without_waiting_for_it() {
+    let server = TestServer::start("").await;
+    let (status, ready) = get(&server, "/readyz").await;
+    assert_eq!((status, ready["ready"].as_bool(), ready["smtp"]["state"].as_str()), (Status::Ok, Some(true), Some("not_configured")));
+
+    #[cfg(feature = "smtp")]
+    {
+        crate::test_support::install_crypto_provider();
+        let port = unused_port().await;
+        let server = TestServer::start(&format!("[smtp]\nhost = \"127.0.0.1\"\nport = {}\nencryption = \"none\"\nuser = \"farm@example.com\"\npassword = \"\"", port)).await;
+        let (_, ready) = get(&server, "/readyz").await;
+        assert_eq!(ready["smtp"]["state"], "connecting");
+        // The self-test connects, and the failure is retried in the background
+        let (_, report) = get(&server, "/api/admin/selftest").await;
+        assert_eq!(report["ok"], false);
+        let (status, ready) = get(&server, "/readyz").await;
+        assert_eq!((status, ready["ready"].as_bool()), (Status::Ok, Some(true)));
+        assert_eq!((ready["smtp"]["state"].as_str(), ready["smtp"]["failed_attempts"].as_u64()), (Some("retrying"), Some(1)));
+    }
+}
+
+#[tokio::test]
+async fn malformed_responses_fail_only_that_request() {
+    let server = TestServer::start("").await;
+    server.mock.respond("M115", "CMD M115 Received.\nsomething unexpected\nok\n");
+    let (status, error) = get(&server, "/api/printers/main/info").await;
+    assert_eq!(status, Status::BadGateway);
+    assert_eq!(error["error"], "PRINTER_INVALID_RESPONSE");
+
+    server.mock.respond("M27", "CMD M27 Received.\nok\n");
+    let (status, _) = get(&server, "/api/printers/main/progress").await;
+    assert_eq!(status, Status::BadGateway);
+
+    let (status, _) = get(&server, "/api/printers/main/status").await;
+    assert_eq!(status, Status::Ok);
+}
+
+#[cfg(feature = "camera")]
+#[tokio::test]
+async fn snapshots_are_transformed() {
+    let server = TestServer::start("").await;
+    let response = server.client.get("/api/printers/main/snapshot?rotate=90&width=4").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let image = decode(&response.into_bytes().await.unwrap());
+    assert_eq!((image.width(), image.height()), (4, 8));
+    assert!(is_red(image.get_pixel(2, 1)) && !is_red(image.get_pixel(2, 6)));
+
+    let (status, error) = get(&server, "/api/printers/main/snapshot?rotate=45").await;
+    assert_eq!((status, error["error"].as_str()), (Status::BadRequest, Some("INVALID_TRANSFORM")));
+    let (status, error) = get(&server, "/api/printers/main/snapshot?flip=diagonal").await;
+    assert_eq!((status, error["error"].as_str()), (Status::BadRequest, Some("INVALID_TRANSFORM")));
+    let (status, error) = get(&server, "/api/printers/main/snapshot?width=0").await;
+    assert_eq!((status, error["error"].as_str()), (Status::BadRequest, Some("INVALID_TRANSFORM")));
+}
+
+#[cfg(feature = "camera")]
+#[tokio::test]
+async fn configured_transform_is_applied() {
+    let server = TestServer::start("").await;
+    let manager = server.client.rocket().state::<PrinterManager>().unwrap();
+    let printer = manager.lock().await.get_printer("main").unwrap();
+    printer.camera().set_transform(crate::camera::Transform::new(0, Some(crate::config::Flip::Horizontal)).unwrap());
+    let response = server.client.get("/api/printers/main/snapshot").dispatch().await;
+    let image = decode(&response.into_bytes().await.unwrap());
+    assert!(!is_red(image.get_pixel(2, 4)));
+    // Query parameters turn it further
+    let response = server.client.get("/api/printers/main/snapshot?rotate=180").dispatch().await;
+    let image = decode(&response.into_bytes().await.unwrap());
+    assert!(is_red(image.get_pixel(2, 4)));
+    // The stored frame notifications attach is the configured one
+    assert!(!is_red(decode(&printer.camera().last_image().unwrap().0).get_pixel(2, 4)));
+}
+
+#[cfg(feature = "camera")]
+#[tokio::test]
+async fn kept_frames_are_served_as_a_gif() {
+    let server = TestServer::start("").await;
+    let (status, error) = get(&server, "/api/printers/main/snapshot.gif").await;
+    assert_eq!((status, error["error"].as_str()), (Status::ServiceUnavailable, Some("CAMERA_UNAVAILABLE")));
+
+    // The snapshot's frame is kept
+    server.client.get("/api/printers/main/snapshot").dispatch().await;
+    let response = server.client.get("/api/printers/main/snapshot.gif").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::GIF));
+    assert!(response.into_bytes().await.unwrap().starts_with(b"GIF89a"));
+}
+
+#[cfg(feature = "camera")]
+#[tokio::test]
+async fn camera_routes_serve_the_stream() {
+    let server = TestServer::start("").await;
+    let response = server.client.get("/api/printers/main/snapshot").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JPEG));
+    let etag = response.headers().get_one("ETag").unwrap().to_string();
+    assert!(response.headers().get_one("Last-Modified").unwrap().ends_with(" GMT"));
+    assert_eq!(response.into_bytes().await.unwrap(), CAMERA_IMAGE);
+    // Every frame is the same image
+    let response = server.client.get("/api/printers/main/snapshot").header(Header::new("If-None-Match", etag)).dispatch().await;
+    assert_eq!(response.status(), Status::NotModified);
+
+    // The stream never ends, only the headers are checked
+    let response = server.client.get("/api/printers/main/camera").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type().unwrap().to_string(), "multipart/x-mixed-replace; boundary=boundarydonotcross");
+}
+
+#[cfg(feature = "camera")]
+#[tokio::test]
+async fn failed_snapshots_are_told_apart() {
+    let server = TestServer::start("").await;
+    let response = server.client.get("/api/printers/offline/snapshot").dispatch().await;
+    assert_eq!(response.status(), Status::BadGateway);
+    assert_eq!(response.content_type(), Some(ContentType::PNG));
+    assert_eq!(response.headers().get_one("X-Snapshot-Placeholder"), Some("true"));
+
+    let response = server.client.get("/api/printers/offline/snapshot").header(Header::new("Accept", "application/json")).dispatch().await;
+    assert_eq!(response.status(), Status::BadGateway);
+    assert_eq!(json(response).await["error"], "CAMERA_UNAVAILABLE");
+    let (status, error) = get(&server, "/api/printers/offline/snapshot?on_error=json").await;
+    assert_eq!(status, Status::BadGateway);
+    assert_eq!(error["error"], "CAMERA_UNAVAILABLE");
+
+    let placeholder = std::env::temp_dir().join(format!("flashforge-placeholder-{}.jpg", std::process::id()));
+    std::fs::write(&placeholder, CAMERA_IMAGE).unwrap();
+    let server = TestServer::start(&format!("[camera]\nplaceholder_path = {:?}", placeholder)).await;
+    let response = server.client.get("/api/printers/offline/snapshot").dispatch().await;
+    assert_eq!(response.content_type(), Some(ContentType::JPEG));
+    assert_eq!(response.into_bytes().await.unwrap(), CAMERA_IMAGE);
+    std::fs::remove_file(&placeholder).ok();
+}
+
+#[cfg(not(feature = "camera"))]
+#[tokio::test]
+async fn camera_routes_are_not_mounted_without_the_feature() {
+    let server = TestServer::start("").await;
+    for uri in ["/api/printers/main/snapshot", "/api/printers/main/camera"] {
+        let (status, _) = get(&server, uri).await;
+        assert_eq!(status, Status::NotFound, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn history_requires_config() {
+    let server = TestServer::start("").await;
+    let (status, error) = get(&server, "/api/printers/main/history?metric=nozzle_temp").await;
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error["error"], "HISTORY_DISABLED");
+
+    let (status, error) = get(&server, "/api/printers/main/history?metric=speed").await;
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(error["error"], "UNKNOWN_METRIC");
+
+    // Too many days to fit in seconds
+    let (status, error) = get(&server, "/api/printers/main/history?metric=nozzle_temp&resolution=300000000000000000d").await;
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(error["error"], "INVALID_RESOLUTION");
+}
+
+#[tokio::test]
+async fn write_access_requires_password() {
+    let server = TestServer::start(r#"
+        [auth]
+        password_for_write = true
+        password_for_read = false
+        password = "secret"
+    "#).await;
+    let (status, _) = get(&server, "/api/printers/main/status").await;
+    assert_eq!(status, Status::Ok);
+
+    let response = server.client.post("/api/printers/main/set-temperature/0/200").dispatch().await;
+    assert_eq!(response.status(), Status::Unauthorized);
+    let response = server.client.post("/api/printers/main/set-temperature/0/200")
+        .header(Header::new("x-secret", "secret"))
+        .dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[cfg(feature = "camera")]
+#[tokio::test]
+async fn expensive_routes_are_rate_limited() {
+    let server = TestServer::start(r#"
+        [http.rate_limit]
+        expensive_requests_per_second = 0.1
+        expensive_burst = 2
+    "#).await;
+    let client: std::net::SocketAddr = "192.168.1.2:40000".parse().unwrap();
+    for _ in 0..2 {
+        let response = server.client.get("/api/printers/main/snapshot").remote(client).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+    let response = server.client.get("/api/printers/main/snapshot").remote(client).dispatch().await;
+    assert_eq!(response.status(), Status::TooManyRequests);
+    assert_eq!(response.headers().get_one("Retry-After"), Some("10"));
+    assert_eq!(json(response).await["error"], "RATE_LIMITED");
+
+    let response = server.client.get("/api/printers/main/status").remote(client).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = server.client.get("/api/printers/main/snapshot").remote("192.168.1.3:40000".parse().unwrap()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[tokio::test]
+async fn notification_routes() {
+    let server = TestServer::start(r#"
+        [webhook]
+        retries = 0
+        [notifications.on_done]
+        webhooks = ["http://127.0.0.1:9/hook"]
+    "#).await;
+    let (status, deliveries) = get(&server, "/api/notifications/deliveries").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(deliveries, serde_json::json!([]));
+
+    let response = server.client.post("/api/notifications/test")
+        .header(ContentType::JSON)
+        .body(r#"{"printer": "main", "type": "print_complete", "dry_run": true}"#)
+        .dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let results = json(response).await;
+    assert_eq!(results[0]["kind"], "webhook");
+    assert_eq!(results[0]["status"], "dry_run");
+
+    let response = server.client.post("/api/notifications/test")
+        .header(ContentType::JSON)
+        .body(r#"{"printer": "main", "type": "exploded"}"#)
+        .dispatch().await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(json(response).await["details"][0]["field"], "type");
+
+    // Dry runs are not recorded
+    let (_, history) = get(&server, "/api/notifications/history").await;
+    assert_eq!(history, serde_json::json!([]));
+    server.client.post("/api/notifications/test")
+        .header(ContentType::JSON)
+        .body(r#"{"printer": "main", "type": "print_complete"}"#)
+        .dispatch().await;
+    let (status, history) = get(&server, "/api/notifications/history?printer=main&limit=10").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!((history[0]["printer"].as_str(), history[0]["type"].as_str()), (Some("main"), Some("print_complete")));
+    assert_eq!(history[0]["results"][0]["destination"], "http://127.0.0.1:9/...");
+    assert_eq!(history[0]["results"][0]["status"], "failed");
+    let (_, history) = get(&server, "/api/notifications/history?printer=offline").await;
+    assert_eq!(history, serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn destinations_can_be_turned_off() {
+    let (url, bodies) = mock_webhook().await;
+    let server = TestServer::start(&format!(r#"
+        [webhook]
+        retries = 0
+        [notifications.on_done]
+        webhooks = ["http://127.0.0.1:9/hook", {{ url = "{url}" }}]
+        [notifications.digest]
+        webhooks = [{{ url = "{url}", enabled = false }}]
+    "#)).await;
+    let (status, destinations) = get(&server, "/api/notifications/destinations").await;
+    assert_eq!(status, Status::Ok);
+    let listed: Vec<_> = destinations.as_array().unwrap().iter()
+        .map(|destination| (destination["index"].as_u64().unwrap(), destination["notification"].as_str().unwrap(), destination["enabled"].as_bool().unwrap()))
+        .collect();
+    assert_eq!(listed, [(0, "on_done", true), (1, "on_done", true), (2, "digest", false)]);
+    assert_eq!(destinations[0]["kind"], "webhook");
+    assert_eq!(destinations[0]["last_delivery"], Value::Null);
+
+    let response = server.client.put("/api/notifications/destinations/0/enabled")
+        .header(ContentType::JSON)
+        .body(r#"{"enabled": false}"#)
+        .dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let destination = json(response).await;
+    assert_eq!((destination["target"].as_str(), destination["enabled"].as_bool()), (Some("http://127.0.0.1:9/..."), Some(false)));
+
+    let response = server.client.post("/api/notifications/test")
+        .header(ContentType::JSON)
+        .body(r#"{"printer": "main", "type": "print_complete"}"#)
+        .dispatch().await;
+    let results = json(response).await;
+    assert_eq!(results.as_array().unwrap().len(), 1);
+    assert_eq!((results[0]["destination"].as_str(), results[0]["status"].as_str()), (Some(url.as_str()), Some("sent")));
+    assert_eq!(bodies.lock().unwrap().len(), 1);
+    let (_, destinations) = get(&server, "/api/notifications/destinations").await;
+    assert_eq!(destinations[1]["last_delivery"]["status"], 204);
+    assert_eq!(destinations[1]["last_delivery"]["url"], redact_url(&url));
+    assert!(!destinations[1]["target"].as_str().unwrap().ends_with("/hook"));
+    let (_, deliveries) = get(&server, "/api/notifications/deliveries").await;
+    assert_eq!(deliveries[0]["url"], redact_url(&url));
+    assert_eq!(destinations[0]["last_delivery"], Value::Null);
+
+    let response = server.client.put("/api/notifications/destinations/3/enabled")
+        .header(ContentType::JSON)
+        .body(r#"{"enabled": true}"#)
+        .dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(json(response).await["error"], "UNKNOWN_DESTINATION");
+}
+
+#[tokio::test]
+async fn discovery_lists_nothing_without_printers() {
+    let server = TestServer::start(r#"
+        [discovery]
+        broadcast_addresses = ["127.0.0.1"]
+        timeout_secs = 1
+    "#).await;
+    let (status, discovered) = get(&server, "/api/discover").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(discovered, serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn dashboard_pages() {
+    let server = TestServer::start("").await;
+    for uri in ["/", "/printers/main", "/ui/dashboard.js", "/ui/dashboard.css"] {
+        let response = server.client.get(uri).dispatch().await;
+        assert_eq!(response.status(), Status::Ok, "{}", uri);
+    }
+    let response = server.client.get("/printers/missing").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[tokio::test]
+async fn moonraker_routes_only_when_enabled() {
+    let server = TestServer::start("").await;
+    let response = server.client.get("/server/info").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let server = TestServer::start(r#"
+        [moonraker]
+        enabled = true
+        printer = "main"
+    "#).await;
+    server.refresh("main").await;
+    let (status, info) = get(&server, "/server/info").await;
+    assert_eq!(status, Status::Ok);
+    assert!(info["result"].is_object());
+    let (status, objects) = get(&server, "/printer/objects/list").await;
+    assert_eq!(status, Status::Ok);
+    assert!(objects["result"]["objects"].as_array().unwrap().iter().any(|object| object == "extruder"));
+    let (status, query) = get(&server, "/printer/objects/query?extruder=temperature").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(query["result"]["status"]["extruder"]["temperature"], 210.0);
+}
+
+#[tokio::test]
+async fn debug_routes_return_raw_responses() {
+    let server = TestServer::start("").await;
+    let response = server.client.get("/api/printers/main/debug/raw?cmd=info").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let dir = std::env::temp_dir().join(format!("flashforge-recordings-{}", std::process::id()));
+    let server = TestServer::start(&format!(r#"
+        [debug]
+        enabled = true
+        record_dir = {:?}
+    "#, dir)).await;
+    let (status, raw) = get(&server, "/api/printers/main/debug/raw?cmd=info").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(raw["raw"], fixture("M115"));
+    assert_eq!(raw["parsed"]["info"]["sn"], "SNADVA9501234");
+    assert_eq!(raw["warnings"], serde_json::json!([]));
+
+    server.mock.respond("M115", "CMD M115 Received.\nsomething unexpected\nok\n");
+    let (status, raw) = get(&server, "/api/printers/main/debug/raw?cmd=info").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(raw["parsed"], Value::Null);
+    assert!(raw["error"].is_string());
+    assert_eq!(raw["warnings"], serde_json::json!(["Invalid line: something unexpected"]));
+
+    let (status, _) = get(&server, "/api/printers/main/debug/raw?cmd=reboot").await;
+    assert_eq!(status, Status::BadRequest);
+
+    let response = server.client.post("/api/printers/main/debug/record").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let recording = json(response).await;
+    assert_eq!(recording["responses"].as_array().unwrap().len(), 5);
+    let contents = std::fs::read_to_string(recording["path"].as_str().unwrap()).unwrap();
+    assert!(contents.contains("## ~M119 (status)\nCMD M119 Received."));
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn metrics_count_requests_by_route() {
+    let server = TestServer::start("").await;
+    server.client.get("/api/printers/main/status").dispatch().await;
+    server.client.get("/api/printers/missing/status").dispatch().await;
+    #[cfg(feature = "camera")]
+    server.client.get("/api/printers/main/snapshot").dispatch().await;
+
+    let response = server.client.get("/metrics").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let text = response.into_string().await.unwrap();
+    assert!(text.contains("http_requests_total{route=\"/api/printers/<printer_id>/status\",kind=\"api\",status=\"200\"} 1\n"));
+    assert!(text.contains("http_requests_total{route=\"/api/printers/<printer_id>/status\",kind=\"api\",status=\"404\"} 1\n"));
+    #[cfg(feature = "camera")]
+    assert!(text.contains("http_request_duration_seconds_count{route=\"/api/printers/<printer_id>/snapshot\",kind=\"camera\"} 1\n"));
+    // Taking control and the status request
+    assert!(text.contains("printer_exchange_duration_seconds_count{printer=\"main\",command=\"M601\"} 1\n"));
+    assert!(text.contains("printer_exchange_duration_seconds_count{printer=\"main\",command=\"M119\"} 1\n"));
+}
+
+#[tokio::test]
+async fn version_matches_the_build() {
+    let server = TestServer::start("").await;
+    let (status, version) = get(&server, "/api/version").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+    assert!(version["rustc_version"].as_str().unwrap().starts_with("rustc "));
+    assert!(version["features"].is_array());
+}
+
+#[tokio::test]
+async fn write_requests_are_audited() {
+    let path = std::env::temp_dir().join(format!("flashforge-audit-{}.log", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let server = TestServer::start(&format!(r#"
+        [auth]
+        password_for_write = true
+        password_for_read = false
+        password = "secret"
+        tokens = [{{ name = "shop", token = "shop-token", scope = "write" }}, {{ name = "owner", token = "owner-token", scope = "admin" }}]
+        [audit]
+        path = {:?}
+    "#, path)).await;
+    let response = server.client.put("/api/printers/main/maintenance")
+        .header(Header::new("Authorization", "Bearer shop-token"))
+        .body(r#"{"enabled": true}"#)
+        .dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = server.client.post("/api/printers/main/set-temperature/0/200").dispatch().await;
+    assert_eq!(response.status(), Status::Unauthorized);
+    // Reads are not audited
+    let (status, _) = get(&server, "/api/printers/main/status").await;
+    assert_eq!(status, Status::Ok);
+
+    let response = server.client.get("/api/admin/audit").header(Header::new("Authorization", "Bearer shop-token")).dispatch().await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = server.client.get("/api/admin/audit?limit=10").header(Header::new("Authorization", "Bearer owner-token")).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let entries = json(response).await;
+    assert_eq!(entries.as_array().unwrap().len(), 2);
+    assert_eq!(entries[0]["uri"], "/api/printers/main/set-temperature/0/200");
+    assert_eq!((entries[0]["auth"].as_str(), entries[0]["status"].as_u64()), (Some("none"), Some(401)));
+    assert_eq!(entries[1]["route"], "/api/printers/<printer_id>/maintenance");
+    assert_eq!(entries[1]["printer_id"], "main");
+    assert_eq!((entries[1]["auth"].as_str(), entries[1]["token"].as_str()), (Some("token"), Some("shop")));
+    assert_eq!(entries[1]["payload"]["enabled"], true);
+    assert_eq!(entries[1]["status"], 200);
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn requests_are_access_logged() {
+    let path = std::env::temp_dir().join(format!("flashforge-access-routes-{}.log", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let server = TestServer::start(&format!(r#"
+        [auth]
+        password_for_write = true
+        password_for_read = true
+        password = "secret"
+        tokens = [{{ name = "shop", token = "shop-token", scope = "read" }}]
+        [http.access_log]
+        path = {:?}
+        format = "json"
+        trusted_proxies = ["127.0.0.1"]
+    "#, path)).await;
+    let response = server.client.get("/api/printers/main/status")
+        .remote("127.0.0.1:40000".parse().unwrap())
+        .header(Header::new("X-Forwarded-For", "203.0.113.7"))
+        .header(Header::new("Authorization", "Bearer shop-token"))
+        .dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.into_bytes().await.unwrap();
+    // Not from a trusted proxy, so the header is ignored
+    let response = server.client.post("/api/printers/main/set-temperature/0/200")
+        .remote("198.51.100.1:40000".parse().unwrap())
+        .header(Header::new("X-Forwarded-For", "203.0.113.7"))
+        .dispatch().await;
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    // Written in the background
+    let mut lines: Vec<Value> = Vec::new();
+    for _ in 0..100 {
+        lines = std::fs::read_to_string(&path).unwrap_or_default().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        if lines.len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(lines.len(), 2);
+    assert_eq!((lines[0]["client_ip"].as_str(), lines[0]["principal"].as_str()), (Some("203.0.113.7"), Some("token:shop")));
+    assert_eq!((lines[0]["method"].as_str(), lines[0]["path"].as_str()), (Some("GET"), Some("/api/printers/main/status")));
+    assert_eq!((lines[0]["status"].as_u64(), lines[0]["bytes"].as_u64()), (Some(200), Some(body.len() as u64)));
+    assert_eq!(lines[0]["printer_id"], "main");
+    assert_eq!((lines[1]["client_ip"].as_str(), lines[1]["principal"].as_str()), (Some("198.51.100.1"), None));
+    assert_eq!(lines[1]["status"], 401);
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn macros_run_in_order_and_report_the_failed_step() {
+    let server = TestServer::start(r#"
+        [macros.preheat_pla]
+        printer_commands = ["M104 S200 T0", "M140 S60", "~M146 r255 g255 b255 F0"]
+    "#).await;
+    let (_, macros) = get(&server, "/api/macros").await;
+    assert_eq!(macros, serde_json::json!([{"name": "preheat_pla", "printer_commands": ["M104 S200 T0", "M140 S60", "~M146 r255 g255 b255 F0"]}]));
+
+    let response = server.client.post("/api/printers/main/macros/preheat_pla").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let run = json(response).await;
+    assert_eq!(run["steps"].as_array().unwrap().len(), 3);
+    assert_eq!(run["steps"][1], serde_json::json!({"command": "~M140 S60", "response": "CMD M140 Received.\r\nok"}));
+    let received = server.mock.received().concat();
+    let sent: Vec<&String> = received.iter().filter(|line| !line.starts_with("~M601")).collect();
+    assert_eq!(sent, ["~M104 S200 T0", "~M140 S60", "~M146 r255 g255 b255 F0"]);
+
+    let response = server.client.post("/api/printers/main/macros/preheat_petg").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(json(response).await["error"], "UNKNOWN_MACRO");
+    let response = server.client.post("/api/printers/offline/macros/preheat_pla").dispatch().await;
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+    let error = json(response).await;
+    assert_eq!(error["error"], "MACRO_STEP_FAILED");
+    assert!(error["message"].as_str().unwrap().starts_with("step 1 of preheat_pla (~M104 S200 T0) failed"), "{}", error["message"]);
+}
+
+#[tokio::test]
+async fn selftest_probes_printers_cameras_and_webhooks() {
+    let (url, bodies) = mock_webhook().await;
+    let server = TestServer::start(&format!(r#"
+        [notifications.on_done]
+        webhooks = ["{url}"]
+        [notifications.on_error]
+        webhooks = ["{url}", "http://127.0.0.1:9/hook"]
+    "#)).await;
+    fn probe<'a>(report: &'a Value, kind: &str, target: &str) -> &'a Value {
+        report["probes"].as_array().unwrap().iter()
+            .find(|probe| probe["kind"] == kind && probe["target"] == target)
+            .unwrap_or_else(|| panic!("no {} probe of {}", kind, target))
+    }
+
+    let (status, report) = get(&server, "/api/admin/selftest").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(report["ok"], false);
+    assert_eq!(probe(&report, "printer_api", "main")["ok"], true);
+    assert_eq!(probe(&report, "printer_api", "offline")["ok"], false);
+    assert!(probe(&report, "printer_api", "offline")["error"].is_string());
+    #[cfg(feature = "camera")]
+    assert_eq!((probe(&report, "camera", "main")["ok"].as_bool(), probe(&report, "camera", "offline")["ok"].as_bool()), (Some(true), Some(false)));
+    assert!(!report["probes"].as_array().unwrap().iter().any(|probe| probe["kind"] == "webhook"));
+    assert!(bodies.lock().unwrap().is_empty());
+
+    let (_, report) = get(&server, "/api/admin/selftest?include_webhooks=true").await;
+    assert_eq!(report["probes"].as_array().unwrap().iter().filter(|probe| probe["kind"] == "webhook").count(), 2);
+    assert_eq!(probe(&report, "webhook", &url)["ok"], true);
+    assert_eq!(probe(&report, "webhook", "http://127.0.0.1:9/hook")["ok"], false);
+    assert_eq!(*bodies.lock().unwrap(), [r#"{"event":"selftest"}"#]);
+}
+
+#[tokio::test]
+async fn printers_sharing_an_address_or_serial_are_reported() {
+    let server = TestServer::start("").await;
+    let manager = server.client.rocket().state::<PrinterManager>().unwrap();
+    let port = manager.lock().await.get_printer("main").unwrap().api_port();
+    let added = manager.lock().await.add_printer("again".to_string(), "127.0.0.1".to_string(), port, IDLE);
+    assert_eq!(added, Err(format!("127.0.0.1:{} is already used by printer main", port)));
+    manager.lock().await.add_printer("v6".to_string(), "fd00::50".to_string(), port, IDLE).unwrap();
+    let added = manager.lock().await.add_printer("v6-again".to_string(), "fd00:0::50".to_string(), port, IDLE);
+    assert_eq!(added, Err(format!("[fd00::50]:{} is already used by printer v6", port)));
+
+    // Same printer under another port, like a forwarded one
+    manager.lock().await.insert_printer(Printer::with_ports("copy".to_string(), "localhost".to_string(), port, 0, IDLE));
+    for id in ["main", "copy"] {
+        let printer = manager.lock().await.get_printer(id).unwrap();
+        printer.refresh().await.unwrap();
+    }
+    let (_, health) = get(&server, "/api/printers/main/health").await;
+    assert_eq!(health["same_serial_as"], serde_json::json!(["copy"]));
+    let (_, health) = get(&server, "/api/printers/offline/health").await;
+    assert_eq!(health["same_serial_as"], serde_json::json!([]));
+    let (_, printers) = get(&server, "/api/printers").await;
+    let main = printers.as_array().unwrap().iter().find(|printer| printer["name"] == "main").unwrap();
+    assert_eq!(main["sn"], "SNADVA9501234");
+}
@@ -0,0 +1,30 @@
+use crate::manager::PrinterManager;
+use rocket::http::ContentType;
+use rocket::response::content::RawHtml;
+use rocket::{get, State};
+
+// Embedded so the server stays a single binary, the pages only use the public API
+const INDEX_HTML: &str = include_str!("../../ui/index.html");
+const PRINTER_HTML: &str = include_str!("../../ui/printer.html");
+const DASHBOARD_JS: &str = include_str!("../../ui/dashboard.js");
+const DASHBOARD_CSS: &str = include_str!("../../ui/dashboard.css");
+
+#[get("/")]
+pub fn index() -> RawHtml<&'static str> {
+    RawHtml(INDEX_HTML)
+}
+
+#[get("/printers/<printer_id>")]
+pub async fn printer(manager: &State<PrinterManager>, printer_id: &str) -> Option<RawHtml<&'static str>> {
+    manager.lock().await.get_printer(printer_id).map(|_| RawHtml(PRINTER_HTML))
+}
+
+#[get("/ui/dashboard.js")]
+pub fn dashboard_js() -> (ContentType, &'static str) {
+    (ContentType::JavaScript, DASHBOARD_JS)
+}
+
+#[get("/ui/dashboard.css")]
+pub fn dashboard_css() -> (ContentType, &'static str) {
+    (ContentType::CSS, DASHBOARD_CSS)
+}
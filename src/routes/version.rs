@@ -0,0 +1,13 @@
+use crate::models::GenericError;
+use crate::util::{AccessType, AuthGuard};
+use crate::version::{self, VersionInfo};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::get;
+
+/// The version and build details, to include in bug reports
+#[get("/version")]
+pub async fn get_version(auth: AuthGuard) -> Result<Json<VersionInfo>, (Status, Json<GenericError>)> {
+    auth.check_auth(AccessType::Read)?;
+    Ok(Json(version::info()))
+}
@@ -0,0 +1,61 @@
+//! Checks that everything the config points at answers, for GET /api/admin/selftest
+use std::future::Future;
+use std::time::{Duration, Instant};
+use futures::future::{join_all, BoxFuture};
+use futures::FutureExt;
+use crate::config::ConfigManager;
+use crate::manager::PrinterContainer;
+use crate::models::{SelfTestKind, SelfTestProbe, SelfTestReport};
+use crate::notifications::Notifier;
+
+/// Each probe's own limit, so one dead printer or webhook doesn't hold up the report
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Runs every probe concurrently: a TCP connection to each printer's API port, a HEAD of each camera stream (or the
+/// connections already open to them, within printers.<id>.connections),
+/// a NOOP on the SMTP session and, with include_webhooks, an empty payload to each webhook
+pub async fn run(printers: &[PrinterContainer], config: &ConfigManager, notifier: &Notifier, include_webhooks: bool) -> SelfTestReport {
+    let mut probes: Vec<BoxFuture<SelfTestProbe>> = Vec::new();
+    for printer in printers {
+        let api = printer.clone();
+        probes.push(probe(SelfTestKind::PrinterApi, printer.name().to_string(), async move {
+            api.probe_api().await
+        }).boxed());
+        #[cfg(feature = "camera")]
+        if printer.capabilities().camera {
+            let camera = printer.clone();
+            probes.push(probe(SelfTestKind::Camera, printer.name().to_string(), async move {
+                camera.camera().probe().await
+            }).boxed());
+        }
+    }
+    if let Some(smtp) = config.smtp() {
+        let target = format!("{}:{}", smtp.host, smtp.port);
+        #[cfg(feature = "smtp")]
+        probes.push(probe(SelfTestKind::Smtp, target, config.noop_mailer()).boxed());
+        #[cfg(not(feature = "smtp"))]
+        probes.push(probe(SelfTestKind::Smtp, target, async {
+            Err("compiled without email support, rebuild with the smtp feature".to_string())
+        }).boxed());
+    }
+    if include_webhooks {
+        for webhook in config.webhooks() {
+            probes.push(probe(SelfTestKind::Webhook, webhook.url.clone(), notifier.probe_webhook(webhook)).boxed());
+        }
+    }
+    let probes = join_all(probes).await;
+    SelfTestReport { ok: probes.iter().all(|probe| probe.ok), probes }
+}
+
+async fn probe(kind: SelfTestKind, target: String, check: impl Future<Output = Result<(), String>>) -> SelfTestProbe {
+    let started = Instant::now();
+    let result = tokio::time::timeout(PROBE_TIMEOUT, check).await
+        .unwrap_or_else(|_| Err(format!("no answer within {} seconds", PROBE_TIMEOUT.as_secs())));
+    SelfTestProbe {
+        kind,
+        target,
+        ok: result.is_ok(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        error: result.err()
+    }
+}
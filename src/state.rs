@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::path::Path;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use crate::models::JobRecord;
+
+/// What the watch thread knows that would otherwise be lost on restart, saved as JSON to state.path
+/// whenever it changes and on shutdown
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct SavedState {
+    /// Printer id -> file a print done notification was sent for
+    #[serde(default)]
+    pub notification_sent: HashMap<String, NotifiedFile>,
+    /// Printer id -> machine status an error notification was sent for
+    #[serde(default)]
+    pub error_notified: HashMap<String, String>,
+    /// Printer id -> what was last seen of the printer
+    #[serde(default)]
+    pub printers: HashMap<String, SavedPrinter>,
+    /// Prints that ended in the last [crate::jobs::JOB_RETENTION], oldest first
+    #[serde(default)]
+    pub jobs: Vec<JobRecord>
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct SavedPrinter {
+    #[serde(default)]
+    pub current_file: Option<String>,
+    /// As reported by the printer, see [crate::models::MachineStatus::as_str]
+    #[serde(default)]
+    pub machine_status: Option<String>,
+    #[serde(default)]
+    pub job: Option<SavedJob>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SavedJob {
+    pub file: String,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub started_at: Option<OffsetDateTime>
+}
+
+/// The file a print done notification was sent for, and when. Saved before the time was kept as only the file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "SavedNotifiedFile")]
+pub struct NotifiedFile {
+    pub file: String,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub sent_at: Option<OffsetDateTime>
+}
+
+impl NotifiedFile {
+    pub fn now(file: String) -> Self {
+        Self { file, sent_at: Some(OffsetDateTime::now_utc()) }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedNotifiedFile {
+    File(String),
+    Notified {
+        file: String,
+        #[serde(default, with = "time::serde::rfc3339::option")]
+        sent_at: Option<OffsetDateTime>
+    }
+}
+
+impl From<SavedNotifiedFile> for NotifiedFile {
+    fn from(saved: SavedNotifiedFile) -> Self {
+        match saved {
+            SavedNotifiedFile::File(file) => Self { file, sent_at: None },
+            SavedNotifiedFile::Notified { file, sent_at } => Self { file, sent_at }
+        }
+    }
+}
+
+impl SavedState {
+    /// Reads the saved state, starting fresh if the file is missing or can't be parsed
+    pub fn load(path: &Path) -> Self {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                debug!("state: not loading {}: {}", path.display(), e);
+                return Self::default();
+            }
+        };
+        serde_json::from_str(&contents).unwrap_or_else(|e| {
+            debug!("state: ignoring {}, could not be parsed: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    /// Written next to the file and renamed over it, so a crash never leaves it half written
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, json).map_err(|e| e.to_string())?;
+        std::fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_or_corrupt_file_starts_fresh() {
+        let path = std::env::temp_dir().join(format!("flashforge-state-{}.json", std::process::id()));
+        assert_eq!(SavedState::load(&path), SavedState::default());
+        std::fs::write(&path, "{\"notification_sent\": {\"main\"").unwrap();
+        assert_eq!(SavedState::load(&path), SavedState::default());
+
+        let mut state = SavedState::default();
+        state.notification_sent.insert("main".to_string(), NotifiedFile::now("benchy.gx".to_string()));
+        state.printers.insert("main".to_string(), SavedPrinter {
+            current_file: Some("benchy.gx".to_string()),
+            machine_status: Some("BUILDING_FROM_SD".to_string()),
+            job: Some(SavedJob { file: "benchy.gx".to_string(), started_at: None })
+        });
+        state.jobs.push(JobRecord {
+            printer: "main".to_string(),
+            file: "cube.gx".to_string(),
+            result: crate::models::JobResult::Completed,
+            ended_at: time::macros::datetime!(2026-10-14 08:00 UTC),
+            elapsed_seconds: Some(3600)
+        });
+        state.save(&path).unwrap();
+        assert_eq!(SavedState::load(&path), state);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn notified_files_without_a_time_are_read() {
+        let state: SavedState = serde_json::from_str(r#"{"notification_sent": {"main": "benchy.gx", "second": {"file": "cube.gx", "sent_at": "2026-10-14T08:00:00Z"}}}"#).unwrap();
+        assert_eq!(state.notification_sent["main"], NotifiedFile { file: "benchy.gx".to_string(), sent_at: None });
+        assert_eq!(state.notification_sent["second"].sent_at, Some(time::macros::datetime!(2026-10-14 08:00 UTC)));
+    }
+}
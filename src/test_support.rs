@@ -0,0 +1,222 @@
+//! A fake FlashForge printer for tests, answering with the Adventurer 3 responses in flashforge-protocol/tests/fixtures
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+
+/// The image every frame of [mock_camera] contains, a 16x8 JPEG red on the left half and blue on the right
+#[cfg(feature = "camera")]
+pub const CAMERA_IMAGE: &[u8] = include_bytes!("../tests/fixtures/camera.jpg");
+
+#[cfg(feature = "camera")]
+pub fn decode(jpeg: &[u8]) -> image::RgbImage {
+    image::load_from_memory(jpeg).unwrap().to_rgb8()
+}
+
+/// Whether the pixel of [CAMERA_IMAGE] came from its red half, JPEG doesn't keep colors exact
+#[cfg(feature = "camera")]
+pub fn is_red(pixel: &image::Rgb<u8>) -> bool {
+    pixel[0] > 128 && pixel[2] < 128
+}
+
+/// Reads flashforge-protocol/tests/fixtures/<name>.txt, with the CRLF line endings of the printer
+pub fn fixture(name: &str) -> String {
+    let path = format!("{}/flashforge-protocol/tests/fixtures/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+    let contents = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("missing fixture {}: {}", path, e));
+    contents.replace("\r\n", "\n").replace('\n', "\r\n")
+}
+
+/// The G-code of a request line, "~M104 S200 T0" is M104
+fn gcode(line: &str) -> String {
+    line.trim_start_matches('~').split_whitespace().next().unwrap_or_default().to_string()
+}
+
+pub struct MockPrinter {
+    pub port: u16,
+    /// G-code -> response, replacing the fixture
+    responses: Arc<Mutex<HashMap<String, String>>>,
+    /// G-code -> how long to wait before answering it
+    delays: Arc<Mutex<HashMap<String, Duration>>>,
+    /// Lines received, one entry per connection
+    received: Arc<Mutex<Vec<Vec<String>>>>,
+    /// Text sent to every open connection between responses
+    pushed: broadcast::Sender<String>
+}
+
+impl MockPrinter {
+    pub async fn start() -> Self {
+        Self::start_with(usize::MAX).await
+    }
+
+    /// Closes each connection after answering `lines_per_connection` requests, like a printer that hangs up
+    pub async fn start_with(lines_per_connection: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let responses: Arc<Mutex<HashMap<String, String>>> = Default::default();
+        let received: Arc<Mutex<Vec<Vec<String>>>> = Default::default();
+        let delays: Arc<Mutex<HashMap<String, Duration>>> = Default::default();
+        let (overrides, connections, slow) = (responses.clone(), received.clone(), delays.clone());
+        let pushed: broadcast::Sender<String> = broadcast::channel(16).0;
+        let pushes = pushed.clone();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                let index = {
+                    let mut connections = connections.lock().unwrap();
+                    connections.push(Vec::new());
+                    connections.len() - 1
+                };
+                let (overrides, connections, slow) = (overrides.clone(), connections.clone(), slow.clone());
+                let mut pushed = pushes.subscribe();
+                tokio::spawn(async move {
+                    let (read, mut write) = conn.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    let mut answered = 0;
+                    while answered < lines_per_connection {
+                        let line = tokio::select! {
+                            line = lines.next_line() => line,
+                            Ok(text) = pushed.recv() => {
+                                if write.write_all(text.as_bytes()).await.is_err() {
+                                    break;
+                                }
+                                continue;
+                            }
+                        };
+                        let Ok(Some(line)) = line else { break };
+                        answered += 1;
+                        let gcode = gcode(&line);
+                        let response = overrides.lock().unwrap().get(&gcode).cloned()
+                            .unwrap_or_else(|| match gcode.as_str() {
+                                "M601" | "M602" | "M115" | "M105" | "M119" | "M27" | "M114" => fixture(&gcode),
+                                _ => format!("CMD {} Received.\r\nok\r\n", gcode)
+                            });
+                        connections.lock().unwrap()[index].push(line);
+                        let delay = slow.lock().unwrap().get(&gcode).copied();
+                        if let Some(delay) = delay {
+                            tokio::time::sleep(delay).await;
+                        }
+                        if write.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        MockPrinter { port, responses, delays, received, pushed }
+    }
+
+    /// Answers the G-code with the response from now on, line endings are converted to CRLF
+    pub fn respond(&self, gcode: &str, response: &str) {
+        let response = response.replace("\r\n", "\n").replace('\n', "\r\n");
+        self.responses.lock().unwrap().insert(gcode.to_string(), response);
+    }
+
+    /// Waits before answering the G-code from now on, like a printer busy homing
+    pub fn delay(&self, gcode: &str, delay: Duration) {
+        self.delays.lock().unwrap().insert(gcode.to_string(), delay);
+    }
+
+    /// Sends the text to the open connections without being asked, like the status lines printers send on their own.
+    /// Line endings are converted to CRLF
+    pub fn push(&self, text: &str) {
+        self.pushed.send(text.replace("\r\n", "\n").replace('\n', "\r\n")).ok();
+    }
+
+    pub fn received(&self) -> Vec<Vec<String>> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+/// A port nothing listens on, for printers that are offline
+pub async fn unused_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// The TLS provider main installs, needed before an SMTP client is built
+#[cfg(feature = "smtp")]
+pub fn install_crypto_provider() {
+    // Another test may have installed it already
+    tokio_rustls::rustls::crypto::ring::default_provider().install_default().ok();
+}
+
+/// An HTTP server answering every request with a 204, returns its url and the bodies it received
+pub async fn mock_webhook() -> (String, Arc<Mutex<Vec<String>>>) {
+    slow_mock_webhook(Duration::ZERO).await
+}
+
+/// Like [mock_webhook], answering each request after delay. Requests are answered concurrently
+pub async fn slow_mock_webhook(delay: Duration) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let bodies: Arc<Mutex<Vec<String>>> = Default::default();
+    let received = bodies.clone();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            let received = received.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // Reads the headers, then as much of the body as Content-Length says
+                let body_start = loop {
+                    let Ok(n) = conn.read(&mut buf).await else { break None };
+                    if n == 0 {
+                        break None;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                    if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                        break Some(end + 4);
+                    }
+                };
+                let Some(body_start) = body_start else { return };
+                let headers = String::from_utf8_lossy(&request[..body_start]).to_ascii_lowercase();
+                let length: usize = headers.lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .and_then(|length| length.trim().parse().ok())
+                    .unwrap_or(0);
+                while request.len() < body_start + length {
+                    match conn.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n])
+                    }
+                }
+                received.lock().unwrap().push(String::from_utf8_lossy(&request[body_start..]).into_owned());
+                tokio::time::sleep(delay).await;
+                conn.write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await.ok();
+            });
+        }
+    });
+    (url, bodies)
+}
+
+/// An MJPEG stream like the printer's camera, sending a frame of [CAMERA_IMAGE] every 100ms after `first_frame_delay`
+#[cfg(feature = "camera")]
+pub async fn mock_camera(first_frame_delay: Duration) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                assert!(conn.read(&mut buf).await.unwrap() > 0);
+                conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace;boundary=boundarydonotcross\r\n\r\n").await.unwrap();
+                tokio::time::sleep(first_frame_delay).await;
+                let frame = format!("--boundarydonotcross\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n", CAMERA_IMAGE.len());
+                // Runs until the client hangs up, like a real camera
+                loop {
+                    let sent = async {
+                        conn.write_all(frame.as_bytes()).await?;
+                        conn.write_all(CAMERA_IMAGE).await?;
+                        conn.write_all(b"\r\n").await
+                    }.await;
+                    if sent.is_err() {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            });
+        }
+    });
+    port
+}
@@ -0,0 +1,130 @@
+//! Thumbnails of the sliced files in gcode.dir, extracted once per file contents
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use log::debug;
+use ring::digest;
+use crate::config::GcodeConfig;
+use crate::gcode::{self, Thumbnail};
+
+/// Files remembered, both caches are cleared once they hold more
+const MAX_CACHED: usize = 64;
+
+/// Shared by the watch thread and the thumbnail route
+#[derive(Clone)]
+pub struct Thumbnails(Arc<ThumbnailsInner>);
+
+struct ThumbnailsInner {
+    dir: PathBuf,
+    /// Hash of each file read with its size and modification time, so unchanged files are not read again
+    hashes: Mutex<HashMap<PathBuf, (FileStamp, String)>>,
+    /// By the SHA-256 of the file, None when it has no thumbnail
+    images: Mutex<HashMap<String, Option<Thumbnail>>>
+}
+
+type FileStamp = (u64, Option<SystemTime>);
+
+impl Thumbnails {
+    pub fn new(config: &GcodeConfig) -> Self {
+        Self(Arc::new(ThumbnailsInner {
+            dir: config.dir.clone(),
+            hashes: Mutex::new(HashMap::new()),
+            images: Mutex::new(HashMap::new())
+        }))
+    }
+
+    /// The largest thumbnail of the sliced file with the name of the printer's file, or why there is none
+    pub async fn of(&self, file: &str) -> Result<Thumbnail, String> {
+        let inner = self.0.clone();
+        let owned = file.to_string();
+        let thumbnail = tokio::task::spawn_blocking(move || inner.lookup(&owned)).await.map_err(|e| e.to_string())?;
+        if let Err(e) = &thumbnail {
+            debug!("No thumbnail for {}: {}", file, e);
+        }
+        thumbnail
+    }
+}
+
+impl ThumbnailsInner {
+    fn lookup(&self, file: &str) -> Result<Thumbnail, String> {
+        let path = gcode::sliced_file(&self.dir, file).ok_or_else(|| format!("{} has no file name", file))?;
+        let metadata = std::fs::metadata(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let stamp = (metadata.len(), metadata.modified().ok());
+        let known = self.hashes.lock().unwrap().get(&path).filter(|(known, _)| *known == stamp).map(|(_, hash)| hash.clone());
+        let hash = match known {
+            Some(hash) => hash,
+            None => {
+                let hash = hash_file(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                insert_bounded(&self.hashes, path.clone(), (stamp, hash.clone()));
+                hash
+            }
+        };
+        let cached = self.images.lock().unwrap().get(&hash).cloned();
+        let thumbnail = match cached {
+            Some(thumbnail) => thumbnail,
+            None => {
+                let thumbnail = gcode::read_thumbnail(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                insert_bounded(&self.images, hash, thumbnail.clone());
+                thumbnail
+            }
+        };
+        thumbnail.ok_or_else(|| format!("{} has no PNG or JPEG thumbnail", path.display()))
+    }
+}
+
+fn insert_bounded<K: std::hash::Hash + Eq, V>(cache: &Mutex<HashMap<K, V>>, key: K, value: V) {
+    let mut cache = cache.lock().unwrap();
+    if cache.len() >= MAX_CACHED {
+        cache.clear();
+    }
+    cache.insert(key, value);
+}
+
+/// Hex SHA-256 of the file's contents
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => context.update(&buffer[..read])
+        }
+    }
+    let mut hash = String::new();
+    for byte in context.finish().as_ref() {
+        write!(hash, "{:02x}", byte).ok();
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+
+    #[tokio::test]
+    async fn thumbnails_are_cached_by_the_file_contents() {
+        let dir = std::env::temp_dir().join(format!("flashforge-thumbnails-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let encoded = base64::engine::general_purpose::STANDARD.encode(b"\x89PNG\r\n\x1a\nbenchy");
+        let sliced = format!("; thumbnail begin 300x300 {}\n; {}\n; thumbnail end\nG28\n", encoded.len(), encoded);
+        std::fs::write(dir.join("benchy.gcode"), &sliced).unwrap();
+        std::fs::write(dir.join("copy.gcode"), &sliced).unwrap();
+        std::fs::write(dir.join("cube.gcode"), "G28\n").unwrap();
+        let thumbnails = Thumbnails::new(&GcodeConfig { dir: dir.clone() });
+
+        let thumbnail = thumbnails.of("0:/user/benchy.gcode").await.unwrap();
+        assert_eq!(thumbnail.image.as_ref(), b"\x89PNG\r\n\x1a\nbenchy");
+        assert_eq!(thumbnails.of("copy.gcode").await, Ok(thumbnail));
+        // The same contents are only extracted once
+        assert_eq!(thumbnails.0.images.lock().unwrap().len(), 1);
+        assert!(thumbnails.of("cube.gcode").await.unwrap_err().contains("has no PNG or JPEG thumbnail"));
+        assert!(thumbnails.of("missing.gcode").await.is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,283 @@
+//! Serving on a unix domain socket, for a reverse proxy on the same host. Rocket 0.5 only listens on TCP itself, so
+//! with [http] unix_socket set hyper accepts the connections, on the socket and on the port when it is also set, and
+//! hands each request to Rocket as a local request. Responses are read as they are produced, so streams and SSE work
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use log::{debug, error, info, warn};
+use rocket::http::{Header, Method};
+use rocket::local::asynchronous::Client;
+use rocket::{Build, Rocket};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::oneshot;
+use crate::config::HttpConfig;
+
+/// Bytes of the response read at once
+const CHUNK_SIZE: usize = 8192;
+
+#[derive(Debug)]
+pub struct UnixSocket {
+    path: PathBuf,
+    listener: UnixListener
+}
+
+impl UnixSocket {
+    /// Creates the socket file with the configured permissions, replacing one left behind by an earlier run
+    pub fn bind(path: &Path, config: &HttpConfig) -> Result<Self, String> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+                .map_err(|e| format!("could not remove the old socket {}: {}", path.display(), e))?,
+            Ok(_) => return Err(format!("{} exists and is not a socket", path.display())),
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(path).map_err(|e| format!("could not bind {}: {}", path.display(), e))?;
+        let mode = config.unix_socket_mode().unwrap_or(0o660);
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("could not set the permissions of {}: {}", path.display(), e))?;
+        if config.unix_socket_uid.is_some() || config.unix_socket_gid.is_some() {
+            std::os::unix::fs::chown(path, config.unix_socket_uid, config.unix_socket_gid)
+                .map_err(|e| format!("could not change the owner of {}: {}", path.display(), e))?;
+        }
+        Ok(Self { path: path.to_path_buf(), listener })
+    }
+
+    /// Serves the socket, and Rocket's address and port when serves_tcp, until Ctrl-C, SIGTERM or
+    /// [rocket::Shutdown::notify]. The socket file is removed before returning, connections still open are left
+    /// to be dropped with the runtime
+    pub async fn serve(self, rocket: Rocket<Build>, serves_tcp: bool) {
+        let rocket = match rocket.ignite().await {
+            Ok(rocket) => rocket,
+            Err(e) => {
+                error!("Could not start the server: {}", e);
+                self.remove();
+                std::process::exit(1);
+            }
+        };
+        let tcp = if serves_tcp {
+            let address = SocketAddr::new(rocket.config().address, rocket.config().port);
+            match TcpListener::bind(address).await {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    error!("Could not listen on {}: {}", address, e);
+                    self.remove();
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            None
+        };
+        // Runs the liftoff fairings
+        let client = match Client::untracked(rocket).await {
+            Ok(client) => Arc::new(client),
+            Err(e) => {
+                error!("Could not start the server: {}", e);
+                self.remove();
+                std::process::exit(1);
+            }
+        };
+        info!("Server ready and listening on unix:{}", self.path.display());
+        self.accept(client, tcp).await;
+    }
+
+    async fn accept(self, client: Arc<Client>, tcp: Option<TcpListener>) {
+        let shutdown = client.rocket().shutdown();
+        tokio::spawn(notify_on_signal(shutdown.clone()));
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_connection(client.clone(), stream, None));
+                    },
+                    Err(e) => warn!("Could not accept a connection on the unix socket: {}", e)
+                },
+                accepted = async { tcp.as_ref().unwrap().accept().await }, if tcp.is_some() => match accepted {
+                    Ok((stream, remote)) => {
+                        tokio::spawn(serve_connection(client.clone(), stream, Some(remote)));
+                    },
+                    Err(e) => warn!("Could not accept a connection: {}", e)
+                }
+            }
+        }
+        info!("Shutting down");
+        self.remove();
+    }
+
+    fn remove(&self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Could not remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Shuts Rocket down on Ctrl-C or SIGTERM, which Rocket only listens for when it serves itself
+async fn notify_on_signal(shutdown: rocket::Shutdown) {
+    let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("Could not listen for SIGTERM: {}", e);
+            tokio::signal::ctrl_c().await.ok();
+            shutdown.notify();
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {}
+    }
+    shutdown.notify();
+}
+
+async fn serve_connection<S>(client: Arc<Client>, stream: S, remote: Option<SocketAddr>)
+where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    let service = hyper::service::service_fn(move |request| dispatch(client.clone(), request, remote));
+    if let Err(e) = hyper::server::conn::Http::new().http1_only(true).serve_connection(stream, service).await {
+        debug!("Connection closed: {}", e);
+    }
+}
+
+/// The status and headers of the response, sent before its body is read
+type ResponseHead = (u16, Vec<(String, String)>);
+
+/// Hands the request to Rocket, answering with its status and headers once the route returned and streaming its
+/// body as it is read. Requests over the json limit are cut one byte after it, so Rocket answers 413
+async fn dispatch(client: Arc<Client>, request: hyper::Request<hyper::Body>, remote: Option<SocketAddr>) -> Result<hyper::Response<hyper::Body>, Infallible> {
+    let Ok(method) = request.method().as_str().parse::<Method>() else {
+        let mut response = hyper::Response::new(hyper::Body::empty());
+        *response.status_mut() = hyper::StatusCode::NOT_IMPLEMENTED;
+        return Ok(response);
+    };
+    let (head_tx, head_rx) = oneshot::channel::<ResponseHead>();
+    let (mut body_tx, body) = hyper::Body::channel();
+    tokio::spawn(async move {
+        let limit = client.rocket().config().limits.get("json").map(|limit| limit.as_u64()).unwrap_or(u64::MAX).saturating_add(1);
+        let (parts, mut request_body) = request.into_parts();
+        let uri = parts.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/").to_string();
+        let mut local = client.req(method, uri);
+        for (name, value) in &parts.headers {
+            if let Ok(value) = value.to_str() {
+                local.add_header(Header::new(name.as_str().to_string(), value.to_string()));
+            }
+        }
+        if let Some(remote) = remote {
+            local = local.remote(remote);
+        }
+        let mut data = Vec::new();
+        while (data.len() as u64) < limit {
+            match request_body.data().await {
+                Some(Ok(chunk)) => data.extend_from_slice(&chunk[..chunk.len().min((limit - data.len() as u64) as usize)]),
+                Some(Err(e)) => {
+                    debug!("Could not read the request body: {}", e);
+                    return;
+                },
+                None => break
+            }
+        }
+        local.set_body(data);
+
+        let mut response = local.dispatch().await;
+        let headers = response.headers().iter().map(|header| (header.name().to_string(), header.value().to_string())).collect();
+        if head_tx.send((response.status().code, headers)).is_err() {
+            return;
+        }
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            match response.read(&mut buffer).await {
+                Ok(0) => break,
+                // The client disconnected
+                Ok(read) => if body_tx.send_data(Bytes::copy_from_slice(&buffer[..read])).await.is_err() {
+                    break;
+                },
+                Err(e) => {
+                    debug!("Could not read the response body: {}", e);
+                    body_tx.abort();
+                    break;
+                }
+            }
+        }
+    });
+
+    let Ok((status, headers)) = head_rx.await else {
+        let mut response = hyper::Response::new(hyper::Body::empty());
+        *response.status_mut() = hyper::StatusCode::BAD_REQUEST;
+        return Ok(response);
+    };
+    let mut response = hyper::Response::builder().status(status);
+    for (name, value) in headers {
+        response = response.header(name, value);
+    }
+    Ok(response.body(body).unwrap_or_else(|e| {
+        error!("Could not send the response: {}", e);
+        let mut response = hyper::Response::new(hyper::Body::empty());
+        *response.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+        response
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::get;
+    use rocket::response::stream::TextStream;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::UnixStream;
+    use super::*;
+    use crate::config::ConfigManager;
+
+    #[get("/hello")]
+    fn hello(remote: Option<SocketAddr>) -> String {
+        format!("hello {:?}", remote)
+    }
+
+    #[get("/stream")]
+    fn stream() -> TextStream![&'static str] {
+        TextStream! {
+            yield "first ";
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            yield "second";
+        }
+    }
+
+    async fn get(path: &Path, uri: &str) -> String {
+        let mut stream = UnixStream::connect(path).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", uri).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn requests_are_served_on_the_socket() {
+        let path = std::env::temp_dir().join(format!("flashforge-api-{}.sock", std::process::id()));
+        let config = ConfigManager::from_toml(&format!("[http]\nunix_socket = {:?}\nunix_socket_mode = \"600\"\n[printers]", path));
+        // One left behind by a run that didn't shut down cleanly
+        drop(UnixSocket::bind(&path, config.http()).unwrap());
+        let socket = UnixSocket::bind(&path, config.http()).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let rocket = rocket::custom(rocket::Config::debug_default()).mount("/", rocket::routes![hello, stream]);
+        let client = Arc::new(Client::untracked(rocket).await.unwrap());
+        let shutdown = client.rocket().shutdown();
+        let served = tokio::spawn(socket.accept(client, None));
+
+        let response = get(&path, "/hello").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("hello None"), "{}", response);
+        let response = get(&path, "/stream").await;
+        assert!(response.contains("first ") && response.contains("second"), "{}", response);
+        assert!(get(&path, "/missing").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        shutdown.notify();
+        served.await.unwrap();
+        assert!(!path.exists());
+
+        std::fs::write(&path, "").unwrap();
+        assert!(UnixSocket::bind(&path, config.http()).unwrap_err().ends_with("exists and is not a socket"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+//! JSON bodies of the routes that change something. Bodies are parsed into their request struct and checked field by
+//! field before the route runs, any problem is answered with the codes of the catchers along with `details` listing
+//! each field that is wrong
+use std::fmt::Display;
+use std::ops::{Deref, RangeInclusive};
+use rocket::data::{self, Data, FromData, Limits};
+use rocket::http::Status;
+use rocket::Request;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use time::OffsetDateTime;
+use crate::errors::ErrorCode;
+use crate::models::{DestinationEnabled, FieldError, GenericError, MaintenanceMode, PrintFactor, PrinterPatch, TestNotificationRequest};
+use crate::notifications::NotificationType;
+
+/// Speeds and flows that can be set, in percent of the file's
+pub const PRINT_FACTOR_PERCENT: RangeInclusive<u16> = 10..=300;
+/// Of a printer's display_name and location
+pub const MAX_LABEL_CHARS: usize = 100;
+
+/// The checks serde can't do, such as ranges
+pub trait Validate {
+    fn validate(&self, errors: &mut FieldErrors);
+}
+
+#[derive(Default)]
+pub struct FieldErrors(Vec<FieldError>);
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &str, message: impl Into<String>) {
+        self.0.push(FieldError { field: Some(field.to_string()), message: message.into() });
+    }
+
+    pub fn in_range<T: PartialOrd + Display>(&mut self, field: &str, value: T, range: &RangeInclusive<T>) {
+        if !range.contains(&value) {
+            self.add(field, format!("must be between {} and {}", range.start(), range.end()));
+        }
+    }
+
+    pub fn not_empty(&mut self, field: &str, value: &str) {
+        if value.trim().is_empty() {
+            self.add(field, "must not be empty");
+        }
+    }
+
+    pub fn max_chars(&mut self, field: &str, value: Option<&str>, max: usize) {
+        if value.is_some_and(|value| value.chars().count() > max) {
+            self.add(field, format!("must be at most {} characters", max));
+        }
+    }
+
+    pub fn one_of(&mut self, field: &str, value: &str, allowed: &[&str]) {
+        if !allowed.contains(&value) {
+            self.add(field, format!("must be one of {}", allowed.join(", ")));
+        }
+    }
+}
+
+/// A JSON body that was parsed and passed [Validate], rejected ones are answered 400, 413 or 422
+pub struct Json<T>(pub T);
+
+impl<T> Json<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Json<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// Why the body was rejected, for the catcher of its status
+struct RejectedBody(Option<GenericError>);
+
+/// The error body of a request whose [Json] was rejected
+pub fn rejected_body(request: &Request) -> Option<GenericError> {
+    request.local_cache(|| RejectedBody(None)).0.clone()
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned + Validate> FromData<'r> for Json<T> {
+    type Error = GenericError;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+        let parsed = match data.open(limit).into_bytes().await {
+            Ok(body) if body.is_complete() => parse(&body),
+            Ok(_) => Err((Status::PayloadTooLarge, ErrorCode::PayloadTooLarge.with_message(format!("the body is over the {} limit of JSON bodies", limit)))),
+            Err(e) => Err((Status::BadRequest, ErrorCode::BadRequest.with_message(format!("the body could not be read: {}", e))))
+        };
+        match parsed {
+            Ok(value) => data::Outcome::Success(Json(value)),
+            Err((status, error)) => {
+                request.local_cache(|| RejectedBody(Some(error.clone())));
+                data::Outcome::Error((status, error))
+            }
+        }
+    }
+}
+
+/// Syntax errors are a 400, missing fields, wrong types and values failing [Validate] a 422
+fn parse<T: DeserializeOwned + Validate>(body: &[u8]) -> Result<T, (Status, GenericError)> {
+    let value: Value = serde_json::from_slice(body)
+        .map_err(|e| (Status::BadRequest, ErrorCode::BadRequest.with_message(format!("the body is not JSON: {}", e))))?;
+    let mut errors = FieldErrors::default();
+    match T::deserialize(&value) {
+        Ok(parsed) => {
+            parsed.validate(&mut errors);
+            if errors.0.is_empty() {
+                return Ok(parsed);
+            }
+        },
+        Err(e) => {
+            let error = match missing_field(&e) {
+                Some(field) => FieldError { field: Some(field), message: "is required".to_string() },
+                None => FieldError { field: value.as_object().and_then(field_of::<T>), message: e.to_string() }
+            };
+            errors.0.push(error);
+        }
+    }
+    let summary: Vec<String> = errors.0.iter()
+        .map(|error| match &error.field {
+            Some(field) => format!("{} {}", field, error.message),
+            None => error.message.clone()
+        })
+        .collect();
+    let mut error = ErrorCode::UnprocessableRequest.with_message(summary.join(", "));
+    error.details = errors.0;
+    Err((Status::UnprocessableEntity, error))
+}
+
+/// As worded by serde's Error::missing_field, serde_json adds no position to errors of values
+fn missing_field(e: &serde_json::Error) -> Option<String> {
+    e.to_string().strip_prefix("missing field `")?.strip_suffix('`').map(str::to_string)
+}
+
+/// Serde stops at the first error without telling the field it was in. In an object it is the one without which the
+/// body parses or only misses that field, None when several are wrong
+fn field_of<T: DeserializeOwned>(object: &Map<String, Value>) -> Option<String> {
+    object.keys().find(|key| {
+        let mut without = object.clone();
+        without.remove(*key);
+        match T::deserialize(&Value::Object(without)) {
+            Ok(_) => true,
+            Err(e) => missing_field(&e).as_ref() == Some(*key)
+        }
+    }).cloned()
+}
+
+impl Validate for PrintFactor {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.in_range("percent", self.percent, &PRINT_FACTOR_PERCENT);
+    }
+}
+
+impl Validate for MaintenanceMode {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if self.enabled && self.until.is_some_and(|until| until <= OffsetDateTime::now_utc()) {
+            errors.add("until", "is in the past");
+        }
+    }
+}
+
+impl Validate for PrinterPatch {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.max_chars("display_name", self.display_name.as_ref().and_then(Option::as_deref), MAX_LABEL_CHARS);
+        errors.max_chars("location", self.location.as_ref().and_then(Option::as_deref), MAX_LABEL_CHARS);
+    }
+}
+
+impl Validate for DestinationEnabled {
+    fn validate(&self, _: &mut FieldErrors) {}
+}
+
+impl Validate for TestNotificationRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.not_empty("printer", &self.printer);
+        errors.one_of("type", &self.notification_type, &NotificationType::NAMES);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected<T: DeserializeOwned + Validate>(body: &str) -> (Status, GenericError) {
+        parse::<T>(body.as_bytes()).err().expect("the body should be rejected")
+    }
+
+    fn fields(error: &GenericError) -> Vec<(Option<&str>, &str)> {
+        error.details.iter().map(|error| (error.field.as_deref(), error.message.as_str())).collect()
+    }
+
+    #[test]
+    fn missing_fields_and_wrong_types_name_the_field() {
+        let (status, error) = rejected::<PrintFactor>("{}");
+        assert_eq!((status, error.error.as_str()), (Status::UnprocessableEntity, "UNPROCESSABLE_REQUEST"));
+        assert_eq!(fields(&error), [(Some("percent"), "is required")]);
+        assert_eq!(error.message.as_deref(), Some("percent is required"));
+
+        let (_, error) = rejected::<MaintenanceMode>(r#"{"enabled": true, "until": 5}"#);
+        assert_eq!(fields(&error)[0].0, Some("until"));
+        let (_, error) = rejected::<PrinterPatch>(r#"{"location": "Lab 2", "display_name": ["Door"]}"#);
+        assert_eq!(fields(&error)[0].0, Some("display_name"));
+        // Not an object
+        let (_, error) = rejected::<PrintFactor>("110");
+        assert_eq!(fields(&error)[0].0, None);
+
+        let (status, error) = rejected::<PrintFactor>(r#"{"percent": "#);
+        assert_eq!((status, error.error.as_str()), (Status::BadRequest, "BAD_REQUEST"));
+        assert!(error.details.is_empty());
+    }
+
+    #[test]
+    fn out_of_range_values_are_all_listed() {
+        let (_, error) = rejected::<PrintFactor>(r#"{"percent": 400}"#);
+        assert_eq!(fields(&error), [(Some("percent"), "must be between 10 and 300")]);
+        let (_, error) = rejected::<PrintFactor>(r#"{"percent": -5}"#);
+        assert_eq!(fields(&error)[0].0, Some("percent"));
+
+        let (_, error) = rejected::<TestNotificationRequest>(r#"{"printer": " ", "type": "print_done"}"#);
+        assert_eq!(fields(&error), [
+            (Some("printer"), "must not be empty"),
+            (Some("type"), "must be one of print_started, print_complete, print_error, print_cancelled, print_failed, temperature_alert, temperature_recovered")
+        ]);
+        let (_, error) = rejected::<PrinterPatch>(&format!(r#"{{"display_name": "{}"}}"#, "x".repeat(MAX_LABEL_CHARS + 1)));
+        assert_eq!(fields(&error), [(Some("display_name"), "must be at most 100 characters")]);
+        let (_, error) = rejected::<MaintenanceMode>(r#"{"enabled": true, "until": "2020-01-01T00:00:00Z"}"#);
+        assert_eq!(fields(&error), [(Some("until"), "is in the past")]);
+
+        assert!(parse::<PrintFactor>(br#"{"percent": 110}"#).is_ok());
+        assert!(parse::<MaintenanceMode>(br#"{"enabled": false, "until": "2020-01-01T00:00:00Z"}"#).is_ok());
+    }
+}
@@ -0,0 +1,79 @@
+//! What is running, gathered at compile time by build.rs
+use std::fmt::{Display, Formatter};
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct VersionInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    /// Empty when not built from a git checkout
+    pub git_commit: &'static str,
+    /// Whether tracked files had uncommitted changes
+    pub git_dirty: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub built_at: OffsetDateTime,
+    pub rustc_version: &'static str,
+    pub features: Vec<&'static str>
+}
+
+pub fn info() -> VersionInfo {
+    VersionInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: VERSION,
+        git_commit: env!("BUILD_GIT_COMMIT"),
+        git_dirty: env!("BUILD_GIT_DIRTY") == "true",
+        built_at: env!("BUILD_TIMESTAMP").parse().ok()
+            .and_then(|timestamp| OffsetDateTime::from_unix_timestamp(timestamp).ok())
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH),
+        rustc_version: env!("BUILD_RUSTC_VERSION"),
+        features: env!("BUILD_FEATURES").split(',').filter(|feature| !feature.is_empty()).collect()
+    }
+}
+
+/// Sent by the webhook client, with the same version as /api/version
+pub fn user_agent() -> String {
+    format!("jackzmc/{} {}", env!("CARGO_PKG_NAME"), VERSION)
+}
+
+/// "flashforge-api-server 0.1.0 (1a2b3c4d5e6f-dirty, built 2024-06-01T12:00:00Z, rustc 1.83.0 ...)"
+impl Display for VersionInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} (", self.name, self.version)?;
+        match (self.git_commit.get(..12), self.git_dirty) {
+            (Some(commit), true) => write!(f, "{}-dirty, ", commit)?,
+            (Some(commit), false) => write!(f, "{}, ", commit)?,
+            (None, _) => write!(f, "unknown commit, ")?
+        }
+        write!(f, "built {}, {}", self.built_at.format(&Rfc3339).unwrap_or_default(), self.rustc_version)?;
+        if !self.features.is_empty() {
+            write!(f, ", features {}", self.features.join(","))?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn displays_the_short_commit() {
+        let info = VersionInfo {
+            name: "flashforge-api-server",
+            version: "0.1.0",
+            git_commit: "1a2b3c4d5e6f7a8b9c0d1a2b3c4d5e6f7a8b9c0d",
+            git_dirty: true,
+            built_at: datetime!(2024-06-01 12:00 UTC),
+            rustc_version: "rustc 1.83.0",
+            features: vec!["camera", "mqtt"]
+        };
+        assert_eq!(info.to_string(), "flashforge-api-server 0.1.0 (1a2b3c4d5e6f-dirty, built 2024-06-01T12:00:00Z, rustc 1.83.0, features camera,mqtt)");
+        let info = VersionInfo { git_commit: "", features: vec![], ..info };
+        assert_eq!(info.to_string(), "flashforge-api-server 0.1.0 (unknown commit, built 2024-06-01T12:00:00Z, rustc 1.83.0)");
+    }
+}
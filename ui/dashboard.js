@@ -0,0 +1,80 @@
+// Shared by the dashboard pages, only uses the public API
+const SNAPSHOT_INTERVAL = 10000;
+const REFRESH_INTERVAL = 5000;
+
+async function api(path) {
+    const secret = localStorage.getItem("secret");
+    const res = await fetch(`/api/printers${path}`, { headers: secret ? { "x-secret": secret } : {} });
+    if (res.status === 401 || res.status === 403) {
+        document.getElementById("secret-form").hidden = false;
+    }
+    if (!res.ok) {
+        throw new Error(`${path} returned ${res.status}`);
+    }
+    return snakeKeys(await res.json());
+}
+
+// Responses are camelCase with http.json_case = "camel", the pages read snake_case
+function snakeKeys(value) {
+    if (Array.isArray(value)) return value.map(snakeKeys);
+    if (value === null || typeof value !== "object") return value;
+    return Object.fromEntries(Object.entries(value).map(([key, v]) => [key.replace(/([a-z0-9])([A-Z])/g, (_, before, upper) => `${before}_${upper.toLowerCase()}`), snakeKeys(v)]));
+}
+
+function setupSecretForm() {
+    const form = document.getElementById("secret-form");
+    form.addEventListener("submit", (e) => {
+        e.preventDefault();
+        localStorage.setItem("secret", form.elements.secret.value);
+        form.hidden = true;
+    });
+}
+
+function el(tag, props = {}, children = []) {
+    const element = Object.assign(document.createElement(tag), props);
+    element.append(...children);
+    return element;
+}
+
+function formatTemp(measurement) {
+    return measurement ? `${measurement.current.toFixed(0)}° / ${measurement.target.toFixed(0)}°` : "-";
+}
+
+function formatTemps(temps) {
+    const parts = temps.extruders.map((t, i) => `T${i} ${formatTemp(t)}`);
+    if (temps.bed) parts.push(`Bed ${formatTemp(temps.bed)}`);
+    if (temps.chamber) parts.push(`Chamber ${formatTemp(temps.chamber)}`);
+    return parts.join(", ");
+}
+
+function progressBar(percent) {
+    return el("div", { className: "progress", title: `${percent ?? 0}%` }, [
+        el("div", { style: `width: ${percent ?? 0}%` })
+    ]);
+}
+
+function stateBadge(state) {
+    return el("span", { className: `state ${state}`, textContent: state });
+}
+
+function printerCard(printer, detailed) {
+    const id = encodeURIComponent(printer.name);
+    const name = printer.display_name ?? printer.name;
+    const title = detailed ? name : el("a", { href: `/printers/${id}`, textContent: name });
+    const card = el("div", { className: "card" }, [
+        el("h2", {}, [title, " ", stateBadge(printer.state)]),
+        el("div", { className: "muted", textContent: [printer.location, printer.model_name, printer.firmware_version].filter(Boolean).join(" · ") }),
+        el("div", { textContent: printer.current_file ? `Printing ${printer.current_file}` : "Idle" }),
+    ]);
+    if (printer.current_file) {
+        card.append(progressBar(printer.progress_percent), el("div", { className: "muted", textContent: `${printer.progress_percent ?? 0}%` }));
+    }
+    const temps = el("div", { className: "temps muted" });
+    card.append(temps);
+    if (printer.is_online) {
+        api(`/${id}/temperatures?normalized=true`)
+            .then((t) => temps.textContent = formatTemps(t))
+            .catch(() => temps.textContent = "Temperatures unavailable");
+    }
+    return card;
+}